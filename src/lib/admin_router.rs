@@ -0,0 +1,165 @@
+//! Admin Router Module
+//! 
+//! This module provides queue administration routes. They require a valid JWT
+//! token whose subject has the `admin` role. It includes:
+//! - Listing the messages of a dead-letter queue
+//! - Redriving dead-lettered messages back to their source queue
+
+use crate::app_error::{AppError, AppJson, AppPath, AppQuery, Problem};
+use crate::app_state::MyAppState;
+use crate::auth_claim_mid::auth;
+use crate::authorization::RequireRole;
+use crate::queue::QueuedMessage;
+use axum::middleware::{self};
+use axum::{Extension, Json};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use utoipa::{IntoParams, ToSchema};
+use utoipa_axum::router::OpenApiRouter;
+use utoipa_axum::routes;
+
+/// Most dead letters listed by default
+const DEFAULT_LIMIT: usize = 100;
+
+/// Creates a new router with the admin routes
+/// 
+/// # Returns
+/// 
+/// A configured `OpenApiRouter` requiring the `admin` role on every route
+pub fn router() -> OpenApiRouter {
+    OpenApiRouter::new()
+        .routes(routes!(dead_letters))
+        .routes(routes!(redrive))
+        .route_layer(RequireRole::new("admin"))
+        .layer(middleware::from_fn(auth))
+}
+
+/// Query parameters of the dead letter listing
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct DeadLetterParams {
+    /// Most messages to list, defaults to 100
+    pub limit: Option<usize>,
+}
+
+/// A message waiting in a dead-letter queue
+#[derive(Debug, Serialize, ToSchema)]
+pub struct DeadLetter {
+    pub message_id: String,
+    pub body: String,
+    pub attributes: HashMap<String, String>,
+    /// Receives before the message was dead-lettered
+    pub receive_count: u32,
+    pub sent_at: DateTime<Utc>,
+    pub first_received_at: Option<DateTime<Utc>>,
+    /// Queue the message was moved from, and is redriven to
+    pub source_queue: Option<String>,
+    /// Message group of a FIFO message
+    pub group_id: Option<String>,
+    /// Sequence number of a FIFO message
+    pub sequence_number: Option<String>,
+}
+
+impl From<QueuedMessage> for DeadLetter {
+    fn from(message: QueuedMessage) -> Self {
+        Self {
+            message_id: message.message_id,
+            body: message.body,
+            attributes: message.attributes,
+            receive_count: message.receive_count,
+            sent_at: message.sent_at,
+            first_received_at: message.first_received_at,
+            source_queue: message.source_queue,
+            group_id: message.group_id,
+            sequence_number: message.sequence_number,
+        }
+    }
+}
+
+/// Redrive request payload
+#[derive(Debug, Default, Deserialize, ToSchema)]
+pub struct RedriveRequest {
+    /// Only messages carrying all of these attribute values are moved; empty moves all
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
+}
+
+/// Redrive response body
+#[derive(Debug, Serialize, ToSchema)]
+pub struct RedriveResponse {
+    /// How many messages were moved back
+    pub moved: usize,
+}
+
+/// Lists the messages of a dead-letter queue without receiving them
+/// 
+/// # Arguments
+/// 
+/// * `Extension(state)` - The application state holding the message queue
+/// * `AppPath(name)` - The dead-letter queue
+/// * `AppQuery(params)` - How many messages to list
+/// 
+/// # Returns
+/// 
+/// A `Result` containing either:
+/// * `Ok(Json<Vec<DeadLetter>>)` - The messages in queue order
+/// * `Err(AppError)` - If the queue does not exist
+#[utoipa::path(
+    get,
+    path = "/queues/{name}/dead-letters",
+    tag = "admin",
+    params(("name" = String, Path, description = "Dead-letter queue name"), DeadLetterParams),
+    responses(
+        (status = 200, description = "Messages in the dead-letter queue", body = [DeadLetter]),
+        (status = 403, description = "Subject lacks the `admin` role", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Queue does not exist", body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn dead_letters(
+    Extension(state): Extension<MyAppState>,
+    AppPath(name): AppPath<String>,
+    AppQuery(params): AppQuery<DeadLetterParams>,
+) -> Result<Json<Vec<DeadLetter>>, AppError> {
+    let limit = params.limit.unwrap_or(DEFAULT_LIMIT);
+    let messages = state.queue.peek(&name, limit).await?;
+    Ok(Json(messages.into_iter().map(DeadLetter::from).collect()))
+}
+
+/// Moves dead-lettered messages back to their source queue
+/// 
+/// # Arguments
+/// 
+/// * `Extension(state)` - The application state holding the message queue
+/// * `AppPath(name)` - The dead-letter queue
+/// * `payload` - Optional attribute filter; without it every message is moved
+/// 
+/// # Returns
+/// 
+/// A `Result` containing either:
+/// * `Ok(Json<RedriveResponse>)` - How many messages were moved
+/// * `Err(AppError)` - If the queue does not exist
+#[utoipa::path(
+    post,
+    path = "/queues/{name}/redrive",
+    tag = "admin",
+    params(("name" = String, Path, description = "Dead-letter queue name")),
+    request_body(content = Option<RedriveRequest>, description = "Optional attribute filter"),
+    responses(
+        (status = 200, description = "Messages moved back", body = RedriveResponse),
+        (status = 403, description = "Subject lacks the `admin` role", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Queue does not exist", body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn redrive(
+    Extension(state): Extension<MyAppState>,
+    AppPath(name): AppPath<String>,
+    payload: Option<AppJson<RedriveRequest>>,
+) -> Result<Json<RedriveResponse>, AppError> {
+    let request = payload.map(|AppJson(request)| request).unwrap_or_default();
+    let moved = state.queue.redrive(&name, &request.attributes).await?;
+    tracing::info!(queue = name, moved, "dead letters redriven");
+    Ok(Json(RedriveResponse { moved }))
+}
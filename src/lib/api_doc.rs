@@ -0,0 +1,79 @@
+//! API Documentation Module
+//!
+//! This module assembles the OpenAPI document describing the server. Handlers
+//! carry `#[utoipa::path]` annotations and are registered with `OpenApiRouter`,
+//! so the document is built from the same routes that serve requests.
+//!
+//! The document is served at `/openapi.json` and rendered with Scalar at `/docs`.
+
+use crate::auth_claim::Claims;
+use crate::input_schemas::Pagination;
+use axum::{Json, Router, routing::get};
+use utoipa::openapi::OpenApi as OpenApiDocument;
+use utoipa::openapi::security::{HttpAuthScheme, HttpBuilder, SecurityScheme};
+use utoipa::{Modify, OpenApi};
+use utoipa_scalar::{Scalar, Servable};
+
+/// Name of the bearer token security scheme, as referenced by `security(("bearer_auth" = []))`
+pub const BEARER_AUTH: &str = "bearer_auth";
+
+/// Base OpenAPI document that the routers add their paths to
+/// 
+/// Schemas not reachable from any operation, such as the claims carried by an
+/// access token, are listed here explicitly.
+#[derive(OpenApi)]
+#[openapi(
+    info(
+        title = "axum-sqs-example",
+        description = "Axum web server with JWT authentication"
+    ),
+    components(schemas(Claims, Pagination)),
+    modifiers(&BearerSecurity),
+    tags(
+        (name = "auth", description = "Token issuing, refresh and revocation"),
+        (name = "protected", description = "Endpoints requiring a bearer token"),
+        (name = "admin", description = "Queue administration, requiring the `admin` role"),
+        (name = "users", description = "User lookup examples"),
+        (name = "health", description = "Liveness and readiness probes"),
+        (name = "metrics", description = "Prometheus metrics"),
+        (name = "extractors", description = "Request extractor examples")
+    )
+)]
+pub struct ApiDoc;
+
+/// Adds the bearer token security scheme to the document
+struct BearerSecurity;
+
+impl Modify for BearerSecurity {
+    fn modify(&self, openapi: &mut OpenApiDocument) {
+        let components = openapi.components.get_or_insert_with(Default::default);
+        components.add_security_scheme(
+            BEARER_AUTH,
+            SecurityScheme::Http(
+                HttpBuilder::new()
+                    .scheme(HttpAuthScheme::Bearer)
+                    .bearer_format("JWT")
+                    .description(Some("Access token issued by `/authorization`"))
+                    .build(),
+            ),
+        );
+    }
+}
+
+/// Creates the routes serving the OpenAPI document and its Scalar page
+/// 
+/// # Arguments
+/// 
+/// * `openapi` - The document collected from the application routes
+/// 
+/// # Returns
+/// 
+/// A `Router` serving `/openapi.json` and `/docs`
+pub fn router(openapi: OpenApiDocument) -> Router {
+    Router::new()
+        .route("/openapi.json", get({
+            let openapi = openapi.clone();
+            move || async move { Json(openapi) }
+        }))
+        .merge(Scalar::with_url("/docs", openapi))
+}
@@ -0,0 +1,306 @@
+//! Application Error Module
+//!
+//! This module provides the single error type returned by every handler,
+//! extractor and middleware in the crate. Errors are rendered as RFC 9457
+//! `application/problem+json` documents:
+//!
+//! ```json
+//! {
+//!   "type": "/problems/invalid-token",
+//!   "title": "Invalid token",
+//!   "status": 401,
+//!   "detail": "The bearer token is missing, malformed, expired or not signed by a known key",
+//!   "request_id": "5f0c3a9e-..."
+//! }
+//! ```
+//!
+//! It also provides `AppJson`, `AppPath` and `AppQuery`, drop-in replacements for
+//! axum's extractors whose rejections are rendered the same way.
+
+use crate::auth_claim::AuthError;
+use crate::metrics::AuthFailure;
+use crate::outbox::OutboxError;
+use crate::queue::QueueError;
+use crate::request_id::current_request_id;
+use axum::extract::rejection::{JsonRejection, PathRejection, QueryRejection};
+use axum::extract::{FromRequest, FromRequestParts, OptionalFromRequest, Request};
+use axum::http::{HeaderValue, StatusCode, header};
+use axum::response::{IntoResponse, Response};
+use serde::Serialize;
+use serde_json::{Map, Value};
+use std::fmt::Display;
+use utoipa::ToSchema;
+
+/// Media type of problem detail responses
+pub const PROBLEM_JSON: &str = "application/problem+json";
+
+/// Application error types
+#[derive(Debug)]
+pub enum AppError {
+    /// Authentication or authorization failed
+    Auth(AuthError),
+    /// The request could not be understood
+    BadRequest(String),
+    /// An extractor rejected the request; the status comes from the rejection
+    InvalidRequest(StatusCode, String),
+    /// No route or resource matches the request
+    NotFound(String),
+    /// The route exists but not for this method
+    MethodNotAllowed,
+    /// Something failed on the server side; the message is logged, not returned
+    Internal(String),
+}
+
+impl Display for AppError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AppError::Auth(e) => write!(f, "{}", e),
+            AppError::BadRequest(detail) => write!(f, "bad request: {}", detail),
+            AppError::InvalidRequest(status, detail) => write!(f, "{}: {}", status, detail),
+            AppError::NotFound(detail) => write!(f, "not found: {}", detail),
+            AppError::MethodNotAllowed => write!(f, "method not allowed"),
+            AppError::Internal(detail) => write!(f, "internal error: {}", detail),
+        }
+    }
+}
+
+impl std::error::Error for AppError {}
+
+impl From<AuthError> for AppError {
+    fn from(e: AuthError) -> Self {
+        AppError::Auth(e)
+    }
+}
+
+/// Queue errors caused by the request become client errors, the rest internal ones
+impl From<QueueError> for AppError {
+    fn from(e: QueueError) -> Self {
+        match e {
+            QueueError::QueueDoesNotExist(_) => AppError::NotFound(e.to_string()),
+            QueueError::QueueNameExists(_)
+            | QueueError::ReceiptHandleIsInvalid
+            | QueueError::InvalidParameter(_) => AppError::BadRequest(e.to_string()),
+            QueueError::Backend(_) => AppError::Internal(e.to_string()),
+        }
+    }
+}
+
+impl From<OutboxError> for AppError {
+    fn from(e: OutboxError) -> Self {
+        AppError::Internal(e.to_string())
+    }
+}
+
+impl From<JsonRejection> for AppError {
+    fn from(rejection: JsonRejection) -> Self {
+        AppError::InvalidRequest(rejection.status(), rejection.body_text())
+    }
+}
+
+impl From<PathRejection> for AppError {
+    fn from(rejection: PathRejection) -> Self {
+        AppError::InvalidRequest(rejection.status(), rejection.body_text())
+    }
+}
+
+impl From<QueryRejection> for AppError {
+    fn from(rejection: QueryRejection) -> Self {
+        AppError::InvalidRequest(rejection.status(), rejection.body_text())
+    }
+}
+
+/// RFC 9457 problem details document
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Problem {
+    /// URI reference identifying the problem type
+    #[serde(rename = "type")]
+    pub problem_type: String,
+    /// Short summary of the problem type
+    pub title: String,
+    /// HTTP status code
+    pub status: u16,
+    /// Explanation specific to this occurrence
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub detail: Option<String>,
+    /// Identifier of the request, as echoed in the `x-request-id` header
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub request_id: Option<String>,
+    /// Problem type specific members, such as `required_scope`
+    #[serde(flatten)]
+    #[schema(ignore)]
+    pub extensions: Map<String, Value>,
+}
+
+impl Problem {
+    /// Creates a problem for the current request
+    ///
+    /// # Arguments
+    ///
+    /// * `status` - The HTTP status of the response
+    /// * `slug` - Short kebab-case name of the problem type, e.g. `invalid-token`
+    /// * `title` - Short summary of the problem type
+    /// * `detail` - Explanation specific to this occurrence
+    pub fn new(status: StatusCode, slug: &str, title: &str, detail: Option<String>) -> Self {
+        Self {
+            problem_type: format!("/problems/{}", slug),
+            title: title.to_owned(),
+            status: status.as_u16(),
+            detail,
+            request_id: current_request_id(),
+            extensions: Map::new(),
+        }
+    }
+
+    /// Adds a problem type specific member
+    pub fn with(mut self, key: &str, value: impl Into<Value>) -> Self {
+        self.extensions.insert(key.to_owned(), value.into());
+        self
+    }
+}
+
+impl IntoResponse for Problem {
+    fn into_response(self) -> Response {
+        let status = StatusCode::from_u16(self.status).unwrap_or(StatusCode::INTERNAL_SERVER_ERROR);
+        let body = serde_json::to_vec(&self).unwrap_or_default();
+        (
+            status,
+            [(header::CONTENT_TYPE, HeaderValue::from_static(PROBLEM_JSON))],
+            body,
+        )
+            .into_response()
+    }
+}
+
+/// Converts authentication errors into problems
+///
+/// Every 401 carries a `WWW-Authenticate: Bearer` challenge; a missing scope is
+/// reported with `error="insufficient_scope"` as RFC 6750 describes.
+fn auth_problem(e: AuthError) -> Response {
+    let detail = Some(e.to_string());
+    let (status, slug, title) = match &e {
+        AuthError::WrongCredentials => (StatusCode::UNAUTHORIZED, "wrong-credentials", "Wrong credentials"),
+        AuthError::MissingCredentials => (StatusCode::BAD_REQUEST, "missing-credentials", "Missing credentials"),
+        AuthError::TokenCreation => (StatusCode::INTERNAL_SERVER_ERROR, "token-creation", "Token creation error"),
+        AuthError::InvalidToken => (StatusCode::UNAUTHORIZED, "invalid-token", "Invalid token"),
+        AuthError::InvalidRefreshToken => {
+            (StatusCode::UNAUTHORIZED, "invalid-refresh-token", "Invalid refresh token")
+        }
+        AuthError::ClientStore => (StatusCode::INTERNAL_SERVER_ERROR, "client-store", "Client store error"),
+        AuthError::RevokedToken => (StatusCode::UNAUTHORIZED, "revoked-token", "Token has been revoked"),
+        AuthError::RevocationStore => {
+            (StatusCode::INTERNAL_SERVER_ERROR, "revocation-store", "Revocation list error")
+        }
+        AuthError::Misconfigured => {
+            (StatusCode::INTERNAL_SERVER_ERROR, "misconfigured", "Authentication is not configured")
+        }
+        AuthError::InsufficientScope(_) => (StatusCode::FORBIDDEN, "insufficient-scope", "Insufficient scope"),
+        AuthError::MissingRole(_) => (StatusCode::FORBIDDEN, "missing-role", "Missing role"),
+        AuthError::MissingClientCertificate => {
+            (StatusCode::FORBIDDEN, "client-certificate-required", "Client certificate required")
+        }
+        AuthError::QueueNotAllowed(_) => (StatusCode::FORBIDDEN, "queue-not-allowed", "Queue not allowed"),
+    };
+    let problem = Problem::new(status, slug, title, detail);
+
+    let mut response = match e {
+        AuthError::InsufficientScope(scope) => {
+            let challenge = format!("Bearer error=\"insufficient_scope\", scope=\"{}\"", scope);
+            let problem = problem.with("required_scope", scope);
+            ([(header::WWW_AUTHENTICATE, challenge)], problem).into_response()
+        }
+        AuthError::MissingRole(role) => problem.with("required_role", role).into_response(),
+        AuthError::InvalidToken | AuthError::RevokedToken => {
+            let challenge = "Bearer error=\"invalid_token\"";
+            ([(header::WWW_AUTHENTICATE, challenge)], problem).into_response()
+        }
+        _ if status == StatusCode::UNAUTHORIZED => {
+            ([(header::WWW_AUTHENTICATE, "Bearer")], problem).into_response()
+        }
+        _ => problem.into_response(),
+    };
+    // Rejected credentials are counted in `auth_failures_total` by the metrics layer
+    if status.is_client_error() {
+        response.extensions_mut().insert(AuthFailure { reason: slug });
+    }
+    response
+}
+
+/// Implementation of `IntoResponse` for `AppError`
+///
+/// Renders every error as `application/problem+json`
+impl IntoResponse for AppError {
+    fn into_response(self) -> Response {
+        match self {
+            AppError::Auth(e) => auth_problem(e),
+            AppError::BadRequest(detail) => {
+                Problem::new(StatusCode::BAD_REQUEST, "bad-request", "Bad request", Some(detail))
+                    .into_response()
+            }
+            AppError::InvalidRequest(status, detail) => {
+                let title = status.canonical_reason().unwrap_or("Invalid request");
+                Problem::new(status, "invalid-request", title, Some(detail)).into_response()
+            }
+            AppError::NotFound(detail) => {
+                Problem::new(StatusCode::NOT_FOUND, "not-found", "Not found", Some(detail))
+                    .into_response()
+            }
+            AppError::MethodNotAllowed => Problem::new(
+                StatusCode::METHOD_NOT_ALLOWED,
+                "method-not-allowed",
+                "Method not allowed",
+                None,
+            )
+            .into_response(),
+            AppError::Internal(detail) => {
+                tracing::error!(request_id = current_request_id(), "internal error: {}", detail);
+                Problem::new(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "internal",
+                    "Internal server error",
+                    None,
+                )
+                .into_response()
+            }
+        }
+    }
+}
+
+/// Fallback handler for unknown routes
+pub async fn not_found(req: Request) -> AppError {
+    AppError::NotFound(format!("no route for {}", req.uri().path()))
+}
+
+/// Fallback handler for known routes called with the wrong method
+pub async fn method_not_allowed() -> AppError {
+    AppError::MethodNotAllowed
+}
+
+/// JSON body extractor rejecting with `AppError`
+#[derive(Debug, FromRequest)]
+#[from_request(via(axum::Json), rejection(AppError))]
+pub struct AppJson<T>(pub T);
+
+impl<T, S> OptionalFromRequest<S> for AppJson<T>
+where
+    axum::Json<T>: OptionalFromRequest<S, Rejection = JsonRejection>,
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request(req: Request, state: &S) -> Result<Option<Self>, Self::Rejection> {
+        <axum::Json<T> as OptionalFromRequest<S>>::from_request(req, state)
+            .await
+            .map(|json| json.map(|axum::Json(value)| AppJson(value)))
+            .map_err(AppError::from)
+    }
+}
+
+/// Path parameter extractor rejecting with `AppError`
+#[derive(Debug, FromRequestParts)]
+#[from_request(via(axum::extract::Path), rejection(AppError))]
+pub struct AppPath<T>(pub T);
+
+/// Query string extractor rejecting with `AppError`
+#[derive(Debug, FromRequestParts)]
+#[from_request(via(axum::extract::Query), rejection(AppError))]
+pub struct AppQuery<T>(pub T);
@@ -1,11 +1,66 @@
+use crate::auth_claim::TokenSettings;
+use crate::client_store::ClientStore;
+use crate::health::HealthCheck;
+use crate::jwt_keys::KeyRing;
+use crate::metrics::Metrics;
+use crate::outbox::Outbox;
+use crate::queue::{InMemoryMessageQueue, MessageQueue};
+use crate::refresh_token::{InMemoryRefreshTokenStore, RefreshTokenStore};
+use crate::revocation::{InMemoryRevocationStore, RevocationStore};
+use crate::shutdown::Shutdown;
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
 
-#[derive(Deserialize, Serialize, Debug, Clone)]
+#[derive(Debug, Clone)]
 pub struct MyAppState {
     pub db_enpoint: String,
-    pub is_connected: bool,
     pub conntection_string: String,
+    pub clients: Arc<dyn ClientStore>,
+    pub keys: Arc<KeyRing>,
+    pub tokens: TokenSettings,
+    pub refresh_tokens: Arc<dyn RefreshTokenStore>,
+    pub revocations: Arc<dyn RevocationStore>,
+    pub queue: Arc<dyn MessageQueue>,
+    /// Serves the SQS JSON protocol on `POST /`, see `sqs_api`
+    pub sqs_emulator: bool,
+    /// Queues each company may use over HTTP, see `protected_router`
+    pub http_queues: HashMap<String, HashSet<String>>,
+    /// Outbox in the application's database, if one is configured
+    pub outbox: Option<Outbox>,
+    /// Triggered when a graceful shutdown begins, see `shutdown`
+    pub shutdown: Shutdown,
+    /// Checks run by `/health/ready` besides the built-in ones, see `health::checks`
+    pub health_checks: Vec<Arc<dyn HealthCheck>>,
+    /// Request metrics served at `/metrics`, see `metrics`
+    pub metrics: Metrics,
+}
+
+impl MyAppState {
+    /// Creates the application state around the given client store and key ring
+    /// 
+    /// Token settings start at their defaults while refresh tokens,
+    /// revocations and queued messages are kept in memory; all can be
+    /// replaced afterwards.
+    pub fn new(clients: Arc<dyn ClientStore>, keys: Arc<KeyRing>) -> Self {
+        Self {
+            db_enpoint: String::from("this is db enpoint string"),
+            conntection_string: String::from("this is connection string"),
+            clients,
+            keys,
+            tokens: TokenSettings::default(),
+            refresh_tokens: Arc::new(InMemoryRefreshTokenStore::new()),
+            revocations: Arc::new(InMemoryRevocationStore::new()),
+            queue: Arc::new(InMemoryMessageQueue::new()),
+            sqs_emulator: false,
+            http_queues: HashMap::new(),
+            outbox: None,
+            shutdown: Shutdown::new(),
+            health_checks: Vec::new(),
+            metrics: Metrics::new(),
+        }
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -2,13 +2,16 @@
 //! 
 //! This module handles JWT token generation, validation, and claims processing.
 //! It provides functionality for:
-//! - User authentication and token generation
+//! - Client authentication against the `ClientStore` and token generation
 //! - JWT token validation
 //! - Claims extraction from requests
 //! - Error handling for authentication failures
 
+use crate::app_error::{AppError, AppJson, Problem};
+use crate::app_state::MyAppState;
+use crate::client_store::{ClientRecord, verify_dummy_secret};
 use axum::{
-    Json, RequestPartsExt,
+    Extension, Json, RequestPartsExt,
     extract::FromRequestParts,
     http::{StatusCode, request::Parts},
     response::{IntoResponse, Response},
@@ -17,78 +20,276 @@ use axum_extra::{
     TypedHeader,
     headers::{Authorization, authorization::Bearer},
 };
-use dotenvy;
-use jsonwebtoken::{DecodingKey, EncodingKey, Header, Validation, decode, encode};
+use jsonwebtoken::Validation;
+use jsonwebtoken::jwk::JwkSet;
 use serde::{Deserialize, Serialize};
-use serde_json::json;
 use std::fmt::Display;
-use std::sync::LazyLock;
+use std::time::Duration;
+use utoipa::ToSchema;
+use uuid::Uuid;
 
-/// JWT signing keys for token encoding and decoding
+/// Token issuing and validation settings
 /// 
-/// Initialized from the `JWT_SECRET` environment variable
-static KEYS: LazyLock<Keys> = LazyLock::new(|| {
-    let secret = dotenvy::var("JWT_SECRET").expect("JWT_SECRET must be set");
-    Keys::new(secret.as_bytes())
-});
-
-/// JWT signing keys container
-/// 
-/// Holds both encoding and decoding keys for JWT operations
-pub struct Keys {
-    pub encoding: EncodingKey,
-    pub decoding: DecodingKey,
+/// Controls the `iss` and `aud` claims stamped into issued tokens, how long
+/// tokens live and how much clock skew is tolerated when validating them.
+#[derive(Debug, Clone)]
+pub struct TokenSettings {
+    /// Value of the `iss` claim; tokens from other issuers are rejected
+    pub issuer: String,
+    /// Value of the `aud` claim; tokens for other audiences are rejected
+    pub audience: String,
+    /// Lifetime of issued access tokens
+    pub ttl: Duration,
+    /// Lifetime of a refresh token family, counted from the initial login
+    pub refresh_ttl: Duration,
+    /// Clock skew tolerated when checking `exp` and `nbf`
+    pub leeway: Duration,
 }
 
-impl Keys {
-    /// Creates new JWT signing keys from a secret
-    /// 
-    /// # Arguments
-    /// 
-    /// * `secret` - The secret key bytes used for JWT signing
-    pub fn new(secret: &[u8]) -> Self {
+impl Default for TokenSettings {
+    fn default() -> Self {
         Self {
-            encoding: EncodingKey::from_secret(secret),
-            decoding: DecodingKey::from_secret(secret),
+            issuer: "axum-sqs-example".to_owned(),
+            audience: "axum-sqs-example".to_owned(),
+            ttl: Duration::from_secs(15 * 60),
+            refresh_ttl: Duration::from_secs(14 * 24 * 60 * 60),
+            leeway: Duration::from_secs(30),
         }
     }
 }
 
-/// Handles user authentication and token generation
+impl TokenSettings {
+    /// Builds the `Validation` used for every incoming token
+    /// 
+    /// Checks the signature, `exp`, `nbf`, issuer and audience.
+    pub fn validation(&self) -> Validation {
+        let mut validation = Validation::default();
+        validation.set_issuer(&[&self.issuer]);
+        validation.set_audience(&[&self.audience]);
+        validation.set_required_spec_claims(&["exp", "nbf", "iss", "aud", "sub"]);
+        validation.validate_nbf = true;
+        validation.leeway = self.leeway.as_secs();
+        validation
+    }
+}
+
+/// Handles client authentication and token generation
 /// 
-/// Validates user credentials and generates a JWT token if valid.
+/// Looks the client up in the `ClientStore`, verifies its secret and generates
+/// a JWT token carrying the client's subject and company, together with a
+/// refresh token starting a new refresh token family.
 /// 
 /// # Arguments
 /// 
-/// * `Json(payload)` - The authentication payload containing client credentials
+/// * `Extension(state)` - The application state holding the client store
+/// * `AppJson(payload)` - The authentication payload containing client credentials
 /// 
 /// # Returns
 /// 
 /// A `Result` containing either:
-/// * `Ok(Json<AuthBody>)` - The generated JWT token
-/// * `Err(AuthError)` - If authentication fails
-pub async fn authorize(Json(payload): Json<AuthPayload>) -> Result<Json<AuthBody>, AuthError> {
+/// * `Ok(Json<AuthBody>)` - The generated JWT and refresh tokens
+/// * `Err(AppError)` - If authentication fails
+#[utoipa::path(
+    post,
+    path = "/authorization",
+    tag = "auth",
+    request_body = AuthPayload,
+    responses(
+        (status = 200, description = "Client authenticated", body = AuthBody),
+        (status = 400, description = "Credentials are missing", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Credentials are wrong", body = Problem, content_type = "application/problem+json")
+    )
+)]
+pub async fn authorize(
+    Extension(state): Extension<MyAppState>,
+    AppJson(payload): AppJson<AuthPayload>,
+) -> Result<Json<AuthBody>, AppError> {
     // Check if the user sent the credentials
     if payload.client_id.is_empty() || payload.client_secret.is_empty() {
-        return Err(AuthError::MissingCredentials);
+        return Err(AuthError::MissingCredentials.into());
+    }
+    let client = state
+        .clients
+        .find(&payload.client_id)
+        .await
+        .map_err(|_| AuthError::ClientStore)?
+        .filter(|client| client.enabled);
+    // Argon2 verification is CPU bound, keep it off the async workers. Unknown and
+    // disabled clients are checked against a dummy hash so that they get the same
+    // answer as a wrong secret, just as slowly.
+    let secret = payload.client_secret;
+    let client = tokio::task::spawn_blocking(move || match client {
+        Some(client) => client.verify_secret(&secret).then_some(client),
+        None => {
+            verify_dummy_secret(&secret);
+            None
+        }
+    })
+    .await
+    .map_err(|_| AuthError::ClientStore)?
+    .ok_or(AuthError::WrongCredentials)?;
+    let refresh_token = state
+        .refresh_tokens
+        .issue(&client.client_id, state.tokens.refresh_ttl)
+        .await
+        .map_err(|_| AuthError::TokenCreation)?;
+
+    tracing::info!(client_id = client.client_id, company = client.company, "client authorised");
+    // Send the authorized token
+    Ok(Json(issue_tokens(&state, client, refresh_token)?))
+}
+
+/// Exchanges a refresh token for a new access and refresh token pair
+/// 
+/// The presented refresh token is rotated out. Presenting a rotated token again
+/// revokes its whole family, so a stolen refresh token stops working for both
+/// the thief and the legitimate client. The client is looked up again so that
+/// disabled clients cannot keep refreshing.
+/// 
+/// # Arguments
+/// 
+/// * `Extension(state)` - The application state holding the token stores
+/// * `AppJson(payload)` - The refresh token to exchange
+/// 
+/// # Returns
+/// 
+/// A `Result` containing either:
+/// * `Ok(Json<AuthBody>)` - The new JWT and refresh tokens
+/// * `Err(AppError)` - If the refresh token is unknown, expired, revoked or reused
+#[utoipa::path(
+    post,
+    path = "/authorization/refresh",
+    tag = "auth",
+    request_body = RefreshPayload,
+    responses(
+        (status = 200, description = "Tokens rotated", body = AuthBody),
+        (status = 400, description = "Refresh token is missing", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Refresh token is unknown, expired, revoked or reused", body = Problem, content_type = "application/problem+json")
+    )
+)]
+pub async fn refresh(
+    Extension(state): Extension<MyAppState>,
+    AppJson(payload): AppJson<RefreshPayload>,
+) -> Result<Json<AuthBody>, AppError> {
+    if payload.refresh_token.is_empty() {
+        return Err(AuthError::MissingCredentials.into());
     }
-    // Here you can check the user credentials from a database
-    if payload.client_id != "foo" || payload.client_secret != "bar" {
-        return Err(AuthError::WrongCredentials);
+    let (refresh_token, grant) = state
+        .refresh_tokens
+        .rotate(&payload.refresh_token)
+        .await
+        .map_err(|_| AuthError::InvalidRefreshToken)?;
+    let client = state
+        .clients
+        .find(&grant.client_id)
+        .await
+        .map_err(|_| AuthError::ClientStore)?
+        .filter(|client| client.enabled);
+    let Some(client) = client else {
+        // Best effort: the family is useless without an enabled client anyway
+        let _ = state.refresh_tokens.revoke_family(&grant.family_id).await;
+        return Err(AuthError::InvalidRefreshToken.into());
+    };
+
+    Ok(Json(issue_tokens(&state, client, refresh_token)?))
+}
+
+/// Revokes the presented access token and, optionally, a refresh token family
+/// 
+/// The access token's `jti` is added to the revocation list until its `exp`
+/// plus the validation leeway, after which both the `Claims` extractor and the
+/// `auth` middleware reject it.
+/// Sending the matching refresh token logs the client out completely.
+/// 
+/// # Arguments
+/// 
+/// * `Extension(state)` - The application state holding the token stores
+/// * `claims` - The claims of the access token being revoked
+/// * `payload` - Optional refresh token whose family is revoked as well
+/// 
+/// # Returns
+/// 
+/// A `Result` containing either:
+/// * `Ok(StatusCode)` - `NO_CONTENT` once the tokens are revoked
+/// * `Err(AppError)` - If the token is invalid or the revocation list is unavailable
+#[utoipa::path(
+    post,
+    path = "/authorization/revoke",
+    tag = "auth",
+    request_body(content = Option<RevokePayload>, description = "Optional refresh token to revoke as well"),
+    responses(
+        (status = 204, description = "Tokens revoked"),
+        (status = 401, description = "Access token is invalid", body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn revoke(
+    Extension(state): Extension<MyAppState>,
+    claims: Claims,
+    payload: Option<AppJson<RevokePayload>>,
+) -> Result<StatusCode, AppError> {
+    // Validation accepts the token until `exp` plus the leeway, so it stays listed until then
+    let expires_at = chrono::DateTime::from_timestamp(claims.exp as i64, 0)
+        .and_then(|exp| exp.checked_add_signed(chrono::Duration::from_std(state.tokens.leeway).ok()?))
+        .ok_or(AuthError::InvalidToken)?;
+    state
+        .revocations
+        .revoke(&claims.jti, expires_at)
+        .await
+        .map_err(|_| AuthError::RevocationStore)?;
+
+    if let Some(AppJson(RevokePayload {
+        refresh_token: Some(refresh_token),
+    })) = payload
+    {
+        // Unknown refresh tokens are ignored so logout never leaks token validity
+        let _ = state.refresh_tokens.revoke(&refresh_token).await;
     }
+
+    Ok(StatusCode::NO_CONTENT)
+}
+
+/// Publishes the public token verification keys as a JWK Set
+/// 
+/// Downstream services use this to verify tokens without sharing a secret,
+/// picking the key whose `kid` matches the token header. HMAC secrets are
+/// never published, so the set only holds the asymmetric keys of the key ring.
+/// 
+/// # Arguments
+/// 
+/// * `Extension(state)` - The application state holding the key ring
+/// 
+/// # Returns
+/// 
+/// The JWK Set served at `/.well-known/jwks.json`
+#[utoipa::path(
+    get,
+    path = "/.well-known/jwks.json",
+    tag = "auth",
+    responses((status = 200, description = "Public verification keys as a JWK Set", body = Object))
+)]
+pub async fn jwks(Extension(state): Extension<MyAppState>) -> Json<JwkSet> {
+    Json(state.keys.jwks())
+}
+
+/// Signs an access token for a client and pairs it with a refresh token
+fn issue_tokens(
+    state: &MyAppState,
+    client: ClientRecord,
+    refresh_token: String,
+) -> Result<AuthBody, AuthError> {
     let claims = Claims {
-        sub: "b@b.com".to_owned(),
-        company: "ACME".to_owned(),
-        // Mandatory expiry time as UTC timestamp
-        exp: 2000000000, // May 2033
+        roles: client.roles,
+        scope: client.scopes.join(" "),
+        ..Claims::new(client.subject, client.company, &state.tokens)
     };
     // Create the authorization token
-    let token = encode(&Header::default(), &claims, &KEYS.encoding)
+    let token = state
+        .keys
+        .encode(&claims)
         .map_err(|_| AuthError::TokenCreation)?;
 
-    println!("Client Authorised: {}", claims.company);
-    // Send the authorized token
-    Ok(Json(AuthBody::new(token)))
+    Ok(AuthBody::new(token, refresh_token, state.tokens.ttl.as_secs()))
 }
 
 /// Authentication error types
@@ -102,12 +303,30 @@ pub enum AuthError {
     TokenCreation,
     /// Invalid or malformed JWT token
     InvalidToken,
+    /// Unknown, expired, revoked or reused refresh token
+    InvalidRefreshToken,
+    /// The client store could not be queried
+    ClientStore,
+    /// The application state is not available to the extractor
+    Misconfigured,
+    /// The token was revoked before its expiry
+    RevokedToken,
+    /// The revocation list could not be queried
+    RevocationStore,
+    /// The token lacks the scope named by the route
+    InsufficientScope(String),
+    /// The subject lacks the role named by the route
+    MissingRole(String),
+    /// The client did not authenticate with a TLS client certificate
+    MissingClientCertificate,
+    /// The subject's company may not use the queue over HTTP
+    QueueNotAllowed(String),
 }
 
 /// JWT claims structure
 /// 
 /// Contains the data that will be encoded in the JWT token
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Clone, Serialize, Deserialize, ToSchema)]
 pub struct Claims {
     /// Subject (typically user identifier)
     pub sub: String,
@@ -115,23 +334,79 @@ pub struct Claims {
     pub company: String,
     /// Token expiration timestamp
     pub exp: usize,
+    /// Issued-at timestamp
+    pub iat: usize,
+    /// Not-before timestamp
+    pub nbf: usize,
+    /// Unique token identifier
+    pub jti: String,
+    /// Issuer of the token
+    pub iss: String,
+    /// Intended audience of the token
+    pub aud: String,
+    /// Roles of the subject
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub roles: Vec<String>,
+    /// Space separated scopes granted to the token
+    #[serde(default, skip_serializing_if = "String::is_empty")]
+    pub scope: String,
+}
+
+impl Claims {
+    /// Creates claims for a subject, valid from now for the configured TTL
+    /// 
+    /// # Arguments
+    /// 
+    /// * `sub` - The subject of the token
+    /// * `company` - The company of the subject
+    /// * `settings` - The issuer, audience and lifetime to apply
+    pub fn new(sub: String, company: String, settings: &TokenSettings) -> Self {
+        let now = chrono::Utc::now().timestamp() as usize;
+        Self {
+            sub,
+            company,
+            exp: now + settings.ttl.as_secs() as usize,
+            iat: now,
+            nbf: now,
+            jti: Uuid::new_v4().to_string(),
+            iss: settings.issuer.clone(),
+            aud: settings.audience.clone(),
+            roles: Vec::new(),
+            scope: String::new(),
+        }
+    }
+
+    /// Checks whether the token was granted a scope
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scope.split_whitespace().any(|granted| granted == scope)
+    }
+
+    /// Checks whether the subject has a role
+    pub fn has_role(&self, role: &str) -> bool {
+        self.roles.iter().any(|granted| granted == role)
+    }
 }
 
 /// Authentication response body
 /// 
-/// Contains the generated JWT token and its type
-#[derive(Debug, Serialize, Deserialize)]
+/// Contains the generated JWT token, its type and lifetime, and the refresh
+/// token to use once it expires
+#[derive(Debug, Serialize, Deserialize, ToSchema)]
 pub struct AuthBody {
     /// The JWT access token
     pub access_token: String,
     /// The type of token (always "Bearer")
     pub token_type: String,
+    /// Seconds until the access token expires
+    pub expires_in: u64,
+    /// Single-use token for `/authorization/refresh`
+    pub refresh_token: String,
 }
 
 /// Authentication request payload
 /// 
 /// Contains the credentials needed for authentication
-#[derive(Debug, Deserialize)]
+#[derive(Debug, Deserialize, ToSchema)]
 pub struct AuthPayload {
     /// Client identifier
     pub client_id: String,
@@ -139,6 +414,20 @@ pub struct AuthPayload {
     pub client_secret: String,
 }
 
+/// Refresh request payload
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct RefreshPayload {
+    /// Refresh token from the previous `AuthBody`
+    pub refresh_token: String,
+}
+
+/// Revoke request payload
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct RevokePayload {
+    /// Refresh token whose family should be revoked along with the access token
+    pub refresh_token: Option<String>,
+}
+
 impl Display for Claims {
     fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
         write!(
@@ -156,62 +445,124 @@ impl<S> FromRequestParts<S> for Claims
 where
     S: Send + Sync,
 {
-    type Rejection = AuthError;
+    type Rejection = AppError;
 
     /// Extracts and validates JWT claims from the request
     /// 
+    /// Behind the `auth` middleware the claims it already verified are reused,
+    /// so the token is only decoded once per request.
+    /// 
     /// # Arguments
     /// 
-    /// * `parts` - The request parts containing the authorization header
-    /// * `_state` - The application state (unused)
+    /// * `parts` - The request parts containing the authorization header and
+    ///   the `MyAppState` extension
+    /// * `_state` - The router state (unused)
     /// 
     /// # Returns
     /// 
     /// A `Result` containing either:
     /// * `Ok(Claims)` - The validated claims from the JWT token
-    /// * `Err(AuthError)` - If token extraction or validation fails
+    /// * `Err(AppError)` - If token extraction or validation fails
     async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        if let Some(claims) = parts.extensions.get::<Claims>() {
+            return Ok(claims.clone());
+        }
         // Extract the token from the authorization header
         let TypedHeader(Authorization(bearer)) = parts
             .extract::<TypedHeader<Authorization<Bearer>>>()
             .await
             .map_err(|_| AuthError::InvalidToken)?;
-        // Decode the user data
-        let token_data = decode::<Claims>(bearer.token(), &KEYS.decoding, &Validation::default())
-            .map_err(|_| AuthError::InvalidToken)?;
+        let Extension(state) = parts
+            .extract::<Extension<MyAppState>>()
+            .await
+            .map_err(|_| AuthError::Misconfigured)?;
 
-        Ok(token_data.claims)
+        Ok(verify_token(&state, bearer.token()).await?)
     }
 }
 
+/// Verifies a bearer token and returns its claims
+/// 
+/// Checks the signature with the key named by the token's `kid`, the registered
+/// claims against the configured `TokenSettings`, and the revocation list.
+/// 
+/// # Arguments
+/// 
+/// * `state` - The application state holding the key ring and revocation list
+/// * `token` - The encoded JWT
+pub async fn verify_token(state: &MyAppState, token: &str) -> Result<Claims, AuthError> {
+    // Decode the user data
+    let token_data = state
+        .keys
+        .decode::<Claims>(token, &state.tokens.validation())
+        .map_err(|_| AuthError::InvalidToken)?;
+    // Reject tokens revoked before their expiry
+    if state
+        .revocations
+        .is_revoked(&token_data.claims.jti)
+        .await
+        .map_err(|_| AuthError::RevocationStore)?
+    {
+        return Err(AuthError::RevokedToken);
+    }
+
+    Ok(token_data.claims)
+}
+
 impl AuthBody {
     /// Creates a new authentication response body
     /// 
     /// # Arguments
     /// 
     /// * `access_token` - The JWT token to be returned
-    fn new(access_token: String) -> Self {
+    /// * `refresh_token` - The refresh token to be returned
+    /// * `expires_in` - Seconds until the access token expires
+    fn new(access_token: String, refresh_token: String, expires_in: u64) -> Self {
         Self {
             access_token,
             token_type: "Bearer".to_string(),
+            expires_in,
+            refresh_token,
+        }
+    }
+}
+
+impl Display for AuthError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            AuthError::WrongCredentials => write!(f, "The client id or secret is wrong"),
+            AuthError::MissingCredentials => write!(f, "The request lacks the required credentials"),
+            AuthError::TokenCreation => write!(f, "The token could not be created"),
+            AuthError::InvalidToken => write!(
+                f,
+                "The bearer token is missing, malformed, expired or not signed by a known key"
+            ),
+            AuthError::InvalidRefreshToken => {
+                write!(f, "The refresh token is unknown, expired, revoked or was already used")
+            }
+            AuthError::ClientStore => write!(f, "The client store could not be queried"),
+            AuthError::Misconfigured => write!(f, "Authentication is not configured for this route"),
+            AuthError::RevokedToken => write!(f, "The bearer token has been revoked"),
+            AuthError::RevocationStore => write!(f, "The revocation list could not be queried"),
+            AuthError::InsufficientScope(scope) => {
+                write!(f, "The token lacks the {} scope", scope)
+            }
+            AuthError::MissingRole(role) => write!(f, "The subject lacks the {} role", role),
+            AuthError::MissingClientCertificate => {
+                write!(f, "The connection was not authenticated with a TLS client certificate")
+            }
+            AuthError::QueueNotAllowed(queue) => write!(f, "The company may not use the {} queue", queue),
         }
     }
 }
 
+impl std::error::Error for AuthError {}
+
 /// Implementation of `IntoResponse` for `AuthError`
 /// 
-/// Converts authentication errors into appropriate HTTP responses
+/// Converts authentication errors into problem responses through `AppError`
 impl IntoResponse for AuthError {
     fn into_response(self) -> Response {
-        let (status, error_message) = match self {
-            AuthError::WrongCredentials => (StatusCode::UNAUTHORIZED, "Wrong credentials"),
-            AuthError::MissingCredentials => (StatusCode::BAD_REQUEST, "Missing credentials"),
-            AuthError::TokenCreation => (StatusCode::INTERNAL_SERVER_ERROR, "Token creation error"),
-            AuthError::InvalidToken => (StatusCode::BAD_REQUEST, "Invalid token"),
-        };
-        let body = Json(json!({
-            "error": error_message,
-        }));
-        (status, body).into_response()
+        AppError::from(self).into_response()
     }
 }
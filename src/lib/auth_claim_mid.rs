@@ -1,36 +1,45 @@
 //! Authentication Middleware Module
 //! 
 //! This module provides JWT-based authentication middleware for protected routes.
-//! It handles token validation and user context management using task-local storage.
+//! It handles token validation and user context management using request extensions
+//! and task-local storage.
 
 use axum_extra::TypedHeader;
 use axum_extra::headers::Authorization;
 use axum_extra::headers::authorization::Bearer;
-use axum::http::StatusCode;
-use crate::auth_claim::{Keys, Claims};
+use crate::app_error::AppError;
+use crate::app_state::MyAppState;
+use crate::auth_claim::{AuthError, Claims, verify_token};
 use tokio::task_local;
-use axum::extract::Request;
+use axum::extract::{FromRequestParts, Request};
+use axum::http::request::Parts;
 use axum::response::Response;
 use axum::middleware::Next;
 use axum::RequestExt;
-use jsonwebtoken::{Validation, decode};
-use std::sync::LazyLock;
-
-/// JWT signing keys for token validation
-/// 
-/// Initialized from the `JWT_SECRET` environment variable
-static KEYS: LazyLock<Keys> = LazyLock::new(|| {
-    let secret = dotenvy::var("JWT_SECRET").expect("JWT_SECRET must be set");
-    Keys::new(secret.as_bytes())
-});
 
 /// Represents the current authenticated user
 /// 
-/// This struct holds the user information extracted from the JWT token
-#[derive(Clone)]
-#[allow(dead_code)]
-struct CurrentUser {
-    name: String,
+/// This struct holds the user information extracted from the JWT token.
+/// The `auth` middleware stores it in the request extensions, from where
+/// handlers extract it like any other argument.
+#[derive(Debug, Clone)]
+pub struct CurrentUser {
+    /// Subject of the token
+    pub sub: String,
+    /// Company of the subject
+    pub company: String,
+    /// Roles of the subject
+    pub roles: Vec<String>,
+}
+
+impl From<&Claims> for CurrentUser {
+    fn from(claims: &Claims) -> Self {
+        Self {
+            sub: claims.sub.clone(),
+            company: claims.company.clone(),
+            roles: claims.roles.clone(),
+        }
+    }
 }
 
 task_local! {
@@ -39,13 +48,33 @@ task_local! {
     pub static USER: CurrentUser;
 }
 
+/// Implementation of `FromRequestParts` for `CurrentUser`
+/// 
+/// Reads the user stored by the `auth` middleware; the token is not decoded again.
+/// Routes without the middleware reject the request as unauthenticated.
+impl<S> FromRequestParts<S> for CurrentUser
+where
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        parts
+            .extensions
+            .get::<CurrentUser>()
+            .cloned()
+            .ok_or(AuthError::InvalidToken.into())
+    }
+}
+
 /// Authentication middleware that validates JWT tokens
 /// 
 /// This middleware:
 /// 1. Extracts the Bearer token from the Authorization header
-/// 2. Validates the JWT token
+/// 2. Validates the JWT token and checks it has not been revoked
 /// 3. Creates a user context from the token claims
-/// 4. Makes the user context available to subsequent handlers
+/// 4. Makes the user context and the claims available to subsequent handlers
+///    through the request extensions and the `USER` task-local
 /// 
 /// # Arguments
 /// 
@@ -56,29 +85,31 @@ task_local! {
 /// 
 /// A `Result` containing either:
 /// * `Ok(Response)` - The response from the next middleware
-/// * `Err(StatusCode)` - `UNAUTHORIZED` if authentication fails
-pub async fn auth(mut req: Request, n: Next) -> Result<Response, StatusCode> {
+/// * `Err(AppError)` - A 401 problem if authentication fails
+pub async fn auth(mut req: Request, n: Next) -> Result<Response, AppError> {
+    // Keys, issuer, audience, leeway and revocations come from the shared application state
+    let state = req
+        .extensions()
+        .get::<MyAppState>()
+        .cloned()
+        .ok_or(AuthError::Misconfigured)?;
+
     // Extract the authorization header
     let auth_header = req
         .extract_parts::<TypedHeader<Authorization<Bearer>>>()
         .await
-        .map_err(|_| StatusCode::UNAUTHORIZED)?;
+        .map_err(|_| AuthError::InvalidToken)?;
 
     // Decode and validate the token
-    let token_data = decode::<Claims>(
-        auth_header.0.token(),
-        &KEYS.decoding,
-        &Validation::default(),
-    )
-    .map_err(|_| StatusCode::UNAUTHORIZED)?;
+    let claims = verify_token(&state, auth_header.0.token()).await?;
 
     // Create current user from token claims
-    let cur_usr = CurrentUser {
-        name: token_data.claims.company,
-    };
+    let cur_usr = CurrentUser::from(&claims);
 
-    println!("at auth: {}", cur_usr.name);
+    // Handlers and route layers read the verified user and claims from the extensions
+    req.extensions_mut().insert(cur_usr.clone());
+    req.extensions_mut().insert(claims);
 
     // Run the next middleware with the current user in scope
     Ok(USER.scope(cur_usr, n.run(req)).await)
-}
\ No newline at end of file
+}
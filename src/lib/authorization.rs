@@ -0,0 +1,128 @@
+//! Authorization Module
+//!
+//! This module provides route layers that check the scopes and roles carried by
+//! an access token. They run after the `auth_claim_mid::auth` middleware, which
+//! verifies the token and stores its `Claims` in the request extensions.
+//!
+//! # Example
+//!
+//! ```ignore
+//! Router::new()
+//!     .route("/norm", post(protected_norm).route_layer(RequireScope::new("protected:write")))
+//!     .route("/admin", post(admin).route_layer(RequireRole::new("admin")))
+//!     .layer(middleware::from_fn(auth))
+//! ```
+
+use crate::app_error::AppError;
+use crate::auth_claim::{AuthError, Claims};
+use axum::extract::Request;
+use axum::response::{IntoResponse, Response};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::task::{Context, Poll};
+use tower::{Layer, Service};
+
+/// A check applied to the claims of an authenticated request
+pub trait Requirement: Clone + Send + Sync + 'static {
+    /// Returns the error to respond with when the claims do not qualify
+    fn check(&self, claims: &Claims) -> Result<(), AuthError>;
+}
+
+/// Route layer rejecting tokens without a scope with 403 Forbidden
+#[derive(Debug, Clone)]
+pub struct RequireScope(Arc<str>);
+
+impl RequireScope {
+    /// Requires the given scope in the token's `scope` claim
+    pub fn new(scope: &str) -> Self {
+        Self(scope.into())
+    }
+}
+
+impl Requirement for RequireScope {
+    fn check(&self, claims: &Claims) -> Result<(), AuthError> {
+        if claims.has_scope(&self.0) {
+            Ok(())
+        } else {
+            Err(AuthError::InsufficientScope(self.0.to_string()))
+        }
+    }
+}
+
+/// Route layer rejecting subjects without a role with 403 Forbidden
+#[derive(Debug, Clone)]
+pub struct RequireRole(Arc<str>);
+
+impl RequireRole {
+    /// Requires the given role in the token's `roles` claim
+    pub fn new(role: &str) -> Self {
+        Self(role.into())
+    }
+}
+
+impl Requirement for RequireRole {
+    fn check(&self, claims: &Claims) -> Result<(), AuthError> {
+        if claims.has_role(&self.0) {
+            Ok(())
+        } else {
+            Err(AuthError::MissingRole(self.0.to_string()))
+        }
+    }
+}
+
+impl<S> Layer<S> for RequireScope {
+    type Service = RequireService<S, Self>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequireService {
+            inner,
+            requirement: self.clone(),
+        }
+    }
+}
+
+impl<S> Layer<S> for RequireRole {
+    type Service = RequireService<S, Self>;
+
+    fn layer(&self, inner: S) -> Self::Service {
+        RequireService {
+            inner,
+            requirement: self.clone(),
+        }
+    }
+}
+
+/// Service produced by `RequireScope` and `RequireRole`
+#[derive(Debug, Clone)]
+pub struct RequireService<S, R> {
+    inner: S,
+    requirement: R,
+}
+
+impl<S, R> Service<Request> for RequireService<S, R>
+where
+    S: Service<Request, Response = Response> + Clone + Send + 'static,
+    S::Future: Send + 'static,
+    R: Requirement,
+{
+    type Response = Response;
+    type Error = S::Error;
+    type Future = Pin<Box<dyn Future<Output = Result<Response, S::Error>> + Send>>;
+
+    fn poll_ready(&mut self, cx: &mut Context<'_>) -> Poll<Result<(), Self::Error>> {
+        self.inner.poll_ready(cx)
+    }
+
+    fn call(&mut self, req: Request) -> Self::Future {
+        // Without the auth middleware there are no claims to check
+        let verdict = match req.extensions().get::<Claims>() {
+            Some(claims) => self.requirement.check(claims),
+            None => Err(AuthError::InvalidToken),
+        };
+        match verdict {
+            Ok(()) => Box::pin(self.inner.call(req)),
+            Err(e) => Box::pin(async move { Ok(AppError::from(e).into_response()) }),
+        }
+    }
+}
@@ -3,37 +3,96 @@
 //! This module provides the core functionality for setting up and running the web server,
 //! including route configuration, middleware setup, and server initialization.
 
-use axum::{Router, routing::get, extract::Extension, routing::post};
-use crate::{app_state, auth_claim, my_extractors, protected_router, users_router};
+use axum::{Router, middleware, routing::get, extract::Extension};
+use crate::{admin_router, api_doc, app_error, app_state::MyAppState, auth_claim, health, metrics, my_consumers, my_extractors, protected_router, sqs_api, users_router};
+use crate::api_doc::ApiDoc;
+use crate::request_id::request_id;
+use crate::client_store::{ClientStore, InMemoryClientStore, SqliteClientStore};
+use crate::config::Config;
+use crate::jwt_keys::KeyRing;
+use crate::outbox::Outbox;
+use crate::queue::{QueueConfig, QueueError};
+use crate::queue_worker::Consumer;
+use crate::revocation::InMemoryRevocationStore;
+use crate::shutdown::{self, Shutdown};
+use crate::sqlite_queue::SqliteMessageQueue;
+use crate::tls::{ServerTls, TlsConnectInfo, TlsListener};
+use std::future::IntoFuture;
+use std::sync::Arc;
+use std::time::Duration;
 use tower_http::trace::{TraceLayer, DefaultMakeSpan, DefaultOnResponse};
 use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt};
+use utoipa::OpenApi;
+use utoipa_axum::router::OpenApiRouter;
+use utoipa_axum::routes;
 
 /// Initialize the application router with all routes and middleware
 /// 
 /// This function sets up the Axum router with all routes, middleware,
-/// and application state. It can be used both for the main server
-/// and for testing.
-pub fn init_app() -> Router {
-    // Initialize application state
-    let shared_app_state = app_state::MyAppState {
-        db_enpoint: String::from("this is db enpoint string"),
-        is_connected: false,
-        conntection_string: String::from("this is connection string"),
-    };
+/// and application state backed by an in-memory client store holding
+/// the demo client and the keys and token settings of the configuration.
+/// It can be used both for the main server and for testing.
+/// 
+/// # Arguments
+/// 
+/// * `config` - The loaded configuration, see `Config::load`
+pub fn init_app(config: &Config) -> Router {
+    let clients = InMemoryClientStore::with_demo_client().expect("demo client secret must hash");
+    let keys = KeyRing::from_config(&config.keys).unwrap_or_else(|e| panic!("JWT keys: {}", e));
+    init_app_with_state(MyAppState {
+        tokens: config.tokens.clone(),
+        sqs_emulator: config.queues.sqs_emulator,
+        http_queues: config.queues.http_access.clone(),
+        ..MyAppState::new(Arc::new(clients), Arc::new(keys))
+    })
+}
 
-    // Build the application router with all routes and middleware
-    Router::new()
-        .route("/", get(|| async { "Hello, World!" }))
+/// Initialize the application router around an existing application state
+/// 
+/// Use this when the state needs non-default dependencies, such as a
+/// persistent client store.
+/// 
+/// Documented routes are registered through `OpenApiRouter`, and the collected
+/// OpenAPI document is served at `/openapi.json` with a Scalar page at `/docs`.
+/// When `sqs_emulator` is set, `POST /` speaks the SQS JSON protocol.
+pub fn init_app_with_state(shared_app_state: MyAppState) -> Router {
+    // Build the documented routes, collecting the OpenAPI document alongside
+    let (router, openapi) = OpenApiRouter::with_openapi(ApiDoc::openapi())
         .nest("/users", users_router::router())
         .nest("/protected", protected_router::router())
-        .route("/foo", post(post_foo).get(my_extractors::headers))
-        .route("/echo", post(my_extractors::echo_bytes))
-        .route("/headers", get(my_extractors::headers))
-        .route("/input-string", post(my_extractors::input_string))
-        .route("/json", post(my_extractors::input_json))
-        .route("/sample-request", post(my_extractors::sample_request))
-        .route("/string-handler", get(my_extractors::string_handler))
-        .route("/authorization", post(auth_claim::authorize))
+        .nest("/admin", admin_router::router())
+        .routes(routes!(post_foo))
+        .routes(routes!(my_extractors::echo_bytes))
+        .routes(routes!(my_extractors::headers))
+        .routes(routes!(my_extractors::input_string))
+        .routes(routes!(my_extractors::input_json))
+        .routes(routes!(my_extractors::sample_request))
+        .routes(routes!(my_extractors::string_handler))
+        .routes(routes!(auth_claim::authorize))
+        .routes(routes!(auth_claim::refresh))
+        .routes(routes!(auth_claim::revoke))
+        .routes(routes!(auth_claim::jwks))
+        .routes(routes!(health::live))
+        .routes(routes!(health::ready))
+        .routes(routes!(metrics::render))
+        .split_for_parts();
+
+    // The SQS endpoint shares the root path with the greeting, as the AWS SDKs post to it
+    let mut root = get(|| async { "Hello, World!" });
+    if shared_app_state.sqs_emulator {
+        root = root.post(sqs_api::handle);
+    }
+
+    // Build the application router with all routes and middleware
+    router
+        .route("/", root)
+        .route("/foo", get(my_extractors::headers))
+        .merge(api_doc::router(openapi))
+        // Unknown routes and methods get problem responses too
+        .fallback(app_error::not_found)
+        .method_not_allowed_fallback(app_error::method_not_allowed)
+        // Record request metrics by matched route, which only layers inside routing can see
+        .layer(middleware::from_fn_with_state(shared_app_state.metrics.clone(), metrics::track))
         .layer(Extension(shared_app_state))
         // Add request tracing middleware
         .layer(
@@ -41,42 +100,228 @@ pub fn init_app() -> Router {
                 .make_span_with(DefaultMakeSpan::new().level(tracing::Level::INFO))
                 .on_response(DefaultOnResponse::new().level(tracing::Level::INFO)),
         )
+        // Assign request ids first so every layer and error response can use them
+        .layer(middleware::from_fn(request_id))
 }
 
-/// Start the server with configuration from environment variables
+/// Start the server with the given configuration
+/// 
+/// Every setting, from the listening address to the optional databases, is
+/// described in `Config`. The example handlers consume the queues listed in
+/// `queues.consumers`, see `my_consumers::from_config`.
 /// 
-/// The server configuration is read from environment variables:
-/// - `HOST`: The host address to bind to
-/// - `PORT`: The port number to listen on
-/// - `RUST_LOG`: The logging level (defaults to "info")
-pub async fn run_server() -> Result<(), Box<dyn std::error::Error>> {
-    // Initialize tracing with configurable log level
+/// # Arguments
+/// 
+/// * `config` - The loaded configuration, see `Config::load`
+pub async fn run_server(config: Config) -> Result<(), Box<dyn std::error::Error>> {
+    let consumers = my_consumers::from_config(&config);
+    run_server_with_consumers(config, consumers).await
+}
+
+/// Start the server together with background queue consumers
+/// 
+/// Each consumer's queue is created with the default configuration unless it
+/// already exists, then the consumer is spawned on the application state
+/// shared with the HTTP handlers.
+/// 
+/// The server runs until SIGINT or SIGTERM, then shuts down gracefully: readiness
+/// fails and in-flight requests drain, see `serve`, while consumers finish the
+/// batch in hand. The outbox relay then sends what the drained requests staged.
+/// Requests and background tasks each get `server.shutdown_timeout`; a second
+/// signal stops the server at once.
+/// 
+/// With a `tls` section the server speaks HTTPS, see `serve_tls`, and reloads
+/// its certificate when the files change.
+/// 
+/// # Arguments
+/// 
+/// * `config` - The loaded configuration, see `Config::load`
+/// * `consumers` - The consumers to run next to the HTTP server
+pub async fn run_server_with_consumers(
+    config: Config,
+    consumers: Vec<Consumer>,
+) -> Result<(), Box<dyn std::error::Error>> {
+    // Initialize tracing with the configured log filter
     tracing_subscriber::registry()
-        .with(tracing_subscriber::EnvFilter::new(
-            std::env::var("RUST_LOG").unwrap_or_else(|_| "info".into()),
-        ))
+        .with(tracing_subscriber::EnvFilter::new(&config.server.log))
         .with(tracing_subscriber::fmt::layer())
         .init();
 
-    // Configure server address from environment variables
-    let mut host_variable = dotenvy::var("HOST").unwrap();
-    let port = dotenvy::var("PORT").unwrap();
-    host_variable.push_str(String::from(":").as_str());
-    host_variable.push_str(port.as_str());
-    println!("HOST:Port={}", host_variable);
+    // Select the client store
+    let clients: Arc<dyn ClientStore> = match &config.storage.client_store_url {
+        Some(url) => Arc::new(SqliteClientStore::connect(url).await?),
+        None => Arc::new(InMemoryClientStore::with_demo_client()?),
+    };
+
+    // Signing keys, reloaded whenever the key manifest changes
+    let keys = Arc::new(KeyRing::from_config(&config.keys)?);
+    let key_watcher = keys.clone().watch(config.keys.reload_every);
+
+    let mut state = MyAppState {
+        tokens: config.tokens.clone(),
+        sqs_emulator: config.queues.sqs_emulator,
+        http_queues: config.queues.http_access.clone(),
+        ..MyAppState::new(clients, keys)
+    };
+    if let Some(path) = &config.storage.revocation_snapshot {
+        state.revocations = Arc::new(InMemoryRevocationStore::persistent(path).await?);
+    }
+    if let Some(url) = &config.storage.queue_store_url {
+        state.queue = Arc::new(SqliteMessageQueue::connect(url).await?);
+    }
+    if let Some(url) = &config.storage.database_url {
+        state.outbox = Some(Outbox::connect(url).await?);
+    }
+
+    // Start the queue consumers; they stop with the state's shutdown
+    let mut background = Vec::new();
+    for consumer in consumers {
+        match state.queue.create_queue(consumer.queue(), QueueConfig::default()).await {
+            Ok(()) | Err(QueueError::QueueNameExists(_)) => {}
+            Err(e) => return Err(e.into()),
+        }
+        background.push(consumer.spawn(state.clone()));
+    }
+
+    // Relay staged messages to the queue the handlers see, until the requests have drained
+    let relay_stop = Shutdown::new();
+    if let Some(outbox) = &state.outbox {
+        background.push(outbox.clone().spawn_relay(state.queue.clone(), relay_stop.clone()));
+    }
+
+    // Start a graceful shutdown on SIGINT or SIGTERM
+    let shutdown = state.shutdown.clone();
+    tokio::spawn({
+        let shutdown = shutdown.clone();
+        async move {
+            shutdown::signal().await;
+            shutdown.trigger();
+        }
+    });
 
     // Get the router
-    let app = init_app();
+    let app = init_app_with_state(state);
 
-    // Start the server
-    let listener = tokio::net::TcpListener::bind(host_variable).await?;
-    axum::serve(listener, app).await?;
+    // Load the certificate before binding, so a bad one fails the start
+    let tls = match &config.tls {
+        Some(settings) => Some(Arc::new(ServerTls::load(settings)?)),
+        None => None,
+    };
+    let cert_watcher = tls.clone().map(ServerTls::watch);
+
+    // Start the server; host names are resolved, IPv6 addresses need no brackets
+    let listener =
+        tokio::net::TcpListener::bind((config.server.host.as_str(), config.server.port)).await?;
+    tracing::info!(address = %listener.local_addr()?, tls = tls.is_some(), "listening");
+    let timeout = config.server.shutdown_timeout;
+    let stopped = async {
+        match tls {
+            Some(tls) => serve_tls(TlsListener::new(listener, tls)?, app, shutdown.clone(), timeout).await?,
+            None => serve(listener, app, shutdown.clone(), timeout).await?,
+        }
+        relay_stop.trigger();
+        let finished = async {
+            for task in background {
+                let _ = task.await;
+            }
+        };
+        if tokio::time::timeout(timeout, finished).await.is_err() {
+            tracing::warn!("background tasks still running after the shutdown timeout");
+        }
+        Ok::<_, std::io::Error>(())
+    };
+    // A second signal cuts the drain short
+    let forced = async {
+        shutdown.triggered().await;
+        shutdown::signal().await;
+    };
+    tokio::select! {
+        result = stopped => result?,
+        _ = forced => tracing::warn!("second shutdown signal, stopping without draining"),
+    }
+    for watcher in key_watcher.into_iter().chain(cert_watcher) {
+        watcher.abort();
+    }
+    tracing::info!("server stopped");
 
     Ok(())
 }
 
+/// Serves the application until `shutdown` is triggered, then drains it
+/// 
+/// Once the shutdown begins the listener stops accepting connections, idle
+/// connections are closed and readiness reports 503. Requests in flight get up
+/// to `drain_timeout` to finish before their connections are dropped.
+/// 
+/// # Arguments
+/// 
+/// * `listener` - The bound listener to accept connections on
+/// * `app` - The router, see `init_app_with_state`
+/// * `shutdown` - The shutdown flag of the router's application state
+/// * `drain_timeout` - How long in-flight requests may take once shutdown begins
+pub async fn serve(
+    listener: tokio::net::TcpListener,
+    app: Router,
+    shutdown: Shutdown,
+    drain_timeout: Duration,
+) -> std::io::Result<()> {
+    let server = axum::serve(listener, app).with_graceful_shutdown(shutdown.triggered());
+    drain(tokio::spawn(server.into_future()), shutdown, drain_timeout).await
+}
+
+/// Serves the application over TLS until `shutdown` is triggered, then drains it
+/// 
+/// Behaves like `serve`. HTTP/2 is negotiated through ALPN, and handlers can
+/// read `ConnectInfo<TlsConnectInfo>` or extract the `ClientCertificate` of
+/// mutual TLS clients.
+/// 
+/// # Arguments
+/// 
+/// * `listener` - The TLS listener to accept connections on
+/// * `app` - The router, see `init_app_with_state`
+/// * `shutdown` - The shutdown flag of the router's application state
+/// * `drain_timeout` - How long in-flight requests may take once shutdown begins
+pub async fn serve_tls(
+    listener: TlsListener,
+    app: Router,
+    shutdown: Shutdown,
+    drain_timeout: Duration,
+) -> std::io::Result<()> {
+    let app = app.into_make_service_with_connect_info::<TlsConnectInfo>();
+    let server = axum::serve(listener, app).with_graceful_shutdown(shutdown.triggered());
+    drain(tokio::spawn(server.into_future()), shutdown, drain_timeout).await
+}
+
+/// Waits for the spawned server to stop, giving it `drain_timeout` once `shutdown` begins
+async fn drain(
+    mut server: tokio::task::JoinHandle<std::io::Result<()>>,
+    shutdown: Shutdown,
+    drain_timeout: Duration,
+) -> std::io::Result<()> {
+    // Serve until the shutdown begins, unless the server fails first
+    tokio::select! {
+        result = &mut server => return result.map_err(std::io::Error::other)?,
+        _ = shutdown.triggered() => {}
+    }
+    tracing::info!(timeout = ?drain_timeout, "shutting down, draining in-flight requests");
+    match tokio::time::timeout(drain_timeout, &mut server).await {
+        Ok(result) => result.map_err(std::io::Error::other)?,
+        Err(_) => {
+            tracing::warn!("requests still in flight after the drain timeout, dropping them");
+            server.abort();
+            Ok(())
+        }
+    }
+}
+
 /// Handler for POST requests to /foo
 /// 
 /// This is a placeholder handler that currently does nothing.
 /// It can be extended to handle POST requests to the /foo endpoint.
+#[utoipa::path(
+    post,
+    path = "/foo",
+    tag = "extractors",
+    responses((status = 200, description = "Nothing happens yet"))
+)]
 async fn post_foo() {}
@@ -0,0 +1,329 @@
+//! Client Store Module
+//!
+//! This module holds the registered API clients that are allowed to request tokens
+//! from the `/authorization` endpoint. It provides:
+//! - The `ClientStore` trait used by `auth_claim::authorize`
+//! - An in-memory implementation for development and tests
+//! - A SQLite-backed implementation for persistent deployments
+//! - Argon2 hashing and verification of client secrets
+
+use argon2::{
+    Argon2,
+    password_hash::{PasswordHash, PasswordHasher, PasswordVerifier, SaltString, rand_core::OsRng},
+};
+use async_trait::async_trait;
+use sqlx::{Row, SqlitePool, sqlite::SqliteConnectOptions};
+use std::collections::HashMap;
+use std::fmt::{Debug, Display};
+use std::str::FromStr;
+use tokio::sync::RwLock;
+
+/// A registered client as held by a `ClientStore`
+///
+/// The secret is never stored in plain text, only its Argon2 PHC hash.
+#[derive(Debug, Clone)]
+pub struct ClientRecord {
+    /// Client identifier sent as `client_id`
+    pub client_id: String,
+    /// Argon2 hash of the client secret in PHC string format
+    pub secret_hash: String,
+    /// Subject placed in the `sub` claim of issued tokens
+    pub subject: String,
+    /// Company placed in the `company` claim of issued tokens
+    pub company: String,
+    /// Disabled clients can no longer obtain tokens
+    pub enabled: bool,
+    /// Roles placed in the `roles` claim of issued tokens
+    pub roles: Vec<String>,
+    /// Scopes placed in the `scope` claim of issued tokens
+    pub scopes: Vec<String>,
+}
+
+impl ClientRecord {
+    /// Creates a new enabled client record, hashing the plain text secret
+    ///
+    /// # Arguments
+    ///
+    /// * `client_id` - The client identifier
+    /// * `client_secret` - The plain text secret to hash
+    /// * `subject` - The subject issued in the token claims
+    /// * `company` - The company issued in the token claims
+    pub fn new(
+        client_id: &str,
+        client_secret: &str,
+        subject: &str,
+        company: &str,
+    ) -> Result<Self, ClientStoreError> {
+        Ok(Self {
+            client_id: client_id.to_owned(),
+            secret_hash: hash_secret(client_secret)?,
+            subject: subject.to_owned(),
+            company: company.to_owned(),
+            enabled: true,
+            roles: Vec::new(),
+            scopes: Vec::new(),
+        })
+    }
+
+    /// Sets the roles granted to the client
+    pub fn with_roles<I, R>(mut self, roles: I) -> Self
+    where
+        I: IntoIterator<Item = R>,
+        R: Into<String>,
+    {
+        self.roles = roles.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Sets the scopes granted to the client
+    pub fn with_scopes<I, R>(mut self, scopes: I) -> Self
+    where
+        I: IntoIterator<Item = R>,
+        R: Into<String>,
+    {
+        self.scopes = scopes.into_iter().map(Into::into).collect();
+        self
+    }
+
+    /// Checks a plain text secret against the stored hash
+    ///
+    /// Returns `false` for malformed hashes instead of failing.
+    pub fn verify_secret(&self, client_secret: &str) -> bool {
+        match PasswordHash::new(&self.secret_hash) {
+            Ok(parsed) => Argon2::default()
+                .verify_password(client_secret.as_bytes(), &parsed)
+                .is_ok(),
+            Err(_) => false,
+        }
+    }
+}
+
+/// Argon2id hash, with the parameters of `hash_secret`, of a secret no client has
+const DUMMY_SECRET_HASH: &str =
+    "$argon2id$v=19$m=19456,t=2,p=1$TQajxVgkExg4xJTgtjmdOA$BVgoExtoNNEb2c8mmaRFnUJ25kQU3gmKvPr2ckdbE10";
+
+/// Verifies a secret against a hash of no client, taking as long as `ClientRecord::verify_secret`
+///
+/// Used when the client is unknown or disabled, so that such requests cannot
+/// be told apart from wrong secrets by their response time.
+pub fn verify_dummy_secret(client_secret: &str) {
+    if let Ok(parsed) = PasswordHash::new(DUMMY_SECRET_HASH) {
+        let _ = Argon2::default().verify_password(client_secret.as_bytes(), &parsed);
+    }
+}
+
+/// Hashes a client secret with Argon2id and a random salt
+///
+/// # Returns
+///
+/// The hash in PHC string format, suitable for `ClientRecord::secret_hash`
+pub fn hash_secret(client_secret: &str) -> Result<String, ClientStoreError> {
+    let salt = SaltString::generate(&mut OsRng);
+    Argon2::default()
+        .hash_password(client_secret.as_bytes(), &salt)
+        .map(|hash| hash.to_string())
+        .map_err(|e| ClientStoreError::Hashing(e.to_string()))
+}
+
+/// Client store error types
+#[derive(Debug)]
+pub enum ClientStoreError {
+    /// The secret could not be hashed
+    Hashing(String),
+    /// The backing database failed
+    Database(sqlx::Error),
+}
+
+impl Display for ClientStoreError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ClientStoreError::Hashing(e) => write!(f, "secret hashing failed: {}", e),
+            ClientStoreError::Database(e) => write!(f, "client store database error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for ClientStoreError {}
+
+impl From<sqlx::Error> for ClientStoreError {
+    fn from(e: sqlx::Error) -> Self {
+        ClientStoreError::Database(e)
+    }
+}
+
+/// Storage for registered clients
+///
+/// Implementations must be cheap to share between requests; the store is held
+/// behind an `Arc` in `MyAppState`.
+#[async_trait]
+pub trait ClientStore: Debug + Send + Sync {
+    /// Looks up a client by its identifier
+    async fn find(&self, client_id: &str) -> Result<Option<ClientRecord>, ClientStoreError>;
+
+    /// Registers a client, replacing any existing client with the same identifier
+    async fn register(&self, client: ClientRecord) -> Result<(), ClientStoreError>;
+
+    /// Enables or disables a client
+    ///
+    /// Returns `false` if the client does not exist.
+    async fn set_enabled(&self, client_id: &str, enabled: bool) -> Result<bool, ClientStoreError>;
+}
+
+/// In-memory client store
+///
+/// Clients are lost when the process exits, so this is meant for development and tests.
+#[derive(Debug, Default)]
+pub struct InMemoryClientStore {
+    clients: RwLock<HashMap<String, ClientRecord>>,
+}
+
+impl InMemoryClientStore {
+    /// Creates an empty in-memory store
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a store pre-populated with the given clients
+    pub fn with_clients(clients: impl IntoIterator<Item = ClientRecord>) -> Self {
+        Self {
+            clients: RwLock::new(
+                clients
+                    .into_iter()
+                    .map(|client| (client.client_id.clone(), client))
+                    .collect(),
+            ),
+        }
+    }
+
+    /// Creates a store holding the demo client `foo` / `bar` used in the README and tests
+    ///
+    /// The demo client has the `user` role and the `protected:read` and
+    /// `protected:write` scopes.
+    pub fn with_demo_client() -> Result<Self, ClientStoreError> {
+        Ok(Self::with_clients([ClientRecord::new(
+            "foo", "bar", "b@b.com", "ACME",
+        )?
+        .with_roles(["user"])
+        .with_scopes(["protected:read", "protected:write"])]))
+    }
+}
+
+#[async_trait]
+impl ClientStore for InMemoryClientStore {
+    async fn find(&self, client_id: &str) -> Result<Option<ClientRecord>, ClientStoreError> {
+        Ok(self.clients.read().await.get(client_id).cloned())
+    }
+
+    async fn register(&self, client: ClientRecord) -> Result<(), ClientStoreError> {
+        self.clients
+            .write()
+            .await
+            .insert(client.client_id.clone(), client);
+        Ok(())
+    }
+
+    async fn set_enabled(&self, client_id: &str, enabled: bool) -> Result<bool, ClientStoreError> {
+        match self.clients.write().await.get_mut(client_id) {
+            Some(client) => {
+                client.enabled = enabled;
+                Ok(true)
+            }
+            None => Ok(false),
+        }
+    }
+}
+
+/// SQLite-backed client store
+///
+/// Clients are kept in the `clients` table, which is created on connect if missing.
+/// Roles and scopes are stored as space separated lists.
+#[derive(Debug, Clone)]
+pub struct SqliteClientStore {
+    pool: SqlitePool,
+}
+
+impl SqliteClientStore {
+    /// Opens (creating if needed) the database at `url` and prepares the schema
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - A SQLite connection string such as `sqlite://clients.db`
+    pub async fn connect(url: &str) -> Result<Self, ClientStoreError> {
+        let options = SqliteConnectOptions::from_str(url)?.create_if_missing(true);
+        let pool = SqlitePool::connect_with(options).await?;
+        Self::from_pool(pool).await
+    }
+
+    /// Builds a store on top of an existing pool and prepares the schema
+    pub async fn from_pool(pool: SqlitePool) -> Result<Self, ClientStoreError> {
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS clients (
+                client_id   TEXT PRIMARY KEY NOT NULL,
+                secret_hash TEXT NOT NULL,
+                subject     TEXT NOT NULL,
+                company     TEXT NOT NULL,
+                enabled     INTEGER NOT NULL DEFAULT 1,
+                roles       TEXT NOT NULL DEFAULT '',
+                scopes      TEXT NOT NULL DEFAULT ''
+            )",
+        )
+        .execute(&pool)
+        .await?;
+        Ok(Self { pool })
+    }
+}
+
+#[async_trait]
+impl ClientStore for SqliteClientStore {
+    async fn find(&self, client_id: &str) -> Result<Option<ClientRecord>, ClientStoreError> {
+        let row = sqlx::query(
+            "SELECT client_id, secret_hash, subject, company, enabled, roles, scopes
+             FROM clients WHERE client_id = ?",
+        )
+        .bind(client_id)
+        .fetch_optional(&self.pool)
+        .await?;
+
+        Ok(row.map(|row| ClientRecord {
+            client_id: row.get("client_id"),
+            secret_hash: row.get("secret_hash"),
+            subject: row.get("subject"),
+            company: row.get("company"),
+            enabled: row.get("enabled"),
+            roles: split_list(row.get("roles")),
+            scopes: split_list(row.get("scopes")),
+        }))
+    }
+
+    async fn register(&self, client: ClientRecord) -> Result<(), ClientStoreError> {
+        sqlx::query(
+            "INSERT OR REPLACE INTO clients
+                (client_id, secret_hash, subject, company, enabled, roles, scopes)
+             VALUES (?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(&client.client_id)
+        .bind(&client.secret_hash)
+        .bind(&client.subject)
+        .bind(&client.company)
+        .bind(client.enabled)
+        .bind(client.roles.join(" "))
+        .bind(client.scopes.join(" "))
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+
+    async fn set_enabled(&self, client_id: &str, enabled: bool) -> Result<bool, ClientStoreError> {
+        let result = sqlx::query("UPDATE clients SET enabled = ? WHERE client_id = ?")
+            .bind(enabled)
+            .bind(client_id)
+            .execute(&self.pool)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+}
+
+/// Splits a space separated list column
+fn split_list(value: String) -> Vec<String> {
+    value.split_whitespace().map(str::to_owned).collect()
+}
@@ -0,0 +1,668 @@
+//! Configuration Module
+//!
+//! This module loads the server's settings once at startup into a typed `Config`.
+//! Every setting can be given in several sources, later ones overriding earlier ones:
+//!
+//! 1. Built-in defaults
+//! 2. A TOML file: the one named by `--config` or `CONFIG_FILE`, otherwise
+//!    `config.toml` in the working directory when it exists
+//! 3. A `.env` file in the working directory or one of its parents
+//! 4. Environment variables
+//! 5. Command line flags, as `--port 8080` or `--port=8080`
+//!
+//! ```toml
+//! [server]
+//! host = "0.0.0.0"
+//! port = 3000
+//!
+//! [jwt]
+//! secret = "change-me"
+//! ttl_seconds = 900
+//!
+//! [queues]
+//! consumers = ["jobs"]
+//!
+//! [tls]
+//! cert_path = "certs/server.pem"
+//! key_path = "certs/server-key.pem"
+//! ```
+//!
+//! Values are checked once all sources are merged, and every problem is reported
+//! at once, naming each setting the way it was written in its source.
+
+use crate::auth_claim::TokenSettings;
+use jsonwebtoken::Algorithm;
+use std::collections::{HashMap, HashSet};
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+/// Variable naming the TOML file to load
+pub const CONFIG_FILE: &str = "CONFIG_FILE";
+
+/// TOML file loaded when none is named and it exists
+pub const DEFAULT_CONFIG_FILE: &str = "config.toml";
+
+/// A setting and its name in each source
+struct Setting {
+    /// Dotted key in the TOML file
+    key: &'static str,
+    /// Environment variable, also read from `.env`
+    env: &'static str,
+    /// Command line flag
+    flag: &'static str,
+    /// Whether the flag may be given without a value, meaning `true`
+    switch: bool,
+}
+
+const fn setting(key: &'static str, env: &'static str, flag: &'static str) -> Setting {
+    Setting { key, env, flag, switch: false }
+}
+
+/// Every setting `Config` understands
+const SETTINGS: &[Setting] = &[
+    setting("server.host", "HOST", "--host"),
+    setting("server.port", "PORT", "--port"),
+    setting("server.log", "RUST_LOG", "--log"),
+    setting("server.shutdown_timeout_seconds", "SHUTDOWN_TIMEOUT_SECONDS", "--shutdown-timeout-seconds"),
+    setting("jwt.issuer", "JWT_ISSUER", "--jwt-issuer"),
+    setting("jwt.audience", "JWT_AUDIENCE", "--jwt-audience"),
+    setting("jwt.ttl_seconds", "JWT_TTL_SECONDS", "--jwt-ttl-seconds"),
+    setting("jwt.refresh_ttl_seconds", "JWT_REFRESH_TTL_SECONDS", "--jwt-refresh-ttl-seconds"),
+    setting("jwt.leeway_seconds", "JWT_LEEWAY_SECONDS", "--jwt-leeway-seconds"),
+    setting("jwt.algorithm", "JWT_ALGORITHM", "--jwt-algorithm"),
+    setting("jwt.key_id", "JWT_KEY_ID", "--jwt-key-id"),
+    setting("jwt.secret", "JWT_SECRET", "--jwt-secret"),
+    setting("jwt.private_key_path", "JWT_PRIVATE_KEY_PATH", "--jwt-private-key-path"),
+    setting("jwt.public_key_path", "JWT_PUBLIC_KEY_PATH", "--jwt-public-key-path"),
+    setting("jwt.keys_file", "JWT_KEYS_FILE", "--jwt-keys-file"),
+    setting("jwt.keys_reload_seconds", "JWT_KEYS_RELOAD_SECONDS", "--jwt-keys-reload-seconds"),
+    setting("storage.client_store_url", "CLIENT_STORE_URL", "--client-store-url"),
+    setting("storage.revocation_snapshot", "REVOCATION_SNAPSHOT", "--revocation-snapshot"),
+    setting("storage.database_url", "DATABASE_URL", "--database-url"),
+    setting("storage.queue_store_url", "QUEUE_STORE_URL", "--queue-store-url"),
+    Setting { switch: true, ..setting("queues.sqs_emulator", "SQS_EMULATOR", "--sqs-emulator") },
+    setting("queues.consumers", "CONSUMER_QUEUES", "--consumer-queues"),
+    setting("queues.http_access", "HTTP_QUEUE_ACCESS", "--http-queue-access"),
+    setting("tls.cert_path", "TLS_CERT_PATH", "--tls-cert-path"),
+    setting("tls.key_path", "TLS_KEY_PATH", "--tls-key-path"),
+    setting("tls.client_ca_path", "TLS_CLIENT_CA_PATH", "--tls-client-ca-path"),
+    setting("tls.client_auth", "TLS_CLIENT_AUTH", "--tls-client-auth"),
+    setting("tls.reload_seconds", "TLS_RELOAD_SECONDS", "--tls-reload-seconds"),
+];
+
+/// Configuration error listing every problem found while loading
+#[derive(Debug)]
+pub struct ConfigError {
+    problems: Vec<String>,
+}
+
+impl ConfigError {
+    /// The problems found, one sentence each
+    pub fn problems(&self) -> &[String] {
+        &self.problems
+    }
+}
+
+impl Display for ConfigError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "invalid configuration:")?;
+        for problem in &self.problems {
+            write!(f, "\n  - {}", problem)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+/// Where the HTTP server listens and what it logs
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    /// Host name or IP address to bind to
+    pub host: String,
+    pub port: u16,
+    /// Log filter in `RUST_LOG` syntax, e.g. `info,axum_sqs_lib=debug`
+    pub log: String,
+    /// How long a graceful shutdown waits for requests and background tasks to finish
+    pub shutdown_timeout: Duration,
+}
+
+/// Where the signing keys come from
+///
+/// A key manifest takes precedence over the single key settings.
+#[derive(Clone)]
+pub struct KeyConfig {
+    /// Algorithm of the single signing key
+    pub algorithm: Algorithm,
+    /// `kid` of the single signing key
+    pub key_id: String,
+    /// Shared secret, for `HS256`
+    pub secret: Option<String>,
+    /// PEM private key, for the asymmetric algorithms
+    pub private_key_path: Option<PathBuf>,
+    /// PEM public key, for the asymmetric algorithms
+    pub public_key_path: Option<PathBuf>,
+    /// Key manifest for rotation, see `KeyRing::from_manifest`
+    pub keys_file: Option<PathBuf>,
+    /// How often the key manifest is checked for changes
+    pub reload_every: Duration,
+}
+
+impl std::fmt::Debug for KeyConfig {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("KeyConfig")
+            .field("algorithm", &self.algorithm)
+            .field("key_id", &self.key_id)
+            .field("secret", &self.secret.as_ref().map(|_| "<redacted>"))
+            .field("private_key_path", &self.private_key_path)
+            .field("public_key_path", &self.public_key_path)
+            .field("keys_file", &self.keys_file)
+            .field("reload_every", &self.reload_every)
+            .finish()
+    }
+}
+
+impl Default for KeyConfig {
+    fn default() -> Self {
+        Self {
+            algorithm: Algorithm::HS256,
+            key_id: "primary".to_owned(),
+            secret: None,
+            private_key_path: None,
+            public_key_path: None,
+            keys_file: None,
+            reload_every: Duration::from_secs(30),
+        }
+    }
+}
+
+/// Databases and files holding state across restarts; all optional
+#[derive(Debug, Clone, Default)]
+pub struct StorageConfig {
+    /// SQLite URL for registered clients; the in-memory demo store otherwise
+    pub client_store_url: Option<String>,
+    /// JSON file persisting revoked tokens
+    pub revocation_snapshot: Option<PathBuf>,
+    /// SQLite URL of the application database holding the outbox
+    pub database_url: Option<String>,
+    /// SQLite URL for durable message queues; queues are kept in memory otherwise
+    pub queue_store_url: Option<String>,
+}
+
+/// Message queue features
+#[derive(Debug, Clone, Default)]
+pub struct QueuesConfig {
+    /// Serve the SQS JSON protocol on `POST /`
+    pub sqs_emulator: bool,
+    /// Queues consumed by the example handlers, see `my_consumers::from_config`
+    pub consumers: Vec<String>,
+    /// Queues each company may use through the HTTP queue endpoints, by company
+    ///
+    /// Given as `company:queue` grants, e.g. `Initech:jobs,Initech:emails`.
+    pub http_access: HashMap<String, HashSet<String>>,
+}
+
+/// Whether clients must present a certificate under mutual TLS
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum ClientAuth {
+    /// Handshakes without a valid client certificate fail
+    #[default]
+    Required,
+    /// Clients may connect without a certificate; handlers decide
+    Optional,
+}
+
+/// TLS termination, see `tls::ServerTls`
+#[derive(Debug, Clone)]
+pub struct TlsConfig {
+    /// PEM certificate chain, the server certificate first
+    pub cert_path: PathBuf,
+    /// PEM private key of the server certificate
+    pub key_path: PathBuf,
+    /// PEM CA certificates client certificates are verified against; enables mutual TLS
+    pub client_ca_path: Option<PathBuf>,
+    /// Whether clients must present a certificate, when `client_ca_path` is set
+    pub client_auth: ClientAuth,
+    /// How often the certificate, key and client CA files are checked for changes
+    pub reload_every: Duration,
+}
+
+/// The server's configuration
+#[derive(Debug, Clone)]
+pub struct Config {
+    pub server: ServerConfig,
+    /// Issued token settings, from the `jwt` section
+    pub tokens: TokenSettings,
+    /// Signing keys, from the `jwt` section
+    pub keys: KeyConfig,
+    pub storage: StorageConfig,
+    pub queues: QueuesConfig,
+    /// Serve HTTPS instead of plain HTTP, when a certificate is configured
+    pub tls: Option<TlsConfig>,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Self {
+            server: ServerConfig {
+                host: "127.0.0.1".to_owned(),
+                port: 3000,
+                log: "info".to_owned(),
+                shutdown_timeout: Duration::from_secs(30),
+            },
+            tokens: TokenSettings::default(),
+            keys: KeyConfig::default(),
+            storage: StorageConfig::default(),
+            queues: QueuesConfig::default(),
+            tls: None,
+        }
+    }
+}
+
+impl Config {
+    /// Loads the configuration from the process's command line and environment
+    ///
+    /// See the module documentation for the sources and their precedence.
+    pub fn load() -> Result<Self, ConfigError> {
+        Self::load_from(std::env::args().skip(1), std::env::vars())
+    }
+
+    /// Loads the configuration from the given flags and environment variables
+    ///
+    /// The TOML file and `.env` are read as in `load`.
+    ///
+    /// # Arguments
+    ///
+    /// * `args` - Command line arguments, without the program name
+    /// * `vars` - Environment variables
+    pub fn load_from(
+        args: impl IntoIterator<Item = String>,
+        vars: impl IntoIterator<Item = (String, String)>,
+    ) -> Result<Self, ConfigError> {
+        let mut problems = Vec::new();
+        let (flags, named_file) = parse_args(args, &mut problems);
+        let vars: HashMap<String, String> = vars.into_iter().collect();
+        let dotenv = read_dotenv(&mut problems);
+
+        let mut raw = HashMap::new();
+        let named_file = named_file
+            .or_else(|| vars.get(CONFIG_FILE).cloned())
+            .or_else(|| dotenv.get(CONFIG_FILE).cloned())
+            .map(PathBuf::from);
+        match named_file {
+            Some(path) => read_file(&path, &mut raw, &mut problems),
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                read_file(Path::new(DEFAULT_CONFIG_FILE), &mut raw, &mut problems)
+            }
+            None => {}
+        }
+        for setting in SETTINGS {
+            if let Some(value) = dotenv.get(setting.env) {
+                raw.insert(setting.key, (value.clone(), Source::DotEnv));
+            }
+            if let Some(value) = vars.get(setting.env) {
+                raw.insert(setting.key, (value.clone(), Source::Environment));
+            }
+        }
+        for (setting, value) in flags {
+            raw.insert(setting.key, (value, Source::CommandLine));
+        }
+
+        let config = Values { raw: &raw, problems: &mut problems }.config();
+        if problems.is_empty() {
+            Ok(config)
+        } else {
+            Err(ConfigError { problems })
+        }
+    }
+}
+
+/// Where a value was given
+#[derive(Debug, Clone)]
+enum Source {
+    File(PathBuf),
+    DotEnv,
+    Environment,
+    CommandLine,
+}
+
+fn lookup(key: &str) -> &'static Setting {
+    SETTINGS
+        .iter()
+        .find(|setting| setting.key == key)
+        .unwrap_or_else(|| panic!("unknown setting {}", key))
+}
+
+/// Names a setting the way it was written in its source
+fn describe(setting: &Setting, source: &Source) -> String {
+    match source {
+        Source::File(path) => format!("{} in {}", setting.key, path.display()),
+        Source::DotEnv => format!("{} in .env", setting.env),
+        Source::Environment => setting.env.to_owned(),
+        Source::CommandLine => setting.flag.to_owned(),
+    }
+}
+
+/// Names a setting that was not given, with every way to give it
+fn describe_missing(key: &str) -> String {
+    let setting = lookup(key);
+    format!("{} ({} or {})", setting.key, setting.env, setting.flag)
+}
+
+/// Splits command line arguments into setting values and the named TOML file
+fn parse_args(
+    args: impl IntoIterator<Item = String>,
+    problems: &mut Vec<String>,
+) -> (Vec<(&'static Setting, String)>, Option<String>) {
+    let mut flags = Vec::new();
+    let mut file = None;
+    let mut args = args.into_iter().peekable();
+    while let Some(arg) = args.next() {
+        if !arg.starts_with("--") {
+            problems.push(format!("unexpected argument {:?}", arg));
+            continue;
+        }
+        let (flag, inline) = match arg.split_once('=') {
+            Some((flag, value)) => (flag.to_owned(), Some(value.to_owned())),
+            None => (arg, None),
+        };
+        let setting = SETTINGS.iter().find(|setting| setting.flag == flag);
+        if setting.is_none() && flag != "--config" {
+            problems.push(format!("unknown flag {}", flag));
+            continue;
+        }
+        let value = match inline {
+            Some(value) => value,
+            None if setting.is_some_and(|setting| setting.switch)
+                && args.peek().is_none_or(|next| next.starts_with("--")) =>
+            {
+                "true".to_owned()
+            }
+            None => match args.next() {
+                Some(value) => value,
+                None => {
+                    problems.push(format!("{} needs a value", flag));
+                    continue;
+                }
+            },
+        };
+        match setting {
+            Some(setting) => flags.push((setting, value)),
+            None => file = Some(value),
+        }
+    }
+    (flags, file)
+}
+
+/// Reads the variables of the nearest `.env` file, if there is one
+fn read_dotenv(problems: &mut Vec<String>) -> HashMap<String, String> {
+    match dotenvy::dotenv_iter() {
+        Ok(entries) => entries
+            .filter_map(|entry| entry.map_err(|e| problems.push(format!(".env: {}", e))).ok())
+            .collect(),
+        Err(e) if e.not_found() => HashMap::new(),
+        Err(e) => {
+            problems.push(format!(".env: {}", e));
+            HashMap::new()
+        }
+    }
+}
+
+/// Reads the settings of a TOML file into `raw`
+fn read_file(
+    path: &Path,
+    raw: &mut HashMap<&'static str, (String, Source)>,
+    problems: &mut Vec<String>,
+) {
+    let table = match std::fs::read_to_string(path) {
+        Ok(text) => match text.parse::<toml::Table>() {
+            Ok(table) => table,
+            Err(e) => return problems.push(format!("{}: {}", path.display(), e)),
+        },
+        Err(e) => return problems.push(format!("cannot read {}: {}", path.display(), e)),
+    };
+
+    for (section, entries) in table {
+        let toml::Value::Table(entries) = entries else {
+            problems.push(format!("unknown setting {} in {}", section, path.display()));
+            continue;
+        };
+        for (name, value) in entries {
+            let key = format!("{}.{}", section, name);
+            let Some(setting) = SETTINGS.iter().find(|setting| setting.key == key) else {
+                problems.push(format!("unknown setting {} in {}", key, path.display()));
+                continue;
+            };
+            // Lists are joined as they would be written in an environment variable
+            let value = match value {
+                toml::Value::Array(items) => items
+                    .iter()
+                    .map(scalar)
+                    .collect::<Option<Vec<_>>>()
+                    .map(|items| items.join(",")),
+                value => scalar(&value),
+            };
+            let source = Source::File(path.to_owned());
+            match value {
+                Some(value) => {
+                    raw.insert(setting.key, (value, source));
+                }
+                None => problems.push(format!("{}: unsupported value", describe(setting, &source))),
+            }
+        }
+    }
+}
+
+/// Converts a TOML string, number or boolean into its text
+fn scalar(value: &toml::Value) -> Option<String> {
+    match value {
+        toml::Value::String(text) => Some(text.clone()),
+        toml::Value::Integer(number) => Some(number.to_string()),
+        toml::Value::Float(number) => Some(number.to_string()),
+        toml::Value::Boolean(flag) => Some(flag.to_string()),
+        _ => None,
+    }
+}
+
+/// Merged raw values being converted into a `Config`
+struct Values<'a> {
+    raw: &'a HashMap<&'static str, (String, Source)>,
+    problems: &'a mut Vec<String>,
+}
+
+impl Values<'_> {
+    /// The text of a setting; empty values count as not given
+    fn string(&self, key: &str) -> Option<String> {
+        self.raw
+            .get(key)
+            .map(|(value, _)| value.trim().to_owned())
+            .filter(|value| !value.is_empty())
+    }
+
+    /// Parses a setting, recording a problem when it is malformed
+    fn parse<T: FromStr>(&mut self, key: &str, what: &str) -> Option<T> {
+        let value = self.string(key)?;
+        match value.parse() {
+            Ok(value) => Some(value),
+            Err(_) => {
+                self.problem(key, format!("invalid {} {:?}", what, value));
+                None
+            }
+        }
+    }
+
+    /// Parses a positive whole number of seconds
+    fn seconds(&mut self, key: &str) -> Option<Duration> {
+        let seconds: u64 = self.parse(key, "number of seconds")?;
+        if seconds == 0 {
+            self.problem(key, "must be greater than zero".to_owned());
+            return None;
+        }
+        Some(Duration::from_secs(seconds))
+    }
+
+    /// Records a problem with a given setting
+    fn problem(&mut self, key: &str, detail: String) {
+        let name = match self.raw.get(key) {
+            Some((_, source)) => describe(lookup(key), source),
+            None => describe_missing(key),
+        };
+        self.problems.push(format!("{}: {}", name, detail));
+    }
+
+    fn config(mut self) -> Config {
+        let defaults = Config::default();
+        let server = ServerConfig {
+            host: self.string("server.host").unwrap_or(defaults.server.host),
+            port: self.parse("server.port", "port").unwrap_or(defaults.server.port),
+            log: self.string("server.log").unwrap_or(defaults.server.log),
+            shutdown_timeout: self
+                .seconds("server.shutdown_timeout_seconds")
+                .unwrap_or(defaults.server.shutdown_timeout),
+        };
+        if let Err(e) = tracing_subscriber::EnvFilter::try_new(&server.log) {
+            self.problem("server.log", format!("invalid log filter: {}", e));
+        }
+
+        let tokens = TokenSettings {
+            issuer: self.string("jwt.issuer").unwrap_or(defaults.tokens.issuer),
+            audience: self.string("jwt.audience").unwrap_or(defaults.tokens.audience),
+            ttl: self.seconds("jwt.ttl_seconds").unwrap_or(defaults.tokens.ttl),
+            refresh_ttl: self
+                .seconds("jwt.refresh_ttl_seconds")
+                .unwrap_or(defaults.tokens.refresh_ttl),
+            leeway: self
+                .parse("jwt.leeway_seconds", "number of seconds")
+                .map(Duration::from_secs)
+                .unwrap_or(defaults.tokens.leeway),
+        };
+
+        let algorithm = self.string("jwt.algorithm").and_then(|name| match Algorithm::from_str(&name) {
+            Ok(
+                algorithm @ (Algorithm::HS256 | Algorithm::RS256 | Algorithm::ES256 | Algorithm::EdDSA),
+            ) => Some(algorithm),
+            _ => {
+                let detail = format!("unsupported JWT algorithm {:?}, use HS256, RS256, ES256 or EdDSA", name);
+                self.problem("jwt.algorithm", detail);
+                None
+            }
+        });
+        let keys = KeyConfig {
+            algorithm: algorithm.unwrap_or(defaults.keys.algorithm),
+            key_id: self.string("jwt.key_id").unwrap_or(defaults.keys.key_id),
+            secret: self.string("jwt.secret"),
+            private_key_path: self.string("jwt.private_key_path").map(PathBuf::from),
+            public_key_path: self.string("jwt.public_key_path").map(PathBuf::from),
+            keys_file: self.string("jwt.keys_file").map(PathBuf::from),
+            reload_every: self
+                .seconds("jwt.keys_reload_seconds")
+                .unwrap_or(defaults.keys.reload_every),
+        };
+        // Without a manifest the single key needs its material
+        if keys.keys_file.is_none() {
+            if keys.algorithm == Algorithm::HS256 && keys.secret.is_none() {
+                self.problem("jwt.secret", "required for HS256 signing".to_owned());
+            }
+            if keys.algorithm != Algorithm::HS256 {
+                let name = format!("{:?}", keys.algorithm);
+                if keys.private_key_path.is_none() {
+                    self.problem("jwt.private_key_path", format!("required for {} signing", name));
+                }
+                if keys.public_key_path.is_none() {
+                    self.problem("jwt.public_key_path", format!("required for {} signing", name));
+                }
+            }
+        }
+
+        let storage = StorageConfig {
+            client_store_url: self.string("storage.client_store_url"),
+            revocation_snapshot: self.string("storage.revocation_snapshot").map(PathBuf::from),
+            database_url: self.string("storage.database_url"),
+            queue_store_url: self.string("storage.queue_store_url"),
+        };
+
+        let queues = QueuesConfig {
+            sqs_emulator: self.parse("queues.sqs_emulator", "boolean").unwrap_or_default(),
+            consumers: self
+                .string("queues.consumers")
+                .unwrap_or_default()
+                .split(',')
+                .map(str::trim)
+                .filter(|queue| !queue.is_empty())
+                .map(str::to_owned)
+                .collect(),
+            http_access: self.http_access(),
+        };
+
+        let tls = self.tls();
+
+        Config { server, tokens, keys, storage, queues, tls }
+    }
+
+    /// Reads the `company:queue` grants of the HTTP queue endpoints
+    fn http_access(&mut self) -> HashMap<String, HashSet<String>> {
+        let mut access: HashMap<String, HashSet<String>> = HashMap::new();
+        for grant in self.string("queues.http_access").unwrap_or_default().split(',') {
+            let grant = grant.trim();
+            if grant.is_empty() {
+                continue;
+            }
+            // Queue names cannot contain colons, company names might
+            match grant.rsplit_once(':') {
+                Some((company, queue)) if !company.trim().is_empty() && !queue.trim().is_empty() => {
+                    access
+                        .entry(company.trim().to_owned())
+                        .or_default()
+                        .insert(queue.trim().to_owned());
+                }
+                _ => self.problem("queues.http_access", format!("invalid grant {:?}, use company:queue", grant)),
+            }
+        }
+        access
+    }
+
+    /// Reads the `tls` section; TLS is off unless a certificate is given
+    fn tls(&mut self) -> Option<TlsConfig> {
+        let cert_path = self.string("tls.cert_path").map(PathBuf::from);
+        let key_path = self.string("tls.key_path").map(PathBuf::from);
+        let client_ca_path = self.string("tls.client_ca_path").map(PathBuf::from);
+        let client_auth = self.string("tls.client_auth").and_then(|mode| match mode.as_str() {
+            "required" => Some(ClientAuth::Required),
+            "optional" => Some(ClientAuth::Optional),
+            _ => {
+                self.problem("tls.client_auth", format!("invalid mode {:?}, use required or optional", mode));
+                None
+            }
+        });
+        let reload_every = self.seconds("tls.reload_seconds").unwrap_or(Duration::from_secs(30));
+
+        if client_auth.is_some() && client_ca_path.is_none() {
+            self.problem("tls.client_auth", format!("requires {}", describe_missing("tls.client_ca_path")));
+        }
+        match (cert_path, key_path) {
+            (Some(cert_path), Some(key_path)) => Some(TlsConfig {
+                cert_path,
+                key_path,
+                client_ca_path,
+                client_auth: client_auth.unwrap_or_default(),
+                reload_every,
+            }),
+            (Some(_), None) => {
+                self.problem("tls.key_path", "required with tls.cert_path".to_owned());
+                None
+            }
+            (None, Some(_)) => {
+                self.problem("tls.cert_path", "required with tls.key_path".to_owned());
+                None
+            }
+            (None, None) => {
+                if client_ca_path.is_some() {
+                    self.problem("tls.client_ca_path", format!("requires {}", describe_missing("tls.cert_path")));
+                }
+                None
+            }
+        }
+    }
+}
@@ -0,0 +1,224 @@
+//! Health Module
+//!
+//! This module provides the endpoints polled by load balancers and orchestrators:
+//! - `GET /health/live` answers as long as the process serves requests
+//! - `GET /health/ready` probes the server's dependencies, the application
+//!   database, the queue backend and the signing keys, along with any checks
+//!   registered in `MyAppState::health_checks`
+//!
+//! Readiness fails when a critical check fails, and as soon as a graceful
+//! shutdown begins, so that traffic is routed elsewhere while in-flight
+//! requests drain.
+
+use crate::app_state::MyAppState;
+use crate::jwt_keys::KeyRing;
+use crate::queue::MessageQueue;
+use async_trait::async_trait;
+use axum::http::StatusCode;
+use axum::{Extension, Json};
+use serde::Serialize;
+use sqlx::SqlitePool;
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use utoipa::ToSchema;
+
+/// How long a check may take before it counts as failed
+const CHECK_TIMEOUT: Duration = Duration::from_secs(2);
+
+/// A dependency probed by the readiness endpoint
+#[async_trait]
+pub trait HealthCheck: Debug + Send + Sync {
+    /// Name of the check in the readiness response; unique per server
+    fn name(&self) -> &str;
+
+    /// Whether a failure makes the server unready; other failures are only reported
+    fn critical(&self) -> bool {
+        true
+    }
+
+    /// Probes the dependency
+    async fn check(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>>;
+}
+
+/// The application database holding the outbox
+#[derive(Debug)]
+struct DatabaseCheck(SqlitePool);
+
+#[async_trait]
+impl HealthCheck for DatabaseCheck {
+    fn name(&self) -> &str {
+        "database"
+    }
+
+    async fn check(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        sqlx::query("SELECT 1").execute(&self.0).await?;
+        Ok(())
+    }
+}
+
+/// The message queue backend
+#[derive(Debug)]
+struct QueueCheck(Arc<dyn MessageQueue>);
+
+#[async_trait]
+impl HealthCheck for QueueCheck {
+    fn name(&self) -> &str {
+        "queue"
+    }
+
+    async fn check(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        Ok(self.0.ping().await?)
+    }
+}
+
+/// The key ring, which must be able to sign tokens
+#[derive(Debug)]
+struct KeyRingCheck(Arc<KeyRing>);
+
+#[async_trait]
+impl HealthCheck for KeyRingCheck {
+    fn name(&self) -> &str {
+        "key_ring"
+    }
+
+    async fn check(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        self.0.encode(&serde_json::json!({ "sub": "health-check" }))?;
+        Ok(())
+    }
+}
+
+/// The checks run for the state: its dependencies, then the registered checks
+///
+/// The database check is only included when an application database is configured.
+pub fn checks(state: &MyAppState) -> Vec<Arc<dyn HealthCheck>> {
+    let mut checks: Vec<Arc<dyn HealthCheck>> = Vec::new();
+    if let Some(outbox) = &state.outbox {
+        checks.push(Arc::new(DatabaseCheck(outbox.pool().clone())));
+    }
+    checks.push(Arc::new(QueueCheck(state.queue.clone())));
+    checks.push(Arc::new(KeyRingCheck(state.keys.clone())));
+    checks.extend(state.health_checks.iter().cloned());
+    checks
+}
+
+/// Outcome of one check
+#[derive(Debug, Serialize, ToSchema)]
+pub struct CheckResult {
+    /// `up` or `down`
+    pub status: &'static str,
+    /// Whether a failure makes the server unready
+    pub critical: bool,
+    /// How long the check took, in milliseconds
+    pub latency_ms: f64,
+    /// Why the check failed
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+/// Whether the process is up
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Liveness {
+    /// Always `alive`
+    pub status: &'static str,
+}
+
+/// Whether the server accepts traffic
+#[derive(Debug, Serialize, ToSchema)]
+pub struct Readiness {
+    /// `ready`, `not_ready` when a critical check fails, or `shutting_down`
+    /// once a graceful shutdown has begun
+    pub status: &'static str,
+    /// Outcome of every check, by name
+    pub checks: BTreeMap<String, CheckResult>,
+}
+
+/// Reports that the process is up
+///
+/// Dependencies are not checked, so that an outage elsewhere does not get the
+/// server restarted.
+///
+/// # Returns
+///
+/// 200 OK whenever the server handles requests
+#[utoipa::path(
+    get,
+    path = "/health/live",
+    tag = "health",
+    responses((status = 200, description = "Alive", body = Liveness))
+)]
+pub async fn live() -> Json<Liveness> {
+    Json(Liveness { status: "alive" })
+}
+
+/// Reports whether the server should receive traffic
+///
+/// Runs every check concurrently, each given two seconds.
+///
+/// # Arguments
+///
+/// * `Extension(state)` - The application state holding the dependencies and the shutdown flag
+///
+/// # Returns
+///
+/// 200 OK while serving, 503 Service Unavailable when a critical check fails
+/// or once shutdown has begun
+#[utoipa::path(
+    get,
+    path = "/health/ready",
+    tag = "health",
+    responses(
+        (status = 200, description = "Ready for traffic", body = Readiness),
+        (status = 503, description = "A critical check failed or shutting down", body = Readiness)
+    )
+)]
+pub async fn ready(Extension(state): Extension<MyAppState>) -> (StatusCode, Json<Readiness>) {
+    let registered = checks(&state);
+    let mut running = tokio::task::JoinSet::new();
+    for check in &registered {
+        let check = check.clone();
+        running.spawn(async move {
+            let started = Instant::now();
+            let outcome = match tokio::time::timeout(CHECK_TIMEOUT, check.check()).await {
+                Ok(outcome) => outcome.map_err(|e| e.to_string()),
+                Err(_) => Err(format!("timed out after {:?}", CHECK_TIMEOUT)),
+            };
+            let result = CheckResult {
+                status: if outcome.is_ok() { "up" } else { "down" },
+                critical: check.critical(),
+                latency_ms: started.elapsed().as_secs_f64() * 1000.0,
+                error: outcome.err(),
+            };
+            (check.name().to_owned(), result)
+        });
+    }
+    let mut checks = BTreeMap::new();
+    while let Some(finished) = running.join_next().await {
+        match finished {
+            Ok((name, result)) => {
+                checks.insert(name, result);
+            }
+            Err(e) => tracing::error!("health check panicked: {}", e),
+        }
+    }
+    // Checks that panicked have no result yet
+    for check in &registered {
+        checks.entry(check.name().to_owned()).or_insert_with(|| CheckResult {
+            status: "down",
+            critical: check.critical(),
+            latency_ms: 0.0,
+            error: Some("the check panicked".to_owned()),
+        });
+    }
+
+    let failed = checks.values().any(|check| check.critical && check.error.is_some());
+    let (status, readiness) = if state.shutdown.is_triggered() {
+        (StatusCode::SERVICE_UNAVAILABLE, "shutting_down")
+    } else if failed {
+        (StatusCode::SERVICE_UNAVAILABLE, "not_ready")
+    } else {
+        (StatusCode::OK, "ready")
+    };
+    (status, Json(Readiness { status: readiness, checks }))
+}
@@ -0,0 +1,144 @@
+//! Idempotency Module
+//!
+//! Queues deliver messages at least once, so a handler may see the same message
+//! twice: after a visibility timeout ran out mid-processing, a failed delete or
+//! a repeated send. This module provides the ledger of processed messages that
+//! `Consumer::idempotent` checks before running a handler, so that a duplicate
+//! is acknowledged without running the business logic again.
+//!
+//! Entries are keyed by the queue and the message id, or an idempotency
+//! attribute set by the sender, and kept for a retention period after which the
+//! message is treated as new again.
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::SqlitePool;
+use std::collections::HashMap;
+use std::fmt::{Debug, Display};
+use tokio::sync::RwLock;
+
+/// Ledger error types
+#[derive(Debug)]
+pub enum LedgerError {
+    /// The database operation failed
+    Database(sqlx::Error),
+}
+
+impl Display for LedgerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LedgerError::Database(e) => write!(f, "processed message ledger error: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for LedgerError {}
+
+impl From<sqlx::Error> for LedgerError {
+    fn from(e: sqlx::Error) -> Self {
+        LedgerError::Database(e)
+    }
+}
+
+/// Storage of the keys of processed messages
+#[async_trait]
+pub trait ProcessedLedger: Debug + Send + Sync {
+    /// Checks whether a message with the given key was processed and not yet forgotten
+    async fn is_processed(&self, key: &str) -> Result<bool, LedgerError>;
+
+    /// Records that the message with the given key was processed, until `expires_at`
+    async fn mark_processed(&self, key: &str, expires_at: DateTime<Utc>) -> Result<(), LedgerError>;
+}
+
+/// In-memory ledger with TTL eviction
+///
+/// Expired entries are dropped whenever a message is marked. The ledger is
+/// lost on restart; use `SqliteProcessedLedger` to keep it.
+#[derive(Debug, Default)]
+pub struct InMemoryProcessedLedger {
+    processed: RwLock<HashMap<String, DateTime<Utc>>>,
+}
+
+impl InMemoryProcessedLedger {
+    /// Creates an empty ledger
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl ProcessedLedger for InMemoryProcessedLedger {
+    async fn is_processed(&self, key: &str) -> Result<bool, LedgerError> {
+        Ok(self
+            .processed
+            .read()
+            .await
+            .get(key)
+            .is_some_and(|expires_at| *expires_at > Utc::now()))
+    }
+
+    async fn mark_processed(&self, key: &str, expires_at: DateTime<Utc>) -> Result<(), LedgerError> {
+        let now = Utc::now();
+        let mut processed = self.processed.write().await;
+        processed.retain(|_, expires_at| *expires_at > now);
+        processed.insert(key.to_owned(), expires_at);
+        Ok(())
+    }
+}
+
+/// SQLite-backed ledger
+///
+/// Entries are kept in the `processed_messages` table, which is created if
+/// missing, typically in the application database next to the outbox.
+/// Expired entries are deleted whenever a message is marked.
+#[derive(Debug, Clone)]
+pub struct SqliteProcessedLedger {
+    pool: SqlitePool,
+}
+
+impl SqliteProcessedLedger {
+    /// Builds a ledger on top of an existing pool and prepares the schema
+    pub async fn from_pool(pool: SqlitePool) -> Result<Self, LedgerError> {
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS processed_messages (
+                key          TEXT PRIMARY KEY NOT NULL,
+                processed_at INTEGER NOT NULL,
+                expires_at   INTEGER NOT NULL
+            )",
+        )
+        .execute(&pool)
+        .await?;
+        Ok(Self { pool })
+    }
+}
+
+#[async_trait]
+impl ProcessedLedger for SqliteProcessedLedger {
+    async fn is_processed(&self, key: &str) -> Result<bool, LedgerError> {
+        let found: Option<i64> =
+            sqlx::query_scalar("SELECT 1 FROM processed_messages WHERE key = ? AND expires_at > ?")
+                .bind(key)
+                .bind(Utc::now().timestamp_millis())
+                .fetch_optional(&self.pool)
+                .await?;
+        Ok(found.is_some())
+    }
+
+    async fn mark_processed(&self, key: &str, expires_at: DateTime<Utc>) -> Result<(), LedgerError> {
+        let now = Utc::now().timestamp_millis();
+        sqlx::query("DELETE FROM processed_messages WHERE expires_at <= ?")
+            .bind(now)
+            .execute(&self.pool)
+            .await?;
+        sqlx::query(
+            "INSERT OR REPLACE INTO processed_messages (key, processed_at, expires_at)
+             VALUES (?, ?, ?)",
+        )
+        .bind(key)
+        .bind(now)
+        .bind(expires_at.timestamp_millis())
+        .execute(&self.pool)
+        .await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,592 @@
+//! JWT Keys Module
+//!
+//! This module loads the keys used to sign and verify access tokens. It supports:
+//! - HMAC (HS256) keys built from a shared secret
+//! - RS256, ES256 and EdDSA key pairs loaded from PEM files
+//! - Key identifiers (`kid`) stamped into token headers
+//! - Public JWK export for the `/.well-known/jwks.json` endpoint
+//! - A `KeyRing` with one signing key and several verification keys, selected by
+//!   `kid` and reloadable at runtime from a key manifest file
+
+use crate::config::KeyConfig;
+use base64::{Engine, engine::general_purpose::URL_SAFE_NO_PAD};
+use jsonwebtoken::jwk::{
+    AlgorithmParameters, CommonParameters, EllipticCurve, EllipticCurveKeyParameters,
+    EllipticCurveKeyType, Jwk, JwkSet, KeyAlgorithm, OctetKeyPairParameters, OctetKeyPairType,
+    PublicKeyUse, RSAKeyParameters, RSAKeyType,
+};
+use jsonwebtoken::{
+    Algorithm, DecodingKey, EncodingKey, Header, TokenData, Validation, decode, decode_header,
+    encode,
+};
+use pkcs1::RsaPublicKey;
+use pkcs1::der::Decode;
+use serde::{Deserialize, Serialize, de::DeserializeOwned};
+use spki::{ObjectIdentifier, SubjectPublicKeyInfoRef};
+use std::collections::HashMap;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+
+/// OID of `rsaEncryption`
+const OID_RSA: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.113549.1.1.1");
+/// OID of `id-ecPublicKey`
+const OID_EC: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.2.1");
+/// OID of the `prime256v1` (P-256) curve
+const OID_P256: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.2.840.10045.3.1.7");
+/// OID of `id-Ed25519`
+const OID_ED25519: ObjectIdentifier = ObjectIdentifier::new_unwrap("1.3.101.112");
+
+/// Key loading error types
+#[derive(Debug)]
+pub enum KeyError {
+    /// A required setting is missing
+    MissingSetting(&'static str),
+    /// The configured algorithm is unknown or not supported
+    UnsupportedAlgorithm(String),
+    /// A key file could not be read
+    Io(String, std::io::Error),
+    /// A key is not valid PEM/DER for the configured algorithm
+    InvalidKey(String),
+    /// The key manifest is malformed or inconsistent
+    Manifest(String),
+}
+
+impl Display for KeyError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            KeyError::MissingSetting(name) => write!(f, "{} must be set", name),
+            KeyError::UnsupportedAlgorithm(alg) => {
+                write!(f, "unsupported JWT algorithm {}, use HS256, RS256, ES256 or EdDSA", alg)
+            }
+            KeyError::Io(path, e) => write!(f, "cannot read key file {}: {}", path, e),
+            KeyError::InvalidKey(reason) => write!(f, "invalid key: {}", reason),
+            KeyError::Manifest(reason) => write!(f, "invalid key manifest: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for KeyError {}
+
+/// JWT signing keys container
+///
+/// Holds the encoding and decoding keys for one algorithm, the `kid`
+/// identifying them and, for asymmetric algorithms, the public JWK.
+/// Verification-only keys have no encoding key.
+pub struct Keys {
+    /// Key identifier stamped into the header of signed tokens
+    pub kid: String,
+    /// Signing algorithm
+    pub algorithm: Algorithm,
+    pub encoding: Option<EncodingKey>,
+    pub decoding: DecodingKey,
+    /// Public key as JWK; `None` for HMAC secrets, which must never be published
+    pub jwk: Option<Jwk>,
+}
+
+impl Keys {
+    /// Creates new HS256 signing keys from a secret
+    ///
+    /// # Arguments
+    ///
+    /// * `secret` - The secret key bytes used for JWT signing
+    pub fn new(secret: &[u8]) -> Self {
+        Self::hmac("primary", secret)
+    }
+
+    /// Creates new HS256 signing keys from a secret with the given `kid`
+    pub fn hmac(kid: &str, secret: &[u8]) -> Self {
+        Self {
+            kid: kid.to_owned(),
+            algorithm: Algorithm::HS256,
+            encoding: Some(EncodingKey::from_secret(secret)),
+            decoding: DecodingKey::from_secret(secret),
+            jwk: None,
+        }
+    }
+
+    /// Creates asymmetric signing keys from a PEM encoded key pair
+    ///
+    /// The private key must be PKCS#8 (`BEGIN PRIVATE KEY`, or `BEGIN RSA PRIVATE KEY`
+    /// for RS256) and the public key SubjectPublicKeyInfo (`BEGIN PUBLIC KEY`).
+    /// The pair is checked by signing and verifying a probe message.
+    ///
+    /// # Arguments
+    ///
+    /// * `kid` - The key identifier
+    /// * `algorithm` - One of `RS256`, `ES256` or `EdDSA`
+    /// * `private_pem` - The PEM encoded private key
+    /// * `public_pem` - The PEM encoded public key
+    pub fn from_pem(
+        kid: &str,
+        algorithm: Algorithm,
+        private_pem: &[u8],
+        public_pem: &[u8],
+    ) -> Result<Self, KeyError> {
+        let invalid = |e: jsonwebtoken::errors::Error| KeyError::InvalidKey(e.to_string());
+        let encoding = match algorithm {
+            Algorithm::RS256 => EncodingKey::from_rsa_pem(private_pem).map_err(invalid)?,
+            Algorithm::ES256 => EncodingKey::from_ec_pem(private_pem).map_err(invalid)?,
+            Algorithm::EdDSA => EncodingKey::from_ed_pem(private_pem).map_err(invalid)?,
+            other => return Err(KeyError::UnsupportedAlgorithm(format!("{:?}", other))),
+        };
+        let jwk = public_jwk(kid, algorithm, public_pem)?;
+        let decoding = DecodingKey::from_jwk(&jwk).map_err(invalid)?;
+
+        // Catch a private key paired with someone else's public key at startup
+        let probe = b"key pair probe";
+        let signature = jsonwebtoken::crypto::sign(probe, &encoding, algorithm).map_err(invalid)?;
+        if !jsonwebtoken::crypto::verify(&signature, probe, &decoding, algorithm).map_err(invalid)? {
+            return Err(KeyError::InvalidKey(format!(
+                "private and public key of {} do not match",
+                kid
+            )));
+        }
+
+        Ok(Self {
+            kid: kid.to_owned(),
+            algorithm,
+            encoding: Some(encoding),
+            decoding,
+            jwk: Some(jwk),
+        })
+    }
+
+    /// Creates verification-only keys from a PEM encoded public key
+    ///
+    /// Used for retired signing keys whose tokens must stay valid until they expire.
+    pub fn verify_only_pem(kid: &str, algorithm: Algorithm, public_pem: &[u8]) -> Result<Self, KeyError> {
+        let jwk = public_jwk(kid, algorithm, public_pem)?;
+        let decoding =
+            DecodingKey::from_jwk(&jwk).map_err(|e| KeyError::InvalidKey(e.to_string()))?;
+        Ok(Self {
+            kid: kid.to_owned(),
+            algorithm,
+            encoding: None,
+            decoding,
+            jwk: Some(jwk),
+        })
+    }
+
+    /// Loads the single signing key described by the configuration
+    ///
+    /// `HS256` keys are built from `secret`, the other algorithms from the
+    /// `private_key_path` and `public_key_path` PEM files.
+    pub fn from_config(config: &KeyConfig) -> Result<Self, KeyError> {
+        if config.algorithm == Algorithm::HS256 {
+            let secret = config
+                .secret
+                .as_ref()
+                .ok_or(KeyError::MissingSetting("jwt.secret"))?;
+            return Ok(Self::hmac(&config.key_id, secret.as_bytes()));
+        }
+
+        let read = |name: &'static str, path: &Option<PathBuf>| {
+            let path = path.as_ref().ok_or(KeyError::MissingSetting(name))?;
+            std::fs::read(path).map_err(|e| KeyError::Io(path.display().to_string(), e))
+        };
+        Self::from_pem(
+            &config.key_id,
+            config.algorithm,
+            &read("jwt.private_key_path", &config.private_key_path)?,
+            &read("jwt.public_key_path", &config.public_key_path)?,
+        )
+    }
+
+    /// Signs claims into a token whose header carries this key's `alg` and `kid`
+    ///
+    /// Fails for verification-only keys.
+    pub fn encode<T: Serialize>(&self, claims: &T) -> Result<String, jsonwebtoken::errors::Error> {
+        let encoding = self
+            .encoding
+            .as_ref()
+            .ok_or(jsonwebtoken::errors::ErrorKind::InvalidKeyFormat)?;
+        let mut header = Header::new(self.algorithm);
+        header.kid = Some(self.kid.clone());
+        encode(&header, claims, encoding)
+    }
+
+    /// Verifies and decodes a token signed with this key
+    ///
+    /// Only this key's algorithm is accepted, and tokens naming a different
+    /// `kid` are rejected.
+    pub fn decode<T: DeserializeOwned>(
+        &self,
+        token: &str,
+        validation: &Validation,
+    ) -> Result<TokenData<T>, jsonwebtoken::errors::Error> {
+        let header = decode_header(token)?;
+        if header.kid.is_some_and(|kid| kid != self.kid) {
+            return Err(jsonwebtoken::errors::ErrorKind::InvalidToken.into());
+        }
+        let mut validation = validation.clone();
+        validation.algorithms = vec![self.algorithm];
+        decode(token, &self.decoding, &validation)
+    }
+}
+
+/// The keys of a `KeyRing` at one point in time
+struct KeySet {
+    /// Key used for every newly issued token
+    signing: Arc<Keys>,
+    /// Keys accepted for verification by `kid`, including the signing key
+    verifying: HashMap<String, Arc<Keys>>,
+}
+
+/// Shared set of signing and verification keys held in `MyAppState`
+///
+/// Exactly one key signs new tokens while any number of keys verify them, so a
+/// signing key can be rotated by adding the new key, switching `signing_kid`
+/// and keeping the old key for verification until its tokens expire. When
+/// loaded from a manifest file, the ring can be reloaded at runtime without
+/// dropping sessions, either explicitly with `KeyRing::reload` or by `KeyRing::watch`.
+pub struct KeyRing {
+    current: RwLock<Arc<KeySet>>,
+    manifest: Option<PathBuf>,
+}
+
+impl std::fmt::Debug for KeyRing {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let set = self.snapshot();
+        let mut kids: Vec<&String> = set.verifying.keys().collect();
+        kids.sort();
+        f.debug_struct("KeyRing")
+            .field("signing_kid", &set.signing.kid)
+            .field("verifying_kids", &kids)
+            .field("manifest", &self.manifest)
+            .finish()
+    }
+}
+
+impl KeyRing {
+    /// Creates a ring with a single key used for both signing and verification
+    pub fn new(signing: Keys) -> Self {
+        Self::build(signing, Vec::new(), None)
+            .expect("a single signing key is always a valid key ring")
+    }
+
+    /// Creates a ring from a signing key and additional verification-only keys
+    pub fn with_verification_keys(
+        signing: Keys,
+        verifying: impl IntoIterator<Item = Keys>,
+    ) -> Result<Self, KeyError> {
+        Self::build(signing, verifying.into_iter().collect(), None)
+    }
+
+    /// Loads a reloadable ring from a key manifest file
+    ///
+    /// The manifest is JSON naming the signing key and listing every key:
+    ///
+    /// ```json
+    /// {
+    ///   "signing_kid": "2026-10",
+    ///   "keys": [
+    ///     { "kid": "2026-10", "algorithm": "ES256",
+    ///       "private_key_path": "es256-2026-10.pem", "public_key_path": "es256-2026-10.pub.pem" },
+    ///     { "kid": "2026-09", "algorithm": "ES256", "public_key_path": "es256-2026-09.pub.pem" },
+    ///     { "kid": "legacy", "algorithm": "HS256", "secret": "..." }
+    ///   ]
+    /// }
+    /// ```
+    ///
+    /// Relative key paths are resolved against the manifest's directory.
+    pub fn from_manifest(path: impl Into<PathBuf>) -> Result<Self, KeyError> {
+        let path = path.into();
+        let (signing, verifying) = load_manifest(&path)?;
+        Self::build(signing, verifying, Some(path))
+    }
+
+    /// Loads the ring described by the configuration
+    ///
+    /// Uses the key manifest `keys_file` when set, otherwise a single key as
+    /// described in `Keys::from_config`.
+    pub fn from_config(config: &KeyConfig) -> Result<Self, KeyError> {
+        match &config.keys_file {
+            Some(path) => Self::from_manifest(path),
+            None => Ok(Self::new(Keys::from_config(config)?)),
+        }
+    }
+
+    fn build(signing: Keys, verifying: Vec<Keys>, manifest: Option<PathBuf>) -> Result<Self, KeyError> {
+        Ok(Self {
+            current: RwLock::new(Arc::new(key_set(signing, verifying)?)),
+            manifest,
+        })
+    }
+
+    fn snapshot(&self) -> Arc<KeySet> {
+        self.current
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    /// The `kid` of the key currently signing new tokens
+    pub fn signing_kid(&self) -> String {
+        self.snapshot().signing.kid.clone()
+    }
+
+    /// Signs claims with the current signing key
+    pub fn encode<T: Serialize>(&self, claims: &T) -> Result<String, jsonwebtoken::errors::Error> {
+        self.snapshot().signing.encode(claims)
+    }
+
+    /// Verifies and decodes a token with the key named by its `kid`
+    ///
+    /// Tokens without a `kid` are checked against the signing key; tokens naming
+    /// a `kid` the ring does not know are rejected.
+    pub fn decode<T: DeserializeOwned>(
+        &self,
+        token: &str,
+        validation: &Validation,
+    ) -> Result<TokenData<T>, jsonwebtoken::errors::Error> {
+        let set = self.snapshot();
+        let keys = match decode_header(token)?.kid {
+            Some(kid) => set
+                .verifying
+                .get(&kid)
+                .ok_or(jsonwebtoken::errors::ErrorKind::InvalidToken)?,
+            None => &set.signing,
+        };
+        keys.decode(token, validation)
+    }
+
+    /// The public JWKs of every verification key
+    pub fn jwks(&self) -> JwkSet {
+        let set = self.snapshot();
+        let mut keys: Vec<Jwk> = set.verifying.values().filter_map(|keys| keys.jwk.clone()).collect();
+        keys.sort_by(|a, b| a.common.key_id.cmp(&b.common.key_id));
+        JwkSet { keys }
+    }
+
+    /// Atomically replaces all keys
+    ///
+    /// Requests already holding the previous keys finish with them.
+    pub fn replace(&self, signing: Keys, verifying: impl IntoIterator<Item = Keys>) -> Result<(), KeyError> {
+        let set = Arc::new(key_set(signing, verifying.into_iter().collect())?);
+        *self
+            .current
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = set;
+        Ok(())
+    }
+
+    /// Reloads the keys from the manifest file
+    ///
+    /// On failure the current keys stay in place. Rings not loaded from a
+    /// manifest have nothing to reload and succeed trivially.
+    pub fn reload(&self) -> Result<(), KeyError> {
+        let Some(path) = &self.manifest else {
+            return Ok(());
+        };
+        let (signing, verifying) = load_manifest(path)?;
+        self.replace(signing, verifying)?;
+        tracing::info!(signing_kid = self.signing_kid(), "JWT key ring reloaded");
+        Ok(())
+    }
+
+    /// Watches the manifest and its key files, reloading when any of them changes
+    ///
+    /// Polls modification times every `interval`. Returns `None` for rings not
+    /// loaded from a manifest.
+    pub fn watch(self: Arc<Self>, interval: Duration) -> Option<tokio::task::JoinHandle<()>> {
+        let path = self.manifest.clone()?;
+        Some(tokio::spawn(async move {
+            let mut last_seen = manifest_modified(&path);
+            let mut ticker = tokio::time::interval(interval);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                ticker.tick().await;
+                let modified = manifest_modified(&path);
+                if modified == last_seen {
+                    continue;
+                }
+                last_seen = modified;
+                if let Err(e) = self.reload() {
+                    tracing::error!("keeping current JWT keys, reload failed: {}", e);
+                }
+            }
+        }))
+    }
+}
+
+/// Indexes the keys by `kid`, making sure the signing key can sign and is unique
+fn key_set(signing: Keys, verifying: Vec<Keys>) -> Result<KeySet, KeyError> {
+    if signing.encoding.is_none() {
+        return Err(KeyError::Manifest(format!(
+            "signing key {} has no private key",
+            signing.kid
+        )));
+    }
+    let signing = Arc::new(signing);
+    let mut by_kid = HashMap::from([(signing.kid.clone(), signing.clone())]);
+    for keys in verifying {
+        let kid = keys.kid.clone();
+        if by_kid.insert(kid.clone(), Arc::new(keys)).is_some() {
+            return Err(KeyError::Manifest(format!("duplicate kid {}", kid)));
+        }
+    }
+    Ok(KeySet {
+        signing,
+        verifying: by_kid,
+    })
+}
+
+/// Key manifest file format, see `KeyRing::from_manifest`
+#[derive(Debug, Deserialize)]
+struct KeyManifest {
+    signing_kid: String,
+    keys: Vec<KeyManifestEntry>,
+}
+
+#[derive(Debug, Deserialize)]
+struct KeyManifestEntry {
+    kid: String,
+    algorithm: String,
+    /// Shared secret, for HS256
+    secret: Option<String>,
+    /// PEM private key; omit for verification-only keys
+    private_key_path: Option<PathBuf>,
+    /// PEM public key, for asymmetric algorithms
+    public_key_path: Option<PathBuf>,
+}
+
+impl KeyManifest {
+    fn read(path: &Path) -> Result<Self, KeyError> {
+        let bytes = std::fs::read(path).map_err(|e| KeyError::Io(path.display().to_string(), e))?;
+        serde_json::from_slice(&bytes).map_err(|e| KeyError::Manifest(e.to_string()))
+    }
+
+    /// Key file paths, resolved against the manifest directory
+    fn key_files(&self, base: &Path) -> Vec<PathBuf> {
+        self.keys
+            .iter()
+            .flat_map(|entry| [&entry.private_key_path, &entry.public_key_path])
+            .flatten()
+            .map(|path| base.join(path))
+            .collect()
+    }
+}
+
+/// Reads a manifest and loads every key it lists
+fn load_manifest(path: &Path) -> Result<(Keys, Vec<Keys>), KeyError> {
+    let manifest = KeyManifest::read(path)?;
+    let base = path.parent().unwrap_or(Path::new("."));
+    let read = |file: &Path| {
+        let file = base.join(file);
+        std::fs::read(&file).map_err(|e| KeyError::Io(file.display().to_string(), e))
+    };
+
+    let mut signing = None;
+    let mut verifying = Vec::new();
+    for entry in manifest.keys {
+        let algorithm = Algorithm::from_str(&entry.algorithm)
+            .map_err(|_| KeyError::UnsupportedAlgorithm(entry.algorithm.clone()))?;
+        let keys = match (algorithm, &entry.secret, &entry.private_key_path, &entry.public_key_path) {
+            (Algorithm::HS256, Some(secret), _, _) => Keys::hmac(&entry.kid, secret.as_bytes()),
+            (Algorithm::HS256, None, _, _) => {
+                return Err(KeyError::Manifest(format!("key {} needs a secret", entry.kid)));
+            }
+            (_, _, Some(private), Some(public)) => {
+                Keys::from_pem(&entry.kid, algorithm, &read(private)?, &read(public)?)?
+            }
+            (_, _, None, Some(public)) => Keys::verify_only_pem(&entry.kid, algorithm, &read(public)?)?,
+            (_, _, _, None) => {
+                return Err(KeyError::Manifest(format!("key {} needs a public_key_path", entry.kid)));
+            }
+        };
+        if keys.kid == manifest.signing_kid {
+            signing = Some(keys);
+        } else {
+            verifying.push(keys);
+        }
+    }
+
+    let signing = signing.ok_or_else(|| {
+        KeyError::Manifest(format!("signing key {} is not listed", manifest.signing_kid))
+    })?;
+    Ok((signing, verifying))
+}
+
+/// Latest modification time of the manifest and the key files it references
+fn manifest_modified(path: &Path) -> Option<SystemTime> {
+    let modified = |file: &Path| std::fs::metadata(file).and_then(|meta| meta.modified()).ok();
+    let base = path.parent().unwrap_or(Path::new("."));
+    let key_files = KeyManifest::read(path)
+        .map(|manifest| manifest.key_files(base))
+        .unwrap_or_default();
+    key_files
+        .iter()
+        .filter_map(|file| modified(file))
+        .chain(modified(path))
+        .max()
+}
+
+/// Builds the public JWK of a PEM encoded SubjectPublicKeyInfo
+fn public_jwk(kid: &str, algorithm: Algorithm, public_pem: &[u8]) -> Result<Jwk, KeyError> {
+    let pem = pem::parse(public_pem).map_err(|e| KeyError::InvalidKey(e.to_string()))?;
+    let spki = SubjectPublicKeyInfoRef::try_from(pem.contents())
+        .map_err(|e| KeyError::InvalidKey(e.to_string()))?;
+    let public_key = spki
+        .subject_public_key
+        .as_bytes()
+        .ok_or_else(|| KeyError::InvalidKey("malformed public key bit string".into()))?;
+    // Parameters are absent for Ed25519, NULL for RSA and the curve OID for EC
+    let curve = spki.algorithm.parameters_oid().ok();
+    let b64 = |bytes: &[u8]| URL_SAFE_NO_PAD.encode(bytes);
+
+    let (key_algorithm, parameters) = match algorithm {
+        Algorithm::RS256 if spki.algorithm.oid == OID_RSA => {
+            let key = RsaPublicKey::from_der(public_key).map_err(|e| KeyError::InvalidKey(e.to_string()))?;
+            (
+                KeyAlgorithm::RS256,
+                AlgorithmParameters::RSA(RSAKeyParameters {
+                    key_type: RSAKeyType::RSA,
+                    n: b64(key.modulus.as_bytes()),
+                    e: b64(key.public_exponent.as_bytes()),
+                }),
+            )
+        }
+        Algorithm::ES256 if spki.algorithm.oid == OID_EC && curve == Some(OID_P256) => {
+            // Uncompressed point: 0x04 || x || y
+            match public_key {
+                [0x04, point @ ..] if point.len() == 64 => (
+                    KeyAlgorithm::ES256,
+                    AlgorithmParameters::EllipticCurve(EllipticCurveKeyParameters {
+                        key_type: EllipticCurveKeyType::EC,
+                        curve: EllipticCurve::P256,
+                        x: b64(&point[..32]),
+                        y: b64(&point[32..]),
+                    }),
+                ),
+                _ => return Err(KeyError::InvalidKey("expected an uncompressed P-256 point".into())),
+            }
+        }
+        Algorithm::EdDSA if spki.algorithm.oid == OID_ED25519 && public_key.len() == 32 => (
+            KeyAlgorithm::EdDSA,
+            AlgorithmParameters::OctetKeyPair(OctetKeyPairParameters {
+                key_type: OctetKeyPairType::OctetKeyPair,
+                curve: EllipticCurve::Ed25519,
+                x: b64(public_key),
+            }),
+        ),
+        other => {
+            return Err(KeyError::InvalidKey(format!(
+                "public key {} is not a {:?} key",
+                kid, other
+            )));
+        }
+    };
+
+    Ok(Jwk {
+        common: CommonParameters {
+            public_key_use: Some(PublicKeyUse::Signature),
+            key_algorithm: Some(key_algorithm),
+            key_id: Some(kid.to_owned()),
+            ..Default::default()
+        },
+        algorithm: parameters,
+    })
+}
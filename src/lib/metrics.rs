@@ -0,0 +1,280 @@
+//! Metrics Module
+//!
+//! This module records request metrics and exposes them at `/metrics` in the
+//! Prometheus text format:
+//! - `http_requests_total` counts handled requests
+//! - `http_request_duration_seconds` is a histogram of their latency
+//! - `http_requests_in_flight` gauges the requests being handled
+//! - `auth_failures_total` counts rejected authentication attempts by reason
+//!
+//! Requests are labelled by method, status and matched route template, such as
+//! `/users/{user_id}`, so that labels stay few whatever paths clients send.
+
+use crate::app_state::MyAppState;
+use axum::Extension;
+use axum::extract::{MatchedPath, Request, State};
+use axum::http::{HeaderValue, Method, header};
+use axum::middleware::Next;
+use axum::response::{IntoResponse, Response};
+use std::collections::BTreeMap;
+use std::fmt::Write;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// Upper bounds of the latency histogram buckets, in seconds
+const BUCKETS: [f64; 11] = [0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0];
+
+/// Route label of requests that matched no route, such as 404s
+const UNMATCHED: &str = "unmatched";
+
+/// Content type of the Prometheus text exposition format
+const PROMETHEUS_TEXT: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+/// Marks a response rejecting authentication, for `auth_failures_total`
+///
+/// Inserted into the response extensions by the problem rendering of the
+/// `AuthError`s that reject a client, not those reporting server failures.
+#[derive(Debug, Clone, Copy)]
+pub struct AuthFailure {
+    /// The problem slug, e.g. `invalid-token`
+    pub reason: &'static str,
+}
+
+/// Labels of a handled request
+#[derive(Debug, Clone, PartialEq, Eq, PartialOrd, Ord)]
+struct RequestLabels {
+    method: &'static str,
+    route: String,
+    status: u16,
+}
+
+/// Latency histogram of one label set; bucket counts are cumulative
+#[derive(Debug, Default)]
+struct Histogram {
+    buckets: [u64; BUCKETS.len()],
+    sum: f64,
+    count: u64,
+}
+
+impl Histogram {
+    fn observe(&mut self, seconds: f64) {
+        for (bucket, bound) in self.buckets.iter_mut().zip(BUCKETS) {
+            if seconds <= bound {
+                *bucket += 1;
+            }
+        }
+        self.sum += seconds;
+        self.count += 1;
+    }
+}
+
+#[derive(Debug, Default)]
+struct Registry {
+    requests: BTreeMap<RequestLabels, Histogram>,
+    in_flight: BTreeMap<(&'static str, String), i64>,
+    auth_failures: BTreeMap<&'static str, u64>,
+}
+
+/// Request metrics shared by the metrics layer and the `/metrics` endpoint
+///
+/// Cloning is cheap; clones record into the same registry.
+#[derive(Debug, Clone, Default)]
+pub struct Metrics {
+    registry: Arc<Mutex<Registry>>,
+}
+
+impl Metrics {
+    /// Creates an empty registry
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn registry(&self) -> std::sync::MutexGuard<'_, Registry> {
+        self.registry.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+
+    /// Renders every metric in the Prometheus text format
+    pub fn render(&self) -> String {
+        let registry = self.registry();
+        let mut text = String::new();
+
+        family(&mut text, "http_requests_total", "counter", "HTTP requests handled.");
+        for (labels, histogram) in &registry.requests {
+            let _ = writeln!(text, "http_requests_total{{{}}} {}", labels.render(), histogram.count);
+        }
+
+        family(
+            &mut text,
+            "http_request_duration_seconds",
+            "histogram",
+            "Time taken to handle HTTP requests, in seconds.",
+        );
+        for (labels, histogram) in &registry.requests {
+            let labels = labels.render();
+            for (count, bound) in histogram.buckets.iter().zip(BUCKETS) {
+                let _ = writeln!(
+                    text,
+                    "http_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels, bound, count
+                );
+            }
+            let _ = writeln!(
+                text,
+                "http_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                labels, histogram.count
+            );
+            let _ = writeln!(text, "http_request_duration_seconds_sum{{{}}} {}", labels, histogram.sum);
+            let _ = writeln!(text, "http_request_duration_seconds_count{{{}}} {}", labels, histogram.count);
+        }
+
+        family(&mut text, "http_requests_in_flight", "gauge", "HTTP requests being handled.");
+        for ((method, route), count) in &registry.in_flight {
+            let _ = writeln!(
+                text,
+                "http_requests_in_flight{{method=\"{}\",route=\"{}\"}} {}",
+                method,
+                escape(route),
+                count
+            );
+        }
+
+        family(
+            &mut text,
+            "auth_failures_total",
+            "counter",
+            "Requests rejected by authentication or authorization, by reason.",
+        );
+        for (reason, count) in &registry.auth_failures {
+            let _ = writeln!(text, "auth_failures_total{{reason=\"{}\"}} {}", reason, count);
+        }
+        text
+    }
+
+    /// Counts a request as in flight until the returned guard is dropped
+    fn start(&self, method: &'static str, route: &str) -> InFlight {
+        *self
+            .registry()
+            .in_flight
+            .entry((method, route.to_owned()))
+            .or_default() += 1;
+        InFlight {
+            metrics: self.clone(),
+            key: (method, route.to_owned()),
+        }
+    }
+
+    /// Records a handled request
+    fn finish(&self, labels: RequestLabels, seconds: f64, auth_failure: Option<AuthFailure>) {
+        let mut registry = self.registry();
+        registry.requests.entry(labels).or_default().observe(seconds);
+        if let Some(failure) = auth_failure {
+            *registry.auth_failures.entry(failure.reason).or_default() += 1;
+        }
+    }
+}
+
+impl RequestLabels {
+    fn render(&self) -> String {
+        format!(
+            "method=\"{}\",route=\"{}\",status=\"{}\"",
+            self.method,
+            escape(&self.route),
+            self.status
+        )
+    }
+}
+
+/// Decrements the in-flight gauge, also when the client goes away mid-request
+struct InFlight {
+    metrics: Metrics,
+    key: (&'static str, String),
+}
+
+impl Drop for InFlight {
+    fn drop(&mut self) {
+        if let Some(count) = self.metrics.registry().in_flight.get_mut(&self.key) {
+            *count -= 1;
+        }
+    }
+}
+
+/// Writes the `HELP` and `TYPE` lines of a metric family
+fn family(text: &mut String, name: &str, kind: &str, help: &str) {
+    let _ = writeln!(text, "# HELP {} {}", name, help);
+    let _ = writeln!(text, "# TYPE {} {}", name, kind);
+}
+
+/// Escapes a label value
+fn escape(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
+
+/// The method label; extension methods share one label
+fn method_label(method: &Method) -> &'static str {
+    match *method {
+        Method::GET => "GET",
+        Method::POST => "POST",
+        Method::PUT => "PUT",
+        Method::PATCH => "PATCH",
+        Method::DELETE => "DELETE",
+        Method::HEAD => "HEAD",
+        Method::OPTIONS => "OPTIONS",
+        Method::TRACE => "TRACE",
+        Method::CONNECT => "CONNECT",
+        _ => "OTHER",
+    }
+}
+
+/// Middleware recording request metrics
+///
+/// Must be added with `Router::layer` so that it sees the `MatchedPath` of
+/// the route handling the request.
+///
+/// # Arguments
+///
+/// * `State(metrics)` - The registry to record into
+/// * `req` - The incoming request
+/// * `next` - The rest of the middleware stack
+pub async fn track(State(metrics): State<Metrics>, req: Request, next: Next) -> Response {
+    let method = method_label(req.method());
+    let route = req
+        .extensions()
+        .get::<MatchedPath>()
+        .map_or(UNMATCHED, MatchedPath::as_str)
+        .to_owned();
+
+    let started = Instant::now();
+    let in_flight = metrics.start(method, &route);
+    let response = next.run(req).await;
+    drop(in_flight);
+
+    let labels = RequestLabels {
+        method,
+        route,
+        status: response.status().as_u16(),
+    };
+    let auth_failure = response.extensions().get::<AuthFailure>().copied();
+    metrics.finish(labels, started.elapsed().as_secs_f64(), auth_failure);
+    response
+}
+
+/// Exposes the recorded metrics in the Prometheus text format
+///
+/// # Arguments
+///
+/// * `Extension(state)` - The application state holding the metrics registry
+#[utoipa::path(
+    get,
+    path = "/metrics",
+    tag = "metrics",
+    responses((status = 200, description = "Metrics in the Prometheus text format", body = String, content_type = "text/plain"))
+)]
+pub async fn render(Extension(state): Extension<MyAppState>) -> impl IntoResponse {
+    (
+        [(header::CONTENT_TYPE, HeaderValue::from_static(PROMETHEUS_TEXT))],
+        state.metrics.render(),
+    )
+}
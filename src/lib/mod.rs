@@ -1,9 +1,30 @@
+pub mod admin_router;
+pub mod api_doc;
+pub mod app_error;
 pub mod app_state;
 pub mod auth_claim;
+pub mod authorization;
+pub mod client_store;
+pub mod config;
+pub mod health;
+pub mod idempotency;
 pub mod input_schemas;
+pub mod jwt_keys;
+pub mod metrics;
+pub mod my_consumers;
 pub mod my_extractors;
 pub mod my_math;
+pub mod outbox;
 pub mod protected_router;
+pub mod queue;
+pub mod queue_worker;
+pub mod refresh_token;
+pub mod request_id;
+pub mod revocation;
+pub mod shutdown;
+pub mod sqlite_queue;
+pub mod sqs_api;
+pub mod tls;
 pub mod auth_claim_mid;
 pub mod users_router;
 pub mod backend_server;
@@ -0,0 +1,79 @@
+//! Example Consumers Module
+//! 
+//! This module demonstrates message handlers for the queue worker, mirroring
+//! what `my_extractors` does for HTTP handlers. Each handler takes its
+//! arguments through `FromMessage` extractors:
+//! - Body as text
+//! - Body deserialized from JSON
+//! - Message attributes
+//! - Application state
+
+use crate::config::Config;
+use crate::idempotency::InMemoryProcessedLedger;
+use crate::queue_worker::{Attributes, Body, Consumer, JsonBody, State};
+use serde::Deserialize;
+use std::sync::Arc;
+use std::time::Duration;
+
+/// How long the example consumer remembers handled messages
+pub const IDEMPOTENCY_RETENTION: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Attribute senders may set to mark repeated sends of the same message
+pub const IDEMPOTENCY_KEY_ATTRIBUTE: &str = "idempotency-key";
+
+/// Payload of `greeting` messages
+#[derive(Debug, Deserialize)]
+pub struct Greeting {
+    pub name: String,
+}
+
+/// Logs a message body together with its attributes
+/// 
+/// # Arguments
+/// 
+/// * `Body(body)` - The message body as text
+/// * `Attributes(attributes)` - The message attributes
+pub async fn log_message(Body(body): Body, Attributes(attributes): Attributes) {
+    tracing::info!(?attributes, "message: {}", body);
+}
+
+/// Greets the sender of a `greeting` message
+/// 
+/// # Arguments
+/// 
+/// * `JsonBody(greeting)` - The body deserialized into a `Greeting`
+/// * `State(state)` - The application state
+/// 
+/// # Returns
+/// 
+/// A `Result` containing either:
+/// * `Ok(())` - The message is deleted
+/// * `Err(String)` - The message is left for redelivery
+pub async fn greet(JsonBody(greeting): JsonBody<Greeting>, State(state): State) -> Result<(), String> {
+    if greeting.name.is_empty() {
+        return Err("nobody to greet".to_owned());
+    }
+    tracing::info!(issuer = state.tokens.issuer, "Hello, {}!", greeting.name);
+    Ok(())
+}
+
+/// Creates the example consumer of a queue
+/// 
+/// `greeting` messages go to `greet`, everything else is logged. Messages
+/// handled in the last day, by message id or `idempotency-key` attribute, are
+/// only acknowledged.
+pub fn consumer(queue: &str) -> Consumer {
+    Consumer::new(queue)
+        .handler("greeting", greet)
+        .fallback(log_message)
+        .idempotent(Arc::new(InMemoryProcessedLedger::new()), IDEMPOTENCY_RETENTION)
+        .idempotency_attribute(IDEMPOTENCY_KEY_ATTRIBUTE)
+}
+
+/// Creates example consumers for the queues listed in `queues.consumers`
+/// 
+/// Set with `CONSUMER_QUEUES` as comma separated queue names; when empty no
+/// consumer runs.
+pub fn from_config(config: &Config) -> Vec<Consumer> {
+    config.queues.consumers.iter().map(|queue| consumer(queue)).collect()
+}
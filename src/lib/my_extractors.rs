@@ -10,29 +10,37 @@
 //! - Full request handling
 
 use crate::{
+    app_error::{AppError, AppJson, AppPath, AppQuery, Problem},
     app_state::MyAppState,
     input_schemas::{GetUserWithId, Pagination, UserDetail},
 };
 use axum::{
     Extension,
     body::Bytes,
-    extract::{Json, Path, Query, Request},
-    http::{
-        StatusCode,
-        header::{self, HeaderMap},
-    },
+    extract::Request,
+    http::header::{self, HeaderMap},
 };
 
 /// Extracts and handles path parameters from the URL
 /// 
 /// # Arguments
 /// 
-/// * `Path(GetUserWithId { user_id })` - The user ID extracted from the URL path
+/// * `AppPath(GetUserWithId { user_id })` - The user ID extracted from the URL path
 /// 
 /// # Example
 /// 
 /// For a request to `/users/123`, `user_id` will be `123`
-pub async fn path_param(Path(GetUserWithId { user_id }): Path<GetUserWithId>) {
+#[utoipa::path(
+    get,
+    path = "/{user_id}",
+    tag = "users",
+    params(GetUserWithId),
+    responses(
+        (status = 200, description = "User id logged"),
+        (status = 400, description = "User id is not a number", body = Problem, content_type = "application/problem+json")
+    )
+)]
+pub async fn path_param(AppPath(GetUserWithId { user_id }): AppPath<GetUserWithId>) {
     println!("user_id={}", user_id);
 }
 
@@ -40,12 +48,22 @@ pub async fn path_param(Path(GetUserWithId { user_id }): Path<GetUserWithId>) {
 /// 
 /// # Arguments
 /// 
-/// * `Query(Pagination { page, per_page })` - The pagination parameters from the query string
+/// * `AppQuery(Pagination { page, per_page })` - The pagination parameters from the query string
 /// 
 /// # Example
 /// 
 /// For a request to `/users?page=1&per_page=10`, `page` will be `1` and `per_page` will be `10`
-pub async fn query(Query(Pagination { page, per_page }): Query<Pagination>) {
+#[utoipa::path(
+    get,
+    path = "/",
+    tag = "users",
+    params(Pagination),
+    responses(
+        (status = 200, description = "Pagination logged"),
+        (status = 400, description = "Invalid pagination", body = Problem, content_type = "application/problem+json")
+    )
+)]
+pub async fn query(AppQuery(Pagination { page, per_page }): AppQuery<Pagination>) {
     println!("user_id: {}    name:{}", page, per_page);
 }
 
@@ -58,23 +76,39 @@ pub async fn query(Query(Pagination { page, per_page }): Query<Pagination>) {
 /// Currently handles:
 /// - User-Agent header
 /// - Content-Type header
-pub async fn headers(headers: HeaderMap) {
+/// 
+/// # Returns
+/// 
+/// A `Result` containing either:
+/// * `Ok(())` - If both headers are present
+/// * `Err(AppError)` - `BadRequest` naming the missing header
+#[utoipa::path(
+    get,
+    path = "/headers",
+    tag = "extractors",
+    responses(
+        (status = 200, description = "Both headers are present"),
+        (status = 400, description = "A header is missing", body = Problem, content_type = "application/problem+json")
+    )
+)]
+pub async fn headers(headers: HeaderMap) -> Result<(), AppError> {
     let user_agent = headers.get(header::USER_AGENT);
     let content_type = headers.get(header::CONTENT_TYPE);
     match user_agent {
         Some(usr_agent) => {
             println!("user_agent: {:?}", usr_agent)
         }
-        None => todo!(),
+        None => return Err(AppError::BadRequest("missing User-Agent header".to_owned())),
     }
     match content_type {
         Some(c_type) => {
             println!("content type: {:?}", c_type);
         }
         None => {
-            todo!()
+            return Err(AppError::BadRequest("missing Content-Type header".to_owned()));
         }
     }
+    Ok(())
 }
 
 /// Returns a simple string response
@@ -82,6 +116,12 @@ pub async fn headers(headers: HeaderMap) {
 /// # Returns
 /// 
 /// A static string message
+#[utoipa::path(
+    get,
+    path = "/string-handler",
+    tag = "extractors",
+    responses((status = 200, description = "Greeting", body = String, content_type = "text/plain"))
+)]
 pub async fn string_handler() -> String {
     "Hello, from string handler!".to_string()
 }
@@ -96,14 +136,24 @@ pub async fn string_handler() -> String {
 /// 
 /// A `Result` containing either:
 /// * `Ok(String)` - The body converted to a string
-/// * `Err(StatusCode)` - If the body cannot be converted to UTF-8
-pub async fn echo_bytes(body: Bytes) -> Result<String, StatusCode> {
+/// * `Err(AppError)` - If the body cannot be converted to UTF-8
+#[utoipa::path(
+    post,
+    path = "/echo",
+    tag = "extractors",
+    request_body(content = String, content_type = "application/octet-stream"),
+    responses(
+        (status = 200, description = "The body as text", body = String, content_type = "text/plain"),
+        (status = 400, description = "Body is not valid UTF-8", body = Problem, content_type = "application/problem+json")
+    )
+)]
+pub async fn echo_bytes(body: Bytes) -> Result<String, AppError> {
     if let Ok(string) = String::from_utf8(body.to_vec()) {
         println!("String value: {}", string);
         Ok(string)
     } else {
         println!("body cant be converted to string!");
-        Err(StatusCode::BAD_REQUEST)
+        Err(AppError::BadRequest("body is not valid UTF-8".to_owned()))
     }
 }
 
@@ -116,6 +166,13 @@ pub async fn echo_bytes(body: Bytes) -> Result<String, StatusCode> {
 /// # Returns
 /// 
 /// The input string prefixed with "Receive Body:"
+#[utoipa::path(
+    post,
+    path = "/input-string",
+    tag = "extractors",
+    request_body(content = String, content_type = "text/plain"),
+    responses((status = 200, description = "The prefixed body", body = String, content_type = "text/plain"))
+)]
 pub async fn input_string(body: String) -> String {
     let mut output_string = String::from("Receive Body:");
     output_string.push_str(body.as_str());
@@ -126,8 +183,19 @@ pub async fn input_string(body: String) -> String {
 /// 
 /// # Arguments
 /// 
-/// * `Json(payload)` - The request body deserialized into a `UserDetail` struct
-pub async fn input_json(Json(payload): Json<UserDetail>) {
+/// * `AppJson(payload)` - The request body deserialized into a `UserDetail` struct
+#[utoipa::path(
+    post,
+    path = "/json",
+    tag = "extractors",
+    request_body = UserDetail,
+    responses(
+        (status = 200, description = "User logged"),
+        (status = 415, description = "Body is not JSON", body = Problem, content_type = "application/problem+json"),
+        (status = 422, description = "Body does not match `UserDetail`", body = Problem, content_type = "application/problem+json")
+    )
+)]
+pub async fn input_json(AppJson(payload): AppJson<UserDetail>) {
     println!("{:?}", payload);
 }
 
@@ -137,6 +205,12 @@ pub async fn input_json(Json(payload): Json<UserDetail>) {
 /// 
 /// * `Extension(state)` - The application state
 /// * `req` - The complete request object
+#[utoipa::path(
+    post,
+    path = "/sample-request",
+    tag = "extractors",
+    responses((status = 200, description = "Request and state logged"))
+)]
 pub async fn sample_request(Extension(state): Extension<MyAppState>, req: Request) {
     let method = req.method();
     let uri = req.uri();
@@ -0,0 +1,409 @@
+//! Outbox Module
+//!
+//! This module implements the transactional outbox pattern on the application's
+//! SQLite database. A handler stages queue messages in the `outbox` table within
+//! the same transaction as its own writes, so either both are committed or
+//! neither is. A relay task then sends the staged messages to the `MessageQueue`
+//! and marks their rows as sent. Sent rows are deleted after `SENT_RETENTION`.
+//!
+//! ```ignore
+//! async fn place_order(mut tx: OutboxTransaction, AppJson(order): AppJson<Order>) -> Result<(), AppError> {
+//!     sqlx::query("INSERT INTO orders (id, total) VALUES (?, ?)")
+//!         .bind(&order.id)
+//!         .bind(order.total)
+//!         .execute(&mut *tx)
+//!         .await
+//!         .map_err(OutboxError::from)?;
+//!     tx.stage("orders", OutgoingMessage::new(order.id).with_attribute("type", "order.placed")).await?;
+//!     tx.commit().await?;
+//!     Ok(())
+//! }
+//! ```
+//!
+//! Delivery is at least once: a message whose row could not be marked after
+//! sending is sent again. FIFO messages staged without a deduplication id are
+//! sent with `outbox-<row id>`, so the queue drops such repeats. A message that
+//! still cannot be sent after `MAX_ATTEMPTS` tries is marked as failed and kept
+//! for inspection, so that it no longer holds up its queue.
+
+use crate::app_error::AppError;
+use crate::app_state::MyAppState;
+use crate::queue::{MAX_DELAY, MessageQueue, OutgoingMessage};
+use crate::shutdown::Shutdown;
+use axum::extract::FromRequestParts;
+use axum::http::request::Parts;
+use chrono::{DateTime, Utc};
+use sqlx::sqlite::{SqliteConnectOptions, SqliteConnection, SqliteJournalMode};
+use sqlx::{Row, Sqlite, SqlitePool, Transaction};
+use std::collections::{HashMap, HashSet};
+use std::fmt::{Debug, Display};
+use std::ops::{Deref, DerefMut};
+use std::str::FromStr;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::Notify;
+use tokio::task::JoinHandle;
+
+/// How often the relay looks for staged messages when not woken by a commit
+const RELAY_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Most rows the relay sends per round
+const RELAY_BATCH: i64 = 100;
+
+/// Sends tried before a message is marked as failed
+pub const MAX_ATTEMPTS: i64 = 10;
+
+/// How long sent rows are kept before the relay deletes them
+pub const SENT_RETENTION: Duration = Duration::from_secs(7 * 24 * 60 * 60);
+
+/// How often the relay deletes expired sent rows
+const PRUNE_INTERVAL: Duration = Duration::from_secs(60 * 60);
+
+/// Outbox error types
+#[derive(Debug)]
+pub enum OutboxError {
+    /// The database operation failed
+    Database(sqlx::Error),
+    /// Message attributes could not be encoded or decoded
+    Attributes(serde_json::Error),
+}
+
+impl Display for OutboxError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            OutboxError::Database(e) => write!(f, "outbox database error: {}", e),
+            OutboxError::Attributes(e) => write!(f, "invalid outbox message attributes: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for OutboxError {}
+
+impl From<sqlx::Error> for OutboxError {
+    fn from(e: sqlx::Error) -> Self {
+        OutboxError::Database(e)
+    }
+}
+
+impl From<serde_json::Error> for OutboxError {
+    fn from(e: serde_json::Error) -> Self {
+        OutboxError::Attributes(e)
+    }
+}
+
+/// The outbox table in the application's database
+///
+/// Cloning is cheap; clones share the pool and wake the same relay.
+#[derive(Debug, Clone)]
+pub struct Outbox {
+    pool: SqlitePool,
+    /// Wakes the relay when staged messages are committed
+    staged: Arc<Notify>,
+}
+
+impl Outbox {
+    /// Opens (creating if needed) the database at `url` and prepares the outbox table
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - A SQLite connection string such as `sqlite://app.db`
+    pub async fn connect(url: &str) -> Result<Self, OutboxError> {
+        let options = SqliteConnectOptions::from_str(url)?
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal);
+        let pool = SqlitePool::connect_with(options).await?;
+        Self::from_pool(pool).await
+    }
+
+    /// Builds an outbox on top of an existing pool and prepares the outbox table
+    pub async fn from_pool(pool: SqlitePool) -> Result<Self, OutboxError> {
+        // Ids are never reused, so they can serve as deduplication ids
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS outbox (
+                id               INTEGER PRIMARY KEY AUTOINCREMENT,
+                queue            TEXT NOT NULL,
+                body             TEXT NOT NULL,
+                attributes       TEXT NOT NULL,
+                group_id         TEXT,
+                deduplication_id TEXT,
+                delay_ms         INTEGER,
+                deliver_at       INTEGER,
+                created_at       INTEGER NOT NULL,
+                sent_at          INTEGER,
+                message_id       TEXT,
+                attempts         INTEGER NOT NULL DEFAULT 0,
+                last_error       TEXT,
+                failed_at        INTEGER
+            )",
+        )
+        .execute(&pool)
+        .await?;
+        sqlx::query("CREATE INDEX IF NOT EXISTS outbox_pending ON outbox (sent_at, failed_at, id)")
+            .execute(&pool)
+            .await?;
+        Ok(Self {
+            pool,
+            staged: Arc::new(Notify::new()),
+        })
+    }
+
+    /// The application's database, for queries outside an outbox transaction
+    pub fn pool(&self) -> &SqlitePool {
+        &self.pool
+    }
+
+    /// Starts a transaction in which messages can be staged next to other writes
+    pub async fn begin(&self) -> Result<OutboxTransaction, OutboxError> {
+        Ok(OutboxTransaction {
+            tx: self.pool.begin().await?,
+            staged: self.staged.clone(),
+        })
+    }
+
+    /// Sends up to one batch of staged messages, oldest first
+    ///
+    /// Sent rows are marked with the send time and message id. A failed send is
+    /// recorded on its row and retried later; the queue's later messages wait
+    /// for it so that they keep their order. After `MAX_ATTEMPTS` failed sends
+    /// the row is marked with `failed_at` instead, and the queue's later
+    /// messages go ahead without it.
+    ///
+    /// A message's delay counts from when it was staged, not from when it is
+    /// relayed.
+    ///
+    /// # Returns
+    ///
+    /// How many messages were sent
+    pub async fn relay_pending(&self, queue: &dyn MessageQueue) -> Result<usize, OutboxError> {
+        let rows = sqlx::query(
+            "SELECT id, queue, body, attributes, group_id, deduplication_id, delay_ms, deliver_at, created_at, attempts
+             FROM outbox WHERE sent_at IS NULL AND failed_at IS NULL ORDER BY id LIMIT ?",
+        )
+        .bind(RELAY_BATCH)
+        .fetch_all(&self.pool)
+        .await?;
+
+        let mut sent = 0;
+        let mut blocked = HashSet::new();
+        for row in rows {
+            let id: i64 = row.get("id");
+            let target: String = row.get("queue");
+            if blocked.contains(&target) {
+                continue;
+            }
+            let attributes: HashMap<String, String> = serde_json::from_str(row.get("attributes"))?;
+            let group_id: Option<String> = row.get("group_id");
+            let deduplication_id = row
+                .get::<Option<String>, _>("deduplication_id")
+                .or_else(|| group_id.as_ref().map(|_| format!("outbox-{}", id)));
+            let mut delay = row.get::<Option<i64>, _>("delay_ms").map(|ms| Duration::from_millis(ms as u64));
+            let mut deliver_at = row
+                .get::<Option<i64>, _>("deliver_at")
+                .and_then(DateTime::from_timestamp_millis);
+            // Turn the delay into the time it ends; too long ones are left for the queue to reject
+            if let Some(staged_delay) = delay.filter(|delay| *delay <= MAX_DELAY && deliver_at.is_none()) {
+                let staged_at: i64 = row.get("created_at");
+                deliver_at = DateTime::from_timestamp_millis(staged_at + staged_delay.as_millis() as i64);
+                delay = None;
+            }
+            let message = OutgoingMessage {
+                body: row.get("body"),
+                attributes,
+                group_id,
+                deduplication_id,
+                delay,
+                deliver_at,
+            };
+
+            match queue.send(&target, message).await {
+                Ok(message) => {
+                    sqlx::query("UPDATE outbox SET sent_at = ?, message_id = ? WHERE id = ?")
+                        .bind(Utc::now().timestamp_millis())
+                        .bind(&message.message_id)
+                        .bind(id)
+                        .execute(&self.pool)
+                        .await?;
+                    sent += 1;
+                }
+                Err(e) => {
+                    let attempts = row.get::<i64, _>("attempts") + 1;
+                    let failed_at = (attempts >= MAX_ATTEMPTS).then(|| Utc::now().timestamp_millis());
+                    if failed_at.is_some() {
+                        tracing::error!(id, queue = target, attempts, "outbox message failed: {}", e);
+                    } else {
+                        tracing::warn!(id, queue = target, attempts, "outbox message not sent: {}", e);
+                        blocked.insert(target);
+                    }
+                    sqlx::query("UPDATE outbox SET attempts = ?, last_error = ?, failed_at = ? WHERE id = ?")
+                        .bind(attempts)
+                        .bind(e.to_string())
+                        .bind(failed_at)
+                        .bind(id)
+                        .execute(&self.pool)
+                        .await?;
+                }
+            }
+        }
+        Ok(sent)
+    }
+
+    /// Deletes rows sent more than `older_than` ago
+    ///
+    /// Failed rows are kept until removed by hand.
+    ///
+    /// # Returns
+    ///
+    /// How many rows were deleted
+    pub async fn prune_sent(&self, older_than: Duration) -> Result<u64, OutboxError> {
+        let cutoff = Utc::now()
+            .timestamp_millis()
+            .saturating_sub(i64::try_from(older_than.as_millis()).unwrap_or(i64::MAX));
+        let deleted = sqlx::query("DELETE FROM outbox WHERE sent_at < ?")
+            .bind(cutoff)
+            .execute(&self.pool)
+            .await?
+            .rows_affected();
+        Ok(deleted)
+    }
+
+    /// Starts relaying staged messages to `queue` in a background task
+    ///
+    /// The relay runs whenever an outbox transaction with staged messages is
+    /// committed, and every second to retry failed sends and pick up messages
+    /// staged with `stage` directly. Once `stop` is triggered the relay sends
+    /// what is pending one last time and the task ends, so messages committed
+    /// by requests drained during a shutdown still go out. Every hour the
+    /// relay also deletes rows sent more than `SENT_RETENTION` ago.
+    pub fn spawn_relay(self, queue: Arc<dyn MessageQueue>, stop: Shutdown) -> JoinHandle<()> {
+        tokio::spawn(async move {
+            tracing::info!("outbox relay started");
+            let mut pruned_at: Option<tokio::time::Instant> = None;
+            loop {
+                if pruned_at.is_none_or(|at| at.elapsed() >= PRUNE_INTERVAL) {
+                    match self.prune_sent(SENT_RETENTION).await {
+                        Ok(deleted) if deleted > 0 => tracing::debug!(deleted, "outbox pruned"),
+                        Ok(_) => {}
+                        Err(e) => tracing::warn!("outbox pruning failed: {}", e),
+                    }
+                    pruned_at = Some(tokio::time::Instant::now());
+                }
+                let stopping = stop.is_triggered();
+                // Register before looking so a commit in between is not missed
+                let staged = self.staged.notified();
+                tokio::pin!(staged);
+                staged.as_mut().enable();
+
+                match self.relay_pending(queue.as_ref()).await {
+                    // A full batch may have left more behind
+                    Ok(sent) if sent as i64 == RELAY_BATCH => continue,
+                    Ok(_) => {}
+                    Err(e) => tracing::warn!("outbox relay failed: {}", e),
+                }
+                if stopping {
+                    break;
+                }
+                tokio::select! {
+                    _ = tokio::time::timeout(RELAY_INTERVAL, staged) => {}
+                    _ = stop.triggered() => {}
+                }
+            }
+            tracing::info!("outbox relay stopped");
+        })
+    }
+}
+
+/// Stages a message in the outbox on an open connection or transaction
+///
+/// The message is sent by the relay once the surrounding transaction commits.
+/// Prefer `OutboxTransaction::stage`, which also wakes the relay on commit.
+///
+/// # Arguments
+///
+/// * `conn` - The connection or transaction the handler writes with
+/// * `queue` - The queue to send the message to
+/// * `message` - The message to send
+///
+/// # Returns
+///
+/// The id of the outbox row
+pub async fn stage(
+    conn: &mut SqliteConnection,
+    queue: &str,
+    message: OutgoingMessage,
+) -> Result<i64, OutboxError> {
+    let id = sqlx::query(
+        "INSERT INTO outbox (queue, body, attributes, group_id, deduplication_id, delay_ms, deliver_at, created_at)
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(queue)
+    .bind(&message.body)
+    .bind(serde_json::to_string(&message.attributes)?)
+    .bind(&message.group_id)
+    .bind(&message.deduplication_id)
+    .bind(message.delay.map(|delay| i64::try_from(delay.as_millis()).unwrap_or(i64::MAX)))
+    .bind(message.deliver_at.map(|at| at.timestamp_millis()))
+    .bind(Utc::now().timestamp_millis())
+    .execute(conn)
+    .await?
+    .last_insert_rowid();
+    Ok(id)
+}
+
+/// A database transaction that can stage outbox messages
+///
+/// Dereferences to the connection, so the handler's own queries run in the
+/// same transaction. Dropping it without `commit` rolls everything back,
+/// staged messages included.
+#[derive(Debug)]
+pub struct OutboxTransaction {
+    tx: Transaction<'static, Sqlite>,
+    staged: Arc<Notify>,
+}
+
+impl OutboxTransaction {
+    /// Stages a message to be sent to `queue` once the transaction commits
+    pub async fn stage(&mut self, queue: &str, message: OutgoingMessage) -> Result<i64, OutboxError> {
+        stage(&mut self.tx, queue, message).await
+    }
+
+    /// Commits the handler's writes and staged messages, then wakes the relay
+    pub async fn commit(self) -> Result<(), OutboxError> {
+        self.tx.commit().await?;
+        self.staged.notify_waiters();
+        Ok(())
+    }
+}
+
+impl Deref for OutboxTransaction {
+    type Target = SqliteConnection;
+
+    fn deref(&self) -> &Self::Target {
+        &self.tx
+    }
+}
+
+impl DerefMut for OutboxTransaction {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.tx
+    }
+}
+
+/// Extracts a new outbox transaction
+///
+/// Fails with 500 Internal Server Error when the application has no outbox,
+/// see `MyAppState::outbox`.
+impl<S> FromRequestParts<S> for OutboxTransaction
+where
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Self, Self::Rejection> {
+        let outbox = parts
+            .extensions
+            .get::<MyAppState>()
+            .and_then(|state| state.outbox.clone())
+            .ok_or_else(|| AppError::Internal("no outbox database is configured".to_owned()))?;
+        Ok(outbox.begin().await?)
+    }
+}
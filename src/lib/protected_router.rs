@@ -3,27 +3,67 @@
 //! This module provides routes that require authentication to access.
 //! It includes middleware for JWT token validation and protected endpoints
 //! that can only be accessed with valid authentication.
+//!
+//! The queue endpoints let clients without an SQS SDK send, receive and delete
+//! messages over plain HTTPS with their bearer token.
 
+use crate::app_error::{AppError, AppJson, AppPath, AppQuery, Problem};
+use crate::app_state::MyAppState;
 use crate::auth_claim::{AuthError, Claims};
-use crate::auth_claim_mid::auth;
+use crate::auth_claim_mid::{CurrentUser, auth};
+use crate::authorization::RequireScope;
+use crate::queue::{OutgoingMessage, ReceiveOptions, ReceivedMessage};
 use axum::http::StatusCode;
 use axum::middleware::{self};
-use axum::{Router, routing::post};
+use axum::{Extension, Json};
+use chrono::{DateTime, Utc};
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::time::Duration;
+use utoipa::{IntoParams, ToSchema};
+use utoipa_axum::router::{OpenApiRouter, UtoipaMethodRouterExt};
+use utoipa_axum::routes;
+
+/// Message attribute carrying the subject of the token that sent the message
+pub const SENDER_ATTRIBUTE: &str = "sub";
+
+/// Message attribute carrying the company of the subject that sent the message
+pub const COMPANY_ATTRIBUTE: &str = "company";
 
 /// Creates a new router with protected routes
 /// 
 /// The router includes:
 /// - A root endpoint (`/`) that returns protected data
-/// - A normalized endpoint (`/norm`) that processes input text
+/// - A normalized endpoint (`/norm`) that processes input text and requires
+///   the `protected:write` scope
+/// - Queue endpoints under `/queues/{name}/messages`: receiving requires the
+///   `protected:read` scope, sending and deleting the `protected:write` scope.
+///   Each company only reaches the queues granted to it in `MyAppState::http_queues`
 /// - Authentication middleware that validates JWT tokens
 /// 
 /// # Returns
 /// 
-/// A configured `Router` with protected routes and authentication middleware
-pub fn router() -> Router {
-    Router::new()
-        .route("/", post(protected))
-        .route("/norm", post(protected_norm))
+/// A configured `OpenApiRouter` with protected routes and authentication middleware
+pub fn router() -> OpenApiRouter {
+    OpenApiRouter::new()
+        .routes(routes!(protected))
+        .routes(
+            routes!(protected_norm)
+                .map(|method_router| method_router.route_layer(RequireScope::new("protected:write"))),
+        )
+        .routes(
+            routes!(send_message)
+                .map(|method_router| method_router.route_layer(RequireScope::new("protected:write"))),
+        )
+        .routes(
+            routes!(receive_messages)
+                .map(|method_router| method_router.route_layer(RequireScope::new("protected:read"))),
+        )
+        .routes(
+            routes!(delete_message)
+                .map(|method_router| method_router.route_layer(RequireScope::new("protected:write"))),
+        )
         .layer(middleware::from_fn(auth))
 }
 
@@ -40,8 +80,18 @@ pub fn router() -> Router {
 /// 
 /// A `Result` containing either:
 /// * `Ok(String)` - A welcome message with the user's claims data
-/// * `Err(AuthError)` - If there's an authentication error
-pub async fn protected(claims: Claims) -> Result<String, AuthError> {
+/// * `Err(AppError)` - If there's an authentication error
+#[utoipa::path(
+    post,
+    path = "/",
+    tag = "protected",
+    responses(
+        (status = 200, description = "Welcome message with the token claims", body = String, content_type = "text/plain"),
+        (status = 401, description = "Bearer token is missing or invalid", body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = []))
+)]
+pub async fn protected(claims: Claims) -> Result<String, AppError> {
     // Send the protected data to the user
     Ok(format!(
         "Welcome to the protected area :)\nYour data:\n{claims}",
@@ -56,17 +106,268 @@ pub async fn protected(claims: Claims) -> Result<String, AuthError> {
 /// 
 /// # Arguments
 /// 
-/// * `claims` - The JWT claims containing user information
+/// * `user` - The user authenticated by the `auth` middleware
 /// * `input_text` - The text to be processed
 /// 
 /// # Returns
 /// 
 /// A `Result` containing either:
 /// * `Ok(String)` - The processed input text
-/// * `Err(StatusCode)` - If there's an error processing the request
-pub async fn protected_norm(claims: Claims, input_text: String) -> Result<String, StatusCode> {
+/// * `Err(AppError)` - If there's an error processing the request
+#[utoipa::path(
+    post,
+    path = "/norm",
+    tag = "protected",
+    request_body(content = String, content_type = "text/plain"),
+    responses(
+        (status = 200, description = "The input text", body = String, content_type = "text/plain"),
+        (status = 401, description = "Bearer token is missing or invalid", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Token lacks the `protected:write` scope", body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = ["protected:write"]))
+)]
+pub async fn protected_norm(user: CurrentUser, input_text: String) -> Result<String, AppError> {
     let text_data = input_text;
-    println!("input lxt: {} \n claims: {}", text_data, claims);
+    tracing::debug!(sub = %user.sub, company = %user.company, bytes = text_data.len(), "norm input");
     Ok(text_data)
 }
 
+
+/// Message to send to a queue
+#[derive(Debug, Deserialize, ToSchema)]
+pub struct SendMessageRequest {
+    /// Message payload, any JSON value
+    #[schema(value_type = Object)]
+    pub body: Value,
+    /// String attributes; `sub` and `company` are always set from the token
+    #[serde(default)]
+    pub attributes: HashMap<String, String>,
+    /// Seconds the message stays hidden after being sent, at most 900;
+    /// the queue's delay applies when omitted
+    pub delay_seconds: Option<u64>,
+    /// Time at which the message becomes receivable, instead of a delay
+    pub deliver_at: Option<DateTime<Utc>>,
+    /// Message group, required by FIFO queues
+    pub group_id: Option<String>,
+    /// Deduplication id of a FIFO message
+    pub deduplication_id: Option<String>,
+}
+
+/// Acknowledgement of a sent message
+#[derive(Debug, Serialize, ToSchema)]
+pub struct SendMessageResponse {
+    pub message_id: String,
+    /// Sequence number of a FIFO message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub sequence_number: Option<String>,
+}
+
+/// Query parameters of a receive
+#[derive(Debug, Deserialize, IntoParams)]
+#[into_params(parameter_in = Query)]
+pub struct ReceiveParams {
+    /// Most messages to return, 1 to 10, defaults to 1
+    pub max_messages: Option<usize>,
+    /// Seconds to wait for a message to arrive, at most 20, defaults to 0
+    pub wait_seconds: Option<u64>,
+    /// Seconds the messages stay hidden from other receivers, defaults to the queue's
+    pub visibility_timeout: Option<u64>,
+}
+
+/// A received message
+#[derive(Debug, Serialize, ToSchema)]
+pub struct QueueMessage {
+    pub message_id: String,
+    /// Handle to delete the message with
+    pub receipt_handle: String,
+    /// Message payload; bodies that are not JSON are returned as a string
+    #[schema(value_type = Object)]
+    pub body: Value,
+    pub attributes: HashMap<String, String>,
+    /// How many times the message has been received, including this time
+    pub receive_count: u32,
+    pub sent_at: DateTime<Utc>,
+    /// Message group of a FIFO message
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub group_id: Option<String>,
+}
+
+impl From<ReceivedMessage> for QueueMessage {
+    fn from(message: ReceivedMessage) -> Self {
+        let body = serde_json::from_str(&message.body).unwrap_or(Value::String(message.body));
+        Self {
+            message_id: message.message_id,
+            receipt_handle: message.receipt_handle,
+            body,
+            attributes: message.attributes,
+            receive_count: message.receive_count,
+            sent_at: message.sent_at,
+            group_id: message.group_id,
+        }
+    }
+}
+
+/// Checks that the subject's company may use the queue over HTTP
+///
+/// Only granted queues are reachable, which keeps internal queues and
+/// dead-letter queues out of reach of tokens.
+fn check_access(state: &MyAppState, claims: &Claims, queue: &str) -> Result<(), AuthError> {
+    let granted = state
+        .http_queues
+        .get(&claims.company)
+        .is_some_and(|queues| queues.contains(queue));
+    if granted {
+        Ok(())
+    } else {
+        Err(AuthError::QueueNotAllowed(queue.to_owned()))
+    }
+}
+
+/// Sends a message to a queue on behalf of the authenticated subject
+/// 
+/// # Arguments
+/// 
+/// * `Extension(state)` - The application state holding the message queue
+/// * `claims` - The JWT claims whose subject and company are stamped on the message
+/// * `AppPath(name)` - The queue to send to
+/// * `AppJson(request)` - The message body, attributes and delay or delivery time
+/// 
+/// # Returns
+/// 
+/// A `Result` containing either:
+/// * `Ok((StatusCode, Json<SendMessageResponse>))` - 202 Accepted with the message id
+/// * `Err(AppError)` - If the queue is not granted to the company, does not exist
+///   or the message is invalid
+#[utoipa::path(
+    post,
+    path = "/queues/{name}/messages",
+    tag = "protected",
+    params(("name" = String, Path, description = "Queue name")),
+    request_body = SendMessageRequest,
+    responses(
+        (status = 202, description = "Message enqueued", body = SendMessageResponse),
+        (status = 400, description = "Invalid message", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Bearer token is missing or invalid", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Token lacks the `protected:write` scope or the queue is not granted to its company", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Queue does not exist", body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = ["protected:write"]))
+)]
+pub async fn send_message(
+    Extension(state): Extension<MyAppState>,
+    claims: Claims,
+    AppPath(name): AppPath<String>,
+    AppJson(request): AppJson<SendMessageRequest>,
+) -> Result<(StatusCode, Json<SendMessageResponse>), AppError> {
+    check_access(&state, &claims, &name)?;
+    let mut message = OutgoingMessage::new(request.body.to_string());
+    message.delay = request.delay_seconds.map(Duration::from_secs);
+    message.attributes = request.attributes;
+    message.group_id = request.group_id;
+    message.deduplication_id = request.deduplication_id;
+    message.deliver_at = request.deliver_at;
+    // Stamped last so that senders cannot pass for someone else
+    let message = message
+        .with_attribute(SENDER_ATTRIBUTE, claims.sub)
+        .with_attribute(COMPANY_ATTRIBUTE, claims.company);
+
+    let sent = state.queue.send(&name, message).await?;
+    Ok((
+        StatusCode::ACCEPTED,
+        Json(SendMessageResponse {
+            message_id: sent.message_id,
+            sequence_number: sent.sequence_number,
+        }),
+    ))
+}
+
+/// Receives messages from a queue, long-polling if asked to
+/// 
+/// Only messages sent by the subject's company are received. Those of other
+/// companies on a shared queue are left untouched, neither hidden nor counted
+/// as received.
+/// 
+/// # Arguments
+/// 
+/// * `Extension(state)` - The application state holding the message queue
+/// * `claims` - The JWT claims whose company the queue must be granted to
+/// * `AppPath(name)` - The queue to receive from
+/// * `AppQuery(params)` - How many messages, how long to wait and how long to hide them
+/// 
+/// # Returns
+/// 
+/// A `Result` containing either:
+/// * `Ok(Json<Vec<QueueMessage>>)` - The received messages, possibly none
+/// * `Err(AppError)` - If the queue is not granted to the company, does not exist
+///   or a parameter is out of range
+#[utoipa::path(
+    get,
+    path = "/queues/{name}/messages",
+    tag = "protected",
+    params(("name" = String, Path, description = "Queue name"), ReceiveParams),
+    responses(
+        (status = 200, description = "Received messages", body = [QueueMessage]),
+        (status = 400, description = "Parameter out of range", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Bearer token is missing or invalid", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Token lacks the `protected:read` scope or the queue is not granted to its company", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Queue does not exist", body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = ["protected:read"]))
+)]
+pub async fn receive_messages(
+    Extension(state): Extension<MyAppState>,
+    claims: Claims,
+    AppPath(name): AppPath<String>,
+    AppQuery(params): AppQuery<ReceiveParams>,
+) -> Result<Json<Vec<QueueMessage>>, AppError> {
+    check_access(&state, &claims, &name)?;
+    let max_messages = params.max_messages.unwrap_or(1);
+    let wait_time = Duration::from_secs(params.wait_seconds.unwrap_or(0));
+    let mut options =
+        ReceiveOptions::new(max_messages, wait_time).with_filter(COMPANY_ATTRIBUTE, &claims.company);
+    // A per-request timeout replaces the queue's for the messages received
+    options.visibility_timeout = params.visibility_timeout.map(Duration::from_secs);
+    let messages = state.queue.receive_with(&name, &options).await?;
+    Ok(Json(messages.into_iter().map(QueueMessage::from).collect()))
+}
+
+/// Deletes a received message so that it is not delivered again
+/// 
+/// # Arguments
+/// 
+/// * `Extension(state)` - The application state holding the message queue
+/// * `claims` - The JWT claims whose company the queue must be granted to
+/// * `AppPath((name, receipt_handle))` - The queue and the handle of the latest receive
+/// 
+/// # Returns
+/// 
+/// A `Result` containing either:
+/// * `Ok(StatusCode)` - 204 No Content
+/// * `Err(AppError)` - If the queue is not granted to the company, does not exist
+///   or the handle is not the latest
+#[utoipa::path(
+    delete,
+    path = "/queues/{name}/messages/{receipt_handle}",
+    tag = "protected",
+    params(
+        ("name" = String, Path, description = "Queue name"),
+        ("receipt_handle" = String, Path, description = "Receipt handle of the latest receive")
+    ),
+    responses(
+        (status = 204, description = "Message deleted"),
+        (status = 400, description = "Receipt handle is not valid", body = Problem, content_type = "application/problem+json"),
+        (status = 401, description = "Bearer token is missing or invalid", body = Problem, content_type = "application/problem+json"),
+        (status = 403, description = "Token lacks the `protected:write` scope or the queue is not granted to its company", body = Problem, content_type = "application/problem+json"),
+        (status = 404, description = "Queue does not exist", body = Problem, content_type = "application/problem+json")
+    ),
+    security(("bearer_auth" = ["protected:write"]))
+)]
+pub async fn delete_message(
+    Extension(state): Extension<MyAppState>,
+    claims: Claims,
+    AppPath((name, receipt_handle)): AppPath<(String, String)>,
+) -> Result<StatusCode, AppError> {
+    check_access(&state, &claims, &name)?;
+    state.queue.delete(&name, &receipt_handle).await?;
+    Ok(StatusCode::NO_CONTENT)
+}
@@ -0,0 +1,1058 @@
+//! Queue Module
+//!
+//! This module provides the message queue behind the "sqs" in the crate name.
+//! It provides:
+//! - The `MessageQueue` trait, modelled on the SQS operations: send, receive
+//!   with long polling, delete by receipt handle and change visibility
+//! - An in-memory implementation with visibility timeouts; `sqlite_queue`
+//!   provides a durable one
+//! - Redrive policies moving poison messages to a dead-letter queue, and
+//!   redriving them back to their source queue
+//! - FIFO queues, whose names end in `.fifo`, with ordering per message group
+//!   and deduplication of repeated sends
+//! - Delays per queue and per message, and delivery scheduled at any later time
+//!
+//! A received message is hidden from other receivers for the queue's
+//! visibility timeout. Deleting it with its receipt handle acknowledges it;
+//! otherwise it becomes visible again and is redelivered. Once a message of a
+//! queue with a redrive policy has been received `max_receive_count` times
+//! without being deleted, it is moved to the dead-letter queue instead.
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sha2::{Digest, Sha256};
+use std::collections::{BinaryHeap, HashMap, HashSet, VecDeque};
+use std::fmt::{Debug, Display};
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::sync::{Mutex, Notify};
+use uuid::Uuid;
+
+/// Most messages a single receive may return
+pub const MAX_RECEIVE_MESSAGES: usize = 10;
+
+/// Longest a receive may wait for messages to arrive
+pub const MAX_WAIT_TIME: Duration = Duration::from_secs(20);
+
+/// Longest a message may stay hidden after being received
+pub const MAX_VISIBILITY_TIMEOUT: Duration = Duration::from_secs(12 * 60 * 60);
+
+/// Highest `max_receive_count` of a redrive policy
+pub const MAX_RECEIVE_COUNT: u32 = 1000;
+
+/// Longest a message may be delayed before its first delivery
+pub const MAX_DELAY: Duration = Duration::from_secs(15 * 60);
+
+/// Suffix that FIFO queue names, and only those, end with
+pub const FIFO_SUFFIX: &str = ".fifo";
+
+/// How long a FIFO queue remembers deduplication ids
+pub const DEDUPLICATION_WINDOW: Duration = Duration::from_secs(5 * 60);
+
+/// Queue error types
+#[derive(Debug, PartialEq, Eq)]
+pub enum QueueError {
+    /// No queue with the given name exists
+    QueueDoesNotExist(String),
+    /// A queue with the given name exists with a different configuration
+    QueueNameExists(String),
+    /// The receipt handle is unknown or belongs to an earlier receive
+    ReceiptHandleIsInvalid,
+    /// A parameter is out of range
+    InvalidParameter(String),
+    /// The storage backend failed
+    Backend(String),
+}
+
+impl Display for QueueError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            QueueError::QueueDoesNotExist(name) => write!(f, "queue {} does not exist", name),
+            QueueError::QueueNameExists(name) => {
+                write!(f, "queue {} already exists with a different configuration", name)
+            }
+            QueueError::ReceiptHandleIsInvalid => write!(f, "receipt handle is invalid"),
+            QueueError::InvalidParameter(detail) => write!(f, "invalid parameter: {}", detail),
+            QueueError::Backend(detail) => write!(f, "queue backend error: {}", detail),
+        }
+    }
+}
+
+impl std::error::Error for QueueError {}
+
+/// Where messages that keep failing end up
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RedrivePolicy {
+    /// Queue receiving the poison messages; it must exist
+    pub dead_letter_queue: String,
+    /// Receives after which an undeleted message is moved instead of delivered again
+    pub max_receive_count: u32,
+}
+
+impl RedrivePolicy {
+    /// Moves messages to `dead_letter_queue` after `max_receive_count` receives
+    pub fn new(dead_letter_queue: &str, max_receive_count: u32) -> Self {
+        Self {
+            dead_letter_queue: dead_letter_queue.to_owned(),
+            max_receive_count,
+        }
+    }
+}
+
+/// Settings of a single queue
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct QueueConfig {
+    /// How long a received message stays hidden from other receivers
+    pub visibility_timeout: Duration,
+    /// Dead-letter queue for poison messages, if any
+    pub redrive_policy: Option<RedrivePolicy>,
+    /// Delivers messages of a group in order, one receive at a time
+    pub fifo: bool,
+    /// Deduplicates FIFO messages without a deduplication id by their body's SHA-256
+    pub content_based_deduplication: bool,
+    /// How long messages without a delay of their own stay hidden after being sent
+    pub delay: Duration,
+}
+
+impl QueueConfig {
+    /// Settings of a FIFO queue, deduplicating by explicit ids only
+    pub fn fifo() -> Self {
+        Self {
+            fifo: true,
+            ..Self::default()
+        }
+    }
+}
+
+impl Default for QueueConfig {
+    fn default() -> Self {
+        Self {
+            visibility_timeout: Duration::from_secs(30),
+            redrive_policy: None,
+            fifo: false,
+            content_based_deduplication: false,
+            delay: Duration::ZERO,
+        }
+    }
+}
+
+/// Snapshot of a queue's configuration and message counts
+#[derive(Debug, Clone)]
+pub struct QueueAttributes {
+    /// Settings the queue was created with
+    pub config: QueueConfig,
+    /// When the queue was created
+    pub created_at: DateTime<Utc>,
+    /// Messages available for receiving
+    pub visible_messages: usize,
+    /// Messages received but neither deleted nor visible again yet
+    pub in_flight_messages: usize,
+    /// Messages waiting for their delay or scheduled time before the first delivery
+    pub delayed_messages: usize,
+}
+
+/// A message to enqueue
+#[derive(Debug, Clone, Default)]
+pub struct OutgoingMessage {
+    /// Message payload
+    pub body: String,
+    /// String attributes delivered alongside the body, e.g. a message type
+    pub attributes: HashMap<String, String>,
+    /// Group whose messages a FIFO queue delivers in order; required by FIFO queues
+    pub group_id: Option<String>,
+    /// Id under which a FIFO queue drops repeated sends within `DEDUPLICATION_WINDOW`
+    pub deduplication_id: Option<String>,
+    /// How long the message stays hidden after being sent, at most `MAX_DELAY`;
+    /// `None` leaves it to the queue's delay
+    pub delay: Option<Duration>,
+    /// When the message is delivered first, however far ahead; excludes `delay`
+    pub deliver_at: Option<DateTime<Utc>>,
+}
+
+impl OutgoingMessage {
+    /// Creates a message without attributes
+    pub fn new(body: impl Into<String>) -> Self {
+        Self {
+            body: body.into(),
+            ..Self::default()
+        }
+    }
+
+    /// Adds an attribute to the message
+    pub fn with_attribute(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.attributes.insert(name.into(), value.into());
+        self
+    }
+
+    /// Sets the message group of a FIFO message
+    pub fn with_group_id(mut self, group_id: impl Into<String>) -> Self {
+        self.group_id = Some(group_id.into());
+        self
+    }
+
+    /// Hides the message from receivers for a while after it is sent
+    ///
+    /// Replaces the queue's delay, so a zero delay delivers right away.
+    pub fn with_delay(mut self, delay: Duration) -> Self {
+        self.delay = Some(delay);
+        self
+    }
+
+    /// Schedules the first delivery of the message, e.g. for a reminder
+    ///
+    /// Unlike delays, which are capped at `MAX_DELAY`, the time may be
+    /// arbitrarily far ahead; a time in the past delivers right away.
+    pub fn with_delivery_at(mut self, deliver_at: DateTime<Utc>) -> Self {
+        self.deliver_at = Some(deliver_at);
+        self
+    }
+
+    /// When the message becomes visible if sent to a queue with `config` at `now`
+    pub fn first_visible_at(&self, config: &QueueConfig, now: DateTime<Utc>) -> DateTime<Utc> {
+        let delay = self.delay.unwrap_or(config.delay);
+        self.deliver_at
+            .unwrap_or_else(|| now + chrono::Duration::from_std(delay).unwrap_or_default())
+    }
+
+    /// Sets the deduplication id of a FIFO message
+    pub fn with_deduplication_id(mut self, deduplication_id: impl Into<String>) -> Self {
+        self.deduplication_id = Some(deduplication_id.into());
+        self
+    }
+}
+
+/// How `MessageQueue::receive_with` receives messages
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ReceiveOptions {
+    /// Most messages to return, between 1 and `MAX_RECEIVE_MESSAGES`
+    pub max_messages: usize,
+    /// How long to wait for a message, at most `MAX_WAIT_TIME`
+    pub wait_time: Duration,
+    /// How long received messages stay hidden, at most `MAX_VISIBILITY_TIMEOUT`;
+    /// `None` takes the queue's visibility timeout
+    pub visibility_timeout: Option<Duration>,
+    /// Only messages carrying every attribute with the given value are received;
+    /// others are left untouched, as if not there
+    pub filter: HashMap<String, String>,
+}
+
+impl ReceiveOptions {
+    /// Receives up to `max_messages`, waiting up to `wait_time` for one
+    pub fn new(max_messages: usize, wait_time: Duration) -> Self {
+        Self {
+            max_messages,
+            wait_time,
+            visibility_timeout: None,
+            filter: HashMap::new(),
+        }
+    }
+
+    /// Hides the received messages for `timeout` instead of the queue's visibility timeout
+    pub fn with_visibility_timeout(mut self, timeout: Duration) -> Self {
+        self.visibility_timeout = Some(timeout);
+        self
+    }
+
+    /// Receives only messages carrying the attribute with this value
+    pub fn with_filter(mut self, name: impl Into<String>, value: impl Into<String>) -> Self {
+        self.filter.insert(name.into(), value.into());
+        self
+    }
+
+    /// Checks every option against its limit
+    pub(crate) fn check(&self) -> Result<(), QueueError> {
+        check_receive(self.max_messages, self.wait_time)?;
+        self.visibility_timeout.map_or(Ok(()), check_visibility_timeout)
+    }
+}
+
+/// Acknowledgement of an enqueued message
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SentMessage {
+    /// Identifier assigned to the message
+    pub message_id: String,
+    /// Position of a FIFO message in its queue, increasing with every send
+    pub sequence_number: Option<String>,
+}
+
+/// A message handed out by `MessageQueue::receive`
+#[derive(Debug, Clone)]
+pub struct ReceivedMessage {
+    /// Identifier assigned when the message was sent
+    pub message_id: String,
+    /// Handle identifying this receive, needed to delete the message or change its visibility
+    pub receipt_handle: String,
+    /// Message payload
+    pub body: String,
+    /// String attributes sent with the message
+    pub attributes: HashMap<String, String>,
+    /// How many times the message has been received, including this time
+    pub receive_count: u32,
+    /// When the message was sent
+    pub sent_at: DateTime<Utc>,
+    /// When the message was first received
+    pub first_received_at: DateTime<Utc>,
+    /// Message group of a FIFO message
+    pub group_id: Option<String>,
+    /// Deduplication id of a FIFO message, derived from the body if none was given
+    pub deduplication_id: Option<String>,
+    /// Sequence number of a FIFO message
+    pub sequence_number: Option<String>,
+}
+
+/// A message listed by `MessageQueue::peek`, without being received
+#[derive(Debug, Clone)]
+pub struct QueuedMessage {
+    /// Identifier assigned when the message was sent
+    pub message_id: String,
+    /// Message payload
+    pub body: String,
+    /// String attributes sent with the message
+    pub attributes: HashMap<String, String>,
+    /// How many times the message has been received
+    pub receive_count: u32,
+    /// When the message was sent
+    pub sent_at: DateTime<Utc>,
+    /// When the message was first received, if it has been
+    pub first_received_at: Option<DateTime<Utc>>,
+    /// Queue a dead-lettered message was moved from
+    pub source_queue: Option<String>,
+    /// Message group of a FIFO message
+    pub group_id: Option<String>,
+    /// Sequence number of a FIFO message
+    pub sequence_number: Option<String>,
+}
+
+/// Storage of named message queues
+#[async_trait]
+pub trait MessageQueue: Debug + Send + Sync {
+    /// Creates a queue, succeeding if it already exists with the same configuration
+    async fn create_queue(&self, name: &str, config: QueueConfig) -> Result<(), QueueError>;
+
+    /// Returns the configuration and message counts of a queue
+    async fn queue_attributes(&self, queue: &str) -> Result<QueueAttributes, QueueError>;
+
+    /// Deletes every message in a queue
+    async fn purge(&self, queue: &str) -> Result<(), QueueError>;
+
+    /// Enqueues a message
+    async fn send(&self, queue: &str, message: OutgoingMessage) -> Result<SentMessage, QueueError>;
+
+    /// Receives up to `max_messages` visible messages
+    ///
+    /// Returns as soon as at least one message is available, or with an empty
+    /// list once `wait_time` has passed. Returned messages are hidden for the
+    /// queue's visibility timeout.
+    async fn receive(
+        &self,
+        queue: &str,
+        max_messages: usize,
+        wait_time: Duration,
+    ) -> Result<Vec<ReceivedMessage>, QueueError> {
+        self.receive_with(queue, &ReceiveOptions::new(max_messages, wait_time))
+            .await
+    }
+
+    /// Receives visible messages as `receive` does, with further options
+    ///
+    /// The options are checked before any message is taken, and the messages
+    /// are hidden for their visibility timeout as they are received.
+    async fn receive_with(
+        &self,
+        queue: &str,
+        options: &ReceiveOptions,
+    ) -> Result<Vec<ReceivedMessage>, QueueError>;
+
+    /// Deletes a received message
+    async fn delete(&self, queue: &str, receipt_handle: &str) -> Result<(), QueueError>;
+
+    /// Changes how long a received message stays hidden, counting from now
+    ///
+    /// A zero timeout makes the message visible again immediately.
+    async fn change_visibility(
+        &self,
+        queue: &str,
+        receipt_handle: &str,
+        timeout: Duration,
+    ) -> Result<(), QueueError>;
+
+    /// Lists up to `limit` messages in queue order without receiving them
+    ///
+    /// Delayed and scheduled messages are not listed before they are due.
+    async fn peek(&self, queue: &str, limit: usize) -> Result<Vec<QueuedMessage>, QueueError>;
+
+    /// Moves visible dead-lettered messages back to the queues they came from
+    ///
+    /// Only messages carrying every attribute in `filter` with the given value
+    /// are moved; an empty filter moves them all. Moved messages start over
+    /// with a receive count of zero. Returns how many messages were moved.
+    async fn redrive(
+        &self,
+        dead_letter_queue: &str,
+        filter: &HashMap<String, String>,
+    ) -> Result<usize, QueueError>;
+
+    /// Checks that the backing store answers, for the readiness check
+    ///
+    /// Queues kept in memory are always available.
+    async fn ping(&self) -> Result<(), QueueError> {
+        Ok(())
+    }
+}
+
+/// Checks a queue name: 1 to 80 alphanumeric characters, hyphens or underscores,
+/// of which FIFO queue names spend the last five on the `.fifo` suffix
+pub fn check_queue_name(name: &str, fifo: bool) -> Result<(), QueueError> {
+    let stem = match name.strip_suffix(FIFO_SUFFIX) {
+        Some(stem) if fifo => stem,
+        Some(_) => {
+            return Err(QueueError::InvalidParameter(format!(
+                "only FIFO queue names may end with {}",
+                FIFO_SUFFIX
+            )));
+        }
+        None if fifo => {
+            return Err(QueueError::InvalidParameter(format!(
+                "FIFO queue names must end with {}",
+                FIFO_SUFFIX
+            )));
+        }
+        None => name,
+    };
+    let valid = !stem.is_empty()
+        && name.len() <= 80
+        && stem
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+    if valid {
+        Ok(())
+    } else {
+        Err(QueueError::InvalidParameter(format!("invalid queue name {:?}", name)))
+    }
+}
+
+/// Checks the delay and FIFO parameters of a message and resolves its deduplication id
+///
+/// Returns the deduplication id a FIFO queue deduplicates by: the given one or,
+/// with content-based deduplication, the SHA-256 of the body.
+pub(crate) fn check_message(
+    config: &QueueConfig,
+    message: &OutgoingMessage,
+) -> Result<Option<String>, QueueError> {
+    if message.delay.is_some_and(|delay| delay > MAX_DELAY) {
+        return Err(QueueError::InvalidParameter(format!(
+            "delay must be at most {} seconds",
+            MAX_DELAY.as_secs()
+        )));
+    }
+    if message.delay.is_some() && message.deliver_at.is_some() {
+        return Err(QueueError::InvalidParameter(
+            "a message has either a delay or a delivery time".to_owned(),
+        ));
+    }
+    if !config.fifo {
+        if message.group_id.is_some() || message.deduplication_id.is_some() {
+            return Err(QueueError::InvalidParameter(
+                "message group and deduplication ids require a FIFO queue".to_owned(),
+            ));
+        }
+        return Ok(None);
+    }
+    if message.delay.is_some() || message.deliver_at.is_some() {
+        return Err(QueueError::InvalidParameter(
+            "FIFO queues do not support per-message delays or delivery times".to_owned(),
+        ));
+    }
+    if message.group_id.as_deref().is_none_or(str::is_empty) {
+        return Err(QueueError::InvalidParameter(
+            "FIFO messages require a message group id".to_owned(),
+        ));
+    }
+    match &message.deduplication_id {
+        Some(id) => Ok(Some(id.clone())),
+        None if config.content_based_deduplication => Ok(Some(
+            Sha256::digest(message.body.as_bytes())
+                .iter()
+                .map(|byte| format!("{:02x}", byte))
+                .collect(),
+        )),
+        None => Err(QueueError::InvalidParameter(
+            "FIFO messages require a deduplication id unless content-based deduplication is enabled"
+                .to_owned(),
+        )),
+    }
+}
+
+/// Checks the settings of a queue to create, apart from its redrive policy
+pub(crate) fn check_config(name: &str, config: &QueueConfig) -> Result<(), QueueError> {
+    check_queue_name(name, config.fifo)?;
+    if config.content_based_deduplication && !config.fifo {
+        return Err(QueueError::InvalidParameter(
+            "content-based deduplication requires a FIFO queue".to_owned(),
+        ));
+    }
+    if config.delay > MAX_DELAY {
+        return Err(QueueError::InvalidParameter(format!(
+            "delay must be at most {} seconds",
+            MAX_DELAY.as_secs()
+        )));
+    }
+    check_visibility_timeout(config.visibility_timeout)
+}
+
+/// Checks the limits shared by every `MessageQueue` implementation
+pub(crate) fn check_receive(max_messages: usize, wait_time: Duration) -> Result<(), QueueError> {
+    if !(1..=MAX_RECEIVE_MESSAGES).contains(&max_messages) {
+        return Err(QueueError::InvalidParameter(format!(
+            "max messages must be between 1 and {}",
+            MAX_RECEIVE_MESSAGES
+        )));
+    }
+    if wait_time > MAX_WAIT_TIME {
+        return Err(QueueError::InvalidParameter(format!(
+            "wait time must be at most {} seconds",
+            MAX_WAIT_TIME.as_secs()
+        )));
+    }
+    Ok(())
+}
+
+/// Checks a redrive policy, given whether its dead-letter queue exists
+pub(crate) fn check_redrive_policy(
+    queue: &str,
+    policy: &RedrivePolicy,
+    dead_letter_queue_exists: bool,
+) -> Result<(), QueueError> {
+    if !(1..=MAX_RECEIVE_COUNT).contains(&policy.max_receive_count) {
+        return Err(QueueError::InvalidParameter(format!(
+            "max receive count must be between 1 and {}",
+            MAX_RECEIVE_COUNT
+        )));
+    }
+    if policy.dead_letter_queue == queue {
+        return Err(QueueError::InvalidParameter(
+            "a queue cannot be its own dead-letter queue".to_owned(),
+        ));
+    }
+    if !dead_letter_queue_exists {
+        return Err(QueueError::QueueDoesNotExist(policy.dead_letter_queue.clone()));
+    }
+    // Queue names tell FIFO queues apart, so this keeps both queues of the same kind
+    if queue.ends_with(FIFO_SUFFIX) != policy.dead_letter_queue.ends_with(FIFO_SUFFIX) {
+        return Err(QueueError::InvalidParameter(
+            "the dead-letter queue of a FIFO queue must be a FIFO queue, and vice versa".to_owned(),
+        ));
+    }
+    Ok(())
+}
+
+/// Checks a visibility timeout against `MAX_VISIBILITY_TIMEOUT`
+pub(crate) fn check_visibility_timeout(timeout: Duration) -> Result<(), QueueError> {
+    if timeout > MAX_VISIBILITY_TIMEOUT {
+        return Err(QueueError::InvalidParameter(format!(
+            "visibility timeout must be at most {} seconds",
+            MAX_VISIBILITY_TIMEOUT.as_secs()
+        )));
+    }
+    Ok(())
+}
+
+/// A message as held by `InMemoryMessageQueue`
+#[derive(Debug)]
+struct StoredMessage {
+    message_id: String,
+    body: String,
+    attributes: HashMap<String, String>,
+    sent_at: DateTime<Utc>,
+    /// The message is hidden from receivers until then
+    visible_at: DateTime<Utc>,
+    receive_count: u32,
+    first_received_at: Option<DateTime<Utc>>,
+    /// Handle of the latest receive; earlier handles are no longer valid
+    receipt_handle: Option<String>,
+    /// Queue the message was dead-lettered from
+    source_queue: Option<String>,
+    group_id: Option<String>,
+    deduplication_id: Option<String>,
+    sequence_number: Option<String>,
+}
+
+impl StoredMessage {
+    fn is_visible(&self, now: DateTime<Utc>) -> bool {
+        self.visible_at <= now
+    }
+
+    /// Checks whether every attribute in `filter` is present with the given value
+    fn matches(&self, filter: &HashMap<String, String>) -> bool {
+        filter
+            .iter()
+            .all(|(name, value)| self.attributes.get(name) == Some(value))
+    }
+
+    /// Makes the message visible and unreceived in its new queue, remembering where it came from
+    fn requeue(&mut self, now: DateTime<Utc>, source_queue: Option<String>) {
+        self.visible_at = now;
+        self.receipt_handle = None;
+        self.source_queue = source_queue;
+    }
+}
+
+/// A message waiting for its first delivery, ordered to pop the earliest first
+#[derive(Debug)]
+struct Scheduled {
+    /// Tells apart messages due at the same time, keeping their send order
+    order: u64,
+    message: StoredMessage,
+}
+
+impl Scheduled {
+    fn key(&self) -> (DateTime<Utc>, u64) {
+        (self.message.visible_at, self.order)
+    }
+}
+
+impl PartialEq for Scheduled {
+    fn eq(&self, other: &Self) -> bool {
+        self.key() == other.key()
+    }
+}
+
+impl Eq for Scheduled {}
+
+impl PartialOrd for Scheduled {
+    fn partial_cmp(&self, other: &Self) -> Option<std::cmp::Ordering> {
+        Some(self.cmp(other))
+    }
+}
+
+impl Ord for Scheduled {
+    // Reversed, as `BinaryHeap` pops the greatest
+    fn cmp(&self, other: &Self) -> std::cmp::Ordering {
+        other.key().cmp(&self.key())
+    }
+}
+
+#[derive(Debug)]
+struct QueueState {
+    config: QueueConfig,
+    created_at: DateTime<Utc>,
+    messages: VecDeque<StoredMessage>,
+    /// Delayed and scheduled messages, joining `messages` when they are due
+    scheduled: BinaryHeap<Scheduled>,
+    /// Number of messages ever scheduled, to order those due at the same time
+    scheduled_count: u64,
+    /// Wakes long-polling receivers when a message may have become visible
+    arrivals: Arc<Notify>,
+    /// Last sequence number handed out by a FIFO queue
+    sequence: u64,
+    /// Recently sent FIFO messages by deduplication id, with when they were sent
+    deduplication: HashMap<String, (SentMessage, DateTime<Utc>)>,
+}
+
+impl QueueState {
+    /// Appends the scheduled messages that are due to the queue, earliest first
+    fn promote_due(&mut self, now: DateTime<Utc>) {
+        while self
+            .scheduled
+            .peek()
+            .is_some_and(|scheduled| scheduled.message.visible_at <= now)
+        {
+            if let Some(Scheduled { message, .. }) = self.scheduled.pop() {
+                self.messages.push_back(message);
+            }
+        }
+    }
+
+    /// Adds a new message, holding it back until it is due
+    fn enqueue(&mut self, message: StoredMessage, now: DateTime<Utc>) {
+        if message.visible_at <= now {
+            self.messages.push_back(message);
+        } else {
+            self.scheduled_count += 1;
+            self.scheduled.push(Scheduled {
+                order: self.scheduled_count,
+                message,
+            });
+        }
+    }
+
+    fn find_mut(&mut self, receipt_handle: &str) -> Option<&mut StoredMessage> {
+        self.messages
+            .iter_mut()
+            .find(|message| message.receipt_handle.as_deref() == Some(receipt_handle))
+    }
+
+    /// Removes the visible messages that reached the redrive policy's receive count
+    fn take_dead_letters(&mut self, now: DateTime<Utc>) -> Vec<StoredMessage> {
+        let Some(policy) = &self.config.redrive_policy else {
+            return Vec::new();
+        };
+        let max_receive_count = policy.max_receive_count;
+        let (dead, alive) = std::mem::take(&mut self.messages)
+            .into_iter()
+            .partition(|message| message.is_visible(now) && message.receive_count >= max_receive_count);
+        self.messages = alive;
+        dead.into()
+    }
+
+    /// Time until the next hidden message becomes visible again
+    fn next_visible_in(&self, now: DateTime<Utc>) -> Option<Duration> {
+        self.messages
+            .iter()
+            .map(|message| message.visible_at)
+            .chain(self.scheduled.peek().map(|scheduled| scheduled.message.visible_at))
+            .filter(|at| *at > now)
+            .min()
+            .map(|at| (at - now).to_std().unwrap_or_default())
+    }
+
+    /// Positions of the messages a receive may hand out, in queue order
+    ///
+    /// In a FIFO queue a group is blocked while any of its messages is in
+    /// flight or left out by `filter`, so later messages never overtake an
+    /// earlier one of their group.
+    fn receivable(&self, now: DateTime<Utc>, options: &ReceiveOptions) -> Vec<usize> {
+        let mut blocked = HashSet::new();
+        let mut positions = Vec::new();
+        for (position, message) in self.messages.iter().enumerate() {
+            if positions.len() == options.max_messages {
+                break;
+            }
+            let group = message.group_id.as_deref().filter(|_| self.config.fifo);
+            if group.is_some_and(|group| blocked.contains(group)) {
+                continue;
+            }
+            if message.is_visible(now) && message.matches(&options.filter) {
+                positions.push(position);
+            } else if let Some(group) = group {
+                blocked.insert(group);
+            }
+        }
+        positions
+    }
+}
+
+/// In-memory message queue
+///
+/// Messages are lost on restart.
+#[derive(Debug, Default)]
+pub struct InMemoryMessageQueue {
+    queues: Mutex<HashMap<String, QueueState>>,
+}
+
+impl InMemoryMessageQueue {
+    /// Creates a store without queues
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+#[async_trait]
+impl MessageQueue for InMemoryMessageQueue {
+    async fn create_queue(&self, name: &str, config: QueueConfig) -> Result<(), QueueError> {
+        check_config(name, &config)?;
+        let mut queues = self.queues.lock().await;
+        if let Some(policy) = &config.redrive_policy {
+            let exists = queues.contains_key(&policy.dead_letter_queue);
+            check_redrive_policy(name, policy, exists)?;
+        }
+        match queues.get(name) {
+            Some(existing) if existing.config == config => Ok(()),
+            Some(_) => Err(QueueError::QueueNameExists(name.to_owned())),
+            None => {
+                queues.insert(
+                    name.to_owned(),
+                    QueueState {
+                        config,
+                        created_at: Utc::now(),
+                        messages: VecDeque::new(),
+                        scheduled: BinaryHeap::new(),
+                        scheduled_count: 0,
+                        arrivals: Arc::new(Notify::new()),
+                        sequence: 0,
+                        deduplication: HashMap::new(),
+                    },
+                );
+                Ok(())
+            }
+        }
+    }
+
+    async fn queue_attributes(&self, queue: &str) -> Result<QueueAttributes, QueueError> {
+        let mut queues = self.queues.lock().await;
+        let state = queues
+            .get_mut(queue)
+            .ok_or_else(|| QueueError::QueueDoesNotExist(queue.to_owned()))?;
+        let now = Utc::now();
+        state.promote_due(now);
+        let visible_messages = state
+            .messages
+            .iter()
+            .filter(|message| message.is_visible(now))
+            .count();
+        Ok(QueueAttributes {
+            config: state.config.clone(),
+            created_at: state.created_at,
+            visible_messages,
+            in_flight_messages: state.messages.len() - visible_messages,
+            delayed_messages: state.scheduled.len(),
+        })
+    }
+
+    async fn purge(&self, queue: &str) -> Result<(), QueueError> {
+        let mut queues = self.queues.lock().await;
+        let state = queues
+            .get_mut(queue)
+            .ok_or_else(|| QueueError::QueueDoesNotExist(queue.to_owned()))?;
+        state.messages.clear();
+        state.scheduled.clear();
+        Ok(())
+    }
+
+    async fn send(&self, queue: &str, message: OutgoingMessage) -> Result<SentMessage, QueueError> {
+        let mut queues = self.queues.lock().await;
+        let state = queues
+            .get_mut(queue)
+            .ok_or_else(|| QueueError::QueueDoesNotExist(queue.to_owned()))?;
+
+        let deduplication_id = check_message(&state.config, &message)?;
+        let now = Utc::now();
+
+        // A repeated send within the window is acknowledged like the original but not enqueued
+        state
+            .deduplication
+            .retain(|_, (_, sent_at)| *sent_at + DEDUPLICATION_WINDOW > now);
+        if let Some((sent, _)) = deduplication_id
+            .as_ref()
+            .and_then(|id| state.deduplication.get(id))
+        {
+            return Ok(sent.clone());
+        }
+
+        let sequence_number = state.config.fifo.then(|| {
+            state.sequence += 1;
+            format!("{:020}", state.sequence)
+        });
+        let sent = SentMessage {
+            message_id: Uuid::new_v4().to_string(),
+            sequence_number: sequence_number.clone(),
+        };
+        if let Some(id) = &deduplication_id {
+            state.deduplication.insert(id.clone(), (sent.clone(), now));
+        }
+        let visible_at = message.first_visible_at(&state.config, now);
+        let stored = StoredMessage {
+            message_id: sent.message_id.clone(),
+            body: message.body,
+            attributes: message.attributes,
+            sent_at: now,
+            visible_at,
+            receive_count: 0,
+            first_received_at: None,
+            receipt_handle: None,
+            source_queue: None,
+            group_id: message.group_id,
+            deduplication_id,
+            sequence_number,
+        };
+        state.enqueue(stored, now);
+        state.arrivals.notify_waiters();
+        Ok(sent)
+    }
+
+    async fn receive_with(
+        &self,
+        queue: &str,
+        options: &ReceiveOptions,
+    ) -> Result<Vec<ReceivedMessage>, QueueError> {
+        options.check()?;
+        let deadline = tokio::time::Instant::now() + options.wait_time;
+
+        loop {
+            let mut queues = self.queues.lock().await;
+            let now = Utc::now();
+            let state = queues
+                .get_mut(queue)
+                .ok_or_else(|| QueueError::QueueDoesNotExist(queue.to_owned()))?;
+            state.promote_due(now);
+
+            // Poison messages go to the dead-letter queue instead of being delivered again
+            let dead_letters = state.take_dead_letters(now);
+            if !dead_letters.is_empty() {
+                let dead_letter_queue = state
+                    .config
+                    .redrive_policy
+                    .as_ref()
+                    .map(|policy| policy.dead_letter_queue.clone())
+                    .unwrap_or_default();
+                let target = queues
+                    .get_mut(&dead_letter_queue)
+                    .ok_or_else(|| QueueError::QueueDoesNotExist(dead_letter_queue.clone()))?;
+                for mut message in dead_letters {
+                    tracing::info!(
+                        queue,
+                        dead_letter_queue,
+                        message_id = message.message_id,
+                        receive_count = message.receive_count,
+                        "message moved to dead-letter queue"
+                    );
+                    message.requeue(now, Some(queue.to_owned()));
+                    target.messages.push_back(message);
+                }
+                target.arrivals.notify_waiters();
+            }
+            let state = queues
+                .get_mut(queue)
+                .ok_or_else(|| QueueError::QueueDoesNotExist(queue.to_owned()))?;
+
+            let hidden_until = now + options.visibility_timeout.unwrap_or(state.config.visibility_timeout);
+            let receivable = state.receivable(now, options);
+            let received: Vec<ReceivedMessage> = state
+                .messages
+                .iter_mut()
+                .enumerate()
+                .filter(|(position, _)| receivable.contains(position))
+                .map(|(_, message)| {
+                    let receipt_handle = Uuid::new_v4().simple().to_string();
+                    message.visible_at = hidden_until;
+                    message.receive_count += 1;
+                    let first_received_at = *message.first_received_at.get_or_insert(now);
+                    message.receipt_handle = Some(receipt_handle.clone());
+                    ReceivedMessage {
+                        message_id: message.message_id.clone(),
+                        receipt_handle,
+                        body: message.body.clone(),
+                        attributes: message.attributes.clone(),
+                        receive_count: message.receive_count,
+                        sent_at: message.sent_at,
+                        first_received_at,
+                        group_id: message.group_id.clone(),
+                        deduplication_id: message.deduplication_id.clone(),
+                        sequence_number: message.sequence_number.clone(),
+                    }
+                })
+                .collect();
+
+            let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+            if !received.is_empty() || remaining.is_zero() {
+                return Ok(received);
+            }
+
+            // Sleep until a send wakes us, a hidden message reappears or the wait is over
+            let nap = state
+                .next_visible_in(now)
+                .map_or(remaining, |next| next.min(remaining));
+            let arrivals = state.arrivals.clone();
+            let notified = arrivals.notified();
+            tokio::pin!(notified);
+            // Register before unlocking so a send in between is not missed
+            notified.as_mut().enable();
+            drop(queues);
+            let _ = tokio::time::timeout(nap, notified).await;
+        }
+    }
+
+    async fn delete(&self, queue: &str, receipt_handle: &str) -> Result<(), QueueError> {
+        let mut queues = self.queues.lock().await;
+        let state = queues
+            .get_mut(queue)
+            .ok_or_else(|| QueueError::QueueDoesNotExist(queue.to_owned()))?;
+        let position = state
+            .messages
+            .iter()
+            .position(|message| message.receipt_handle.as_deref() == Some(receipt_handle))
+            .ok_or(QueueError::ReceiptHandleIsInvalid)?;
+        state.messages.remove(position);
+        // The next message of a FIFO group becomes receivable
+        if state.config.fifo {
+            state.arrivals.notify_waiters();
+        }
+        Ok(())
+    }
+
+    async fn change_visibility(
+        &self,
+        queue: &str,
+        receipt_handle: &str,
+        timeout: Duration,
+    ) -> Result<(), QueueError> {
+        check_visibility_timeout(timeout)?;
+        let mut queues = self.queues.lock().await;
+        let state = queues
+            .get_mut(queue)
+            .ok_or_else(|| QueueError::QueueDoesNotExist(queue.to_owned()))?;
+        let message = state
+            .find_mut(receipt_handle)
+            .ok_or(QueueError::ReceiptHandleIsInvalid)?;
+        message.visible_at = Utc::now() + timeout;
+        if timeout.is_zero() {
+            state.arrivals.notify_waiters();
+        }
+        Ok(())
+    }
+
+    async fn peek(&self, queue: &str, limit: usize) -> Result<Vec<QueuedMessage>, QueueError> {
+        let mut queues = self.queues.lock().await;
+        let state = queues
+            .get_mut(queue)
+            .ok_or_else(|| QueueError::QueueDoesNotExist(queue.to_owned()))?;
+        state.promote_due(Utc::now());
+        Ok(state
+            .messages
+            .iter()
+            .take(limit)
+            .map(|message| QueuedMessage {
+                message_id: message.message_id.clone(),
+                body: message.body.clone(),
+                attributes: message.attributes.clone(),
+                receive_count: message.receive_count,
+                sent_at: message.sent_at,
+                first_received_at: message.first_received_at,
+                source_queue: message.source_queue.clone(),
+                group_id: message.group_id.clone(),
+                sequence_number: message.sequence_number.clone(),
+            })
+            .collect())
+    }
+
+    async fn redrive(
+        &self,
+        dead_letter_queue: &str,
+        filter: &HashMap<String, String>,
+    ) -> Result<usize, QueueError> {
+        let mut queues = self.queues.lock().await;
+        let now = Utc::now();
+        let state = queues
+            .get_mut(dead_letter_queue)
+            .ok_or_else(|| QueueError::QueueDoesNotExist(dead_letter_queue.to_owned()))?;
+
+        // In-flight messages are being handled by someone and stay put
+        let (moving, staying): (VecDeque<_>, VecDeque<_>) = std::mem::take(&mut state.messages)
+            .into_iter()
+            .partition(|message| {
+                message.is_visible(now) && message.source_queue.is_some() && message.matches(filter)
+            });
+        state.messages = staying;
+
+        let mut moved = 0;
+        for mut message in moving {
+            let source = message.source_queue.clone().unwrap_or_default();
+            match queues.get_mut(&source) {
+                Some(target) => {
+                    message.receive_count = 0;
+                    message.first_received_at = None;
+                    message.requeue(now, None);
+                    target.messages.push_back(message);
+                    target.arrivals.notify_waiters();
+                    moved += 1;
+                }
+                None => {
+                    if let Some(state) = queues.get_mut(dead_letter_queue) {
+                        state.messages.push_back(message);
+                    }
+                }
+            }
+        }
+        Ok(moved)
+    }
+}
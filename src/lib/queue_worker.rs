@@ -0,0 +1,495 @@
+//! Queue Worker Module
+//!
+//! This module runs background consumers that long-poll a queue of the
+//! `MessageQueue` in the application state and dispatch each message to an
+//! async handler chosen by the message's type attribute.
+//!
+//! Handlers look like axum handlers: async functions whose arguments are
+//! extracted from the message.
+//!
+//! ```ignore
+//! async fn order_created(JsonBody(order): JsonBody<Order>, State(state): State) -> Result<(), String> {
+//!     // ...
+//! }
+//!
+//! Consumer::new("orders")
+//!     .handler("order.created", order_created)
+//!     .spawn(state);
+//! ```
+//!
+//! A message is deleted once its handler succeeds. When extraction fails, the
+//! handler returns an error or panics, or no handler matches, the message is
+//! left in the queue and redelivered after its visibility timeout.
+//!
+//! Messages of a FIFO message group are handled one after the other, in order;
+//! after a failure the rest of the group's batch is left for redelivery too.
+//!
+//! An idempotent consumer, see `Consumer::idempotent`, records handled messages
+//! in a `ProcessedLedger` and acknowledges redeliveries without handling them.
+
+use crate::app_state::MyAppState;
+use crate::idempotency::ProcessedLedger;
+use crate::queue::{MAX_RECEIVE_MESSAGES, MAX_WAIT_TIME, ReceivedMessage, check_receive};
+use chrono::{DateTime, Utc};
+use serde::de::DeserializeOwned;
+use std::collections::HashMap;
+use std::fmt::{Debug, Display};
+use std::future::Future;
+use std::pin::Pin;
+use std::sync::Arc;
+use std::time::Duration;
+use tokio::task::JoinHandle;
+
+/// Attribute naming the message type, unless the consumer is told otherwise
+pub const MESSAGE_TYPE_ATTRIBUTE: &str = "type";
+
+/// How long a consumer waits before polling again after a failed receive
+const RECEIVE_BACKOFF: Duration = Duration::from_secs(1);
+
+/// Why a message was not processed
+#[derive(Debug)]
+pub enum HandlerError {
+    /// No handler is registered for the message type
+    NoHandler(String),
+    /// An extractor could not build a handler argument from the message
+    Rejected(String),
+    /// The handler returned an error
+    Failed(String),
+    /// The handler panicked
+    Panicked,
+}
+
+impl Display for HandlerError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            HandlerError::NoHandler(message_type) => {
+                write!(f, "no handler for message type {:?}", message_type)
+            }
+            HandlerError::Rejected(detail) => write!(f, "message rejected: {}", detail),
+            HandlerError::Failed(detail) => write!(f, "handler failed: {}", detail),
+            HandlerError::Panicked => write!(f, "handler panicked"),
+        }
+    }
+}
+
+impl std::error::Error for HandlerError {}
+
+/// Everything a handler argument can be extracted from
+#[derive(Debug, Clone)]
+pub struct MessageContext {
+    /// Name of the queue the message was received from
+    pub queue: String,
+    /// The received message
+    pub message: ReceivedMessage,
+    /// The application state
+    pub state: MyAppState,
+}
+
+/// Types that can be extracted from a received message, like axum's `FromRequestParts`
+pub trait FromMessage: Sized {
+    /// Extracts the value, or explains why the message does not fit
+    fn from_message(ctx: &MessageContext) -> Result<Self, HandlerError>;
+}
+
+/// Extracts the message body as text
+#[derive(Debug, Clone)]
+pub struct Body(pub String);
+
+impl FromMessage for Body {
+    fn from_message(ctx: &MessageContext) -> Result<Self, HandlerError> {
+        Ok(Body(ctx.message.body.clone()))
+    }
+}
+
+/// Extracts the message body by deserializing it from JSON
+#[derive(Debug, Clone)]
+pub struct JsonBody<T>(pub T);
+
+impl<T: DeserializeOwned> FromMessage for JsonBody<T> {
+    fn from_message(ctx: &MessageContext) -> Result<Self, HandlerError> {
+        serde_json::from_str(&ctx.message.body)
+            .map(JsonBody)
+            .map_err(|e| HandlerError::Rejected(format!("invalid JSON body: {}", e)))
+    }
+}
+
+/// Extracts the message attributes
+#[derive(Debug, Clone)]
+pub struct Attributes(pub HashMap<String, String>);
+
+impl FromMessage for Attributes {
+    fn from_message(ctx: &MessageContext) -> Result<Self, HandlerError> {
+        Ok(Attributes(ctx.message.attributes.clone()))
+    }
+}
+
+/// Extracts the application state
+#[derive(Debug, Clone)]
+pub struct State(pub MyAppState);
+
+impl FromMessage for State {
+    fn from_message(ctx: &MessageContext) -> Result<Self, HandlerError> {
+        Ok(State(ctx.state.clone()))
+    }
+}
+
+/// Extracts the whole message, including its id and receive count
+impl FromMessage for ReceivedMessage {
+    fn from_message(ctx: &MessageContext) -> Result<Self, HandlerError> {
+        Ok(ctx.message.clone())
+    }
+}
+
+/// Values a handler may return, like axum's `IntoResponse`
+pub trait IntoHandlerResult {
+    /// Converts the value into the handler outcome
+    fn into_handler_result(self) -> Result<(), HandlerError>;
+}
+
+impl IntoHandlerResult for () {
+    fn into_handler_result(self) -> Result<(), HandlerError> {
+        Ok(())
+    }
+}
+
+impl<E: Display> IntoHandlerResult for Result<(), E> {
+    fn into_handler_result(self) -> Result<(), HandlerError> {
+        self.map_err(|e| HandlerError::Failed(e.to_string()))
+    }
+}
+
+/// Future returned by type-erased handlers
+pub type HandlerFuture = Pin<Box<dyn Future<Output = Result<(), HandlerError>> + Send>>;
+
+/// Async functions usable as message handlers
+///
+/// Implemented for functions taking up to four `FromMessage` arguments and
+/// returning an `IntoHandlerResult`. `T` only tells the implementations apart.
+pub trait MessageHandler<T>: Clone + Send + Sync + 'static {
+    /// Extracts the arguments and runs the handler
+    fn call(self, ctx: MessageContext) -> HandlerFuture;
+}
+
+macro_rules! impl_message_handler {
+    ($($arg:ident),*) => {
+        #[allow(non_snake_case, unused_variables)]
+        impl<F, Fut, R, $($arg,)*> MessageHandler<($($arg,)*)> for F
+        where
+            F: FnOnce($($arg),*) -> Fut + Clone + Send + Sync + 'static,
+            Fut: Future<Output = R> + Send,
+            R: IntoHandlerResult,
+            $($arg: FromMessage + Send,)*
+        {
+            fn call(self, ctx: MessageContext) -> HandlerFuture {
+                Box::pin(async move {
+                    $(let $arg = $arg::from_message(&ctx)?;)*
+                    self($($arg),*).await.into_handler_result()
+                })
+            }
+        }
+    };
+}
+
+impl_message_handler!();
+impl_message_handler!(T1);
+impl_message_handler!(T1, T2);
+impl_message_handler!(T1, T2, T3);
+impl_message_handler!(T1, T2, T3, T4);
+
+type BoxedHandler = Arc<dyn Fn(MessageContext) -> HandlerFuture + Send + Sync>;
+
+/// How a consumer recognizes messages it already handled
+#[derive(Debug, Clone)]
+struct Idempotency {
+    ledger: Arc<dyn ProcessedLedger>,
+    retention: Duration,
+    /// Attribute carrying the idempotency key; the message id is used without it
+    key_attribute: Option<String>,
+}
+
+impl Idempotency {
+    /// Ledger key of a message, scoped by queue
+    fn key(&self, queue: &str, message: &ReceivedMessage) -> String {
+        let key = self
+            .key_attribute
+            .as_ref()
+            .and_then(|name| message.attributes.get(name))
+            .unwrap_or(&message.message_id);
+        format!("{}/{}", queue, key)
+    }
+}
+
+/// Erases the argument types of a handler
+fn boxed<H, T>(handler: H) -> BoxedHandler
+where
+    H: MessageHandler<T>,
+{
+    Arc::new(move |ctx| handler.clone().call(ctx))
+}
+
+/// A long-polling consumer of one queue
+///
+/// Built with `Consumer::new` and the `handler`, `fallback` and tuning methods,
+/// then started with `spawn`.
+#[derive(Clone)]
+pub struct Consumer {
+    queue: String,
+    type_attribute: String,
+    handlers: HashMap<String, BoxedHandler>,
+    fallback: Option<BoxedHandler>,
+    max_messages: usize,
+    wait_time: Duration,
+    idempotency: Option<Idempotency>,
+}
+
+impl Debug for Consumer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("Consumer")
+            .field("queue", &self.queue)
+            .field("type_attribute", &self.type_attribute)
+            .field("handlers", &self.handlers.keys().collect::<Vec<_>>())
+            .field("fallback", &self.fallback.is_some())
+            .field("max_messages", &self.max_messages)
+            .field("wait_time", &self.wait_time)
+            .field("idempotency", &self.idempotency)
+            .finish()
+    }
+}
+
+impl Consumer {
+    /// Creates a consumer of a queue, receiving up to ten messages per 20 second long poll
+    pub fn new(queue: &str) -> Self {
+        Self {
+            queue: queue.to_owned(),
+            type_attribute: MESSAGE_TYPE_ATTRIBUTE.to_owned(),
+            handlers: HashMap::new(),
+            fallback: None,
+            max_messages: MAX_RECEIVE_MESSAGES,
+            wait_time: MAX_WAIT_TIME,
+            idempotency: None,
+        }
+    }
+
+    /// Name of the queue this consumer polls
+    pub fn queue(&self) -> &str {
+        &self.queue
+    }
+
+    /// Routes messages whose type attribute equals `message_type` to `handler`
+    pub fn handler<H, T>(mut self, message_type: &str, handler: H) -> Self
+    where
+        H: MessageHandler<T>,
+    {
+        self.handlers.insert(message_type.to_owned(), boxed(handler));
+        self
+    }
+
+    /// Handles messages without a type attribute or without a matching handler
+    pub fn fallback<H, T>(mut self, handler: H) -> Self
+    where
+        H: MessageHandler<T>,
+    {
+        self.fallback = Some(boxed(handler));
+        self
+    }
+
+    /// Reads the message type from another attribute than `MESSAGE_TYPE_ATTRIBUTE`
+    pub fn type_attribute(mut self, name: &str) -> Self {
+        self.type_attribute = name.to_owned();
+        self
+    }
+
+    /// Sets how many messages are received, and handled concurrently, at once
+    ///
+    /// # Panics
+    /// If `max_messages` is not between 1 and `MAX_RECEIVE_MESSAGES`, since
+    /// every receive of the consumer would fail.
+    pub fn max_messages(mut self, max_messages: usize) -> Self {
+        check_receive(max_messages, self.wait_time)
+            .unwrap_or_else(|e| panic!("consumer of {}: {}", self.queue, e));
+        self.max_messages = max_messages;
+        self
+    }
+
+    /// Sets how long each receive waits for messages to arrive
+    ///
+    /// # Panics
+    /// If `wait_time` is over `MAX_WAIT_TIME`, since every receive of the
+    /// consumer would fail.
+    pub fn wait_time(mut self, wait_time: Duration) -> Self {
+        check_receive(self.max_messages, wait_time)
+            .unwrap_or_else(|e| panic!("consumer of {}: {}", self.queue, e));
+        self.wait_time = wait_time;
+        self
+    }
+
+    /// Skips messages that were already handled within `retention`
+    ///
+    /// After a handler succeeds, the message is recorded in `ledger` before it
+    /// is deleted. A redelivery found in the ledger is deleted without running
+    /// a handler. Duplicates received in the same batch are handled one after
+    /// the other, so only the first one runs.
+    pub fn idempotent(mut self, ledger: Arc<dyn ProcessedLedger>, retention: Duration) -> Self {
+        self.idempotency = Some(Idempotency {
+            ledger,
+            retention,
+            key_attribute: None,
+        });
+        self
+    }
+
+    /// Recognizes duplicates by an attribute set by the sender instead of the message id
+    ///
+    /// Messages without the attribute fall back to their message id. Only has an
+    /// effect on idempotent consumers.
+    pub fn idempotency_attribute(mut self, name: &str) -> Self {
+        if let Some(idempotency) = &mut self.idempotency {
+            idempotency.key_attribute = Some(name.to_owned());
+        }
+        self
+    }
+
+    /// Starts polling the queue in a background task
+    ///
+    /// The task runs until the state's `shutdown` is triggered: it then stops
+    /// polling, finishes the batch in hand and ends. Messages of a long poll cut
+    /// short by the shutdown are redelivered after their visibility timeout.
+    pub fn spawn(self, state: MyAppState) -> JoinHandle<()> {
+        tokio::spawn(self.run(state))
+    }
+
+    async fn run(self, state: MyAppState) {
+        let consumer = Arc::new(self);
+        tracing::info!(queue = consumer.queue, "consumer started");
+        let stopped = state.shutdown.triggered();
+        tokio::pin!(stopped);
+        loop {
+            let received = tokio::select! {
+                received = state.queue.receive(&consumer.queue, consumer.max_messages, consumer.wait_time) => received,
+                _ = &mut stopped => break,
+            };
+            let messages = match received {
+                Ok(messages) => messages,
+                Err(e) => {
+                    tracing::warn!(queue = consumer.queue, "receive failed: {}", e);
+                    tokio::select! {
+                        _ = tokio::time::sleep(RECEIVE_BACKOFF) => continue,
+                        _ = &mut stopped => break,
+                    }
+                }
+            };
+
+            // Lanes of a batch are handled concurrently, each lane in order
+            let mut groups: Vec<(Option<String>, Vec<ReceivedMessage>)> = Vec::new();
+            for message in messages {
+                let lane = consumer.lane(&message);
+                match groups.iter_mut().find(|(other, _)| lane.is_some() && *other == lane) {
+                    Some((_, group)) => group.push(message),
+                    None => groups.push((lane, vec![message])),
+                }
+            }
+            let tasks: Vec<_> = groups
+                .into_iter()
+                .map(|(_, group)| tokio::spawn(consumer.clone().process_group(state.clone(), group)))
+                .collect();
+            for task in tasks {
+                let _ = task.await;
+            }
+        }
+        tracing::info!(queue = consumer.queue, "consumer stopped");
+    }
+
+    /// Names the lane a message is handled in, if it must not run concurrently with others
+    ///
+    /// Messages of a FIFO message group share a lane to keep their order, and so
+    /// do duplicates of an idempotent consumer, so that only the first one runs.
+    fn lane(&self, message: &ReceivedMessage) -> Option<String> {
+        match (&message.group_id, &self.idempotency) {
+            (Some(group_id), _) => Some(format!("group/{}", group_id)),
+            (None, Some(idempotency)) => Some(format!("key/{}", idempotency.key(&self.queue, message))),
+            (None, None) => None,
+        }
+    }
+
+    /// Processes messages in order, stopping at the first that is not handled
+    async fn process_group(self: Arc<Self>, state: MyAppState, group: Vec<ReceivedMessage>) {
+        for message in group {
+            if !self.process(&state, message).await {
+                break;
+            }
+        }
+    }
+
+    /// Runs the handler for one message and deletes the message on success
+    ///
+    /// Returns whether the handler succeeded.
+    async fn process(&self, state: &MyAppState, message: ReceivedMessage) -> bool {
+        let message_id = message.message_id.clone();
+        let receipt_handle = message.receipt_handle.clone();
+        // Redeliveries of handled messages are only acknowledged
+        let idempotency = self
+            .idempotency
+            .as_ref()
+            .map(|idempotency| (idempotency, idempotency.key(&self.queue, &message)));
+        if let Some((idempotency, key)) = &idempotency {
+            match idempotency.ledger.is_processed(key).await {
+                Ok(false) => {}
+                Ok(true) => {
+                    tracing::info!(queue = self.queue, message_id, key, "duplicate message acknowledged");
+                    if let Err(e) = state.queue.delete(&self.queue, &receipt_handle).await {
+                        tracing::warn!(queue = self.queue, message_id, "delete failed: {}", e);
+                    }
+                    return true;
+                }
+                Err(e) => {
+                    tracing::warn!(queue = self.queue, message_id, "message left for redelivery: {}", e);
+                    return false;
+                }
+            }
+        }
+
+        let message_type = message.attributes.get(&self.type_attribute).cloned();
+        let handler = message_type
+            .as_ref()
+            .and_then(|message_type| self.handlers.get(message_type))
+            .or(self.fallback.as_ref())
+            .cloned();
+
+        let outcome = match handler {
+            Some(handler) => {
+                let ctx = MessageContext {
+                    queue: self.queue.clone(),
+                    message,
+                    state: state.clone(),
+                };
+                // A panicking handler only fails its own message
+                tokio::spawn(handler(ctx))
+                    .await
+                    .unwrap_or(Err(HandlerError::Panicked))
+            }
+            None => Err(HandlerError::NoHandler(message_type.unwrap_or_default())),
+        };
+
+        match outcome {
+            Ok(()) => {
+                if let Some((idempotency, key)) = &idempotency {
+                    let expires_at = chrono::Duration::from_std(idempotency.retention)
+                        .ok()
+                        .and_then(|retention| Utc::now().checked_add_signed(retention))
+                        .unwrap_or(DateTime::<Utc>::MAX_UTC);
+                    if let Err(e) = idempotency.ledger.mark_processed(key, expires_at).await {
+                        tracing::warn!(queue = self.queue, message_id, "not recorded as processed: {}", e);
+                    }
+                }
+                if let Err(e) = state.queue.delete(&self.queue, &receipt_handle).await {
+                    tracing::warn!(queue = self.queue, message_id, "delete failed: {}", e);
+                }
+                true
+            }
+            Err(e) => {
+                tracing::warn!(queue = self.queue, message_id, "message left for redelivery: {}", e);
+                false
+            }
+        }
+    }
+}
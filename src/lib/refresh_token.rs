@@ -0,0 +1,197 @@
+//! Refresh Token Module
+//!
+//! This module stores refresh tokens handed out by `/authorization` so clients can
+//! obtain new access tokens without re-sending their secret. It provides:
+//! - Opaque, single-use refresh tokens grouped into families
+//! - Rotation on every use, with the previous token invalidated
+//! - Reuse detection: presenting an already rotated token revokes the whole family
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::fmt::{Debug, Display};
+use std::time::Duration;
+use tokio::sync::Mutex;
+use uuid::Uuid;
+
+/// What a valid refresh token grants: a new token pair for a client
+#[derive(Debug, Clone)]
+pub struct RefreshGrant {
+    /// Family the token belongs to; all rotations of one login share it
+    pub family_id: String,
+    /// Client the family was issued to
+    pub client_id: String,
+}
+
+/// Refresh token error types
+#[derive(Debug, PartialEq, Eq)]
+pub enum RefreshTokenError {
+    /// The token was never issued or its family was purged
+    Unknown,
+    /// The token family has expired
+    Expired,
+    /// The family was revoked, possibly after a reuse was detected
+    Revoked,
+    /// An already rotated token was presented; the family is now revoked
+    Reused,
+}
+
+impl Display for RefreshTokenError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RefreshTokenError::Unknown => write!(f, "unknown refresh token"),
+            RefreshTokenError::Expired => write!(f, "refresh token expired"),
+            RefreshTokenError::Revoked => write!(f, "refresh token family revoked"),
+            RefreshTokenError::Reused => write!(f, "refresh token reuse detected"),
+        }
+    }
+}
+
+impl std::error::Error for RefreshTokenError {}
+
+/// Server-side storage of refresh token families
+#[async_trait]
+pub trait RefreshTokenStore: Debug + Send + Sync {
+    /// Starts a new family for a client and returns its first refresh token
+    async fn issue(&self, client_id: &str, ttl: Duration) -> Result<String, RefreshTokenError>;
+
+    /// Exchanges a refresh token for its successor
+    ///
+    /// The presented token becomes invalid. Presenting it again revokes the family.
+    async fn rotate(&self, token: &str) -> Result<(String, RefreshGrant), RefreshTokenError>;
+
+    /// Revokes every token of a family
+    async fn revoke_family(&self, family_id: &str) -> Result<(), RefreshTokenError>;
+
+    /// Revokes the family the given token belongs to
+    async fn revoke(&self, token: &str) -> Result<(), RefreshTokenError>;
+}
+
+/// A chain of refresh tokens descending from one login
+#[derive(Debug)]
+struct Family {
+    client_id: String,
+    /// The only token of the family that may still be used
+    current: String,
+    expires_at: DateTime<Utc>,
+    revoked: bool,
+}
+
+#[derive(Debug, Default)]
+struct Families {
+    families: HashMap<String, Family>,
+    /// Every token ever issued, current or rotated, mapped to its family
+    tokens: HashMap<String, String>,
+}
+
+impl Families {
+    /// Drops expired families together with their tokens
+    fn purge_expired(&mut self, now: DateTime<Utc>) {
+        self.families.retain(|_, family| family.expires_at > now);
+        let families = &self.families;
+        self.tokens
+            .retain(|_, family_id| families.contains_key(family_id));
+    }
+}
+
+/// In-memory refresh token store
+///
+/// Families are lost on restart, which simply forces clients to log in again.
+#[derive(Debug, Default)]
+pub struct InMemoryRefreshTokenStore {
+    inner: Mutex<Families>,
+}
+
+impl InMemoryRefreshTokenStore {
+    /// Creates an empty store
+    pub fn new() -> Self {
+        Self::default()
+    }
+}
+
+/// Generates an opaque refresh token with 244 bits of randomness
+fn new_token() -> String {
+    format!("{}{}", Uuid::new_v4().simple(), Uuid::new_v4().simple())
+}
+
+#[async_trait]
+impl RefreshTokenStore for InMemoryRefreshTokenStore {
+    async fn issue(&self, client_id: &str, ttl: Duration) -> Result<String, RefreshTokenError> {
+        let now = Utc::now();
+        let mut inner = self.inner.lock().await;
+        inner.purge_expired(now);
+
+        let family_id = Uuid::new_v4().to_string();
+        let token = new_token();
+        inner.tokens.insert(token.clone(), family_id.clone());
+        inner.families.insert(
+            family_id,
+            Family {
+                client_id: client_id.to_owned(),
+                current: token.clone(),
+                expires_at: now + ttl,
+                revoked: false,
+            },
+        );
+        Ok(token)
+    }
+
+    async fn rotate(&self, token: &str) -> Result<(String, RefreshGrant), RefreshTokenError> {
+        let now = Utc::now();
+        let mut inner = self.inner.lock().await;
+        let family_id = inner
+            .tokens
+            .get(token)
+            .cloned()
+            .ok_or(RefreshTokenError::Unknown)?;
+        let family = inner
+            .families
+            .get_mut(&family_id)
+            .ok_or(RefreshTokenError::Unknown)?;
+
+        if family.revoked {
+            return Err(RefreshTokenError::Revoked);
+        }
+        if family.current != token {
+            // Someone holds an old token: either the client or an attacker was
+            // compromised, so nobody in this family gets to continue
+            family.revoked = true;
+            tracing::warn!(family_id, client_id = family.client_id, "refresh token reuse detected");
+            return Err(RefreshTokenError::Reused);
+        }
+        if family.expires_at <= now {
+            return Err(RefreshTokenError::Expired);
+        }
+
+        let next = new_token();
+        family.current = next.clone();
+        let grant = RefreshGrant {
+            family_id: family_id.clone(),
+            client_id: family.client_id.clone(),
+        };
+        inner.tokens.insert(next.clone(), family_id);
+        Ok((next, grant))
+    }
+
+    async fn revoke_family(&self, family_id: &str) -> Result<(), RefreshTokenError> {
+        match self.inner.lock().await.families.get_mut(family_id) {
+            Some(family) => {
+                family.revoked = true;
+                Ok(())
+            }
+            None => Err(RefreshTokenError::Unknown),
+        }
+    }
+
+    async fn revoke(&self, token: &str) -> Result<(), RefreshTokenError> {
+        let family_id = self
+            .inner
+            .lock()
+            .await
+            .tokens
+            .get(token)
+            .cloned()
+            .ok_or(RefreshTokenError::Unknown)?;
+        self.revoke_family(&family_id).await
+    }
+}
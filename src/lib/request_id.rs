@@ -0,0 +1,49 @@
+//! Request ID Module
+//!
+//! This module assigns every request an identifier, echoed in the `x-request-id`
+//! response header and included in problem responses so a client report can be
+//! matched with the server logs. A well-formed `x-request-id` sent by the client
+//! or a proxy is kept; otherwise a UUID is generated.
+
+use axum::extract::Request;
+use axum::http::{HeaderName, HeaderValue};
+use axum::middleware::Next;
+use axum::response::Response;
+use tokio::task_local;
+use uuid::Uuid;
+
+/// Header carrying the request identifier
+pub static X_REQUEST_ID: HeaderName = HeaderName::from_static("x-request-id");
+
+task_local! {
+    /// Task-local storage for the identifier of the request being handled.
+    pub static REQUEST_ID: String;
+}
+
+/// Returns the identifier of the request being handled, if any
+pub fn current_request_id() -> Option<String> {
+    REQUEST_ID.try_with(|id| id.clone()).ok()
+}
+
+/// Middleware assigning the request identifier
+///
+/// Incoming identifiers are accepted when they are 1 to 128 visible ASCII
+/// characters, so they cannot be abused to inject content into logs.
+pub async fn request_id(mut req: Request, next: Next) -> Response {
+    let id = req
+        .headers()
+        .get(&X_REQUEST_ID)
+        .and_then(|value| value.to_str().ok())
+        .filter(|value| {
+            !value.is_empty() && value.len() <= 128 && value.bytes().all(|b| b.is_ascii_graphic())
+        })
+        .map(str::to_owned)
+        .unwrap_or_else(|| Uuid::new_v4().to_string());
+
+    let header = HeaderValue::from_str(&id).expect("request id is visible ASCII");
+    req.headers_mut().insert(X_REQUEST_ID.clone(), header.clone());
+
+    let mut response = REQUEST_ID.scope(id, next.run(req)).await;
+    response.headers_mut().insert(X_REQUEST_ID.clone(), header);
+    response
+}
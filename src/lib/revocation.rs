@@ -0,0 +1,124 @@
+//! Token Revocation Module
+//!
+//! This module keeps track of access tokens that were revoked before their `exp`.
+//! Entries are keyed by the token's `jti` claim and evicted once the token would
+//! have expired anyway, so the list only ever holds still-valid tokens.
+//! The in-memory store can optionally snapshot itself to a JSON file so
+//! revocations survive a restart.
+
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use std::collections::HashMap;
+use std::fmt::{Debug, Display};
+use std::path::PathBuf;
+use tokio::sync::RwLock;
+
+/// Revocation store error types
+#[derive(Debug)]
+pub enum RevocationError {
+    /// The snapshot file could not be read or written
+    Io(std::io::Error),
+    /// The snapshot file is not valid JSON
+    Snapshot(serde_json::Error),
+}
+
+impl Display for RevocationError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            RevocationError::Io(e) => write!(f, "revocation snapshot io error: {}", e),
+            RevocationError::Snapshot(e) => write!(f, "revocation snapshot is invalid: {}", e),
+        }
+    }
+}
+
+impl std::error::Error for RevocationError {}
+
+impl From<std::io::Error> for RevocationError {
+    fn from(e: std::io::Error) -> Self {
+        RevocationError::Io(e)
+    }
+}
+
+impl From<serde_json::Error> for RevocationError {
+    fn from(e: serde_json::Error) -> Self {
+        RevocationError::Snapshot(e)
+    }
+}
+
+/// Storage of revoked token identifiers
+#[async_trait]
+pub trait RevocationStore: Debug + Send + Sync {
+    /// Revokes the token with the given `jti` until `expires_at`
+    async fn revoke(&self, jti: &str, expires_at: DateTime<Utc>) -> Result<(), RevocationError>;
+
+    /// Checks whether the token with the given `jti` was revoked
+    async fn is_revoked(&self, jti: &str) -> Result<bool, RevocationError>;
+}
+
+/// In-memory revocation list with TTL eviction
+///
+/// Expired entries are dropped whenever a token is revoked. When created with
+/// `InMemoryRevocationStore::persistent`, the list is written to a JSON file
+/// after every change and reloaded from it on startup.
+#[derive(Debug, Default)]
+pub struct InMemoryRevocationStore {
+    revoked: RwLock<HashMap<String, DateTime<Utc>>>,
+    snapshot: Option<PathBuf>,
+}
+
+impl InMemoryRevocationStore {
+    /// Creates an empty, non-persistent store
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates a store persisted to `path`, loading existing entries if the file exists
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Location of the JSON snapshot file
+    pub async fn persistent(path: impl Into<PathBuf>) -> Result<Self, RevocationError> {
+        let path = path.into();
+        let mut revoked: HashMap<String, DateTime<Utc>> = match tokio::fs::read(&path).await {
+            Ok(bytes) => serde_json::from_slice(&bytes)?,
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => HashMap::new(),
+            Err(e) => return Err(e.into()),
+        };
+        let now = Utc::now();
+        revoked.retain(|_, expires_at| *expires_at > now);
+
+        Ok(Self {
+            revoked: RwLock::new(revoked),
+            snapshot: Some(path),
+        })
+    }
+}
+
+#[async_trait]
+impl RevocationStore for InMemoryRevocationStore {
+    async fn revoke(&self, jti: &str, expires_at: DateTime<Utc>) -> Result<(), RevocationError> {
+        let now = Utc::now();
+        let mut revoked = self.revoked.write().await;
+        revoked.retain(|_, expires_at| *expires_at > now);
+        if expires_at > now {
+            revoked.insert(jti.to_owned(), expires_at);
+        }
+
+        if let Some(path) = &self.snapshot {
+            // Write next to the target and rename so a crash never leaves a torn file
+            let tmp = path.with_extension("tmp");
+            tokio::fs::write(&tmp, serde_json::to_vec(&*revoked)?).await?;
+            tokio::fs::rename(&tmp, path).await?;
+        }
+        Ok(())
+    }
+
+    async fn is_revoked(&self, jti: &str) -> Result<bool, RevocationError> {
+        Ok(self
+            .revoked
+            .read()
+            .await
+            .get(jti)
+            .is_some_and(|expires_at| *expires_at > Utc::now()))
+    }
+}
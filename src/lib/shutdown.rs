@@ -0,0 +1,82 @@
+//! Shutdown Module
+//!
+//! This module coordinates graceful shutdown. A `Shutdown` handle is shared by
+//! the HTTP server, the readiness check and the background tasks the server
+//! owns; triggering it, typically on SIGINT or SIGTERM, makes readiness fail,
+//! stops the listener from accepting connections while in-flight requests
+//! drain, and lets consumers finish the batch they are handling.
+
+use std::future::Future;
+use std::sync::Arc;
+use tokio::sync::watch;
+
+/// Cloneable shutdown flag that can be awaited
+///
+/// Clones share the flag; once triggered it stays triggered.
+#[derive(Debug, Clone)]
+pub struct Shutdown {
+    sender: Arc<watch::Sender<bool>>,
+}
+
+impl Default for Shutdown {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Shutdown {
+    /// Creates a flag that has not been triggered
+    pub fn new() -> Self {
+        Self {
+            sender: Arc::new(watch::Sender::new(false)),
+        }
+    }
+
+    /// Starts the shutdown, waking everything waiting in `triggered`
+    pub fn trigger(&self) {
+        self.sender.send_replace(true);
+    }
+
+    /// Whether the shutdown has started
+    pub fn is_triggered(&self) -> bool {
+        *self.sender.borrow()
+    }
+
+    /// Waits until the shutdown starts
+    ///
+    /// The returned future does not borrow the flag, so it can be handed to
+    /// `with_graceful_shutdown` or spawned.
+    pub fn triggered(&self) -> impl Future<Output = ()> + Send + 'static {
+        // Holding the sender keeps the wait from ending when every other handle is dropped
+        let sender = self.sender.clone();
+        async move {
+            let mut receiver = sender.subscribe();
+            let _ = receiver.wait_for(|triggered| *triggered).await;
+        }
+    }
+}
+
+/// Waits for SIGINT (Ctrl+C) or, on Unix, SIGTERM
+pub async fn signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install the Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install the SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+}
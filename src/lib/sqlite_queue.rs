@@ -0,0 +1,736 @@
+//! SQLite Queue Module
+//!
+//! This module provides `SqliteMessageQueue`, a `MessageQueue` whose queues and
+//! messages are kept in a SQLite database, so they survive restarts and crashes.
+//!
+//! Every change is committed before the call returns and a message only leaves
+//! its queue through `delete`, so delivery is at least once. Visibility is
+//! stored as an absolute timestamp: messages that were in flight when the
+//! process stopped become visible again once their timeout has passed, as if
+//! the process had kept running.
+//!
+//! Delayed and scheduled messages are stored with the time they become visible;
+//! the `(queue, visible_at)` index keeps them ordered by due time.
+//!
+//! Long polls are woken by changes made through the same store. Changes made
+//! by other processes sharing the database are noticed within `POLL_INTERVAL`.
+
+use crate::queue::{
+    DEDUPLICATION_WINDOW, MessageQueue, OutgoingMessage, QueueAttributes, QueueConfig, QueueError,
+    QueuedMessage, ReceiveOptions, ReceivedMessage, RedrivePolicy, SentMessage, check_config,
+    check_message, check_redrive_policy, check_visibility_timeout,
+};
+use async_trait::async_trait;
+use chrono::{DateTime, Utc};
+use sqlx::sqlite::{
+    SqliteConnectOptions, SqliteConnection, SqliteJournalMode, SqlitePoolOptions, SqliteRow,
+    SqliteSynchronous,
+};
+use sqlx::{Row, SqlitePool};
+use std::collections::HashMap;
+use std::str::FromStr;
+use std::time::Duration;
+use tokio::sync::Notify;
+use uuid::Uuid;
+
+/// Longest a long poll waits before looking at the database again
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// Columns read into a `MessageRow`
+const MESSAGE_COLUMNS: &str = "position, message_id, body, attributes, sent_at, receive_count, \
+     first_received_at, source_queue, group_id, deduplication_id, sequence_number";
+
+impl From<sqlx::Error> for QueueError {
+    fn from(e: sqlx::Error) -> Self {
+        QueueError::Backend(e.to_string())
+    }
+}
+
+impl From<serde_json::Error> for QueueError {
+    fn from(e: serde_json::Error) -> Self {
+        QueueError::Backend(format!("invalid message attributes: {}", e))
+    }
+}
+
+/// SQLite-backed message queue
+///
+/// Queues are kept in the `queues` table, messages in `queue_messages` in
+/// queue order, and the deduplication ids of FIFO queues in
+/// `queue_deduplication`. The tables are created on connect if missing.
+#[derive(Debug)]
+pub struct SqliteMessageQueue {
+    pool: SqlitePool,
+    /// Wakes long-polling receivers when a message may have become receivable
+    arrivals: Notify,
+}
+
+impl SqliteMessageQueue {
+    /// Opens (creating if needed) the database at `url` and prepares the schema
+    ///
+    /// The database is opened in WAL mode with full synchronous commits, so an
+    /// acknowledged send survives a crash.
+    ///
+    /// # Arguments
+    ///
+    /// * `url` - A SQLite connection string such as `sqlite://queues.db`
+    pub async fn connect(url: &str) -> Result<Self, QueueError> {
+        let options = SqliteConnectOptions::from_str(url)?
+            .create_if_missing(true)
+            .journal_mode(SqliteJournalMode::Wal)
+            .synchronous(SqliteSynchronous::Full);
+        // One connection serializes the read-then-update of receives, and keeps
+        // `sqlite::memory:` databases alive for the lifetime of the store
+        let pool = SqlitePoolOptions::new()
+            .max_connections(1)
+            .idle_timeout(None)
+            .max_lifetime(None)
+            .connect_with(options)
+            .await?;
+        Self::from_pool(pool).await
+    }
+
+    /// Builds a queue on top of an existing pool and prepares the schema
+    ///
+    /// The pool should hold a single connection, see `connect`.
+    pub async fn from_pool(pool: SqlitePool) -> Result<Self, QueueError> {
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS queues (
+                name                        TEXT PRIMARY KEY NOT NULL,
+                visibility_timeout_ms       INTEGER NOT NULL,
+                dead_letter_queue           TEXT,
+                max_receive_count           INTEGER,
+                fifo                        INTEGER NOT NULL DEFAULT 0,
+                content_based_deduplication INTEGER NOT NULL DEFAULT 0,
+                created_at                  INTEGER NOT NULL,
+                sequence                    INTEGER NOT NULL DEFAULT 0,
+                delay_ms                    INTEGER NOT NULL DEFAULT 0
+            )",
+        )
+        .execute(&pool)
+        .await?;
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS queue_messages (
+                position          INTEGER PRIMARY KEY NOT NULL,
+                queue             TEXT NOT NULL,
+                message_id        TEXT NOT NULL,
+                body              TEXT NOT NULL,
+                attributes        TEXT NOT NULL,
+                sent_at           INTEGER NOT NULL,
+                visible_at        INTEGER NOT NULL,
+                receive_count     INTEGER NOT NULL DEFAULT 0,
+                first_received_at INTEGER,
+                receipt_handle    TEXT,
+                source_queue      TEXT,
+                group_id          TEXT,
+                deduplication_id  TEXT,
+                sequence_number   TEXT
+            )",
+        )
+        .execute(&pool)
+        .await?;
+        sqlx::query(
+            "CREATE INDEX IF NOT EXISTS queue_messages_visible ON queue_messages (queue, visible_at)",
+        )
+        .execute(&pool)
+        .await?;
+        sqlx::query(
+            "CREATE TABLE IF NOT EXISTS queue_deduplication (
+                queue            TEXT NOT NULL,
+                deduplication_id TEXT NOT NULL,
+                message_id       TEXT NOT NULL,
+                sequence_number  TEXT,
+                sent_at          INTEGER NOT NULL,
+                PRIMARY KEY (queue, deduplication_id)
+            )",
+        )
+        .execute(&pool)
+        .await?;
+
+        // Messages whose receiver went away with the last process are simply visible again
+        let recovered: i64 = sqlx::query_scalar(
+            "SELECT COUNT(*) FROM queue_messages WHERE receipt_handle IS NOT NULL AND visible_at <= ?",
+        )
+        .bind(Utc::now().timestamp_millis())
+        .fetch_one(&pool)
+        .await?;
+        if recovered > 0 {
+            tracing::info!(recovered, "in-flight messages visible again after restart");
+        }
+
+        Ok(Self {
+            pool,
+            arrivals: Notify::new(),
+        })
+    }
+}
+
+/// A stored queue
+struct QueueRow {
+    config: QueueConfig,
+    created_at: DateTime<Utc>,
+}
+
+/// A stored message, without its visibility
+struct MessageRow {
+    position: i64,
+    message_id: String,
+    body: String,
+    attributes: HashMap<String, String>,
+    sent_at: DateTime<Utc>,
+    receive_count: u32,
+    first_received_at: Option<DateTime<Utc>>,
+    source_queue: Option<String>,
+    group_id: Option<String>,
+    deduplication_id: Option<String>,
+    sequence_number: Option<String>,
+}
+
+impl MessageRow {
+    /// Reads the `MESSAGE_COLUMNS` of a row
+    fn from_row(row: &SqliteRow) -> Result<Self, QueueError> {
+        Ok(Self {
+            position: row.get("position"),
+            message_id: row.get("message_id"),
+            body: row.get("body"),
+            attributes: serde_json::from_str(row.get("attributes"))?,
+            sent_at: timestamp(row.get("sent_at")),
+            receive_count: row.get("receive_count"),
+            first_received_at: row.get::<Option<i64>, _>("first_received_at").map(timestamp),
+            source_queue: row.get("source_queue"),
+            group_id: row.get("group_id"),
+            deduplication_id: row.get("deduplication_id"),
+            sequence_number: row.get("sequence_number"),
+        })
+    }
+
+    /// Checks whether every attribute in `filter` is present with the given value
+    fn matches(&self, filter: &HashMap<String, String>) -> bool {
+        filter
+            .iter()
+            .all(|(name, value)| self.attributes.get(name) == Some(value))
+    }
+}
+
+/// Converts a stored timestamp in milliseconds
+fn timestamp(millis: i64) -> DateTime<Utc> {
+    DateTime::from_timestamp_millis(millis).unwrap_or_default()
+}
+
+/// Converts a duration into stored milliseconds
+fn millis(duration: Duration) -> i64 {
+    i64::try_from(duration.as_millis()).unwrap_or(i64::MAX)
+}
+
+/// SQL condition that the message aliased `alias` carries `count` filter attributes
+///
+/// Each attribute binds its name and then its value; no attributes always match.
+fn filter_condition(alias: &str, count: usize) -> String {
+    if count == 0 {
+        return "1".to_owned();
+    }
+    let attribute = format!(
+        "EXISTS (SELECT 1 FROM json_each({}.attributes) WHERE key = ? AND value = ?)",
+        alias
+    );
+    vec![attribute; count].join(" AND ")
+}
+
+/// Loads a queue, failing if it does not exist
+async fn load_queue(conn: &mut SqliteConnection, name: &str) -> Result<QueueRow, QueueError> {
+    let row = sqlx::query(
+        "SELECT visibility_timeout_ms, dead_letter_queue, max_receive_count, fifo,
+                content_based_deduplication, created_at, delay_ms
+         FROM queues WHERE name = ?",
+    )
+    .bind(name)
+    .fetch_optional(&mut *conn)
+    .await?
+    .ok_or_else(|| QueueError::QueueDoesNotExist(name.to_owned()))?;
+
+    let redrive_policy = row
+        .get::<Option<String>, _>("dead_letter_queue")
+        .map(|dead_letter_queue| RedrivePolicy {
+            dead_letter_queue,
+            max_receive_count: row.get::<Option<u32>, _>("max_receive_count").unwrap_or_default(),
+        });
+    Ok(QueueRow {
+        config: QueueConfig {
+            visibility_timeout: Duration::from_millis(row.get("visibility_timeout_ms")),
+            redrive_policy,
+            fifo: row.get("fifo"),
+            content_based_deduplication: row.get("content_based_deduplication"),
+            delay: Duration::from_millis(row.get("delay_ms")),
+        },
+        created_at: timestamp(row.get("created_at")),
+    })
+}
+
+/// Checks whether a queue exists
+async fn queue_exists(conn: &mut SqliteConnection, name: &str) -> Result<bool, QueueError> {
+    let found: Option<i64> = sqlx::query_scalar("SELECT 1 FROM queues WHERE name = ?")
+        .bind(name)
+        .fetch_optional(&mut *conn)
+        .await?;
+    Ok(found.is_some())
+}
+
+/// Moves a message to the back of a queue, visible and unreceived
+async fn requeue(
+    conn: &mut SqliteConnection,
+    position: i64,
+    queue: &str,
+    source_queue: Option<&str>,
+    now: i64,
+) -> Result<(), QueueError> {
+    sqlx::query(
+        "UPDATE queue_messages
+         SET position = (SELECT MAX(position) FROM queue_messages) + 1,
+             queue = ?, visible_at = ?, receipt_handle = NULL, source_queue = ?
+         WHERE position = ?",
+    )
+    .bind(queue)
+    .bind(now)
+    .bind(source_queue)
+    .bind(position)
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
+
+/// Moves the visible messages that reached the redrive policy's receive count
+///
+/// Returns how many messages were moved.
+async fn move_dead_letters(
+    conn: &mut SqliteConnection,
+    queue: &str,
+    policy: &RedrivePolicy,
+    now: i64,
+) -> Result<usize, QueueError> {
+    let rows = sqlx::query(&format!(
+        "SELECT {} FROM queue_messages
+         WHERE queue = ? AND visible_at <= ? AND receive_count >= ?
+         ORDER BY position",
+        MESSAGE_COLUMNS
+    ))
+    .bind(queue)
+    .bind(now)
+    .bind(policy.max_receive_count)
+    .fetch_all(&mut *conn)
+    .await?;
+    if rows.is_empty() {
+        return Ok(0);
+    }
+    if !queue_exists(conn, &policy.dead_letter_queue).await? {
+        return Err(QueueError::QueueDoesNotExist(policy.dead_letter_queue.clone()));
+    }
+    for row in &rows {
+        let message = MessageRow::from_row(row)?;
+        tracing::info!(
+            queue,
+            dead_letter_queue = policy.dead_letter_queue,
+            message_id = message.message_id,
+            receive_count = message.receive_count,
+            "message moved to dead-letter queue"
+        );
+        requeue(conn, message.position, &policy.dead_letter_queue, Some(queue), now).await?;
+    }
+    Ok(rows.len())
+}
+
+#[async_trait]
+impl MessageQueue for SqliteMessageQueue {
+    async fn create_queue(&self, name: &str, config: QueueConfig) -> Result<(), QueueError> {
+        check_config(name, &config)?;
+        let mut tx = self.pool.begin().await?;
+        if let Some(policy) = &config.redrive_policy {
+            let exists = queue_exists(&mut tx, &policy.dead_letter_queue).await?;
+            check_redrive_policy(name, policy, exists)?;
+        }
+        match load_queue(&mut tx, name).await {
+            Ok(existing) if existing.config == config => Ok(()),
+            Ok(_) => Err(QueueError::QueueNameExists(name.to_owned())),
+            Err(QueueError::QueueDoesNotExist(_)) => {
+                let policy = config.redrive_policy.as_ref();
+                sqlx::query(
+                    "INSERT INTO queues
+                        (name, visibility_timeout_ms, dead_letter_queue, max_receive_count, fifo,
+                         content_based_deduplication, created_at, delay_ms)
+                     VALUES (?, ?, ?, ?, ?, ?, ?, ?)",
+                )
+                .bind(name)
+                .bind(millis(config.visibility_timeout))
+                .bind(policy.map(|policy| policy.dead_letter_queue.as_str()))
+                .bind(policy.map(|policy| policy.max_receive_count))
+                .bind(config.fifo)
+                .bind(config.content_based_deduplication)
+                .bind(Utc::now().timestamp_millis())
+                .bind(millis(config.delay))
+                .execute(&mut *tx)
+                .await?;
+                tx.commit().await?;
+                Ok(())
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    async fn queue_attributes(&self, queue: &str) -> Result<QueueAttributes, QueueError> {
+        let mut conn = self.pool.acquire().await?;
+        let QueueRow { config, created_at } = load_queue(&mut conn, queue).await?;
+        // Hidden messages without a receipt handle have not been delivered yet
+        let now = Utc::now().timestamp_millis();
+        let row = sqlx::query(
+            "SELECT COUNT(*) AS total,
+                    COALESCE(SUM(visible_at <= ?), 0) AS visible,
+                    COALESCE(SUM(visible_at > ? AND receipt_handle IS NULL), 0) AS delayed
+             FROM queue_messages WHERE queue = ?",
+        )
+        .bind(now)
+        .bind(now)
+        .bind(queue)
+        .fetch_one(&mut *conn)
+        .await?;
+        let total: i64 = row.get("total");
+        let visible: i64 = row.get("visible");
+        let delayed: i64 = row.get("delayed");
+        Ok(QueueAttributes {
+            config,
+            created_at,
+            visible_messages: usize::try_from(visible).unwrap_or_default(),
+            in_flight_messages: usize::try_from(total - visible - delayed).unwrap_or_default(),
+            delayed_messages: usize::try_from(delayed).unwrap_or_default(),
+        })
+    }
+
+    async fn purge(&self, queue: &str) -> Result<(), QueueError> {
+        let mut tx = self.pool.begin().await?;
+        load_queue(&mut tx, queue).await?;
+        sqlx::query("DELETE FROM queue_messages WHERE queue = ?")
+            .bind(queue)
+            .execute(&mut *tx)
+            .await?;
+        tx.commit().await?;
+        Ok(())
+    }
+
+    async fn send(&self, queue: &str, message: OutgoingMessage) -> Result<SentMessage, QueueError> {
+        let mut tx = self.pool.begin().await?;
+        let QueueRow { config, .. } = load_queue(&mut tx, queue).await?;
+        let deduplication_id = check_message(&config, &message)?;
+        let sent_at = Utc::now();
+        let now = sent_at.timestamp_millis();
+
+        // A repeated send within the window is acknowledged like the original but not enqueued
+        if let Some(id) = &deduplication_id {
+            sqlx::query("DELETE FROM queue_deduplication WHERE queue = ? AND sent_at <= ?")
+                .bind(queue)
+                .bind(now - millis(DEDUPLICATION_WINDOW))
+                .execute(&mut *tx)
+                .await?;
+            let original = sqlx::query(
+                "SELECT message_id, sequence_number FROM queue_deduplication
+                 WHERE queue = ? AND deduplication_id = ?",
+            )
+            .bind(queue)
+            .bind(id)
+            .fetch_optional(&mut *tx)
+            .await?;
+            if let Some(row) = original {
+                return Ok(SentMessage {
+                    message_id: row.get("message_id"),
+                    sequence_number: row.get("sequence_number"),
+                });
+            }
+        }
+
+        let sequence_number = if config.fifo {
+            let sequence: i64 = sqlx::query_scalar(
+                "UPDATE queues SET sequence = sequence + 1 WHERE name = ? RETURNING sequence",
+            )
+            .bind(queue)
+            .fetch_one(&mut *tx)
+            .await?;
+            Some(format!("{:020}", sequence))
+        } else {
+            None
+        };
+        let sent = SentMessage {
+            message_id: Uuid::new_v4().to_string(),
+            sequence_number,
+        };
+        if let Some(id) = &deduplication_id {
+            sqlx::query(
+                "INSERT INTO queue_deduplication
+                    (queue, deduplication_id, message_id, sequence_number, sent_at)
+                 VALUES (?, ?, ?, ?, ?)",
+            )
+            .bind(queue)
+            .bind(id)
+            .bind(&sent.message_id)
+            .bind(&sent.sequence_number)
+            .bind(now)
+            .execute(&mut *tx)
+            .await?;
+        }
+        sqlx::query(
+            "INSERT INTO queue_messages
+                (queue, message_id, body, attributes, sent_at, visible_at, group_id,
+                 deduplication_id, sequence_number)
+             VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+        )
+        .bind(queue)
+        .bind(&sent.message_id)
+        .bind(&message.body)
+        .bind(serde_json::to_string(&message.attributes)?)
+        .bind(now)
+        .bind(message.first_visible_at(&config, sent_at).timestamp_millis())
+        .bind(&message.group_id)
+        .bind(&deduplication_id)
+        .bind(&sent.sequence_number)
+        .execute(&mut *tx)
+        .await?;
+        tx.commit().await?;
+
+        self.arrivals.notify_waiters();
+        Ok(sent)
+    }
+
+    async fn receive_with(
+        &self,
+        queue: &str,
+        options: &ReceiveOptions,
+    ) -> Result<Vec<ReceivedMessage>, QueueError> {
+        options.check()?;
+        let deadline = tokio::time::Instant::now() + options.wait_time;
+        let filter: Vec<(&String, &String)> = options.filter.iter().collect();
+        loop {
+            // Register before looking so a send in between is not missed
+            let notified = self.arrivals.notified();
+            tokio::pin!(notified);
+            notified.as_mut().enable();
+
+            let now = Utc::now();
+            let now_millis = now.timestamp_millis();
+            let mut tx = self.pool.begin().await?;
+            let QueueRow { config, .. } = load_queue(&mut tx, queue).await?;
+
+            // Poison messages go to the dead-letter queue instead of being delivered again
+            let dead_letters = match &config.redrive_policy {
+                Some(policy) => move_dead_letters(&mut tx, queue, policy, now_millis).await?,
+                None => 0,
+            };
+
+            // A message is skipped while an earlier message of its FIFO group is in
+            // flight or left out by the filter
+            let sql = format!(
+                "SELECT {} FROM queue_messages AS m
+                 WHERE queue = ? AND visible_at <= ? AND {}
+                   AND NOT EXISTS (
+                       SELECT 1 FROM queue_messages AS e
+                       WHERE e.queue = m.queue AND e.group_id = m.group_id
+                         AND e.position < m.position AND (e.visible_at > ? OR NOT ({}))
+                   )
+                 ORDER BY position LIMIT ?",
+                MESSAGE_COLUMNS,
+                filter_condition("m", filter.len()),
+                filter_condition("e", filter.len())
+            );
+            let mut query = sqlx::query(&sql).bind(queue).bind(now_millis);
+            for (name, value) in &filter {
+                query = query.bind(*name).bind(*value);
+            }
+            query = query.bind(now_millis);
+            for (name, value) in &filter {
+                query = query.bind(*name).bind(*value);
+            }
+            let rows = query
+                .bind(i64::try_from(options.max_messages).unwrap_or(i64::MAX))
+                .fetch_all(&mut *tx)
+                .await?;
+
+            let hidden_until =
+                now_millis + millis(options.visibility_timeout.unwrap_or(config.visibility_timeout));
+            let mut received = Vec::with_capacity(rows.len());
+            for row in &rows {
+                let message = MessageRow::from_row(row)?;
+                let receipt_handle = Uuid::new_v4().simple().to_string();
+                sqlx::query(
+                    "UPDATE queue_messages
+                     SET visible_at = ?, receive_count = receive_count + 1,
+                         first_received_at = COALESCE(first_received_at, ?), receipt_handle = ?
+                     WHERE position = ?",
+                )
+                .bind(hidden_until)
+                .bind(now_millis)
+                .bind(&receipt_handle)
+                .bind(message.position)
+                .execute(&mut *tx)
+                .await?;
+                received.push(ReceivedMessage {
+                    message_id: message.message_id,
+                    receipt_handle,
+                    body: message.body,
+                    attributes: message.attributes,
+                    receive_count: message.receive_count + 1,
+                    sent_at: message.sent_at,
+                    first_received_at: message.first_received_at.unwrap_or(now),
+                    group_id: message.group_id,
+                    deduplication_id: message.deduplication_id,
+                    sequence_number: message.sequence_number,
+                });
+            }
+
+            let next_visible: Option<i64> = sqlx::query_scalar(
+                "SELECT MIN(visible_at) FROM queue_messages WHERE queue = ? AND visible_at > ?",
+            )
+            .bind(queue)
+            .bind(now_millis)
+            .fetch_one(&mut *tx)
+            .await?;
+            tx.commit().await?;
+            if dead_letters > 0 {
+                self.arrivals.notify_waiters();
+            }
+
+            let remaining = deadline.saturating_duration_since(tokio::time::Instant::now());
+            if !received.is_empty() || remaining.is_zero() {
+                return Ok(received);
+            }
+
+            // Sleep until a send wakes us, a hidden message reappears or the wait is over
+            let nap = next_visible
+                .map(|at| Duration::from_millis(u64::try_from(at - now_millis).unwrap_or_default()))
+                .map_or(remaining, |next| next.min(remaining))
+                .min(POLL_INTERVAL);
+            let _ = tokio::time::timeout(nap, notified).await;
+        }
+    }
+
+    async fn delete(&self, queue: &str, receipt_handle: &str) -> Result<(), QueueError> {
+        let mut tx = self.pool.begin().await?;
+        let QueueRow { config, .. } = load_queue(&mut tx, queue).await?;
+        let result = sqlx::query("DELETE FROM queue_messages WHERE queue = ? AND receipt_handle = ?")
+            .bind(queue)
+            .bind(receipt_handle)
+            .execute(&mut *tx)
+            .await?;
+        if result.rows_affected() == 0 {
+            return Err(QueueError::ReceiptHandleIsInvalid);
+        }
+        tx.commit().await?;
+        // The next message of a FIFO group becomes receivable
+        if config.fifo {
+            self.arrivals.notify_waiters();
+        }
+        Ok(())
+    }
+
+    async fn change_visibility(
+        &self,
+        queue: &str,
+        receipt_handle: &str,
+        timeout: Duration,
+    ) -> Result<(), QueueError> {
+        check_visibility_timeout(timeout)?;
+        let mut tx = self.pool.begin().await?;
+        load_queue(&mut tx, queue).await?;
+        let result = sqlx::query(
+            "UPDATE queue_messages SET visible_at = ? WHERE queue = ? AND receipt_handle = ?",
+        )
+        .bind(Utc::now().timestamp_millis() + millis(timeout))
+        .bind(queue)
+        .bind(receipt_handle)
+        .execute(&mut *tx)
+        .await?;
+        if result.rows_affected() == 0 {
+            return Err(QueueError::ReceiptHandleIsInvalid);
+        }
+        tx.commit().await?;
+        if timeout.is_zero() {
+            self.arrivals.notify_waiters();
+        }
+        Ok(())
+    }
+
+    async fn peek(&self, queue: &str, limit: usize) -> Result<Vec<QueuedMessage>, QueueError> {
+        let mut conn = self.pool.acquire().await?;
+        load_queue(&mut conn, queue).await?;
+        let rows = sqlx::query(&format!(
+            "SELECT {} FROM queue_messages
+             WHERE queue = ? AND (visible_at <= ? OR receipt_handle IS NOT NULL)
+             ORDER BY position LIMIT ?",
+            MESSAGE_COLUMNS
+        ))
+        .bind(queue)
+        .bind(Utc::now().timestamp_millis())
+        .bind(i64::try_from(limit).unwrap_or(i64::MAX))
+        .fetch_all(&mut *conn)
+        .await?;
+        rows.iter()
+            .map(|row| {
+                let message = MessageRow::from_row(row)?;
+                Ok(QueuedMessage {
+                    message_id: message.message_id,
+                    body: message.body,
+                    attributes: message.attributes,
+                    receive_count: message.receive_count,
+                    sent_at: message.sent_at,
+                    first_received_at: message.first_received_at,
+                    source_queue: message.source_queue,
+                    group_id: message.group_id,
+                    sequence_number: message.sequence_number,
+                })
+            })
+            .collect()
+    }
+
+    async fn redrive(
+        &self,
+        dead_letter_queue: &str,
+        filter: &HashMap<String, String>,
+    ) -> Result<usize, QueueError> {
+        let now = Utc::now().timestamp_millis();
+        let mut tx = self.pool.begin().await?;
+        load_queue(&mut tx, dead_letter_queue).await?;
+
+        // In-flight messages are being handled by someone and stay put
+        let rows = sqlx::query(&format!(
+            "SELECT {} FROM queue_messages
+             WHERE queue = ? AND visible_at <= ? AND source_queue IS NOT NULL
+             ORDER BY position",
+            MESSAGE_COLUMNS
+        ))
+        .bind(dead_letter_queue)
+        .bind(now)
+        .fetch_all(&mut *tx)
+        .await?;
+
+        let mut moved = 0;
+        for row in &rows {
+            let message = MessageRow::from_row(row)?;
+            let source = message.source_queue.clone().unwrap_or_default();
+            if !message.matches(filter) || !queue_exists(&mut tx, &source).await? {
+                continue;
+            }
+            sqlx::query(
+                "UPDATE queue_messages SET receive_count = 0, first_received_at = NULL
+                 WHERE position = ?",
+            )
+            .bind(message.position)
+            .execute(&mut *tx)
+            .await?;
+            requeue(&mut tx, message.position, &source, None, now).await?;
+            moved += 1;
+        }
+        tx.commit().await?;
+        if moved > 0 {
+            self.arrivals.notify_waiters();
+        }
+        Ok(moved)
+    }
+
+    async fn ping(&self) -> Result<(), QueueError> {
+        sqlx::query("SELECT 1").execute(&self.pool).await?;
+        Ok(())
+    }
+}
@@ -0,0 +1,822 @@
+//! SQS API Module
+//!
+//! This module exposes the `MessageQueue` in the application state through the
+//! Amazon SQS JSON 1.0 protocol, so the official AWS SDKs and CLI can be pointed
+//! at this server, e.g. with `AWS_ENDPOINT_URL_SQS=http://localhost:3000`.
+//!
+//! Requests are `POST /` with an `X-Amz-Target: AmazonSQS.<Action>` header and a
+//! JSON body. Supported actions are CreateQueue, GetQueueUrl, SendMessage,
+//! SendMessageBatch, ReceiveMessage, DeleteMessage, ChangeMessageVisibility,
+//! GetQueueAttributes and PurgeQueue.
+//!
+//! The emulator is meant for development and tests: request signatures are not
+//! verified, every queue belongs to account `000000000000`, and message
+//! attributes are stored as strings, so they come back with the `String` data type.
+//! As in SQS, a message sent without `DelaySeconds` takes the queue's delay, while
+//! `DelaySeconds` 0 delivers it right away.
+
+use crate::app_state::MyAppState;
+use crate::queue::{
+    MAX_RECEIVE_MESSAGES, OutgoingMessage, QueueConfig, QueueError, ReceiveOptions, ReceivedMessage,
+    RedrivePolicy,
+};
+use crate::request_id::current_request_id;
+use axum::{
+    Extension,
+    body::Bytes,
+    http::{HeaderMap, HeaderName, StatusCode, header},
+    response::{IntoResponse, Response},
+};
+use md5::{Digest, Md5};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Value, json};
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Display;
+use std::time::Duration;
+
+/// Media type of AWS JSON 1.0 requests and responses
+pub const AMZ_JSON: &str = "application/x-amz-json-1.0";
+
+/// Account id placed in queue URLs and ARNs
+pub const ACCOUNT_ID: &str = "000000000000";
+
+/// Region placed in queue ARNs
+pub const REGION: &str = "us-east-1";
+
+/// Largest message body, and largest sum of bodies in a batch
+const MAX_MESSAGE_SIZE: usize = 256 * 1024;
+
+static X_AMZ_TARGET: HeaderName = HeaderName::from_static("x-amz-target");
+static X_AMZN_REQUEST_ID: HeaderName = HeaderName::from_static("x-amzn-requestid");
+static X_AMZN_QUERY_ERROR: HeaderName = HeaderName::from_static("x-amzn-query-error");
+
+/// SQS error types, rendered the way the AWS SDKs expect
+#[derive(Debug)]
+pub enum SqsError {
+    /// The `X-Amz-Target` header is missing or names an unsupported action
+    UnknownOperation(String),
+    /// The body is not valid JSON for the action
+    Serialization(String),
+    /// A parameter is missing or out of range
+    InvalidParameterValue(String),
+    /// A queue attribute name is not supported
+    InvalidAttributeName(String),
+    /// The queue does not exist
+    QueueDoesNotExist,
+    /// The queue exists with different attributes
+    QueueNameExists,
+    /// The receipt handle is unknown or stale
+    ReceiptHandleIsInvalid,
+    /// A batch request holds no entries
+    EmptyBatchRequest,
+    /// A batch request holds more than ten entries
+    TooManyEntriesInBatchRequest,
+    /// Two entries of a batch request share an id
+    BatchEntryIdsNotDistinct,
+    /// The bodies of a batch request exceed the message size limit together
+    BatchRequestTooLong,
+    /// The queue backend failed
+    Internal(String),
+}
+
+impl SqsError {
+    /// Returns the status, the error shape name and the legacy query protocol code
+    fn parts(&self) -> (StatusCode, &'static str, &'static str) {
+        match self {
+            SqsError::UnknownOperation(_) => {
+                (StatusCode::BAD_REQUEST, "UnknownOperationException", "InvalidAction")
+            }
+            SqsError::Serialization(_) => {
+                (StatusCode::BAD_REQUEST, "SerializationException", "MalformedInput")
+            }
+            SqsError::InvalidParameterValue(_) => {
+                (StatusCode::BAD_REQUEST, "InvalidParameterValue", "InvalidParameterValue")
+            }
+            SqsError::InvalidAttributeName(_) => {
+                (StatusCode::BAD_REQUEST, "InvalidAttributeName", "InvalidAttributeName")
+            }
+            SqsError::QueueDoesNotExist => (
+                StatusCode::BAD_REQUEST,
+                "QueueDoesNotExist",
+                "AWS.SimpleQueueService.NonExistentQueue",
+            ),
+            SqsError::QueueNameExists => {
+                (StatusCode::BAD_REQUEST, "QueueNameExists", "QueueAlreadyExists")
+            }
+            SqsError::ReceiptHandleIsInvalid => {
+                (StatusCode::BAD_REQUEST, "ReceiptHandleIsInvalid", "ReceiptHandleIsInvalid")
+            }
+            SqsError::EmptyBatchRequest => (
+                StatusCode::BAD_REQUEST,
+                "EmptyBatchRequest",
+                "AWS.SimpleQueueService.EmptyBatchRequest",
+            ),
+            SqsError::TooManyEntriesInBatchRequest => (
+                StatusCode::BAD_REQUEST,
+                "TooManyEntriesInBatchRequest",
+                "AWS.SimpleQueueService.TooManyEntriesInBatchRequest",
+            ),
+            SqsError::BatchEntryIdsNotDistinct => (
+                StatusCode::BAD_REQUEST,
+                "BatchEntryIdsNotDistinct",
+                "AWS.SimpleQueueService.BatchEntryIdsNotDistinct",
+            ),
+            SqsError::BatchRequestTooLong => (
+                StatusCode::BAD_REQUEST,
+                "BatchRequestTooLong",
+                "AWS.SimpleQueueService.BatchRequestTooLong",
+            ),
+            SqsError::Internal(_) => {
+                (StatusCode::INTERNAL_SERVER_ERROR, "InternalFailure", "InternalFailure")
+            }
+        }
+    }
+}
+
+impl Display for SqsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SqsError::UnknownOperation(target) => write!(f, "unknown operation {:?}", target),
+            SqsError::Serialization(detail) => write!(f, "malformed request: {}", detail),
+            SqsError::InvalidParameterValue(detail) => write!(f, "{}", detail),
+            SqsError::InvalidAttributeName(name) => write!(f, "unknown attribute {}", name),
+            SqsError::QueueDoesNotExist => write!(f, "the specified queue does not exist"),
+            SqsError::QueueNameExists => {
+                write!(f, "a queue with this name already exists with different attributes")
+            }
+            SqsError::ReceiptHandleIsInvalid => write!(f, "the receipt handle is not valid"),
+            SqsError::EmptyBatchRequest => write!(f, "the batch request holds no entries"),
+            SqsError::TooManyEntriesInBatchRequest => {
+                write!(f, "the batch request holds more than {} entries", MAX_RECEIVE_MESSAGES)
+            }
+            SqsError::BatchEntryIdsNotDistinct => write!(f, "batch entry ids are not distinct"),
+            SqsError::BatchRequestTooLong => {
+                write!(f, "the batch request is longer than {} bytes", MAX_MESSAGE_SIZE)
+            }
+            SqsError::Internal(_) => write!(f, "internal failure"),
+        }
+    }
+}
+
+impl std::error::Error for SqsError {}
+
+impl From<QueueError> for SqsError {
+    fn from(e: QueueError) -> Self {
+        match e {
+            QueueError::QueueDoesNotExist(_) => SqsError::QueueDoesNotExist,
+            QueueError::QueueNameExists(_) => SqsError::QueueNameExists,
+            QueueError::ReceiptHandleIsInvalid => SqsError::ReceiptHandleIsInvalid,
+            QueueError::InvalidParameter(detail) => SqsError::InvalidParameterValue(detail),
+            QueueError::Backend(detail) => SqsError::Internal(detail),
+        }
+    }
+}
+
+/// Implementation of `IntoResponse` for `SqsError`
+///
+/// Renders the JSON 1.0 error body with the `__type` the SDKs match on, plus
+/// the `x-amzn-query-error` header used by SDKs still speaking the query protocol.
+impl IntoResponse for SqsError {
+    fn into_response(self) -> Response {
+        let (status, shape, query_code) = self.parts();
+        if let SqsError::Internal(detail) = &self {
+            tracing::error!(request_id = current_request_id(), "sqs backend error: {}", detail);
+        }
+        let fault = if status.is_server_error() { "Receiver" } else { "Sender" };
+        let body = json!({
+            "__type": format!("com.amazonaws.sqs#{}", shape),
+            "message": self.to_string(),
+        });
+        (
+            status,
+            [
+                (header::CONTENT_TYPE, AMZ_JSON.to_owned()),
+                (X_AMZN_QUERY_ERROR.clone(), format!("{};{}", query_code, fault)),
+            ],
+            body.to_string(),
+        )
+            .into_response()
+    }
+}
+
+/// Handler for `POST /` carrying an `X-Amz-Target: AmazonSQS.*` header
+///
+/// # Arguments
+///
+/// * `Extension(state)` - The application state holding the message queue
+/// * `headers` - The request headers, naming the action and the host for queue URLs
+/// * `body` - The JSON request body
+///
+/// # Returns
+///
+/// The action's JSON response, or an `SqsError` response
+pub async fn handle(
+    Extension(state): Extension<MyAppState>,
+    headers: HeaderMap,
+    body: Bytes,
+) -> Response {
+    let target = headers
+        .get(&X_AMZ_TARGET)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or_default();
+    let host = headers
+        .get(header::HOST)
+        .and_then(|value| value.to_str().ok())
+        .unwrap_or("localhost");
+    let base_url = format!("http://{}/{}", host, ACCOUNT_ID);
+
+    let mut response = match dispatch(&state, &base_url, target, &body).await {
+        Ok(output) => (
+            StatusCode::OK,
+            [(header::CONTENT_TYPE, AMZ_JSON)],
+            output.to_string(),
+        )
+            .into_response(),
+        Err(e) => e.into_response(),
+    };
+    if let Some(id) = current_request_id().and_then(|id| id.parse().ok()) {
+        response.headers_mut().insert(X_AMZN_REQUEST_ID.clone(), id);
+    }
+    response
+}
+
+/// Runs the action named by the target header
+async fn dispatch(
+    state: &MyAppState,
+    base_url: &str,
+    target: &str,
+    body: &[u8],
+) -> Result<Value, SqsError> {
+    let action = target
+        .strip_prefix("AmazonSQS.")
+        .ok_or_else(|| SqsError::UnknownOperation(target.to_owned()))?;
+    match action {
+        "CreateQueue" => create_queue(state, base_url, parse(body)?).await,
+        "GetQueueUrl" => get_queue_url(state, base_url, parse(body)?).await,
+        "SendMessage" => send_message(state, parse(body)?).await,
+        "SendMessageBatch" => send_message_batch(state, parse(body)?).await,
+        "ReceiveMessage" => receive_message(state, parse(body)?).await,
+        "DeleteMessage" => delete_message(state, parse(body)?).await,
+        "ChangeMessageVisibility" => change_message_visibility(state, parse(body)?).await,
+        "GetQueueAttributes" => get_queue_attributes(state, parse(body)?).await,
+        "PurgeQueue" => purge_queue(state, parse(body)?).await,
+        _ => Err(SqsError::UnknownOperation(target.to_owned())),
+    }
+}
+
+/// Deserializes a request body, treating an empty body as `{}`
+fn parse<T: DeserializeOwned>(body: &[u8]) -> Result<T, SqsError> {
+    let body = if body.is_empty() { b"{}".as_slice() } else { body };
+    serde_json::from_slice(body).map_err(|e| SqsError::Serialization(e.to_string()))
+}
+
+/// Extracts the queue name from a queue URL: its last path segment
+fn queue_name(queue_url: &str) -> Result<&str, SqsError> {
+    match queue_url.trim_end_matches('/').rsplit('/').next() {
+        Some(name) if !name.is_empty() => Ok(name),
+        _ => Err(SqsError::QueueDoesNotExist),
+    }
+}
+
+/// ARN of a queue, as used in redrive policies
+fn queue_arn(queue: &str) -> String {
+    format!("arn:aws:sqs:{}:{}:{}", REGION, ACCOUNT_ID, queue)
+}
+
+/// Parses the `RedrivePolicy` queue attribute, a JSON document in a string
+///
+/// `maxReceiveCount` may be given as a number or a string, as the SDKs accept both.
+fn parse_redrive_policy(value: &str) -> Result<RedrivePolicy, SqsError> {
+    let invalid = || SqsError::InvalidParameterValue(format!("invalid RedrivePolicy {:?}", value));
+    let policy: Value = serde_json::from_str(value).map_err(|_| invalid())?;
+    let dead_letter_queue = policy["deadLetterTargetArn"]
+        .as_str()
+        .and_then(|arn| arn.rsplit(':').next())
+        .ok_or_else(invalid)?;
+    let max_receive_count = match &policy["maxReceiveCount"] {
+        Value::Number(count) => count.as_u64(),
+        Value::String(count) => count.parse().ok(),
+        _ => None,
+    }
+    .and_then(|count| u32::try_from(count).ok())
+    .ok_or_else(invalid)?;
+    Ok(RedrivePolicy::new(dead_letter_queue, max_receive_count))
+}
+
+/// Converts a whole number of seconds from a request into a `Duration`
+fn seconds(name: &str, value: i64) -> Result<Duration, SqsError> {
+    u64::try_from(value)
+        .map(Duration::from_secs)
+        .map_err(|_| SqsError::InvalidParameterValue(format!("{} must not be negative", name)))
+}
+
+/// Parses a `true` or `false` queue attribute
+fn boolean(name: &str, value: &str) -> Result<bool, SqsError> {
+    value
+        .parse()
+        .map_err(|_| SqsError::InvalidParameterValue(format!("invalid {} {:?}", name, value)))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct CreateQueueInput {
+    queue_name: String,
+    #[serde(default)]
+    attributes: HashMap<String, String>,
+}
+
+async fn create_queue(
+    state: &MyAppState,
+    base_url: &str,
+    input: CreateQueueInput,
+) -> Result<Value, SqsError> {
+    let mut config = QueueConfig::default();
+    for (name, value) in &input.attributes {
+        match name.as_str() {
+            "VisibilityTimeout" => {
+                let value = value.parse().map_err(|_| {
+                    SqsError::InvalidParameterValue(format!("invalid VisibilityTimeout {:?}", value))
+                })?;
+                config.visibility_timeout = seconds("VisibilityTimeout", value)?;
+            }
+            "DelaySeconds" => {
+                let value = value.parse().map_err(|_| {
+                    SqsError::InvalidParameterValue(format!("invalid DelaySeconds {:?}", value))
+                })?;
+                config.delay = seconds("DelaySeconds", value)?;
+            }
+            "RedrivePolicy" => config.redrive_policy = Some(parse_redrive_policy(value)?),
+            "FifoQueue" => config.fifo = boolean(name, value)?,
+            "ContentBasedDeduplication" => config.content_based_deduplication = boolean(name, value)?,
+            _ => return Err(SqsError::InvalidAttributeName(name.clone())),
+        }
+    }
+    state.queue.create_queue(&input.queue_name, config).await?;
+    Ok(json!({ "QueueUrl": format!("{}/{}", base_url, input.queue_name) }))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct GetQueueUrlInput {
+    queue_name: String,
+}
+
+async fn get_queue_url(
+    state: &MyAppState,
+    base_url: &str,
+    input: GetQueueUrlInput,
+) -> Result<Value, SqsError> {
+    state.queue.queue_attributes(&input.queue_name).await?;
+    Ok(json!({ "QueueUrl": format!("{}/{}", base_url, input.queue_name) }))
+}
+
+/// A message attribute as sent and received over the wire
+#[derive(Debug, Deserialize, Serialize)]
+#[serde(rename_all = "PascalCase")]
+struct MessageAttributeValue {
+    data_type: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    string_value: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    binary_value: Option<String>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct SendMessageInput {
+    queue_url: String,
+    #[serde(flatten)]
+    entry: SendMessageEntry,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct SendMessageEntry {
+    message_body: String,
+    #[serde(default)]
+    delay_seconds: Option<i64>,
+    #[serde(default)]
+    message_attributes: BTreeMap<String, MessageAttributeValue>,
+    #[serde(default)]
+    message_group_id: Option<String>,
+    #[serde(default)]
+    message_deduplication_id: Option<String>,
+}
+
+#[derive(Serialize)]
+struct SendMessageOutput {
+    #[serde(rename = "MessageId")]
+    message_id: String,
+    #[serde(rename = "MD5OfMessageBody")]
+    md5_of_message_body: String,
+    #[serde(rename = "MD5OfMessageAttributes", skip_serializing_if = "Option::is_none")]
+    md5_of_message_attributes: Option<String>,
+    #[serde(rename = "SequenceNumber", skip_serializing_if = "Option::is_none")]
+    sequence_number: Option<String>,
+}
+
+/// Checks a message to send and converts it into an `OutgoingMessage`
+fn outgoing_message(entry: SendMessageEntry) -> Result<OutgoingMessage, SqsError> {
+    if entry.message_body.is_empty() || entry.message_body.len() > MAX_MESSAGE_SIZE {
+        return Err(SqsError::InvalidParameterValue(format!(
+            "message body must be between 1 and {} bytes",
+            MAX_MESSAGE_SIZE
+        )));
+    }
+
+    // The queue checks the delay and the group and deduplication ids against its kind
+    let mut message = OutgoingMessage::new(entry.message_body);
+    if let Some(delay) = entry.delay_seconds {
+        message = message.with_delay(seconds("DelaySeconds", delay)?);
+    }
+    message.group_id = entry.message_group_id;
+    message.deduplication_id = entry.message_deduplication_id;
+    for (name, value) in entry.message_attributes {
+        let is_text = value.data_type.starts_with("String") || value.data_type.starts_with("Number");
+        match value.string_value {
+            Some(text) if is_text => message = message.with_attribute(name, text),
+            _ => {
+                return Err(SqsError::InvalidParameterValue(format!(
+                    "message attribute {} must be a String or Number",
+                    name
+                )));
+            }
+        }
+    }
+    Ok(message)
+}
+
+/// Sends a checked message and builds the acknowledgement the SDKs verify
+async fn send_entry(
+    state: &MyAppState,
+    queue: &str,
+    entry: SendMessageEntry,
+) -> Result<SendMessageOutput, SqsError> {
+    let md5_of_message_body = md5_hex(entry.message_body.as_bytes());
+    let md5_of_message_attributes = (!entry.message_attributes.is_empty())
+        .then(|| md5_of_attributes(entry.message_attributes.iter().map(|(name, value)| {
+            (name.as_str(), value.data_type.as_str(), value.string_value.as_deref().unwrap_or_default())
+        })));
+    let message = outgoing_message(entry)?;
+    let sent = state.queue.send(queue, message).await?;
+    Ok(SendMessageOutput {
+        message_id: sent.message_id,
+        md5_of_message_body,
+        md5_of_message_attributes,
+        sequence_number: sent.sequence_number,
+    })
+}
+
+async fn send_message(state: &MyAppState, input: SendMessageInput) -> Result<Value, SqsError> {
+    let queue = queue_name(&input.queue_url)?;
+    let output = send_entry(state, queue, input.entry).await?;
+    Ok(serde_json::to_value(output).unwrap_or_default())
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct SendMessageBatchInput {
+    queue_url: String,
+    #[serde(default)]
+    entries: Vec<SendMessageBatchEntry>,
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct SendMessageBatchEntry {
+    id: String,
+    #[serde(flatten)]
+    entry: SendMessageEntry,
+}
+
+/// Checks the entry count and ids shared by all batch actions
+fn check_batch<'a>(ids: impl ExactSizeIterator<Item = &'a str>) -> Result<(), SqsError> {
+    match ids.len() {
+        0 => return Err(SqsError::EmptyBatchRequest),
+        n if n > MAX_RECEIVE_MESSAGES => return Err(SqsError::TooManyEntriesInBatchRequest),
+        _ => {}
+    }
+    let mut seen = HashSet::new();
+    for id in ids {
+        let valid = (1..=80).contains(&id.len())
+            && id.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_');
+        if !valid {
+            return Err(SqsError::InvalidParameterValue(format!("invalid batch entry id {:?}", id)));
+        }
+        if !seen.insert(id) {
+            return Err(SqsError::BatchEntryIdsNotDistinct);
+        }
+    }
+    Ok(())
+}
+
+async fn send_message_batch(
+    state: &MyAppState,
+    input: SendMessageBatchInput,
+) -> Result<Value, SqsError> {
+    let queue = queue_name(&input.queue_url)?;
+    check_batch(input.entries.iter().map(|entry| entry.id.as_str()))?;
+    let total: usize = input.entries.iter().map(|entry| entry.entry.message_body.len()).sum();
+    if total > MAX_MESSAGE_SIZE {
+        return Err(SqsError::BatchRequestTooLong);
+    }
+    // A missing queue fails the whole request rather than every entry
+    state.queue.queue_attributes(queue).await?;
+
+    let mut successful = Vec::new();
+    let mut failed = Vec::new();
+    for SendMessageBatchEntry { id, entry } in input.entries {
+        match send_entry(state, queue, entry).await {
+            Ok(output) => {
+                let mut result = serde_json::to_value(output).unwrap_or_default();
+                result["Id"] = json!(id);
+                successful.push(result);
+            }
+            Err(e) => {
+                let (status, shape, _) = e.parts();
+                failed.push(json!({
+                    "Id": id,
+                    "SenderFault": !status.is_server_error(),
+                    "Code": shape,
+                    "Message": e.to_string(),
+                }));
+            }
+        }
+    }
+    Ok(json!({ "Successful": successful, "Failed": failed }))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ReceiveMessageInput {
+    queue_url: String,
+    #[serde(default)]
+    max_number_of_messages: Option<i64>,
+    #[serde(default)]
+    wait_time_seconds: Option<i64>,
+    #[serde(default)]
+    visibility_timeout: Option<i64>,
+    #[serde(default)]
+    attribute_names: Vec<String>,
+    #[serde(default)]
+    message_system_attribute_names: Vec<String>,
+    #[serde(default)]
+    message_attribute_names: Vec<String>,
+}
+
+/// Checks whether a message attribute was asked for: by name, `All`, `.*` or a `prefix.*`
+fn wants_attribute(requested: &[String], name: &str) -> bool {
+    requested.iter().any(|pattern| match pattern.as_str() {
+        "All" | ".*" => true,
+        pattern => match pattern.strip_suffix(".*") {
+            Some(prefix) => name.starts_with(prefix) && name[prefix.len()..].starts_with('.'),
+            None => pattern == name,
+        },
+    })
+}
+
+/// Builds the wire form of a received message
+fn message_output(message: ReceivedMessage, system: &[String], requested: &[String]) -> Value {
+    let wants_system = |name: &str| system.iter().any(|s| s == "All" || s == name);
+    let mut attributes = serde_json::Map::new();
+    if wants_system("ApproximateReceiveCount") {
+        attributes.insert(
+            "ApproximateReceiveCount".to_owned(),
+            json!(message.receive_count.to_string()),
+        );
+    }
+    if wants_system("SentTimestamp") {
+        attributes.insert(
+            "SentTimestamp".to_owned(),
+            json!(message.sent_at.timestamp_millis().to_string()),
+        );
+    }
+    if wants_system("ApproximateFirstReceiveTimestamp") {
+        attributes.insert(
+            "ApproximateFirstReceiveTimestamp".to_owned(),
+            json!(message.first_received_at.timestamp_millis().to_string()),
+        );
+    }
+
+    let selected: BTreeMap<&str, &str> = message
+        .attributes
+        .iter()
+        .filter(|(name, _)| wants_attribute(requested, name))
+        .map(|(name, value)| (name.as_str(), value.as_str()))
+        .collect();
+
+    let fifo_attributes = [
+        ("MessageGroupId", &message.group_id),
+        ("MessageDeduplicationId", &message.deduplication_id),
+        ("SequenceNumber", &message.sequence_number),
+    ];
+    for (name, value) in fifo_attributes {
+        if let Some(value) = value.as_ref().filter(|_| wants_system(name)) {
+            attributes.insert(name.to_owned(), json!(value));
+        }
+    }
+
+    let mut output = json!({
+        "MessageId": message.message_id,
+        "ReceiptHandle": message.receipt_handle,
+        "MD5OfBody": md5_hex(message.body.as_bytes()),
+        "Body": message.body,
+    });
+    if !attributes.is_empty() {
+        output["Attributes"] = Value::Object(attributes);
+    }
+    if !selected.is_empty() {
+        output["MD5OfMessageAttributes"] =
+            json!(md5_of_attributes(selected.iter().map(|(name, value)| (*name, "String", *value))));
+        output["MessageAttributes"] = selected
+            .iter()
+            .map(|(name, value)| {
+                let value = MessageAttributeValue {
+                    data_type: "String".to_owned(),
+                    string_value: Some((*value).to_owned()),
+                    binary_value: None,
+                };
+                (name.to_string(), serde_json::to_value(value).unwrap_or_default())
+            })
+            .collect::<serde_json::Map<_, _>>()
+            .into();
+    }
+    output
+}
+
+async fn receive_message(state: &MyAppState, input: ReceiveMessageInput) -> Result<Value, SqsError> {
+    let queue = queue_name(&input.queue_url)?;
+    let max_messages = usize::try_from(input.max_number_of_messages.unwrap_or(1)).unwrap_or(0);
+    let wait_time = seconds("WaitTimeSeconds", input.wait_time_seconds.unwrap_or(0))?;
+    let visibility_timeout = input
+        .visibility_timeout
+        .map(|value| seconds("VisibilityTimeout", value))
+        .transpose()?;
+
+    // A per-request timeout replaces the queue's for the messages received
+    let mut options = ReceiveOptions::new(max_messages, wait_time);
+    options.visibility_timeout = visibility_timeout;
+    let messages = state.queue.receive_with(queue, &options).await?;
+
+    // Both the current and the deprecated parameter name select system attributes
+    let system: Vec<String> = input
+        .message_system_attribute_names
+        .into_iter()
+        .chain(input.attribute_names)
+        .collect();
+    let messages: Vec<Value> = messages
+        .into_iter()
+        .map(|message| message_output(message, &system, &input.message_attribute_names))
+        .collect();
+    Ok(json!({ "Messages": messages }))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct DeleteMessageInput {
+    queue_url: String,
+    receipt_handle: String,
+}
+
+async fn delete_message(state: &MyAppState, input: DeleteMessageInput) -> Result<Value, SqsError> {
+    let queue = queue_name(&input.queue_url)?;
+    state.queue.delete(queue, &input.receipt_handle).await?;
+    Ok(json!({}))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct ChangeMessageVisibilityInput {
+    queue_url: String,
+    receipt_handle: String,
+    visibility_timeout: i64,
+}
+
+async fn change_message_visibility(
+    state: &MyAppState,
+    input: ChangeMessageVisibilityInput,
+) -> Result<Value, SqsError> {
+    let queue = queue_name(&input.queue_url)?;
+    let timeout = seconds("VisibilityTimeout", input.visibility_timeout)?;
+    state
+        .queue
+        .change_visibility(queue, &input.receipt_handle, timeout)
+        .await?;
+    Ok(json!({}))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct GetQueueAttributesInput {
+    queue_url: String,
+    #[serde(default)]
+    attribute_names: Vec<String>,
+}
+
+/// Queue attributes reported by GetQueueAttributes
+const QUEUE_ATTRIBUTES: [&str; 11] = [
+    "ApproximateNumberOfMessages",
+    "ApproximateNumberOfMessagesNotVisible",
+    "ApproximateNumberOfMessagesDelayed",
+    "ContentBasedDeduplication",
+    "CreatedTimestamp",
+    "DelaySeconds",
+    "FifoQueue",
+    "LastModifiedTimestamp",
+    "QueueArn",
+    "RedrivePolicy",
+    "VisibilityTimeout",
+];
+
+async fn get_queue_attributes(
+    state: &MyAppState,
+    input: GetQueueAttributesInput,
+) -> Result<Value, SqsError> {
+    let queue = queue_name(&input.queue_url)?;
+    let names: Vec<&str> = if input.attribute_names.iter().any(|name| name == "All") {
+        QUEUE_ATTRIBUTES.to_vec()
+    } else {
+        input.attribute_names.iter().map(String::as_str).collect()
+    };
+    let attributes = state.queue.queue_attributes(queue).await?;
+
+    let mut output = serde_json::Map::new();
+    for name in names {
+        if name == "RedrivePolicy" {
+            // Only reported for queues that have one
+            if let Some(policy) = &attributes.config.redrive_policy {
+                let policy = json!({
+                    "deadLetterTargetArn": queue_arn(&policy.dead_letter_queue),
+                    "maxReceiveCount": policy.max_receive_count,
+                });
+                output.insert(name.to_owned(), json!(policy.to_string()));
+            }
+            continue;
+        }
+        if matches!(name, "FifoQueue" | "ContentBasedDeduplication") {
+            // Only reported for FIFO queues
+            if attributes.config.fifo {
+                let enabled = name == "FifoQueue" || attributes.config.content_based_deduplication;
+                output.insert(name.to_owned(), json!(enabled.to_string()));
+            }
+            continue;
+        }
+        let value = match name {
+            "ApproximateNumberOfMessages" => attributes.visible_messages.to_string(),
+            "ApproximateNumberOfMessagesNotVisible" => attributes.in_flight_messages.to_string(),
+            "ApproximateNumberOfMessagesDelayed" => attributes.delayed_messages.to_string(),
+            "CreatedTimestamp" | "LastModifiedTimestamp" => {
+                attributes.created_at.timestamp().to_string()
+            }
+            "DelaySeconds" => attributes.config.delay.as_secs().to_string(),
+            "QueueArn" => queue_arn(queue),
+            "VisibilityTimeout" => attributes.config.visibility_timeout.as_secs().to_string(),
+            _ => return Err(SqsError::InvalidAttributeName(name.to_owned())),
+        };
+        output.insert(name.to_owned(), json!(value));
+    }
+    Ok(json!({ "Attributes": output }))
+}
+
+#[derive(Deserialize)]
+#[serde(rename_all = "PascalCase")]
+struct PurgeQueueInput {
+    queue_url: String,
+}
+
+async fn purge_queue(state: &MyAppState, input: PurgeQueueInput) -> Result<Value, SqsError> {
+    let queue = queue_name(&input.queue_url)?;
+    state.queue.purge(queue).await?;
+    Ok(json!({}))
+}
+
+/// Lowercase hex MD5 digest, as returned in the `MD5Of*` fields
+fn md5_hex(data: &[u8]) -> String {
+    Md5::digest(data)
+        .iter()
+        .map(|byte| format!("{:02x}", byte))
+        .collect()
+}
+
+/// MD5 digest of message attributes in the encoding the SDKs verify
+///
+/// Attributes are taken in name order; each contributes its name, data type and
+/// value as length-prefixed byte strings, with a transport type byte of 1
+/// (string) before the value.
+fn md5_of_attributes<'a>(attributes: impl Iterator<Item = (&'a str, &'a str, &'a str)>) -> String {
+    let mut attributes: Vec<_> = attributes.collect();
+    attributes.sort_by_key(|(name, _, _)| *name);
+
+    let mut buffer = Vec::new();
+    for (name, data_type, value) in attributes {
+        for field in [name, data_type] {
+            buffer.extend_from_slice(&(field.len() as u32).to_be_bytes());
+            buffer.extend_from_slice(field.as_bytes());
+        }
+        buffer.push(1);
+        buffer.extend_from_slice(&(value.len() as u32).to_be_bytes());
+        buffer.extend_from_slice(value.as_bytes());
+    }
+    md5_hex(&buffer)
+}
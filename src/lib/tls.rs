@@ -0,0 +1,450 @@
+//! TLS Module
+//!
+//! This module terminates TLS in the server itself, using rustls:
+//! - Certificate chain and private key loaded from PEM files, see `TlsConfig`
+//! - ALPN offering HTTP/2 ahead of HTTP/1.1
+//! - Certificates reloaded when their files change, without a restart
+//! - Optional mutual TLS, with the verified client certificate available to
+//!   handlers through the `ClientCertificate` extractor
+//!
+//! `TlsListener` wraps a TCP listener for `axum::serve`, handshaking each
+//! connection in its own task so that slow clients do not hold up others.
+
+use crate::app_error::AppError;
+use crate::auth_claim::AuthError;
+use crate::config::{ClientAuth, TlsConfig};
+use axum::extract::connect_info::{ConnectInfo, Connected};
+use axum::extract::{FromRequestParts, OptionalFromRequestParts};
+use axum::http::request::Parts;
+use axum::serve::IncomingStream;
+use rustls::RootCertStore;
+use rustls::crypto::CryptoProvider;
+use rustls::pki_types::pem::PemObject;
+use rustls::pki_types::{CertificateDer, PrivateKeyDer};
+use rustls::server::WebPkiClientVerifier;
+use std::convert::Infallible;
+use std::fmt::{Display, Write};
+use std::net::SocketAddr;
+use std::path::Path;
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, SystemTime};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{Semaphore, mpsc};
+use tokio_rustls::TlsAcceptor;
+use tokio_rustls::server::TlsStream;
+use x509_parser::asn1_rs::ToDer;
+use x509_parser::objects::{oid_registry, oid2abbrev};
+use x509_parser::prelude::{AttributeTypeAndValue, FromDer, X509Certificate};
+
+/// How long a client may take to complete the handshake
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Most handshakes in progress at once; further connections wait in the backlog
+const MAX_HANDSHAKES: usize = 256;
+
+/// TLS setup error types
+#[derive(Debug)]
+pub enum TlsError {
+    /// A certificate or key file could not be read
+    Io(String, std::io::Error),
+    /// A file holds no usable PEM certificate or key
+    Pem(String),
+    /// rustls rejected the certificate, key or settings
+    Rustls(rustls::Error),
+    /// The client CA certificates cannot verify clients
+    ClientVerifier(String),
+}
+
+impl Display for TlsError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            TlsError::Io(path, e) => write!(f, "cannot read TLS file {}: {}", path, e),
+            TlsError::Pem(reason) => write!(f, "invalid PEM: {}", reason),
+            TlsError::Rustls(e) => write!(f, "invalid TLS setup: {}", e),
+            TlsError::ClientVerifier(reason) => write!(f, "invalid client CA: {}", reason),
+        }
+    }
+}
+
+impl std::error::Error for TlsError {}
+
+impl From<rustls::Error> for TlsError {
+    fn from(e: rustls::Error) -> Self {
+        TlsError::Rustls(e)
+    }
+}
+
+/// The server's TLS settings, reloadable at runtime
+///
+/// New connections use the configuration loaded last; connections already
+/// established keep the certificate they were served with.
+pub struct ServerTls {
+    settings: TlsConfig,
+    current: RwLock<Arc<rustls::ServerConfig>>,
+}
+
+impl std::fmt::Debug for ServerTls {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.debug_struct("ServerTls").field("settings", &self.settings).finish()
+    }
+}
+
+impl ServerTls {
+    /// Loads the certificate, key and client CA named by the configuration
+    pub fn load(settings: &TlsConfig) -> Result<Self, TlsError> {
+        Ok(Self {
+            current: RwLock::new(Arc::new(server_config(settings)?)),
+            settings: settings.clone(),
+        })
+    }
+
+    /// The rustls configuration for the next handshake
+    pub fn server_config(&self) -> Arc<rustls::ServerConfig> {
+        self.current
+            .read()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .clone()
+    }
+
+    /// Reloads the certificate, key and client CA from their files
+    ///
+    /// On failure the current configuration stays in place.
+    pub fn reload(&self) -> Result<(), TlsError> {
+        let config = Arc::new(server_config(&self.settings)?);
+        *self
+            .current
+            .write()
+            .unwrap_or_else(|poisoned| poisoned.into_inner()) = config;
+        tracing::info!(cert = %self.settings.cert_path.display(), "TLS certificate reloaded");
+        Ok(())
+    }
+
+    /// Watches the certificate, key and client CA files, reloading when any of them changes
+    ///
+    /// Polls modification times every `reload_every` of the configuration.
+    pub fn watch(self: Arc<Self>) -> tokio::task::JoinHandle<()> {
+        tokio::spawn(async move {
+            let mut last_seen = self.files_modified();
+            let mut ticker = tokio::time::interval(self.settings.reload_every);
+            ticker.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                ticker.tick().await;
+                let modified = self.files_modified();
+                if modified == last_seen {
+                    continue;
+                }
+                last_seen = modified;
+                if let Err(e) = self.reload() {
+                    tracing::error!("keeping current TLS certificate, reload failed: {}", e);
+                }
+            }
+        })
+    }
+
+    /// The modification times of the watched files
+    fn files_modified(&self) -> Vec<Option<SystemTime>> {
+        let settings = &self.settings;
+        [Some(&settings.cert_path), Some(&settings.key_path), settings.client_ca_path.as_ref()]
+            .into_iter()
+            .flatten()
+            .map(|file| std::fs::metadata(file).and_then(|meta| meta.modified()).ok())
+            .collect()
+    }
+}
+
+/// Builds the rustls configuration described by the settings
+fn server_config(settings: &TlsConfig) -> Result<rustls::ServerConfig, TlsError> {
+    let provider = Arc::new(rustls::crypto::ring::default_provider());
+    let certs = read_certificates(&settings.cert_path)?;
+    let key = read_private_key(&settings.key_path)?;
+
+    let builder = rustls::ServerConfig::builder_with_provider(provider.clone())
+        .with_safe_default_protocol_versions()?;
+    let builder = match &settings.client_ca_path {
+        Some(path) => builder.with_client_cert_verifier(client_verifier(path, settings.client_auth, provider)?),
+        None => builder.with_no_client_auth(),
+    };
+    let mut config = builder.with_single_cert(certs, key)?;
+    // Clients that speak HTTP/2 get it; everyone else falls back to HTTP/1.1
+    config.alpn_protocols = vec![b"h2".to_vec(), b"http/1.1".to_vec()];
+    Ok(config)
+}
+
+/// Builds the verifier checking client certificates against the CA file
+fn client_verifier(
+    path: &Path,
+    client_auth: ClientAuth,
+    provider: Arc<CryptoProvider>,
+) -> Result<Arc<dyn rustls::server::danger::ClientCertVerifier>, TlsError> {
+    let mut roots = RootCertStore::empty();
+    for cert in read_certificates(path)? {
+        roots
+            .add(cert)
+            .map_err(|e| TlsError::ClientVerifier(format!("{}: {}", path.display(), e)))?;
+    }
+    let mut verifier = WebPkiClientVerifier::builder_with_provider(Arc::new(roots), provider);
+    if client_auth == ClientAuth::Optional {
+        verifier = verifier.allow_unauthenticated();
+    }
+    verifier
+        .build()
+        .map_err(|e| TlsError::ClientVerifier(e.to_string()))
+}
+
+fn read_file(path: &Path) -> Result<Vec<u8>, TlsError> {
+    std::fs::read(path).map_err(|e| TlsError::Io(path.display().to_string(), e))
+}
+
+/// Reads every certificate of a PEM file, the leaf first
+fn read_certificates(path: &Path) -> Result<Vec<CertificateDer<'static>>, TlsError> {
+    let pem = read_file(path)?;
+    let certs = CertificateDer::pem_slice_iter(&pem)
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|e| TlsError::Pem(format!("{}: {}", path.display(), e)))?;
+    if certs.is_empty() {
+        return Err(TlsError::Pem(format!("no certificate in {}", path.display())));
+    }
+    Ok(certs)
+}
+
+/// Reads the first PKCS#8, PKCS#1 or SEC1 private key of a PEM file
+fn read_private_key(path: &Path) -> Result<PrivateKeyDer<'static>, TlsError> {
+    let pem = read_file(path)?;
+    PrivateKeyDer::from_pem_slice(&pem).map_err(|e| TlsError::Pem(format!("{}: {}", path.display(), e)))
+}
+
+/// Listener accepting TLS connections for `axum::serve`
+///
+/// Handshakes run concurrently in their own tasks, at most `MAX_HANDSHAKES`
+/// at a time, and give up after ten seconds; failed handshakes are logged and
+/// never reach the server.
+pub struct TlsListener {
+    local_addr: SocketAddr,
+    accepted: mpsc::Receiver<(TlsStream<TcpStream>, SocketAddr)>,
+    accept_task: tokio::task::JoinHandle<()>,
+}
+
+impl TlsListener {
+    /// Starts accepting connections on a bound TCP listener
+    ///
+    /// # Arguments
+    ///
+    /// * `listener` - The bound TCP listener
+    /// * `tls` - The TLS settings, read again for every handshake so reloads apply
+    pub fn new(listener: TcpListener, tls: Arc<ServerTls>) -> std::io::Result<Self> {
+        let local_addr = listener.local_addr()?;
+        let (sender, accepted) = mpsc::channel(64);
+        let accept_task = tokio::spawn(accept_connections(listener, tls, sender));
+        Ok(Self {
+            local_addr,
+            accepted,
+            accept_task,
+        })
+    }
+}
+
+impl Drop for TlsListener {
+    fn drop(&mut self) {
+        // Stop accepting, closing the socket, once the server lets go of the listener
+        self.accept_task.abort();
+    }
+}
+
+impl axum::serve::Listener for TlsListener {
+    type Io = TlsStream<TcpStream>;
+    type Addr = SocketAddr;
+
+    async fn accept(&mut self) -> (Self::Io, Self::Addr) {
+        match self.accepted.recv().await {
+            Some(connection) => connection,
+            // The accept task only ends when the listener is dropped
+            None => std::future::pending().await,
+        }
+    }
+
+    fn local_addr(&self) -> std::io::Result<Self::Addr> {
+        Ok(self.local_addr)
+    }
+}
+
+/// Accepts TCP connections and hands them over once their handshake completes
+async fn accept_connections(
+    listener: TcpListener,
+    tls: Arc<ServerTls>,
+    sender: mpsc::Sender<(TlsStream<TcpStream>, SocketAddr)>,
+) {
+    let handshakes = Arc::new(Semaphore::new(MAX_HANDSHAKES));
+    loop {
+        // Wait for a free slot before accepting, so that a flood of slow clients
+        // queues up in the kernel instead of spawning unbounded tasks
+        let Ok(permit) = handshakes.clone().acquire_owned().await else {
+            return;
+        };
+        let (stream, remote_addr) = match listener.accept().await {
+            Ok(connection) => connection,
+            Err(e) => {
+                // Typically out of file descriptors; give the server a moment to close some
+                tracing::error!("accepting a connection failed: {}", e);
+                tokio::time::sleep(Duration::from_secs(1)).await;
+                continue;
+            }
+        };
+        let acceptor = TlsAcceptor::from(tls.server_config());
+        let sender = sender.clone();
+        tokio::spawn(async move {
+            match tokio::time::timeout(HANDSHAKE_TIMEOUT, acceptor.accept(stream)).await {
+                Ok(Ok(stream)) => {
+                    let _ = sender.send((stream, remote_addr)).await;
+                }
+                Ok(Err(e)) => tracing::debug!(%remote_addr, "TLS handshake failed: {}", e),
+                Err(_) => tracing::debug!(%remote_addr, "TLS handshake timed out"),
+            }
+            drop(permit);
+        });
+    }
+}
+
+/// Connection details of a TLS connection
+///
+/// Available to handlers as `ConnectInfo<TlsConnectInfo>` when the router is
+/// served by `backend_server::serve_tls`.
+#[derive(Debug, Clone)]
+pub struct TlsConnectInfo {
+    pub remote_addr: SocketAddr,
+    /// The certificate the client authenticated with, under mutual TLS
+    pub client_certificate: Option<ClientCertificate>,
+}
+
+impl Connected<IncomingStream<'_, TlsListener>> for TlsConnectInfo {
+    fn connect_info(stream: IncomingStream<'_, TlsListener>) -> Self {
+        let (_, session) = stream.io().get_ref();
+        let client_certificate = session
+            .peer_certificates()
+            .and_then(|certs| certs.first())
+            .and_then(|cert| ClientCertificate::from_der(cert));
+        Self {
+            remote_addr: *stream.remote_addr(),
+            client_certificate,
+        }
+    }
+}
+
+/// The verified certificate a client presented under mutual TLS
+///
+/// Used as an extractor it rejects requests without a client certificate
+/// with 403 Forbidden; extract `Option<ClientCertificate>` to accept both.
+#[derive(Debug, Clone)]
+pub struct ClientCertificate {
+    /// Subject distinguished name in RFC 4514 form, e.g. `CN=billing,O=Acme,C=DE`
+    pub subject: String,
+    /// The first common name (CN) of the subject, if any
+    pub common_name: Option<String>,
+    /// The DER encoded certificate
+    pub der: Vec<u8>,
+}
+
+impl ClientCertificate {
+    /// Reads the subject of a DER encoded X.509 certificate
+    ///
+    /// Returns `None` when the certificate is malformed.
+    pub fn from_der(der: &[u8]) -> Option<Self> {
+        let (_, certificate) = X509Certificate::from_der(der).ok()?;
+        let subject = certificate.subject();
+        let common_name = subject
+            .iter_common_name()
+            .next()
+            .and_then(|name| name.as_str().ok())
+            .map(str::to_owned);
+        let mut rdns: Vec<String> = subject
+            .iter_rdn()
+            .map(|rdn| {
+                let attributes: Vec<String> = rdn.iter().map(rfc4514_attribute).collect();
+                attributes.join("+")
+            })
+            .collect();
+        // RFC 4514 lists the most specific name first
+        rdns.reverse();
+
+        Some(Self {
+            subject: rdns.join(","),
+            common_name,
+            der: der.to_vec(),
+        })
+    }
+}
+
+impl<S> FromRequestParts<S> for ClientCertificate
+where
+    S: Send + Sync,
+{
+    type Rejection = AppError;
+
+    /// Takes the client certificate from the connection's `TlsConnectInfo`
+    ///
+    /// # Returns
+    ///
+    /// A `Result` containing either:
+    /// * `Ok(ClientCertificate)` - The certificate the client authenticated with
+    /// * `Err(AppError)` - If the connection is not TLS or the client sent no certificate
+    async fn from_request_parts(parts: &mut Parts, state: &S) -> Result<Self, Self::Rejection> {
+        let certificate = <Self as OptionalFromRequestParts<S>>::from_request_parts(parts, state).await;
+        certificate
+            .unwrap_or_else(|never| match never {})
+            .ok_or_else(|| AuthError::MissingClientCertificate.into())
+    }
+}
+
+impl<S> OptionalFromRequestParts<S> for ClientCertificate
+where
+    S: Send + Sync,
+{
+    type Rejection = Infallible;
+
+    async fn from_request_parts(parts: &mut Parts, _state: &S) -> Result<Option<Self>, Self::Rejection> {
+        Ok(parts
+            .extensions
+            .get::<ConnectInfo<TlsConnectInfo>>()
+            .and_then(|ConnectInfo(info)| info.client_certificate.clone()))
+    }
+}
+
+/// Formats an attribute as `type=value`, e.g. `CN=billing`
+///
+/// Types without a short name are given as a dotted OID, and values that are
+/// not strings in the `#` hex form of their DER encoding.
+fn rfc4514_attribute(attribute: &AttributeTypeAndValue) -> String {
+    let oid = attribute.attr_type();
+    let name = oid2abbrev(oid, oid_registry()).map_or_else(|_| oid.to_id_string(), str::to_owned);
+    let value = match attribute.as_str() {
+        Ok(text) => escape_rfc4514(text),
+        Err(_) => format!("#{}", hex(&attribute.attr_value().to_der_vec().unwrap_or_default())),
+    };
+    format!("{}={}", name, value)
+}
+
+/// Escapes an attribute value as RFC 4514 section 2.4 requires
+fn escape_rfc4514(value: &str) -> String {
+    let last = value.chars().count().saturating_sub(1);
+    let mut escaped = String::with_capacity(value.len());
+    for (i, c) in value.chars().enumerate() {
+        match c {
+            '"' | '+' | ',' | ';' | '<' | '>' | '\\' => escaped.push('\\'),
+            '#' if i == 0 => escaped.push('\\'),
+            ' ' if i == 0 || i == last => escaped.push('\\'),
+            '\0' => {
+                escaped.push_str("\\00");
+                continue;
+            }
+            _ => {}
+        }
+        escaped.push(c);
+    }
+    escaped
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().fold(String::with_capacity(bytes.len() * 2), |mut hex, b| {
+        let _ = write!(hex, "{:02x}", b);
+        hex
+    })
+}
@@ -1,12 +1,12 @@
 use crate::my_extractors;
-use axum::{Router, routing::get};
+use utoipa_axum::{router::OpenApiRouter, routes};
 // pub fn api_router() -> Router {
 //     Router::new()
 //         .nest("/users", user::router())
 // }
 
-pub fn router() -> Router {
-    Router::new()
-        .route("/", get(my_extractors::query))
-        .route("/{user_id}", get(my_extractors::path_param))
+pub fn router() -> OpenApiRouter {
+    OpenApiRouter::new()
+        .routes(routes!(my_extractors::query))
+        .routes(routes!(my_extractors::path_param))
 }
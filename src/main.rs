@@ -1,17 +1,23 @@
 //! Axum web application with authentication and request tracing
 //! 
 //! This is the binary entry point for the application.
-//! It handles environment configuration and starts the web server.
+//! It loads the configuration and starts the web server.
 
 use axum_sqs_lib::backend_server::run_server;
-use dotenvy;
+use axum_sqs_lib::config::Config;
 
 #[tokio::main]
 async fn main() -> Result<(), Box<dyn std::error::Error>> {
-    // Load environment variables from .env file
-    dotenvy::dotenv().ok();
+    // Load the configuration from defaults, config.toml, .env, the environment and flags
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            std::process::exit(2);
+        }
+    };
 
     // Start the server
-    run_server().await
+    run_server(config).await
 }
 
@@ -12,14 +12,37 @@ fn add_two_and_two() {
 }
 
 use axum_sqs_lib::{
-    auth_claim::AuthBody,
+    app_error::AppError,
+    app_state::MyAppState,
+    auth_claim::{AuthBody, Claims, TokenSettings},
     backend_server,
+    client_store::{ClientRecord, ClientStore, SqliteClientStore},
+    config::{ClientAuth, Config, TlsConfig},
+    health::HealthCheck,
+    idempotency::{InMemoryProcessedLedger, ProcessedLedger, SqliteProcessedLedger},
+    jwt_keys::{KeyRing, Keys},
+    outbox::{MAX_ATTEMPTS, Outbox, OutboxError, OutboxTransaction, SENT_RETENTION},
+    queue::{
+        InMemoryMessageQueue, MessageQueue, OutgoingMessage, QueueConfig, QueueError, ReceiveOptions,
+        RedrivePolicy,
+    },
+    queue_worker::{Body, Consumer, JsonBody, State},
+    shutdown::Shutdown,
+    sqlite_queue::SqliteMessageQueue,
+    tls::{ClientCertificate, ServerTls, TlsListener},
 };
 use reqwest::{Client, StatusCode};
 use serde_json::json;
 use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::Duration;
 use tokio::net::TcpListener;
 
+/// Helper function to load the configuration from the test's environment
+fn test_config() -> Config {
+    Config::load_from(Vec::new(), std::env::vars()).unwrap()
+}
+
 /// Helper function to start the test server
 /// 
 /// Returns a tuple containing:
@@ -31,7 +54,7 @@ async fn spawn_test_server() -> (SocketAddr, Client) {
     let addr = listener.local_addr().unwrap();
     
     // Get the router from backend_server
-    let app = backend_server::init_app();
+    let app = backend_server::init_app(&test_config());
     
     // Spawn the server in a background task
     tokio::spawn(async move {
@@ -44,6 +67,19 @@ async fn spawn_test_server() -> (SocketAddr, Client) {
     (addr, client)
 }
 
+/// Helper function to start the test server around a custom application state
+async fn spawn_test_server_with_state(state: MyAppState) -> (SocketAddr, Client) {
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = backend_server::init_app_with_state(state);
+
+    tokio::spawn(async move {
+        axum::serve(listener, app).await.unwrap();
+    });
+
+    (addr, Client::new())
+}
+
 #[tokio::test]
 async fn test_hello_world() {
     let (addr, client) = spawn_test_server().await;
@@ -231,3 +267,2215 @@ async fn test_error_handling() {
 
     assert_eq!(response.status(), StatusCode::BAD_REQUEST);
 }
+
+#[tokio::test]
+async fn test_sqlite_client_store() {
+    let store = SqliteClientStore::connect("sqlite::memory:").await.unwrap();
+    store
+        .register(ClientRecord::new("svc", "s3cret", "svc@corp.com", "Initech").unwrap())
+        .await
+        .unwrap();
+    store
+        .register(ClientRecord::new("old", "s3cret", "old@corp.com", "Initech").unwrap())
+        .await
+        .unwrap();
+    assert!(store.set_enabled("old", false).await.unwrap());
+
+    let keys = Arc::new(KeyRing::from_config(&test_config().keys).unwrap());
+    let (addr, client) = spawn_test_server_with_state(MyAppState::new(Arc::new(store), keys)).await;
+
+    // The demo client is not registered in this store
+    let response = client
+        .post(format!("http://{}/authorization", addr))
+        .json(&json!({ "client_id": "foo", "client_secret": "bar" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // Disabled clients are rejected even with the right secret
+    let response = client
+        .post(format!("http://{}/authorization", addr))
+        .json(&json!({ "client_id": "old", "client_secret": "s3cret" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // Claims reflect the registered client
+    let response = client
+        .post(format!("http://{}/authorization", addr))
+        .json(&json!({ "client_id": "svc", "client_secret": "s3cret" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let auth_body: AuthBody = response.json().await.unwrap();
+
+    let response = client
+        .post(format!("http://{}/protected", addr))
+        .header("Authorization", format!("Bearer {}", auth_body.access_token))
+        .send()
+        .await
+        .unwrap();
+    let text = response.text().await.unwrap();
+    assert!(text.contains("svc@corp.com"));
+    assert!(text.contains("Initech"));
+}
+
+#[tokio::test]
+async fn test_token_claims_and_validation() {
+    let (addr, client) = spawn_test_server().await;
+    let settings = TokenSettings::default();
+
+    let response = client
+        .post(format!("http://{}/authorization", addr))
+        .json(&json!({ "client_id": "foo", "client_secret": "bar" }))
+        .send()
+        .await
+        .unwrap();
+    let auth_body: AuthBody = response.json().await.unwrap();
+
+    // Issued tokens carry the registered claims and the configured lifetime
+    let secret = std::env::var("JWT_SECRET").unwrap();
+    let key = jsonwebtoken::DecodingKey::from_secret(secret.as_bytes());
+    let claims = jsonwebtoken::decode::<Claims>(&auth_body.access_token, &key, &settings.validation())
+        .unwrap()
+        .claims;
+    assert_eq!(claims.iss, settings.issuer);
+    assert_eq!(claims.aud, settings.audience);
+    assert_eq!(claims.nbf, claims.iat);
+    assert_eq!(claims.exp - claims.iat, settings.ttl.as_secs() as usize);
+    assert!(!claims.jti.is_empty());
+
+    // A correctly signed token for another audience is rejected
+    let foreign = Claims {
+        aud: "another-service".to_owned(),
+        ..Claims::new("b@b.com".to_owned(), "ACME".to_owned(), &settings)
+    };
+    let foreign_token = jsonwebtoken::encode(
+        &jsonwebtoken::Header::default(),
+        &foreign,
+        &jsonwebtoken::EncodingKey::from_secret(secret.as_bytes()),
+    )
+    .unwrap();
+    let response = client
+        .post(format!("http://{}/protected", addr))
+        .header("Authorization", format!("Bearer {}", foreign_token))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_refresh_token_rotation() {
+    let (addr, client) = spawn_test_server().await;
+
+    let response = client
+        .post(format!("http://{}/authorization", addr))
+        .json(&json!({ "client_id": "foo", "client_secret": "bar" }))
+        .send()
+        .await
+        .unwrap();
+    let first: AuthBody = response.json().await.unwrap();
+    assert!(first.expires_in > 0);
+    assert!(!first.refresh_token.is_empty());
+
+    // Refreshing rotates the refresh token and yields a usable access token
+    let response = client
+        .post(format!("http://{}/authorization/refresh", addr))
+        .json(&json!({ "refresh_token": first.refresh_token }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let second: AuthBody = response.json().await.unwrap();
+    assert_ne!(second.refresh_token, first.refresh_token);
+
+    let response = client
+        .post(format!("http://{}/protected", addr))
+        .header("Authorization", format!("Bearer {}", second.access_token))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Replaying the rotated token is rejected and revokes the family...
+    let response = client
+        .post(format!("http://{}/authorization/refresh", addr))
+        .json(&json!({ "refresh_token": first.refresh_token }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // ...so the latest token of the family no longer works either
+    let response = client
+        .post(format!("http://{}/authorization/refresh", addr))
+        .json(&json!({ "refresh_token": second.refresh_token }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_token_revocation() {
+    let (addr, client) = spawn_test_server().await;
+
+    let response = client
+        .post(format!("http://{}/authorization", addr))
+        .json(&json!({ "client_id": "foo", "client_secret": "bar" }))
+        .send()
+        .await
+        .unwrap();
+    let auth_body: AuthBody = response.json().await.unwrap();
+    let token = format!("Bearer {}", auth_body.access_token);
+
+    // Revoking without a token is rejected
+    let response = client
+        .post(format!("http://{}/authorization/revoke", addr))
+        .send()
+        .await
+        .unwrap();
+    assert_ne!(response.status(), StatusCode::NO_CONTENT);
+
+    // Log out with both the access and the refresh token
+    let response = client
+        .post(format!("http://{}/authorization/revoke", addr))
+        .header("Authorization", &token)
+        .json(&json!({ "refresh_token": auth_body.refresh_token }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    // The access token is dead before its expiry, in the middleware and the extractor
+    let response = client
+        .post(format!("http://{}/protected", addr))
+        .header("Authorization", &token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = client
+        .post(format!("http://{}/authorization/revoke", addr))
+        .header("Authorization", &token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // And so is the refresh token family
+    let response = client
+        .post(format!("http://{}/authorization/refresh", addr))
+        .json(&json!({ "refresh_token": auth_body.refresh_token }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_revoked_token_within_leeway() {
+    let (addr, client) = spawn_test_server().await;
+    let settings = TokenSettings::default();
+    let secret = std::env::var("JWT_SECRET").unwrap();
+
+    // A token that expired a moment ago is still accepted within the leeway...
+    let now = chrono::Utc::now().timestamp() as usize;
+    let claims = Claims {
+        exp: now - 5,
+        iat: now - 65,
+        nbf: now - 65,
+        ..Claims::new("b@b.com".to_owned(), "ACME".to_owned(), &settings)
+    };
+    let token = jsonwebtoken::encode(
+        &jsonwebtoken::Header::default(),
+        &claims,
+        &jsonwebtoken::EncodingKey::from_secret(secret.as_bytes()),
+    )
+    .unwrap();
+    let token = format!("Bearer {}", token);
+    let response = client
+        .post(format!("http://{}/protected", addr))
+        .header("Authorization", &token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // ...so revoking it must hold until the leeway has passed as well
+    let response = client
+        .post(format!("http://{}/authorization/revoke", addr))
+        .header("Authorization", &token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+    let response = client
+        .post(format!("http://{}/protected", addr))
+        .header("Authorization", &token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+}
+
+#[tokio::test]
+async fn test_asymmetric_keys_and_jwks() {
+    let settings = TokenSettings::default();
+    let fixture = |name: &str| std::fs::read(format!("tests/fixtures/{}.pem", name)).unwrap();
+
+    for (algorithm, name) in [
+        (jsonwebtoken::Algorithm::RS256, "rs256"),
+        (jsonwebtoken::Algorithm::ES256, "es256"),
+        (jsonwebtoken::Algorithm::EdDSA, "ed25519"),
+    ] {
+        let keys = Keys::from_pem(
+            name,
+            algorithm,
+            &fixture(&format!("{}_private", name)),
+            &fixture(&format!("{}_public", name)),
+        )
+        .unwrap();
+        let claims = Claims::new("b@b.com".to_owned(), "ACME".to_owned(), &settings);
+        let token = keys.encode(&claims).unwrap();
+
+        // The header names the key and algorithm
+        let header = jsonwebtoken::decode_header(&token).unwrap();
+        assert_eq!(header.kid.as_deref(), Some(name));
+        assert_eq!(header.alg, algorithm);
+
+        // A verifier holding only the published JWK accepts the token
+        let jwk = keys.jwk.as_ref().unwrap();
+        assert_eq!(jwk.common.key_id.as_deref(), Some(name));
+        let decoding = jsonwebtoken::DecodingKey::from_jwk(jwk).unwrap();
+        let mut validation = settings.validation();
+        validation.algorithms = vec![algorithm];
+        let decoded = jsonwebtoken::decode::<Claims>(&token, &decoding, &validation).unwrap();
+        assert_eq!(decoded.claims.jti, claims.jti);
+    }
+
+    // A private key paired with an unrelated public key is refused
+    let mismatched = Keys::from_pem(
+        "mismatched",
+        jsonwebtoken::Algorithm::RS256,
+        &fixture("rs256_private"),
+        &fixture("es256_public"),
+    );
+    assert!(mismatched.is_err());
+
+    // HMAC secrets are never published
+    let (addr, client) = spawn_test_server().await;
+    let response = client
+        .get(format!("http://{}/.well-known/jwks.json", addr))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let jwks: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(jwks, json!({ "keys": [] }));
+}
+
+#[tokio::test]
+async fn test_key_rotation_without_restart() {
+    let fixtures = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+    let dir = std::env::temp_dir().join(format!("keyring-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let manifest = dir.join("keys.json");
+    let write_manifest = |value: serde_json::Value| {
+        std::fs::write(&manifest, serde_json::to_vec(&value).unwrap()).unwrap();
+    };
+    let es256 = json!({
+        "kid": "es-1",
+        "algorithm": "ES256",
+        "private_key_path": format!("{}/es256_private.pem", fixtures),
+        "public_key_path": format!("{}/es256_public.pem", fixtures),
+    });
+    let ed25519 = json!({
+        "kid": "ed-2",
+        "algorithm": "EdDSA",
+        "private_key_path": format!("{}/ed25519_private.pem", fixtures),
+        "public_key_path": format!("{}/ed25519_public.pem", fixtures),
+    });
+    write_manifest(json!({ "signing_kid": "es-1", "keys": [es256] }));
+
+    let keys = Arc::new(KeyRing::from_manifest(&manifest).unwrap());
+    let clients = Arc::new(
+        axum_sqs_lib::client_store::InMemoryClientStore::with_demo_client().unwrap(),
+    );
+    let (addr, client) =
+        spawn_test_server_with_state(MyAppState::new(clients, keys.clone())).await;
+    let login = || async {
+        let response = client
+            .post(format!("http://{}/authorization", addr))
+            .json(&json!({ "client_id": "foo", "client_secret": "bar" }))
+            .send()
+            .await
+            .unwrap();
+        response.json::<AuthBody>().await.unwrap().access_token
+    };
+    let protected = |token: String| {
+        let client = client.clone();
+        async move {
+            client
+                .post(format!("http://{}/protected", addr))
+                .header("Authorization", format!("Bearer {}", token))
+                .send()
+                .await
+                .unwrap()
+                .status()
+        }
+    };
+
+    let old_token = login().await;
+
+    // Rotate: the new key signs, the old one only verifies
+    let mut retired = es256.clone();
+    retired.as_object_mut().unwrap().remove("private_key_path");
+    write_manifest(json!({ "signing_kid": "ed-2", "keys": [ed25519, retired] }));
+    keys.reload().unwrap();
+
+    let new_token = login().await;
+    let header = jsonwebtoken::decode_header(&new_token).unwrap();
+    assert_eq!(header.kid.as_deref(), Some("ed-2"));
+    assert_eq!(protected(old_token.clone()).await, StatusCode::OK);
+    assert_eq!(protected(new_token.clone()).await, StatusCode::OK);
+
+    let jwks: serde_json::Value = client
+        .get(format!("http://{}/.well-known/jwks.json", addr))
+        .send()
+        .await
+        .unwrap()
+        .json()
+        .await
+        .unwrap();
+    let kids: Vec<&str> = jwks["keys"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .map(|key| key["kid"].as_str().unwrap())
+        .collect();
+    assert_eq!(kids, ["ed-2", "es-1"]);
+
+    // A broken manifest leaves the current keys in place
+    std::fs::write(&manifest, b"not json").unwrap();
+    assert!(keys.reload().is_err());
+    assert_eq!(protected(new_token.clone()).await, StatusCode::OK);
+
+    // Dropping the retired key ends its tokens
+    write_manifest(json!({ "signing_kid": "ed-2", "keys": [ed25519] }));
+    keys.reload().unwrap();
+    assert_eq!(protected(old_token).await, StatusCode::UNAUTHORIZED);
+    assert_eq!(protected(new_token).await, StatusCode::OK);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_scope_and_role_requirements() {
+    use axum::{Extension, Router, middleware, routing::get};
+    use axum_sqs_lib::{auth_claim_mid::auth, authorization::RequireRole};
+
+    let store = SqliteClientStore::connect("sqlite::memory:").await.unwrap();
+    store
+        .register(
+            ClientRecord::new("reader", "s3cret", "reader@corp.com", "Initech")
+                .unwrap()
+                .with_roles(["user"])
+                .with_scopes(["protected:read"]),
+        )
+        .await
+        .unwrap();
+    store
+        .register(
+            ClientRecord::new("admin", "s3cret", "admin@corp.com", "Initech")
+                .unwrap()
+                .with_roles(["user", "admin"])
+                .with_scopes(["protected:read", "protected:write"]),
+        )
+        .await
+        .unwrap();
+    let state = MyAppState::new(Arc::new(store), Arc::new(KeyRing::from_config(&test_config().keys).unwrap()));
+
+    // A router declaring a role requirement next to the crate's own routes
+    let app = backend_server::init_app_with_state(state.clone()).merge(
+        Router::new()
+            .route("/admin", get(|| async { "admin area" }).route_layer(RequireRole::new("admin")))
+            .layer(middleware::from_fn(auth))
+            .layer(Extension(state)),
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    let client = Client::new();
+
+    let login = |client_id: &'static str| {
+        let client = client.clone();
+        async move {
+            let response = client
+                .post(format!("http://{}/authorization", addr))
+                .json(&json!({ "client_id": client_id, "client_secret": "s3cret" }))
+                .send()
+                .await
+                .unwrap();
+            format!("Bearer {}", response.json::<AuthBody>().await.unwrap().access_token)
+        }
+    };
+    let reader = login("reader").await;
+    let admin = login("admin").await;
+
+    // Routes without requirements accept any valid token
+    let response = client
+        .post(format!("http://{}/protected", addr))
+        .header("Authorization", &reader)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Missing scope: 403 naming the scope
+    let response = client
+        .post(format!("http://{}/protected/norm", addr))
+        .header("Authorization", &reader)
+        .body("text")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let challenge = response.headers()["www-authenticate"].to_str().unwrap().to_owned();
+    assert!(challenge.contains("insufficient_scope"));
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["required_scope"], "protected:write");
+
+    let response = client
+        .post(format!("http://{}/protected/norm", addr))
+        .header("Authorization", &admin)
+        .body("text")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // Missing role: 403 naming the role
+    let response = client
+        .get(format!("http://{}/admin", addr))
+        .header("Authorization", &reader)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body["required_role"], "admin");
+
+    let response = client
+        .get(format!("http://{}/admin", addr))
+        .header("Authorization", &admin)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_current_user_from_auth_middleware() {
+    use axum::{Extension, Json, Router, middleware, routing::get};
+    use axum_sqs_lib::auth_claim_mid::{CurrentUser, auth};
+
+    let clients = Arc::new(
+        axum_sqs_lib::client_store::InMemoryClientStore::with_demo_client().unwrap(),
+    );
+    let state = MyAppState::new(clients, Arc::new(KeyRing::from_config(&test_config().keys).unwrap()));
+    let whoami = |user: CurrentUser| async move {
+        Json(json!({ "sub": user.sub, "company": user.company, "roles": user.roles }))
+    };
+    let app = backend_server::init_app_with_state(state.clone())
+        .merge(
+            Router::new()
+                .route("/whoami", get(whoami))
+                .layer(middleware::from_fn(auth))
+                .layer(Extension(state.clone())),
+        )
+        // Without the middleware there is no authenticated user
+        .route("/anonymous", get(whoami));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    let client = Client::new();
+
+    let response = client
+        .post(format!("http://{}/authorization", addr))
+        .json(&json!({ "client_id": "foo", "client_secret": "bar" }))
+        .send()
+        .await
+        .unwrap();
+    let token = format!("Bearer {}", response.json::<AuthBody>().await.unwrap().access_token);
+
+    let response = client
+        .get(format!("http://{}/whoami", addr))
+        .header("Authorization", &token)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let body: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(body, json!({ "sub": "b@b.com", "company": "ACME", "roles": ["user"] }));
+
+    let response = client
+        .get(format!("http://{}/anonymous", addr))
+        .header("Authorization", &token)
+        .send()
+        .await
+        .unwrap();
+    assert!(response.status().is_client_error());
+}
+
+#[tokio::test]
+async fn test_problem_json_errors() {
+    let (addr, client) = spawn_test_server().await;
+
+    // Missing token: same status from the middleware and the extractor
+    for path in ["/protected", "/authorization/revoke"] {
+        let response = client
+            .post(format!("http://{}{}", addr, path))
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        assert_eq!(response.headers()["content-type"], "application/problem+json");
+        assert!(response.headers().contains_key("www-authenticate"));
+        let request_id = response.headers()["x-request-id"].to_str().unwrap().to_owned();
+        let problem: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(problem["type"], "/problems/invalid-token");
+        assert_eq!(problem["title"], "Invalid token");
+        assert_eq!(problem["status"], 401);
+        assert!(problem["detail"].is_string());
+        assert_eq!(problem["request_id"], request_id.as_str());
+    }
+
+    // Extractor rejections keep their status but become problems
+    let response = client
+        .post(format!("http://{}/json", addr))
+        .header("content-type", "application/json")
+        .body("{ not json")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(response.headers()["content-type"], "application/problem+json");
+
+    // A client supplied request id is echoed back
+    let response = client
+        .get(format!("http://{}/users/invalid", addr))
+        .header("x-request-id", "trace-1234")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    assert_eq!(response.headers()["x-request-id"], "trace-1234");
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["request_id"], "trace-1234");
+
+    // Unknown routes and methods
+    let response = client
+        .get(format!("http://{}/does-not-exist", addr))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["type"], "/problems/not-found");
+
+    let response = client
+        .delete(format!("http://{}/authorization", addr))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::METHOD_NOT_ALLOWED);
+    assert_eq!(response.headers()["content-type"], "application/problem+json");
+}
+
+#[tokio::test]
+async fn test_openapi_document() {
+    let (addr, client) = spawn_test_server().await;
+
+    let response = client
+        .get(format!("http://{}/openapi.json", addr))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let doc: serde_json::Value = response.json().await.unwrap();
+
+    // Nested routers keep their prefix
+    for path in ["/authorization", "/protected", "/protected/norm", "/users/{user_id}", "/json"] {
+        assert!(doc["paths"].get(path).is_some(), "missing path {}", path);
+    }
+    for schema in ["AuthPayload", "AuthBody", "Claims", "UserDetail", "Pagination", "Problem"] {
+        assert!(doc["components"]["schemas"].get(schema).is_some(), "missing schema {}", schema);
+    }
+
+    // Protected operations reference the bearer scheme
+    let scheme = &doc["components"]["securitySchemes"]["bearer_auth"];
+    assert_eq!(scheme["scheme"], "bearer");
+    assert_eq!(
+        doc["paths"]["/protected/norm"]["post"]["security"][0]["bearer_auth"][0],
+        "protected:write"
+    );
+
+    let response = client
+        .get(format!("http://{}/docs", addr))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.text().await.unwrap().contains("scalar"));
+}
+
+#[tokio::test]
+async fn test_in_memory_queue_visibility() {
+    let queue = Arc::new(InMemoryMessageQueue::new());
+    let config = QueueConfig {
+        visibility_timeout: Duration::from_millis(300),
+        ..QueueConfig::default()
+    };
+    queue.create_queue("jobs", config.clone()).await.unwrap();
+    // Creating it again is a no-op, with other settings an error
+    queue.create_queue("jobs", config).await.unwrap();
+    assert!(matches!(
+        queue.create_queue("jobs", QueueConfig::default()).await,
+        Err(QueueError::QueueNameExists(_))
+    ));
+    assert!(matches!(
+        queue.send("missing", OutgoingMessage::new("x")).await,
+        Err(QueueError::QueueDoesNotExist(_))
+    ));
+
+    let sent = queue
+        .send("jobs", OutgoingMessage::new("hello").with_attribute("type", "greeting"))
+        .await
+        .unwrap();
+    let received = queue.receive("jobs", 10, Duration::ZERO).await.unwrap();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].message_id, sent.message_id);
+    assert_eq!(received[0].attributes["type"], "greeting");
+    assert_eq!(received[0].receive_count, 1);
+
+    // Hidden while in flight, redelivered once the visibility timeout passes
+    assert!(queue.receive("jobs", 10, Duration::ZERO).await.unwrap().is_empty());
+    let redelivered = queue.receive("jobs", 10, Duration::from_secs(2)).await.unwrap();
+    assert_eq!(redelivered.len(), 1);
+    assert_eq!(redelivered[0].receive_count, 2);
+
+    // Only the latest receipt handle is valid
+    assert_eq!(
+        queue.delete("jobs", &received[0].receipt_handle).await,
+        Err(QueueError::ReceiptHandleIsInvalid)
+    );
+    queue
+        .change_visibility("jobs", &redelivered[0].receipt_handle, Duration::ZERO)
+        .await
+        .unwrap();
+    let again = queue.receive("jobs", 10, Duration::ZERO).await.unwrap();
+    assert_eq!(again.len(), 1);
+    queue.delete("jobs", &again[0].receipt_handle).await.unwrap();
+
+    // A long poll returns as soon as a message arrives
+    let poller = {
+        let queue = queue.clone();
+        tokio::spawn(async move { queue.receive("jobs", 1, Duration::from_secs(10)).await })
+    };
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    queue.send("jobs", OutgoingMessage::new("late")).await.unwrap();
+    let late = tokio::time::timeout(Duration::from_secs(2), poller)
+        .await
+        .expect("long poll should wake on send")
+        .unwrap()
+        .unwrap();
+    assert_eq!(late[0].body, "late");
+}
+
+/// Calls an action of the SQS emulator
+async fn sqs_call(
+    client: &Client,
+    addr: SocketAddr,
+    action: &str,
+    body: serde_json::Value,
+) -> (StatusCode, serde_json::Value) {
+    let response = client
+        .post(format!("http://{}/", addr))
+        .header("x-amz-target", format!("AmazonSQS.{}", action))
+        .header("content-type", "application/x-amz-json-1.0")
+        .body(body.to_string())
+        .send()
+        .await
+        .unwrap();
+    let status = response.status();
+    (status, response.json().await.unwrap())
+}
+
+#[tokio::test]
+async fn test_sqs_emulator() {
+    let keys = KeyRing::new(Keys::new(b"sqs-secret"));
+    let state = MyAppState {
+        sqs_emulator: true,
+        ..MyAppState::new(
+            Arc::new(axum_sqs_lib::client_store::InMemoryClientStore::with_demo_client().unwrap()),
+            Arc::new(keys),
+        )
+    };
+    let (addr, client) = spawn_test_server_with_state(state).await;
+
+    let (status, created) = sqs_call(
+        &client,
+        addr,
+        "CreateQueue",
+        json!({ "QueueName": "orders", "Attributes": { "VisibilityTimeout": "60" } }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let queue_url = created["QueueUrl"].as_str().unwrap().to_owned();
+    assert!(queue_url.ends_with("/000000000000/orders"));
+
+    let (_, sent) = sqs_call(
+        &client,
+        addr,
+        "SendMessage",
+        json!({
+            "QueueUrl": queue_url,
+            "MessageBody": "hello",
+            "MessageAttributes": { "type": { "DataType": "String", "StringValue": "greeting" } }
+        }),
+    )
+    .await;
+    assert_eq!(sent["MD5OfMessageBody"], "5d41402abc4b2a76b9719d911017c592");
+    assert!(sent["MD5OfMessageAttributes"].is_string());
+
+    let (_, batch) = sqs_call(
+        &client,
+        addr,
+        "SendMessageBatch",
+        json!({
+            "QueueUrl": queue_url,
+            "Entries": [
+                { "Id": "a", "MessageBody": "first" },
+                { "Id": "b", "MessageBody": "" }
+            ]
+        }),
+    )
+    .await;
+    assert_eq!(batch["Successful"][0]["Id"], "a");
+    assert_eq!(batch["Failed"][0]["Id"], "b");
+
+    let (_, received) = sqs_call(
+        &client,
+        addr,
+        "ReceiveMessage",
+        json!({
+            "QueueUrl": queue_url,
+            "MaxNumberOfMessages": 10,
+            "MessageAttributeNames": ["All"],
+            "MessageSystemAttributeNames": ["ApproximateReceiveCount"]
+        }),
+    )
+    .await;
+    let messages = received["Messages"].as_array().unwrap();
+    assert_eq!(messages.len(), 2);
+    assert_eq!(messages[0]["Body"], "hello");
+    assert_eq!(messages[0]["MessageAttributes"]["type"]["StringValue"], "greeting");
+    assert_eq!(messages[0]["MD5OfMessageAttributes"], sent["MD5OfMessageAttributes"]);
+    assert_eq!(messages[0]["Attributes"]["ApproximateReceiveCount"], "1");
+
+    let (_, attributes) = sqs_call(
+        &client,
+        addr,
+        "GetQueueAttributes",
+        json!({ "QueueUrl": queue_url, "AttributeNames": ["All"] }),
+    )
+    .await;
+    assert_eq!(attributes["Attributes"]["ApproximateNumberOfMessagesNotVisible"], "2");
+    assert_eq!(attributes["Attributes"]["VisibilityTimeout"], "60");
+
+    // Make one message visible again, delete the other, then purge
+    let (status, _) = sqs_call(
+        &client,
+        addr,
+        "ChangeMessageVisibility",
+        json!({ "QueueUrl": queue_url, "ReceiptHandle": messages[1]["ReceiptHandle"], "VisibilityTimeout": 0 }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+
+    // A visibility timeout out of range is refused before any message is taken...
+    let (status, error) = sqs_call(
+        &client,
+        addr,
+        "ReceiveMessage",
+        json!({ "QueueUrl": queue_url, "VisibilityTimeout": 50000 }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error["__type"], "com.amazonaws.sqs#InvalidParameterValue");
+    // ...and a valid one hides the received messages for that long instead of the queue's
+    let (_, received) = sqs_call(
+        &client,
+        addr,
+        "ReceiveMessage",
+        json!({
+            "QueueUrl": queue_url,
+            "VisibilityTimeout": 0,
+            "MessageSystemAttributeNames": ["ApproximateReceiveCount"]
+        }),
+    )
+    .await;
+    assert_eq!(received["Messages"][0]["Body"], "first");
+    assert_eq!(received["Messages"][0]["Attributes"]["ApproximateReceiveCount"], "2");
+    let (status, _) = sqs_call(
+        &client,
+        addr,
+        "DeleteMessage",
+        json!({ "QueueUrl": queue_url, "ReceiptHandle": messages[0]["ReceiptHandle"] }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::OK);
+    let (_, attributes) = sqs_call(
+        &client,
+        addr,
+        "GetQueueAttributes",
+        json!({ "QueueUrl": queue_url, "AttributeNames": ["ApproximateNumberOfMessages"] }),
+    )
+    .await;
+    assert_eq!(attributes["Attributes"]["ApproximateNumberOfMessages"], "1");
+    sqs_call(&client, addr, "PurgeQueue", json!({ "QueueUrl": queue_url })).await;
+    let (_, received) = sqs_call(
+        &client,
+        addr,
+        "ReceiveMessage",
+        json!({ "QueueUrl": queue_url }),
+    )
+    .await;
+    assert!(received["Messages"].as_array().unwrap().is_empty());
+
+    // Errors use the shapes the AWS SDKs match on
+    let (status, error) = sqs_call(
+        &client,
+        addr,
+        "DeleteMessage",
+        json!({ "QueueUrl": queue_url, "ReceiptHandle": messages[0]["ReceiptHandle"] }),
+    )
+    .await;
+    assert_eq!(status, StatusCode::BAD_REQUEST);
+    assert_eq!(error["__type"], "com.amazonaws.sqs#ReceiptHandleIsInvalid");
+    let (_, error) = sqs_call(&client, addr, "GetQueueUrl", json!({ "QueueName": "missing" })).await;
+    assert_eq!(error["__type"], "com.amazonaws.sqs#QueueDoesNotExist");
+    let (_, error) = sqs_call(&client, addr, "ListDeadLetterSourceQueues", json!({})).await;
+    assert_eq!(error["__type"], "com.amazonaws.sqs#UnknownOperationException");
+}
+
+#[tokio::test]
+async fn test_queue_consumer_dispatch() {
+    let state = MyAppState::new(
+        Arc::new(axum_sqs_lib::client_store::InMemoryClientStore::new()),
+        Arc::new(KeyRing::new(Keys::new(b"worker-secret"))),
+    );
+    let config = QueueConfig {
+        visibility_timeout: Duration::from_millis(200),
+        ..QueueConfig::default()
+    };
+    state.queue.create_queue("work", config).await.unwrap();
+
+    #[derive(serde::Deserialize)]
+    struct Order {
+        id: u32,
+    }
+
+    let seen = Arc::new(tokio::sync::Mutex::new(Vec::new()));
+    let attempts = Arc::new(std::sync::atomic::AtomicU32::new(0));
+    let consumer = Consumer::new("work")
+        .wait_time(Duration::from_millis(100))
+        .handler("order", {
+            let seen = seen.clone();
+            move |JsonBody(order): JsonBody<Order>, State(state): State| {
+                let seen = seen.clone();
+                async move {
+                    seen.lock().await.push(format!("order {} for {}", order.id, state.tokens.issuer));
+                }
+            }
+        })
+        .handler("flaky", {
+            let seen = seen.clone();
+            let attempts = attempts.clone();
+            move |Body(body): Body| {
+                let seen = seen.clone();
+                let attempts = attempts.clone();
+                async move {
+                    // Fails the first time, succeeds on redelivery
+                    if attempts.fetch_add(1, std::sync::atomic::Ordering::SeqCst) == 0 {
+                        return Err("not yet");
+                    }
+                    seen.lock().await.push(body);
+                    Ok(())
+                }
+            }
+        });
+    let worker = consumer.spawn(state.clone());
+
+    let queue = &state.queue;
+    queue
+        .send("work", OutgoingMessage::new(r#"{"id": 7}"#).with_attribute("type", "order"))
+        .await
+        .unwrap();
+    queue
+        .send("work", OutgoingMessage::new("retry me").with_attribute("type", "flaky"))
+        .await
+        .unwrap();
+    // Neither a JSON body nor a known type: both stay in the queue
+    queue
+        .send("work", OutgoingMessage::new("not json").with_attribute("type", "order"))
+        .await
+        .unwrap();
+    queue
+        .send("work", OutgoingMessage::new("who?").with_attribute("type", "unknown"))
+        .await
+        .unwrap();
+
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    worker.abort();
+
+    let mut seen = seen.lock().await.clone();
+    seen.sort();
+    assert_eq!(seen, vec!["order 7 for axum-sqs-example", "retry me"]);
+    assert_eq!(attempts.load(std::sync::atomic::Ordering::SeqCst), 2);
+    let attributes = queue.queue_attributes("work").await.unwrap();
+    assert_eq!(attributes.visible_messages + attributes.in_flight_messages, 2);
+}
+
+#[test]
+#[should_panic(expected = "consumer of work: invalid parameter: max messages must be between 1 and 10")]
+fn test_consumer_rejects_max_messages() {
+    let _ = Consumer::new("work").max_messages(0);
+}
+
+#[test]
+#[should_panic(expected = "consumer of work: invalid parameter: wait time must be at most 20 seconds")]
+fn test_consumer_rejects_wait_time() {
+    let _ = Consumer::new("work").wait_time(Duration::from_secs(21));
+}
+
+#[tokio::test]
+async fn test_dead_letter_queue_and_redrive() {
+    let state = MyAppState::new(
+        Arc::new(axum_sqs_lib::client_store::InMemoryClientStore::new()),
+        Arc::new(KeyRing::new(Keys::new(b"dlq-secret"))),
+    );
+    let queue = state.queue.clone();
+    queue.create_queue("orders-dlq", QueueConfig::default()).await.unwrap();
+    let config = QueueConfig {
+        visibility_timeout: Duration::from_millis(100),
+        redrive_policy: Some(RedrivePolicy::new("orders-dlq", 2)),
+        ..QueueConfig::default()
+    };
+    // The dead-letter queue must exist
+    assert!(matches!(
+        queue
+            .create_queue("other", QueueConfig {
+                redrive_policy: Some(RedrivePolicy::new("missing", 2)),
+                ..QueueConfig::default()
+            })
+            .await,
+        Err(QueueError::QueueDoesNotExist(_))
+    ));
+    queue.create_queue("orders", config).await.unwrap();
+
+    for customer in ["acme", "globex"] {
+        queue
+            .send("orders", OutgoingMessage::new("poison").with_attribute("customer", customer))
+            .await
+            .unwrap();
+    }
+    // Received twice without being deleted, then moved instead of delivered a third time
+    for receive_count in 1..=2 {
+        let received = queue.receive("orders", 10, Duration::from_secs(1)).await.unwrap();
+        assert_eq!(received.len(), 2);
+        assert!(received.iter().all(|message| message.receive_count == receive_count));
+        tokio::time::sleep(Duration::from_millis(150)).await;
+    }
+    assert!(queue.receive("orders", 10, Duration::ZERO).await.unwrap().is_empty());
+
+    let (addr, client) = spawn_test_server_with_state(state.clone()).await;
+    let token = |roles: Vec<String>| {
+        let claims = Claims {
+            roles,
+            ..Claims::new("ops@corp.com".into(), "Initech".into(), &state.tokens)
+        };
+        format!("Bearer {}", state.keys.encode(&claims).unwrap())
+    };
+    let admin = token(vec!["admin".into()]);
+
+    let response = client
+        .get(format!("http://{}/admin/queues/orders-dlq/dead-letters", addr))
+        .header("Authorization", token(vec!["user".into()]))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+
+    let response = client
+        .get(format!("http://{}/admin/queues/orders-dlq/dead-letters", addr))
+        .header("Authorization", &admin)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let dead: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(dead.as_array().unwrap().len(), 2);
+    assert_eq!(dead[0]["receive_count"], 2);
+    assert_eq!(dead[0]["source_queue"], "orders");
+    assert!(dead[0]["first_received_at"].is_string());
+
+    // Redrive only one customer's message, then the rest
+    let response = client
+        .post(format!("http://{}/admin/queues/orders-dlq/redrive", addr))
+        .header("Authorization", &admin)
+        .json(&json!({ "attributes": { "customer": "acme" } }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.json::<serde_json::Value>().await.unwrap()["moved"], 1);
+    let redriven = queue.receive("orders", 10, Duration::ZERO).await.unwrap();
+    assert_eq!(redriven.len(), 1);
+    assert_eq!(redriven[0].attributes["customer"], "acme");
+    assert_eq!(redriven[0].receive_count, 1);
+
+    let response = client
+        .post(format!("http://{}/admin/queues/orders-dlq/redrive", addr))
+        .header("Authorization", &admin)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.json::<serde_json::Value>().await.unwrap()["moved"], 1);
+    assert_eq!(queue.queue_attributes("orders-dlq").await.unwrap().visible_messages, 0);
+
+    let response = client
+        .post(format!("http://{}/admin/queues/missing/redrive", addr))
+        .header("Authorization", &admin)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+}
+
+#[tokio::test]
+async fn test_fifo_queue_ordering_and_deduplication() {
+    let queue = InMemoryMessageQueue::new();
+    // FIFO queue names end in .fifo, and only theirs do
+    assert!(matches!(
+        queue.create_queue("orders", QueueConfig::fifo()).await,
+        Err(QueueError::InvalidParameter(_))
+    ));
+    assert!(matches!(
+        queue.create_queue("orders.fifo", QueueConfig::default()).await,
+        Err(QueueError::InvalidParameter(_))
+    ));
+    let config = QueueConfig {
+        content_based_deduplication: true,
+        ..QueueConfig::fifo()
+    };
+    queue.create_queue("orders.fifo", config).await.unwrap();
+
+    // Group ids are required, and duplicates within the window are acknowledged but dropped
+    assert!(matches!(
+        queue.send("orders.fifo", OutgoingMessage::new("no group")).await,
+        Err(QueueError::InvalidParameter(_))
+    ));
+    let mut sent = Vec::new();
+    for (group, body) in [("acme", "a1"), ("globex", "g1"), ("acme", "a2"), ("acme", "a1")] {
+        let message = OutgoingMessage::new(body).with_group_id(group);
+        sent.push(queue.send("orders.fifo", message).await.unwrap());
+    }
+    assert_eq!(sent[0], sent[3]);
+    assert_eq!(sent[0].sequence_number.as_deref(), Some("00000000000000000001"));
+    assert!(sent[2].sequence_number > sent[1].sequence_number);
+    let explicit = OutgoingMessage::new("a1").with_group_id("acme").with_deduplication_id("retry");
+    assert_ne!(queue.send("orders.fifo", explicit).await.unwrap(), sent[0]);
+
+    // One at a time per receive: the rest of a group waits while a message of it is in flight
+    let first = queue.receive("orders.fifo", 1, Duration::ZERO).await.unwrap();
+    assert_eq!(first[0].body, "a1");
+    assert_eq!(first[0].group_id.as_deref(), Some("acme"));
+    let next = queue.receive("orders.fifo", 10, Duration::ZERO).await.unwrap();
+    let bodies: Vec<_> = next.iter().map(|message| message.body.as_str()).collect();
+    assert_eq!(bodies, ["g1"]);
+
+    // Deleting the in-flight message releases the group, in order
+    queue.delete("orders.fifo", &first[0].receipt_handle).await.unwrap();
+    let rest = queue.receive("orders.fifo", 10, Duration::ZERO).await.unwrap();
+    let bodies: Vec<_> = rest.iter().map(|message| message.body.as_str()).collect();
+    assert_eq!(bodies, ["a2", "a1"]);
+
+    // Standard queues reject FIFO parameters
+    queue.create_queue("plain", QueueConfig::default()).await.unwrap();
+    assert!(matches!(
+        queue.send("plain", OutgoingMessage::new("x").with_group_id("acme")).await,
+        Err(QueueError::InvalidParameter(_))
+    ));
+}
+
+#[tokio::test]
+async fn test_http_queue_endpoints() {
+    let grants = |queues: &[&str]| queues.iter().map(|queue| queue.to_string()).collect();
+    let state = MyAppState {
+        http_queues: std::collections::HashMap::from([
+            ("Initech".to_owned(), grants(&["jobs", "missing"])),
+            ("Acme".to_owned(), grants(&["jobs"])),
+        ]),
+        ..MyAppState::new(
+            Arc::new(axum_sqs_lib::client_store::InMemoryClientStore::new()),
+            Arc::new(KeyRing::new(Keys::new(b"http-queue-secret"))),
+        )
+    };
+    state.queue.create_queue("jobs-dlq", QueueConfig::default()).await.unwrap();
+    let config = QueueConfig {
+        redrive_policy: Some(RedrivePolicy::new("jobs-dlq", 1)),
+        ..QueueConfig::default()
+    };
+    state.queue.create_queue("jobs", config).await.unwrap();
+    state.queue.create_queue("internal", QueueConfig::default()).await.unwrap();
+    let (addr, client) = spawn_test_server_with_state(state.clone()).await;
+    let token_for = |company: &str, scope: &str| {
+        let claims = Claims {
+            scope: scope.to_owned(),
+            ..Claims::new("worker@corp.com".into(), company.into(), &state.tokens)
+        };
+        format!("Bearer {}", state.keys.encode(&claims).unwrap())
+    };
+    let token = |scope: &str| token_for("Initech", scope);
+    let writer = token("protected:read protected:write");
+    let url = format!("http://{}/protected/queues/jobs/messages", addr);
+
+    // Sending needs the write scope; the token's subject and company cannot be overridden
+    let job = json!({
+        "body": { "task": "resize", "id": 7 },
+        "attributes": { "type": "image", "sub": "someone@else.com" }
+    });
+    let response = client
+        .post(&url)
+        .header("Authorization", token("protected:read"))
+        .json(&job)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = client.post(&url).header("Authorization", &writer).json(&job).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let message_id = response.json::<serde_json::Value>().await.unwrap()["message_id"].clone();
+    assert!(message_id.is_string());
+
+    // A long poll picks up a delayed message once it becomes visible
+    let delayed = json!({ "body": "later", "delay_seconds": 1 });
+    let response = client.post(&url).header("Authorization", &writer).json(&delayed).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+
+    // Another company granted the same queue never receives these messages, and its
+    // polls leave them alone instead of counting receives towards the dead-letter queue
+    let acme = token_for("Acme", "protected:read protected:write");
+    for _ in 0..3 {
+        let response = client
+            .get(format!("{}?max_messages=10", url))
+            .header("Authorization", &acme)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::OK);
+        assert_eq!(response.json::<serde_json::Value>().await.unwrap(), json!([]));
+    }
+    let queued = state.queue.peek("jobs", 10).await.unwrap();
+    assert_eq!(queued.len(), 1);
+    assert_eq!(queued[0].receive_count, 0);
+    assert!(queued[0].first_received_at.is_none());
+    assert!(state.queue.peek("jobs-dlq", 10).await.unwrap().is_empty());
+
+    let response = client
+        .get(format!("{}?max_messages=10", url))
+        .header("Authorization", token("protected:read"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    let received: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(received.as_array().unwrap().len(), 1);
+    assert_eq!(received[0]["message_id"], message_id);
+    assert_eq!(received[0]["body"], json!({ "task": "resize", "id": 7 }));
+    assert_eq!(received[0]["attributes"]["type"], "image");
+    assert_eq!(received[0]["attributes"]["sub"], "worker@corp.com");
+    assert_eq!(received[0]["attributes"]["company"], "Initech");
+
+    // Deleting is destructive and needs the write scope
+    let delete_url = format!("{}/{}", url, received[0]["receipt_handle"].as_str().unwrap());
+    let response = client
+        .delete(&delete_url)
+        .header("Authorization", token("protected:read"))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    let response = client.delete(&delete_url).header("Authorization", &writer).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::NO_CONTENT);
+
+    let response = client
+        .get(format!("{}?wait_seconds=5", url))
+        .header("Authorization", &writer)
+        .send()
+        .await
+        .unwrap();
+    let received: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(received[0]["body"], "later");
+
+    // Another company's message becoming visible neither ends a long poll nor hides
+    // the poller's own message queued behind it
+    let initech = json!({ "body": "initech", "delay_seconds": 1 });
+    let response = client.post(&url).header("Authorization", &writer).json(&initech).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let poll = tokio::spawn(
+        client
+            .get(format!("{}?max_messages=1&wait_seconds=5", url))
+            .header("Authorization", &acme)
+            .send(),
+    );
+    tokio::time::sleep(Duration::from_millis(1500)).await;
+    let response = client
+        .post(&url)
+        .header("Authorization", &acme)
+        .json(&json!({ "body": "acme" }))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::ACCEPTED);
+    let received: serde_json::Value = poll.await.unwrap().unwrap().json().await.unwrap();
+    assert_eq!(received.as_array().unwrap().len(), 1);
+    assert_eq!(received[0]["body"], "acme");
+    assert_eq!(received[0]["attributes"]["company"], "Acme");
+
+    // Out-of-range parameters and missing queues are problems
+    let response = client
+        .get(format!("{}?max_messages=11", url))
+        .header("Authorization", &writer)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    let response = client
+        .get(format!("{}?visibility_timeout=50000", url))
+        .header("Authorization", &writer)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::BAD_REQUEST);
+    // Nothing was received by the refused request
+    let queued = state.queue.peek("jobs", 10).await.unwrap();
+    let waiting = queued.iter().find(|message| message.body == "\"initech\"").unwrap();
+    assert_eq!(waiting.receive_count, 0);
+    let response = client
+        .post(format!("http://{}/protected/queues/missing/messages", addr))
+        .header("Authorization", &writer)
+        .json(&job)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::NOT_FOUND);
+    let response = client.get(&url).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    // Queues not granted to the company, such as internal ones, are out of reach
+    for method in [reqwest::Method::POST, reqwest::Method::GET] {
+        let response = client
+            .request(method, format!("http://{}/protected/queues/internal/messages", addr))
+            .header("Authorization", &writer)
+            .json(&job)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(response.status(), StatusCode::FORBIDDEN);
+        let problem: serde_json::Value = response.json().await.unwrap();
+        assert_eq!(problem["type"], "/problems/queue-not-allowed");
+    }
+    let response = client
+        .delete(format!("http://{}/protected/queues/internal/messages/handle", addr))
+        .header("Authorization", &writer)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert_eq!(state.queue.queue_attributes("internal").await.unwrap().visible_messages, 0);
+}
+
+#[tokio::test]
+async fn test_sqlite_queue_survives_restart() {
+    let dir = std::env::temp_dir().join(format!("queues-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let url = format!("sqlite://{}", dir.join("queues.db").display());
+
+    let queue = SqliteMessageQueue::connect(&url).await.unwrap();
+    queue.create_queue("jobs-dlq", QueueConfig::default()).await.unwrap();
+    let config = QueueConfig {
+        visibility_timeout: Duration::from_millis(200),
+        redrive_policy: Some(RedrivePolicy::new("jobs-dlq", 2)),
+        ..QueueConfig::default()
+    };
+    queue.create_queue("jobs", config.clone()).await.unwrap();
+    for body in ["first", "second", "third"] {
+        queue
+            .send("jobs", OutgoingMessage::new(body).with_attribute("type", "job"))
+            .await
+            .unwrap();
+    }
+    // One message is acknowledged, one is in flight when the process "crashes"
+    let received = queue.receive("jobs", 2, Duration::ZERO).await.unwrap();
+    assert_eq!(received.len(), 2);
+    queue.delete("jobs", &received[0].receipt_handle).await.unwrap();
+    drop(queue);
+
+    let queue = SqliteMessageQueue::connect(&url).await.unwrap();
+    queue.create_queue("jobs", config.clone()).await.unwrap();
+    let attributes = queue.queue_attributes("jobs").await.unwrap();
+    assert_eq!(attributes.config, config);
+    assert_eq!(attributes.visible_messages + attributes.in_flight_messages, 2);
+
+    // The in-flight message comes back once its visibility expires, in queue order
+    let received = queue.receive("jobs", 10, Duration::from_secs(2)).await.unwrap();
+    let bodies: Vec<_> = received.iter().map(|message| message.body.as_str()).collect();
+    assert_eq!(bodies, ["third"]);
+    let received = queue.receive("jobs", 10, Duration::from_secs(2)).await.unwrap();
+    let bodies: Vec<_> = received.iter().map(|message| message.body.as_str()).collect();
+    assert_eq!(bodies, ["second"]);
+    assert_eq!(received[0].receive_count, 2);
+    assert_eq!(received[0].attributes["type"], "job");
+
+    // Poison messages reach the dead-letter queue and can be redriven
+    tokio::time::sleep(Duration::from_millis(250)).await;
+    let received = queue.receive("jobs", 10, Duration::ZERO).await.unwrap();
+    let bodies: Vec<_> = received.iter().map(|message| message.body.as_str()).collect();
+    assert_eq!(bodies, ["third"]);
+    let dead = queue.peek("jobs-dlq", 10).await.unwrap();
+    assert_eq!(dead.len(), 1);
+    assert_eq!(dead[0].source_queue.as_deref(), Some("jobs"));
+    assert_eq!(queue.redrive("jobs-dlq", &Default::default()).await.unwrap(), 1);
+    // A receive's own visibility timeout replaces the queue's, here leaving the message visible
+    let options = ReceiveOptions::new(1, Duration::ZERO).with_visibility_timeout(Duration::ZERO);
+    let received = queue.receive_with("jobs", &options).await.unwrap();
+    assert_eq!(received[0].body, "second");
+    assert_eq!(received[0].receive_count, 1);
+    let received = queue.receive("jobs", 1, Duration::ZERO).await.unwrap();
+    assert_eq!(received[0].body, "second");
+    assert_eq!(received[0].receive_count, 2);
+    // One out of range is refused before anything is received
+    let options =
+        ReceiveOptions::new(1, Duration::ZERO).with_visibility_timeout(Duration::from_secs(43201));
+    assert!(matches!(
+        queue.receive_with("jobs", &options).await,
+        Err(QueueError::InvalidParameter(_))
+    ));
+    assert_eq!(
+        queue.delete("jobs", "stale").await,
+        Err(QueueError::ReceiptHandleIsInvalid)
+    );
+
+    // FIFO queues keep their ordering and deduplication state
+    queue.create_queue("orders.fifo", QueueConfig::fifo()).await.unwrap();
+    let message = || OutgoingMessage::new("a").with_group_id("g").with_deduplication_id("a");
+    let sent = queue.send("orders.fifo", message()).await.unwrap();
+    assert_eq!(queue.send("orders.fifo", message()).await.unwrap(), sent);
+    assert_eq!(queue.peek("orders.fifo", 10).await.unwrap().len(), 1);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+/// Example handler writing an order and staging its event in one transaction
+async fn place_order(
+    mut tx: OutboxTransaction,
+    axum::Json(order): axum::Json<serde_json::Value>,
+) -> Result<StatusCode, AppError> {
+    let id = order["id"].as_str().unwrap_or_default().to_owned();
+    sqlx::query("INSERT INTO orders (id) VALUES (?)")
+        .bind(&id)
+        .execute(&mut *tx)
+        .await
+        .map_err(OutboxError::from)?;
+    let queue = order["queue"].as_str().unwrap_or("orders");
+    tx.stage(queue, OutgoingMessage::new(id).with_attribute("type", "order.placed"))
+        .await?;
+    if order["fail"] == true {
+        // Dropping the transaction rolls back the order and its message
+        return Err(AppError::BadRequest("payment declined".into()));
+    }
+    tx.commit().await?;
+    Ok(StatusCode::CREATED)
+}
+
+#[tokio::test]
+async fn test_transactional_outbox() {
+    let dir = std::env::temp_dir().join(format!("outbox-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let outbox = Outbox::connect(&format!("sqlite://{}", dir.join("app.db").display()))
+        .await
+        .unwrap();
+    sqlx::query("CREATE TABLE orders (id TEXT PRIMARY KEY NOT NULL)")
+        .execute(outbox.pool())
+        .await
+        .unwrap();
+
+    let mut state = MyAppState::new(
+        Arc::new(axum_sqs_lib::client_store::InMemoryClientStore::new()),
+        Arc::new(KeyRing::new(Keys::new(b"outbox-secret"))),
+    );
+    state.outbox = Some(outbox.clone());
+    state.queue.create_queue("orders", QueueConfig::default()).await.unwrap();
+    let app = axum::Router::new()
+        .route("/orders", axum::routing::post(place_order))
+        .layer(axum::Extension(state.clone()));
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    tokio::spawn(async move { axum::serve(listener, app).await.unwrap() });
+    let client = Client::new();
+    let place = |order: serde_json::Value| {
+        client.post(format!("http://{}/orders", addr)).json(&order).send()
+    };
+
+    // Committed orders stage their message, failed ones leave neither behind
+    assert_eq!(place(json!({ "id": "o-1" })).await.unwrap().status(), StatusCode::CREATED);
+    assert_eq!(
+        place(json!({ "id": "o-2", "fail": true })).await.unwrap().status(),
+        StatusCode::BAD_REQUEST
+    );
+    assert_eq!(
+        place(json!({ "id": "o-3", "queue": "missing" })).await.unwrap().status(),
+        StatusCode::CREATED
+    );
+    let orders: Vec<String> = sqlx::query_scalar("SELECT id FROM orders ORDER BY id")
+        .fetch_all(outbox.pool())
+        .await
+        .unwrap();
+    assert_eq!(orders, ["o-1", "o-3"]);
+
+    // Nothing reaches the queue until the relay runs; failed sends stay pending
+    assert_eq!(state.queue.queue_attributes("orders").await.unwrap().visible_messages, 0);
+    assert_eq!(outbox.relay_pending(state.queue.as_ref()).await.unwrap(), 1);
+    assert_eq!(outbox.relay_pending(state.queue.as_ref()).await.unwrap(), 0);
+    let received = state.queue.receive("orders", 10, Duration::ZERO).await.unwrap();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].body, "o-1");
+    assert_eq!(received[0].attributes["type"], "order.placed");
+    let rows = sqlx::query_as::<_, (String, Option<String>, i64)>(
+        "SELECT queue, message_id, attempts FROM outbox ORDER BY id",
+    )
+    .fetch_all(outbox.pool())
+    .await
+    .unwrap();
+    assert_eq!(rows[0], ("orders".into(), Some(received[0].message_id.clone()), 0));
+    assert_eq!(rows[1], ("missing".into(), None, 2));
+
+    // A message that keeps failing is given up on and no longer holds up its queue
+    for _ in 2..MAX_ATTEMPTS {
+        assert_eq!(outbox.relay_pending(state.queue.as_ref()).await.unwrap(), 0);
+    }
+    let (attempts, failed_at): (i64, Option<i64>) =
+        sqlx::query_as("SELECT attempts, failed_at FROM outbox WHERE queue = 'missing'")
+            .fetch_one(outbox.pool())
+            .await
+            .unwrap();
+    assert_eq!(attempts, MAX_ATTEMPTS);
+    assert!(failed_at.is_some());
+    state.queue.create_queue("missing", QueueConfig::default()).await.unwrap();
+    let mut tx = outbox.begin().await.unwrap();
+    tx.stage("missing", OutgoingMessage::new("later")).await.unwrap();
+    tx.commit().await.unwrap();
+    assert_eq!(outbox.relay_pending(state.queue.as_ref()).await.unwrap(), 1);
+    let received = state.queue.receive("missing", 10, Duration::ZERO).await.unwrap();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].body, "later");
+
+    // Delays count from staging, so a message relayed late is not held back again
+    let mut tx = outbox.begin().await.unwrap();
+    let id = tx
+        .stage("orders", OutgoingMessage::new("delayed").with_delay(Duration::from_secs(60)))
+        .await
+        .unwrap();
+    tx.commit().await.unwrap();
+    sqlx::query("UPDATE outbox SET created_at = created_at - 120000 WHERE id = ?")
+        .bind(id)
+        .execute(outbox.pool())
+        .await
+        .unwrap();
+    assert_eq!(outbox.relay_pending(state.queue.as_ref()).await.unwrap(), 1);
+    let received = state.queue.receive("orders", 10, Duration::ZERO).await.unwrap();
+    assert_eq!(received.len(), 1);
+    assert_eq!(received[0].body, "delayed");
+
+    // Sent rows are pruned after the retention period, failed ones are kept
+    assert_eq!(outbox.prune_sent(SENT_RETENTION).await.unwrap(), 0);
+    sqlx::query("UPDATE outbox SET sent_at = sent_at - ? WHERE sent_at IS NOT NULL")
+        .bind(SENT_RETENTION.as_millis() as i64 + 1000)
+        .execute(outbox.pool())
+        .await
+        .unwrap();
+    assert_eq!(outbox.prune_sent(SENT_RETENTION).await.unwrap(), 3);
+    let left: Vec<String> = sqlx::query_scalar("SELECT queue FROM outbox")
+        .fetch_all(outbox.pool())
+        .await
+        .unwrap();
+    assert_eq!(left, ["missing"]);
+
+    // The background relay is woken by commits
+    let relay = outbox.clone().spawn_relay(state.queue.clone(), Shutdown::new());
+    assert_eq!(place(json!({ "id": "o-4" })).await.unwrap().status(), StatusCode::CREATED);
+    let received = state.queue.receive("orders", 10, Duration::from_secs(2)).await.unwrap();
+    assert_eq!(received[0].body, "o-4");
+    relay.abort();
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_idempotent_consumer() {
+    let state = MyAppState::new(
+        Arc::new(axum_sqs_lib::client_store::InMemoryClientStore::new()),
+        Arc::new(KeyRing::new(Keys::new(b"ledger-secret"))),
+    );
+    state.queue.create_queue("payments", QueueConfig::default()).await.unwrap();
+
+    let ledger = Arc::new(InMemoryProcessedLedger::new());
+    let charged = Arc::new(tokio::sync::Mutex::new(Vec::new()));
+    let consumer = Consumer::new("payments")
+        .wait_time(Duration::from_millis(100))
+        .fallback({
+            let charged = charged.clone();
+            move |Body(body): Body| {
+                let charged = charged.clone();
+                async move { charged.lock().await.push(body) }
+            }
+        })
+        .idempotent(ledger.clone(), Duration::from_secs(60))
+        .idempotency_attribute("idempotency-key");
+
+    // A message recorded earlier, e.g. before a delete failed, is only acknowledged
+    let sent = state.queue.send("payments", OutgoingMessage::new("redelivered")).await.unwrap();
+    ledger
+        .mark_processed(
+            &format!("payments/{}", sent.message_id),
+            chrono::Utc::now() + chrono::Duration::seconds(60),
+        )
+        .await
+        .unwrap();
+    // The same payment sent twice is charged once
+    for _ in 0..2 {
+        let message = OutgoingMessage::new("charge 10").with_attribute("idempotency-key", "pay-1");
+        state.queue.send("payments", message).await.unwrap();
+    }
+    state.queue.send("payments", OutgoingMessage::new("charge 20")).await.unwrap();
+
+    let worker = consumer.spawn(state.clone());
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    worker.abort();
+
+    let mut charged = charged.lock().await.clone();
+    charged.sort();
+    assert_eq!(charged, ["charge 10", "charge 20"]);
+    let attributes = state.queue.queue_attributes("payments").await.unwrap();
+    assert_eq!(attributes.visible_messages + attributes.in_flight_messages, 0);
+    assert!(ledger.is_processed("payments/pay-1").await.unwrap());
+
+    // The SQLite ledger forgets entries after their retention
+    let pool = sqlx::sqlite::SqlitePoolOptions::new()
+        .max_connections(1)
+        .connect("sqlite::memory:")
+        .await
+        .unwrap();
+    let ledger = SqliteProcessedLedger::from_pool(pool).await.unwrap();
+    let now = chrono::Utc::now();
+    ledger.mark_processed("q/old", now - chrono::Duration::seconds(1)).await.unwrap();
+    ledger.mark_processed("q/new", now + chrono::Duration::seconds(60)).await.unwrap();
+    assert!(!ledger.is_processed("q/old").await.unwrap());
+    assert!(ledger.is_processed("q/new").await.unwrap());
+    assert!(!ledger.is_processed("q/other").await.unwrap());
+}
+
+#[tokio::test]
+async fn test_delayed_and_scheduled_messages() {
+    let backends: Vec<Arc<dyn MessageQueue>> = vec![
+        Arc::new(InMemoryMessageQueue::new()),
+        Arc::new(SqliteMessageQueue::connect("sqlite::memory:").await.unwrap()),
+    ];
+    for queue in backends {
+        let config = QueueConfig {
+            delay: Duration::from_millis(300),
+            ..QueueConfig::default()
+        };
+        queue.create_queue("reminders", config.clone()).await.unwrap();
+        assert_eq!(queue.queue_attributes("reminders").await.unwrap().config, config);
+
+        // The queue's delay applies unless the message sets its own, zero included
+        queue.send("reminders", OutgoingMessage::new("queued")).await.unwrap();
+        queue
+            .send("reminders", OutgoingMessage::new("own").with_delay(Duration::from_millis(100)))
+            .await
+            .unwrap();
+        queue
+            .send("reminders", OutgoingMessage::new("now").with_delay(Duration::ZERO))
+            .await
+            .unwrap();
+        // Scheduled delivery is not capped by the 15 minute delay limit
+        let tomorrow = chrono::Utc::now() + chrono::Duration::days(1);
+        queue
+            .send("reminders", OutgoingMessage::new("tomorrow").with_delivery_at(tomorrow))
+            .await
+            .unwrap();
+        let soon = chrono::Utc::now() + chrono::Duration::milliseconds(600);
+        queue
+            .send("reminders", OutgoingMessage::new("soon").with_delivery_at(soon))
+            .await
+            .unwrap();
+
+        let attributes = queue.queue_attributes("reminders").await.unwrap();
+        assert_eq!(attributes.delayed_messages, 4);
+        assert_eq!(attributes.visible_messages, 1);
+        let received = queue.receive("reminders", 10, Duration::ZERO).await.unwrap();
+        let bodies: Vec<_> = received.iter().map(|message| message.body.as_str()).collect();
+        assert_eq!(bodies, ["now"]);
+        assert_eq!(queue.peek("reminders", 10).await.unwrap().len(), 1);
+
+        // A long poll wakes up when a delayed message becomes due
+        let received = queue.receive("reminders", 10, Duration::from_secs(2)).await.unwrap();
+        let bodies: Vec<_> = received.iter().map(|message| message.body.as_str()).collect();
+        assert_eq!(bodies, ["own"]);
+        let received = queue.receive("reminders", 10, Duration::from_secs(2)).await.unwrap();
+        let bodies: Vec<_> = received.iter().map(|message| message.body.as_str()).collect();
+        assert_eq!(bodies, ["queued"]);
+        let received = queue.receive("reminders", 10, Duration::from_secs(2)).await.unwrap();
+        let bodies: Vec<_> = received.iter().map(|message| message.body.as_str()).collect();
+        assert_eq!(bodies, ["soon"]);
+        let attributes = queue.queue_attributes("reminders").await.unwrap();
+        assert_eq!(attributes.delayed_messages, 1);
+        assert_eq!(attributes.in_flight_messages, 4);
+
+        // Delays beyond 15 minutes, and delays combined with a delivery time, are rejected
+        let too_long = OutgoingMessage::new("late").with_delay(Duration::from_secs(901));
+        assert!(matches!(
+            queue.send("reminders", too_long).await,
+            Err(QueueError::InvalidParameter(_))
+        ));
+        let both = OutgoingMessage::new("both")
+            .with_delay(Duration::from_secs(1))
+            .with_delivery_at(tomorrow);
+        assert!(matches!(
+            queue.send("reminders", both).await,
+            Err(QueueError::InvalidParameter(_))
+        ));
+        let config = QueueConfig {
+            delay: Duration::from_secs(901),
+            ..QueueConfig::default()
+        };
+        assert!(matches!(
+            queue.create_queue("slow", config).await,
+            Err(QueueError::InvalidParameter(_))
+        ));
+    }
+}
+
+#[tokio::test]
+async fn test_receive_with_attribute_filter() {
+    let backends: Vec<Arc<dyn MessageQueue>> = vec![
+        Arc::new(InMemoryMessageQueue::new()),
+        Arc::new(SqliteMessageQueue::connect("sqlite::memory:").await.unwrap()),
+    ];
+    for queue in backends {
+        queue.create_queue("shared", QueueConfig::default()).await.unwrap();
+        for (body, company) in [("a1", "acme"), ("g1", "globex"), ("a2", "acme")] {
+            queue
+                .send("shared", OutgoingMessage::new(body).with_attribute("company", company))
+                .await
+                .unwrap();
+        }
+        // Messages of others are passed over without being received
+        let acme = ReceiveOptions::new(10, Duration::ZERO).with_filter("company", "acme");
+        let received = queue.receive_with("shared", &acme).await.unwrap();
+        let bodies: Vec<_> = received.iter().map(|message| message.body.as_str()).collect();
+        assert_eq!(bodies, ["a1", "a2"]);
+        assert!(queue.receive_with("shared", &acme).await.unwrap().is_empty());
+        let queued = queue.peek("shared", 10).await.unwrap();
+        let globex = queued.iter().find(|message| message.body == "g1").unwrap();
+        assert_eq!(globex.receive_count, 0);
+        assert!(globex.first_received_at.is_none());
+        let globex = ReceiveOptions::new(1, Duration::ZERO).with_filter("company", "globex");
+        assert_eq!(queue.receive_with("shared", &globex).await.unwrap()[0].body, "g1");
+
+        // In a FIFO queue a message left out by the filter still holds back its group
+        queue.create_queue("shared.fifo", QueueConfig::fifo()).await.unwrap();
+        for (body, group, company) in [("g1", "g", "globex"), ("a1", "g", "acme"), ("a2", "h", "acme")] {
+            let message = OutgoingMessage::new(body)
+                .with_group_id(group)
+                .with_deduplication_id(body)
+                .with_attribute("company", company);
+            queue.send("shared.fifo", message).await.unwrap();
+        }
+        let received = queue.receive_with("shared.fifo", &acme).await.unwrap();
+        let bodies: Vec<_> = received.iter().map(|message| message.body.as_str()).collect();
+        assert_eq!(bodies, ["a2"]);
+    }
+}
+
+#[test]
+fn test_config_layers_and_validation() {
+    let dir = std::env::temp_dir().join(format!("config-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let file = dir.join("server.toml");
+    std::fs::write(
+        &file,
+        r#"
+[server]
+host = "0.0.0.0"
+port = 4000
+
+[jwt]
+secret = "file-secret"
+issuer = "file-issuer"
+ttl_seconds = 60
+
+[queues]
+consumers = ["jobs", "emails"]
+"#,
+    )
+    .unwrap();
+    let vars = |pairs: &[(&str, &str)]| -> Vec<(String, String)> {
+        pairs.iter().map(|(name, value)| (name.to_string(), value.to_string())).collect()
+    };
+    let args = |args: &[&str]| -> Vec<String> { args.iter().map(|arg| arg.to_string()).collect() };
+
+    // The environment overrides the file, and flags override the environment
+    let config = Config::load_from(
+        args(&["--port", "6000", "--sqs-emulator", "--jwt-leeway-seconds=5"]),
+        vars(&[
+            ("CONFIG_FILE", file.to_str().unwrap()),
+            ("PORT", "5000"),
+            ("JWT_ISSUER", "env-issuer"),
+        ]),
+    )
+    .unwrap();
+    assert_eq!(config.server.host, "0.0.0.0");
+    assert_eq!(config.server.port, 6000);
+    assert_eq!(config.server.log, "info");
+    assert_eq!(config.tokens.issuer, "env-issuer");
+    assert_eq!(config.tokens.ttl, Duration::from_secs(60));
+    assert_eq!(config.tokens.leeway, Duration::from_secs(5));
+    assert_eq!(config.keys.secret.as_deref(), Some("file-secret"));
+    assert!(config.queues.sqs_emulator);
+    assert_eq!(config.queues.consumers, ["jobs", "emails"]);
+    // Secrets stay out of logs
+    assert!(!format!("{:?}", config).contains("file-secret"));
+
+    // Every problem is reported at once, named as in its source
+    std::fs::write(&file, "[server]\nport = \"http\"\nhots = \"x\"\n").unwrap();
+    let error = Config::load_from(
+        args(&["--config", file.to_str().unwrap(), "--bogus", "--jwt-ttl-seconds", "0"]),
+        vars(&[("JWT_ALGORITHM", "RS256"), ("SQS_EMULATOR", "maybe")]),
+    )
+    .unwrap_err();
+    let problems = error.problems().join("\n");
+    for expected in [
+        "unknown flag --bogus",
+        "unknown setting server.hots",
+        "server.port in",
+        "--jwt-ttl-seconds: must be greater than zero",
+        "SQS_EMULATOR: invalid boolean",
+        "jwt.private_key_path (JWT_PRIVATE_KEY_PATH or --jwt-private-key-path): required for RS256",
+        "jwt.public_key_path (JWT_PUBLIC_KEY_PATH or --jwt-public-key-path): required for RS256",
+    ] {
+        assert!(problems.contains(expected), "{:?} not in\n{}", expected, problems);
+    }
+    assert_eq!(error.problems().len(), 7);
+    let error = Config::load_from(Vec::new(), Vec::new()).unwrap_err();
+    assert_eq!(
+        error.to_string(),
+        "invalid configuration:\n  - jwt.secret (JWT_SECRET or --jwt-secret): required for HS256 signing"
+    );
+
+    // HTTP queue grants are company:queue pairs
+    let config = Config::load_from(
+        Vec::new(),
+        vars(&[("JWT_SECRET", "x"), ("HTTP_QUEUE_ACCESS", "Initech:jobs, Initech:emails,Acme:jobs")]),
+    )
+    .unwrap();
+    assert_eq!(config.queues.http_access["Initech"].len(), 2);
+    assert!(config.queues.http_access["Acme"].contains("jobs"));
+    let error = Config::load_from(Vec::new(), vars(&[("JWT_SECRET", "x"), ("HTTP_QUEUE_ACCESS", "jobs")]))
+        .unwrap_err();
+    assert_eq!(error.problems(), ["HTTP_QUEUE_ACCESS: invalid grant \"jobs\", use company:queue"]);
+
+    std::fs::remove_dir_all(&dir).unwrap();
+}
+
+#[tokio::test]
+async fn test_graceful_shutdown() {
+    let state = MyAppState::new(
+        Arc::new(axum_sqs_lib::client_store::InMemoryClientStore::new()),
+        Arc::new(KeyRing::new(Keys::new(b"shutdown-secret"))),
+    );
+    let app = backend_server::init_app_with_state(state.clone()).route(
+        "/slow",
+        axum::routing::get(|| async {
+            tokio::time::sleep(Duration::from_millis(500)).await;
+            "done"
+        }),
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(backend_server::serve(
+        listener,
+        app,
+        state.shutdown.clone(),
+        Duration::from_secs(5),
+    ));
+    state.queue.create_queue("jobs", QueueConfig::default()).await.unwrap();
+    let consumer = Consumer::new("jobs")
+        .wait_time(Duration::from_secs(20))
+        .fallback(|| async {})
+        .spawn(state.clone());
+    let client = Client::new();
+    let response = client.get(format!("http://{}/health/ready", addr)).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+
+    // A request in flight when the shutdown begins still gets its response
+    let slow = tokio::spawn({
+        let client = client.clone();
+        async move { client.get(format!("http://{}/slow", addr)).send().await }
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    state.shutdown.trigger();
+    let (status, body) = axum_sqs_lib::health::ready(axum::Extension(state.clone())).await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body.status, "shutting_down");
+    let response = slow.await.unwrap().unwrap();
+    assert_eq!(response.text().await.unwrap(), "done");
+
+    // The server and the consumer's long poll end promptly
+    tokio::time::timeout(Duration::from_secs(2), server).await.unwrap().unwrap().unwrap();
+    tokio::time::timeout(Duration::from_secs(2), consumer).await.unwrap().unwrap();
+    assert!(Client::new().get(format!("http://{}/", addr)).send().await.is_err());
+
+    // Requests outlasting the drain timeout are dropped
+    let state = MyAppState::new(
+        Arc::new(axum_sqs_lib::client_store::InMemoryClientStore::new()),
+        Arc::new(KeyRing::new(Keys::new(b"shutdown-secret"))),
+    );
+    let app = axum::Router::new().route(
+        "/stuck",
+        axum::routing::get(std::future::pending::<&'static str>),
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(backend_server::serve(
+        listener,
+        app,
+        state.shutdown.clone(),
+        Duration::from_millis(200),
+    ));
+    let stuck = tokio::spawn(async move {
+        Client::new().get(format!("http://{}/stuck", addr)).send().await
+    });
+    tokio::time::sleep(Duration::from_millis(100)).await;
+    state.shutdown.trigger();
+    tokio::time::timeout(Duration::from_secs(2), server).await.unwrap().unwrap().unwrap();
+    stuck.abort();
+}
+
+#[tokio::test]
+async fn test_tls_http2_and_mutual_tls() {
+    use rustls::pki_types::{CertificateDer, pem::PemObject};
+
+    let fixtures = concat!(env!("CARGO_MANIFEST_DIR"), "/tests/fixtures");
+    let fixture = |name: &str| format!("{}/{}", fixtures, name);
+    let dir = std::env::temp_dir().join(format!("tls-{}", uuid::Uuid::new_v4()));
+    std::fs::create_dir_all(&dir).unwrap();
+    let (cert_path, key_path) = (dir.join("server.pem"), dir.join("server_key.pem"));
+    std::fs::copy(fixture("tls_server.pem"), &cert_path).unwrap();
+    std::fs::copy(fixture("tls_server_key.pem"), &key_path).unwrap();
+
+    let settings = TlsConfig {
+        cert_path: cert_path.clone(),
+        key_path: key_path.clone(),
+        client_ca_path: Some(fixture("tls_ca.pem").into()),
+        client_auth: ClientAuth::Optional,
+        reload_every: Duration::from_millis(200),
+    };
+    let tls = Arc::new(ServerTls::load(&settings).unwrap());
+    let watcher = tls.clone().watch();
+    let state = MyAppState::new(
+        Arc::new(axum_sqs_lib::client_store::InMemoryClientStore::new()),
+        Arc::new(KeyRing::new(Keys::new(b"tls-secret"))),
+    );
+    let app = backend_server::init_app_with_state(state.clone()).route(
+        "/whoami",
+        axum::routing::get(|certificate: ClientCertificate| async move { certificate.subject }),
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let server = tokio::spawn(backend_server::serve_tls(
+        TlsListener::new(listener, tls).unwrap(),
+        app,
+        state.shutdown.clone(),
+        Duration::from_secs(5),
+    ));
+
+    // Fresh clients, so that every request makes a new handshake
+    let ca = reqwest::Certificate::from_pem(&std::fs::read(fixture("tls_ca.pem")).unwrap()).unwrap();
+    let client = |with_identity: bool| {
+        let mut builder = Client::builder()
+            .use_rustls_tls()
+            .add_root_certificate(ca.clone())
+            .resolve("localhost", addr)
+            .tls_info(true);
+        if with_identity {
+            let mut pem = std::fs::read(fixture("tls_client.pem")).unwrap();
+            pem.extend(std::fs::read(fixture("tls_client_key.pem")).unwrap());
+            builder = builder.identity(reqwest::Identity::from_pem(&pem).unwrap());
+        }
+        builder.build().unwrap()
+    };
+    let url = |path: &str| format!("https://localhost:{}{}", addr.port(), path);
+
+    // HTTP/2 is negotiated, and the handler sees the client certificate's subject
+    let response = client(true).get(url("/whoami")).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.version(), reqwest::Version::HTTP_2);
+    assert_eq!(response.text().await.unwrap(), r"CN=billing-service,O=Acme\, Inc.,C=DE");
+
+    // Without a certificate the handshake succeeds but the extractor refuses
+    let response = client(false).get(url("/whoami")).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::FORBIDDEN);
+    assert!(response.headers().get("www-authenticate").is_none());
+    let problem: serde_json::Value = response.json().await.unwrap();
+    assert_eq!(problem["type"], "/problems/client-certificate-required");
+    let response = client(false).get(url("/")).send().await.unwrap();
+    assert_eq!(response.text().await.unwrap(), "Hello, World!");
+
+    // A renewed certificate is served without a restart
+    let peer_certificate = |response: &reqwest::Response| {
+        let info = response.extensions().get::<reqwest::tls::TlsInfo>().unwrap();
+        info.peer_certificate().unwrap().to_vec()
+    };
+    let original = CertificateDer::from_pem_file(fixture("tls_server.pem")).unwrap();
+    let renewed = CertificateDer::from_pem_file(fixture("tls_server_renewed.pem")).unwrap();
+    let response = client(true).get(url("/")).send().await.unwrap();
+    assert_eq!(peer_certificate(&response), original.as_ref());
+    std::fs::copy(fixture("tls_server_renewed_key.pem"), &key_path).unwrap();
+    std::fs::copy(fixture("tls_server_renewed.pem"), &cert_path).unwrap();
+    let mut served = Vec::new();
+    for _ in 0..50 {
+        tokio::time::sleep(Duration::from_millis(100)).await;
+        let response = client(true).get(url("/")).send().await.unwrap();
+        served = peer_certificate(&response);
+        if served != original.as_ref() {
+            break;
+        }
+    }
+    assert_eq!(served, renewed.as_ref());
+
+    // Broken files are ignored, keeping the renewed certificate
+    std::fs::write(&cert_path, "not a certificate").unwrap();
+    tokio::time::sleep(Duration::from_millis(500)).await;
+    let response = client(true).get(url("/")).send().await.unwrap();
+    assert_eq!(peer_certificate(&response), renewed.as_ref());
+
+    state.shutdown.trigger();
+    tokio::time::timeout(Duration::from_secs(2), server).await.unwrap().unwrap().unwrap();
+    watcher.abort();
+
+    // With client authentication required, handshakes without a certificate fail
+    let tls = Arc::new(
+        ServerTls::load(&TlsConfig {
+            cert_path: fixture("tls_server.pem").into(),
+            key_path: fixture("tls_server_key.pem").into(),
+            client_auth: ClientAuth::Required,
+            ..settings
+        })
+        .unwrap(),
+    );
+    let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+    let addr = listener.local_addr().unwrap();
+    let app = backend_server::init_app_with_state(MyAppState::new(
+        Arc::new(axum_sqs_lib::client_store::InMemoryClientStore::new()),
+        Arc::new(KeyRing::new(Keys::new(b"tls-secret"))),
+    ));
+    tokio::spawn(backend_server::serve_tls(
+        TlsListener::new(listener, tls).unwrap(),
+        app,
+        Shutdown::new(),
+        Duration::from_secs(5),
+    ));
+    let url = format!("https://localhost:{}/", addr.port());
+    let anonymous = Client::builder()
+        .use_rustls_tls()
+        .add_root_certificate(ca.clone())
+        .resolve("localhost", addr)
+        .build()
+        .unwrap();
+    assert!(anonymous.get(&url).send().await.is_err());
+
+    // A certificate without its key is reported when loading the configuration
+    let vars = vec![("JWT_SECRET".to_owned(), "x".to_owned())];
+    let args = vec!["--tls-cert-path".to_owned(), fixture("tls_server.pem")];
+    let error = Config::load_from(args, vars).unwrap_err();
+    assert!(error.problems().iter().any(|problem| problem.starts_with("tls.key_path")));
+}
+
+/// Health check whose outcome the test switches
+#[derive(Debug)]
+struct SwitchedCheck {
+    name: &'static str,
+    critical: bool,
+    healthy: Arc<std::sync::atomic::AtomicBool>,
+}
+
+#[async_trait::async_trait]
+impl HealthCheck for SwitchedCheck {
+    fn name(&self) -> &str {
+        self.name
+    }
+
+    fn critical(&self) -> bool {
+        self.critical
+    }
+
+    async fn check(&self) -> Result<(), Box<dyn std::error::Error + Send + Sync>> {
+        if self.healthy.load(std::sync::atomic::Ordering::SeqCst) {
+            Ok(())
+        } else {
+            Err(format!("{} is unreachable", self.name).into())
+        }
+    }
+}
+
+#[tokio::test]
+async fn test_liveness_and_readiness_checks() {
+    use std::sync::atomic::{AtomicBool, Ordering};
+
+    let outbox = Outbox::connect("sqlite::memory:").await.unwrap();
+    let cache = Arc::new(AtomicBool::new(true));
+    let payments = Arc::new(AtomicBool::new(true));
+    let state = MyAppState {
+        queue: Arc::new(SqliteMessageQueue::connect("sqlite::memory:").await.unwrap()),
+        outbox: Some(outbox.clone()),
+        health_checks: vec![
+            Arc::new(SwitchedCheck { name: "cache", critical: false, healthy: cache.clone() }),
+            Arc::new(SwitchedCheck { name: "payments", critical: true, healthy: payments.clone() }),
+        ],
+        ..MyAppState::new(
+            Arc::new(axum_sqs_lib::client_store::InMemoryClientStore::new()),
+            Arc::new(KeyRing::new(Keys::new(b"health-secret"))),
+        )
+    };
+    let (addr, client) = spawn_test_server_with_state(state.clone()).await;
+    let ready = || async {
+        let response = client.get(format!("http://{}/health/ready", addr)).send().await.unwrap();
+        (response.status(), response.json::<serde_json::Value>().await.unwrap())
+    };
+
+    let response = client.get(format!("http://{}/health/live", addr)).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert_eq!(response.json::<serde_json::Value>().await.unwrap(), json!({ "status": "alive" }));
+
+    // Every dependency is checked and reported with its latency
+    let (status, body) = ready().await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["status"], "ready");
+    for name in ["database", "queue", "key_ring", "cache", "payments"] {
+        assert_eq!(body["checks"][name]["status"], "up", "{}", name);
+        assert!(body["checks"][name]["latency_ms"].as_f64().unwrap() >= 0.0);
+        assert!(body["checks"][name].get("error").is_none());
+    }
+    assert_eq!(body["checks"]["cache"]["critical"], false);
+
+    // A failing non-critical check is reported without failing readiness
+    cache.store(false, Ordering::SeqCst);
+    let (status, body) = ready().await;
+    assert_eq!(status, StatusCode::OK);
+    assert_eq!(body["checks"]["cache"]["status"], "down");
+    assert_eq!(body["checks"]["cache"]["error"], "cache is unreachable");
+
+    // A failing critical check makes the server unready until it recovers
+    payments.store(false, Ordering::SeqCst);
+    let (status, body) = ready().await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body["status"], "not_ready");
+    assert_eq!(body["checks"]["payments"]["status"], "down");
+    payments.store(true, Ordering::SeqCst);
+    assert_eq!(ready().await.0, StatusCode::OK);
+
+    // Losing the database fails readiness while liveness is unaffected
+    outbox.pool().close().await;
+    let (status, body) = ready().await;
+    assert_eq!(status, StatusCode::SERVICE_UNAVAILABLE);
+    assert_eq!(body["checks"]["database"]["status"], "down");
+    assert_eq!(body["checks"]["queue"]["status"], "up");
+    let response = client.get(format!("http://{}/health/live", addr)).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+}
+
+#[tokio::test]
+async fn test_prometheus_metrics() {
+    let (addr, client) = spawn_test_server().await;
+    let get = |path: &str| client.get(format!("http://{}{}", addr, path)).send();
+
+    for user_id in ["1", "2", "3"] {
+        assert_eq!(get(&format!("/users/{}", user_id)).await.unwrap().status(), StatusCode::OK);
+    }
+    assert_eq!(get("/users/abc").await.unwrap().status(), StatusCode::BAD_REQUEST);
+    assert_eq!(get("/no/such/path").await.unwrap().status(), StatusCode::NOT_FOUND);
+    let response = client.post(format!("http://{}/protected", addr)).send().await.unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+    let response = client
+        .post(format!("http://{}/protected", addr))
+        .bearer_auth("not-a-token")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+
+    let response = get("/metrics").await.unwrap();
+    assert_eq!(response.status(), StatusCode::OK);
+    assert!(response.headers()["content-type"].to_str().unwrap().starts_with("text/plain; version=0.0.4"));
+    let text = response.text().await.unwrap();
+    let lines: Vec<&str> = text.lines().collect();
+    let has = |line: &str| lines.contains(&line);
+
+    // Requests are labelled by route template, never by raw path
+    assert!(has(r#"http_requests_total{method="GET",route="/users/{user_id}",status="200"} 3"#));
+    assert!(has(r#"http_requests_total{method="GET",route="/users/{user_id}",status="400"} 1"#));
+    assert!(has(r#"http_requests_total{method="GET",route="unmatched",status="404"} 1"#));
+    assert!(has(r#"http_requests_total{method="POST",route="/protected",status="401"} 2"#));
+    assert!(!text.contains("/users/1") && !text.contains("/no/such/path"));
+
+    // Histogram buckets are cumulative and end with the total count
+    assert!(has("# TYPE http_request_duration_seconds histogram"));
+    let labels = r#"method="GET",route="/users/{user_id}",status="200""#;
+    assert!(has(&format!(r#"http_request_duration_seconds_bucket{{{},le="+Inf"}} 3"#, labels)));
+    assert!(has(&format!("http_request_duration_seconds_count{{{}}} 3", labels)));
+    assert!(text.contains(&format!("http_request_duration_seconds_sum{{{}}} ", labels)));
+    let buckets: Vec<u64> = lines
+        .iter()
+        .filter(|line| line.starts_with(&format!("http_request_duration_seconds_bucket{{{},", labels)))
+        .map(|line| line.rsplit(' ').next().unwrap().parse().unwrap())
+        .collect();
+    assert_eq!(buckets.len(), 12);
+    assert!(buckets.windows(2).all(|pair| pair[0] <= pair[1]));
+
+    // The scrape itself is in flight while it renders; finished requests are not
+    assert!(has(r#"http_requests_in_flight{method="GET",route="/metrics"} 1"#));
+    assert!(has(r#"http_requests_in_flight{method="GET",route="/users/{user_id}"} 0"#));
+
+    // Both missing and invalid bearer tokens count as auth failures
+    assert!(has("# TYPE auth_failures_total counter"));
+    assert!(has(r#"auth_failures_total{reason="invalid-token"} 2"#));
+}